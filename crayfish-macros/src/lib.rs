@@ -0,0 +1,116 @@
+//! Procedural macros for writing native functions of the crayfish VM.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Error, FnArg, ItemFn, LitStr, ReturnType, Token, Type};
+
+
+/// The arguments of `#[native]`, only `name = "..."` for now.
+struct NativeArgs {
+    name: Option<LitStr>,
+}
+
+impl Parse for NativeArgs {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        if input.is_empty() {
+            return Ok(NativeArgs { name: None });
+        }
+        let key: syn::Ident = input.parse()?;
+        if key != "name" {
+            return Err(Error::new(key.span(), "expected `name = \"...\"`"));
+        }
+        input.parse::<Token![=]>()?;
+        let name = input.parse()?;
+        Ok(NativeArgs { name: Some(name) })
+    }
+}
+
+/// Whether a parameter is the `&mut NativeContext` of the call instead of an argument.
+fn is_context(ty: &Type) -> bool {
+    let Type::Reference(reference) = ty else {
+        return false;
+    };
+    let Type::Path(path) = reference.elem.as_ref() else {
+        return false;
+    };
+    reference.mutability.is_some() && path.path.segments.last().is_some_and(|segment| segment.ident == "NativeContext")
+}
+
+/// Generates the binding that lets bytecode call a Rust function.
+///
+/// The function is left as it is and a module of the same name is added next to it with
+/// `NAME`, the name the function is registered under, `call`, the native function itself,
+/// and `register`, which adds `call` to a `Module`.
+///
+/// Arguments are read from r8 onwards with `FromValue` and the result is returned in r0 with `IntoValue`,
+/// so numbers, `bool`, `String`, `Vec`s of those and `Value` can be passed both ways and an `Err`
+/// becomes the fault of the call. A `&mut NativeContext` parameter gets the context of the call
+/// and doesn't take up an argument register.
+///
+/// `#[native(name = "...")]` registers the function under another name.
+///
+/// The generated code refers to the VM as `crayfish_vm2`, which re-exports this macro as `crayfish_vm2::native`.
+#[proc_macro_attribute]
+pub fn native(attribute: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attribute as NativeArgs);
+    let function = parse_macro_input!(item as ItemFn);
+
+    if let Some(asyncness) = &function.sig.asyncness {
+        return Error::new(asyncness.span, "native functions can't be async").to_compile_error().into();
+    }
+    if !function.sig.generics.params.is_empty() {
+        return Error::new_spanned(&function.sig.generics, "native functions can't be generic").to_compile_error().into();
+    }
+
+    let ident = &function.sig.ident;
+    let visibility = &function.vis;
+    let name = args.name.unwrap_or_else(|| LitStr::new(&ident.to_string(), Span::call_site()));
+
+    let mut bindings = Vec::new();
+    let mut call_args = Vec::new();
+    let mut index = 0usize;
+    for (position, input) in function.sig.inputs.iter().enumerate() {
+        let FnArg::Typed(input) = input else {
+            return Error::new_spanned(input, "native functions can't take self").to_compile_error().into();
+        };
+        if is_context(&input.ty) {
+            call_args.push(quote! { &mut *context });
+            continue;
+        }
+        let binding = format_ident!("__argument_{}", position);
+        bindings.push(quote! { let #binding = context.get(#index)?; });
+        call_args.push(quote! { #binding });
+        index += 1;
+    }
+
+    let set_return = match &function.sig.output {
+        ReturnType::Default => quote! { super::#ident(#(#call_args),*); },
+        ReturnType::Type(..) => quote! {
+            let result = super::#ident(#(#call_args),*);
+            context.set_return_value(result)?;
+        },
+    };
+    let doc = format!("The native binding of [`{}`].", ident);
+
+    quote! {
+        #function
+
+        #[doc = #doc]
+        #visibility mod #ident {
+            /// The name the function is registered under.
+            pub const NAME: &str = #name;
+
+            pub fn call(context: &mut ::crayfish_vm2::machine::native::NativeContext) -> Result<::crayfish_vm2::machine::InstructionResult, ::crayfish_vm2::machine::Fault> {
+                #(#bindings)*
+                #set_return
+                Ok(::crayfish_vm2::machine::InstructionResult::Continue)
+            }
+
+            pub fn register(module: &mut ::crayfish_vm2::program::Module) {
+                module.add_native_function(NAME, call);
+            }
+        }
+    }.into()
+}
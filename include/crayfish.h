@@ -0,0 +1,165 @@
+#ifndef CRAYFISH_H
+#define CRAYFISH_H
+
+/* Generated from src/capi.rs by `cargo build --features header`, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define CRAYFISH_OK 0
+
+#define CRAYFISH_ERROR -1
+
+// The type of a `CrayfishValue`.
+typedef enum CrayfishKind {
+  CRAYFISH_KIND_I64,
+  CRAYFISH_KIND_U64,
+  CRAYFISH_KIND_F64,
+  CRAYFISH_KIND_STRING,
+} CrayfishKind;
+
+// The arguments and results of a call to a `CrayfishNative`, only valid during the call.
+typedef struct CrayfishContext CrayfishContext;
+
+// A VM, set up by registering natives and loading a module before the first call.
+typedef struct CrayfishVm CrayfishVm;
+
+// A native function written in C.
+// It returns `CRAYFISH_OK`, or something else to fault with the message given to `crayfish_context_set_error`.
+// It may be called from any thread the program spawns.
+typedef int32_t (*CrayfishNative)(struct CrayfishContext *context, void *user_data);
+
+// An argument or result, only the field for its kind is used.
+typedef struct CrayfishValue {
+  enum CrayfishKind kind;
+  int64_t i64;
+  uint64_t u64;
+  double f64;
+  // UTF-8 that isn't NUL terminated, `length` bytes long.
+  const char *string;
+  size_t length;
+} CrayfishValue;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Creates a VM with the standard library, free it with `crayfish_vm_free`.
+struct CrayfishVm *crayfish_vm_new(void);
+
+// # Safety
+// `vm` must come from `crayfish_vm_new` and not be used afterwards.
+void crayfish_vm_free(struct CrayfishVm *vm);
+
+// Registers `callback` as the native function at `path`, like `host::log`, for modules loaded afterwards to import.
+// Natives can only be registered before the first call.
+//
+// # Safety
+// `vm` must be a live VM and `path` a NUL terminated string.
+int32_t crayfish_vm_register_native(struct CrayfishVm *vm,
+                                    const char *path,
+                                    CrayfishNative callback,
+                                    void *user_data);
+
+// Loads a serialized bytecode module as the program, its native imports are looked up in the standard library
+// and the registered natives. A module can only be loaded before the first call.
+//
+// # Safety
+// `vm` must be a live VM and `bytes` point to `length` readable bytes.
+int32_t crayfish_vm_load_module(struct CrayfishVm *vm,
+                                const uint8_t *bytes,
+                                size_t length);
+
+// Calls the function at `path` with `count` arguments and stores what it returns in `result`
+// as the kind `result` has when it is passed in. A string result stays valid until the next call.
+// The first call verifies the program.
+//
+// # Safety
+// `vm` must be a live VM, `path` a NUL terminated string, `arguments` point to `count` values
+// and `result` to a value.
+int32_t crayfish_vm_call(struct CrayfishVm *vm,
+                         const char *path,
+                         const struct CrayfishValue *arguments,
+                         size_t count,
+                         struct CrayfishValue *result);
+
+// Describes the last error, or returns null if the last call succeeded.
+// The string stays valid until the next call on `vm`.
+//
+// # Safety
+// `vm` must be a live VM.
+const char *crayfish_vm_get_error(const struct CrayfishVm *vm);
+
+// The backtrace of the last fault, one function per line with the innermost last,
+// or null if the last error wasn't a fault in running code.
+//
+// # Safety
+// `vm` must be a live VM.
+const char *crayfish_vm_get_backtrace(const struct CrayfishVm *vm);
+
+// Stores argument `index` in `value`, or returns `CRAYFISH_ERROR` if it isn't a number.
+// The native can return that status to fault with the reason.
+//
+// # Safety
+// `context` must be the context a native was called with and `value` point to an `int64_t`.
+int32_t crayfish_context_get_i64(struct CrayfishContext *context, size_t index, int64_t *value);
+
+// Stores argument `index` in `value` like `crayfish_context_get_i64`, references are read as their handle.
+//
+// # Safety
+// `context` must be the context a native was called with and `value` point to a `uint64_t`.
+int32_t crayfish_context_get_u64(struct CrayfishContext *context,
+                                 size_t index,
+                                 uint64_t *value);
+
+// Stores argument `index` in `value` like `crayfish_context_get_i64`.
+//
+// # Safety
+// `context` must be the context a native was called with and `value` point to a `double`.
+int32_t crayfish_context_get_f64(struct CrayfishContext *context, size_t index, double *value);
+
+// The string argument `index` refers to, its length is stored in `length`.
+// The string isn't NUL terminated and is only valid until the native returns.
+// Returns null if the argument isn't a string.
+//
+// # Safety
+// `context` must be the context a native was called with and `length` point to a `size_t`.
+const char *crayfish_context_get_string(const struct CrayfishContext *context,
+                                        size_t index,
+                                        size_t *length);
+
+// # Safety
+// `context` must be the context a native was called with.
+void crayfish_context_set_i64(struct CrayfishContext *context, int64_t value);
+
+// # Safety
+// `context` must be the context a native was called with.
+void crayfish_context_set_u64(struct CrayfishContext *context, uint64_t value);
+
+// # Safety
+// `context` must be the context a native was called with.
+void crayfish_context_set_f64(struct CrayfishContext *context, double value);
+
+// Returns a copy of the `length` bytes of UTF-8 at `string` as a string on the heap.
+//
+// # Safety
+// `context` must be the context a native was called with and `string` point to `length` readable bytes.
+int32_t crayfish_context_set_string(struct CrayfishContext *context,
+                                    const char *string,
+                                    size_t length);
+
+// Sets the message of the fault the native faults with when it returns something other than `CRAYFISH_OK`.
+//
+// # Safety
+// `context` must be the context a native was called with and `message` a NUL terminated string.
+void crayfish_context_set_error(struct CrayfishContext *context,
+                                const char *message);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* CRAYFISH_H */
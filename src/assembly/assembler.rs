@@ -0,0 +1,1029 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::assembly::{parse_comparison_type, parse_condition, parse_register_type};
+use crate::instruction::{CallTarget, Condition, Immediate, Instruction, JumpTarget, RealInstruction, Source, Target};
+use crate::program::class::Class;
+use crate::program::function::{Function, FunctionPath, LabelTable, NativeRegistry};
+use crate::program::{Module, StringTablePath};
+
+
+/// An error produced while assembling source text.
+/// Lines and columns start at 1.
+#[derive(Debug)]
+pub struct AssemblyError {
+    pub file: Box<str>,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for AssemblyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for AssemblyError {}
+
+/// A column and a message, turned into an `AssemblyError` once the line is known.
+type ParseResult<T> = Result<T, (usize, String)>;
+
+/// Assembles `.cfasm` source text into a module.
+///
+/// A file is the root module. It contains `.module name` ... `.end` blocks for sub modules,
+/// `.function name` ... `.end` blocks for bytecode functions, `.native name` imports resolved
+/// against `natives`, `.string [name] "text"` string table entries and
+/// `.class name[, super::path]` ... `.end` blocks holding `.field name, type` and `.method name, function::path` lines.
+/// Super classes and methods are paths from the root module.
+/// Inside a function every line holds an optional `label:` followed by an instruction,
+/// for example `add.wrap r1:u64, 1u64` or `goto loop, lt`. Comments start with `;`.
+/// Floats without a decimal form, such as NaN, are written as their bits, for example `f32bits:0x7fc00000`.
+///
+/// The line and column of each instruction point at its mnemonic.
+pub fn assemble(source: &str, file_name: &str, natives: &NativeRegistry) -> Result<Module, AssemblyError> {
+    let mut assembler = Assembler {
+        file: file_name,
+        natives,
+        line: 0,
+        modules: vec![ModuleState::new(Module::default(), Vec::new(), 0, 0)],
+        function: None,
+        class: None,
+    };
+
+    for (index, line) in source.lines().enumerate() {
+        assembler.line = index + 1;
+        let tokens = tokenize(line).map_err(|error| assembler.error(error))?;
+        let finished = assembler.assemble_line(&tokens, line.chars().count() + 1)
+            .map_err(|error| assembler.error(error))?;
+        if let Some(function) = finished {
+            assembler.finish_function(function)?;
+        }
+    }
+
+    if let Some(function) = &assembler.function {
+        return Err(assembler.error_at(function.line, function.column, format!("function {} is missing .end", function.name)));
+    }
+    if let Some(class) = &assembler.class {
+        return Err(assembler.error_at(class.line, class.column, format!("class {} is missing .end", class.class.get_name())));
+    }
+    let module = assembler.modules.pop().expect("root module");
+    if !assembler.modules.is_empty() {
+        return Err(assembler.error_at(module.line, module.column, format!("module {} is missing .end", module.module.get_module_name())));
+    }
+    Ok(module.module)
+}
+
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Identifier(Box<str>),
+    Number(Box<str>),
+    String(Box<str>),
+    Comma,
+    Colon,
+    PathSeparator,
+    Plus,
+    Minus,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    column: usize,
+}
+
+fn is_identifier_start(c: char) -> bool {
+    c.is_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_identifier_continue(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.'
+}
+
+fn tokenize(line: &str) -> ParseResult<Vec<Token>> {
+    let chars = line.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let c = chars[index];
+        let column = index + 1;
+        let kind = match c {
+            ';' => break,
+            c if c.is_whitespace() => {
+                index += 1;
+                continue;
+            }
+            ',' => {
+                index += 1;
+                TokenKind::Comma
+            }
+            '+' => {
+                index += 1;
+                TokenKind::Plus
+            }
+            '-' => {
+                index += 1;
+                TokenKind::Minus
+            }
+            ':' => {
+                if chars.get(index + 1) == Some(&':') {
+                    index += 2;
+                    TokenKind::PathSeparator
+                } else {
+                    index += 1;
+                    TokenKind::Colon
+                }
+            }
+            '"' => {
+                let (string, end) = tokenize_string(&chars, index)?;
+                index = end;
+                TokenKind::String(string.into())
+            }
+            c if c.is_ascii_digit() => {
+                let start = index;
+                while index < chars.len() && is_identifier_continue(chars[index]) {
+                    index += 1;
+                }
+                TokenKind::Number(chars[start..index].iter().collect::<String>().into())
+            }
+            c if is_identifier_start(c) => {
+                let start = index;
+                while index < chars.len() && is_identifier_continue(chars[index]) {
+                    index += 1;
+                }
+                TokenKind::Identifier(chars[start..index].iter().collect::<String>().into())
+            }
+            c => return Err((column, format!("unexpected character '{}'", c))),
+        };
+        tokens.push(Token { kind, column });
+    }
+
+    Ok(tokens)
+}
+
+/// Reads a string literal starting at the opening quote.
+/// Returns the unescaped string and the index after the closing quote.
+fn tokenize_string(chars: &[char], start: usize) -> ParseResult<(String, usize)> {
+    let mut string = String::new();
+    let mut index = start + 1;
+    loop {
+        match chars.get(index) {
+            None => return Err((start + 1, "unterminated string literal".to_string())),
+            Some('"') => return Ok((string, index + 1)),
+            Some('\\') => {
+                let escape = chars.get(index + 1).ok_or((index + 1, "unterminated string literal".to_string()))?;
+                match escape {
+                    'n' => string.push('\n'),
+                    't' => string.push('\t'),
+                    'r' => string.push('\r'),
+                    '0' => string.push('\0'),
+                    '\\' => string.push('\\'),
+                    '"' => string.push('"'),
+                    '\'' => string.push('\''),
+                    'u' => {
+                        if chars.get(index + 2) != Some(&'{') {
+                            return Err((index + 1, "expected '{' after \\u".to_string()));
+                        }
+                        let end = chars[index..].iter().position(|c| *c == '}')
+                            .ok_or((index + 1, "unterminated unicode escape".to_string()))? + index;
+                        let digits = chars[index + 3..end].iter().collect::<String>();
+                        let character = u32::from_str_radix(&digits, 16).ok()
+                            .and_then(char::from_u32)
+                            .ok_or((index + 1, format!("invalid unicode escape '{}'", digits)))?;
+                        string.push(character);
+                        index = end + 1;
+                        continue;
+                    }
+                    c => return Err((index + 1, format!("unknown escape '\\{}'", c))),
+                }
+                index += 2;
+            }
+            Some(c) => {
+                string.push(*c);
+                index += 1;
+            }
+        }
+    }
+}
+
+
+struct Cursor<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    end_column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<&'a TokenKind> {
+        self.tokens.get(self.position).map(|token| &token.kind)
+    }
+
+    fn peek_nth(&self, n: usize) -> Option<&'a TokenKind> {
+        self.tokens.get(self.position + n).map(|token| &token.kind)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.position).map(|token| token.column).unwrap_or(self.end_column)
+    }
+
+    fn next(&mut self) -> Option<&'a TokenKind> {
+        let token = self.peek();
+        self.position += 1;
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.tokens.len()
+    }
+
+    fn expected<T>(&self, what: &str) -> ParseResult<T> {
+        match self.peek() {
+            Some(token) => Err((self.column(), format!("expected {}, found {}", what, describe(token)))),
+            None => Err((self.column(), format!("expected {}, found end of line", what))),
+        }
+    }
+
+    fn expect_end(&self) -> ParseResult<()> {
+        if self.at_end() {
+            Ok(())
+        } else {
+            self.expected("end of line")
+        }
+    }
+
+    fn expect_comma(&mut self) -> ParseResult<()> {
+        match self.peek() {
+            Some(TokenKind::Comma) => {
+                self.position += 1;
+                Ok(())
+            }
+            _ => self.expected("','"),
+        }
+    }
+
+    fn identifier(&mut self) -> ParseResult<&'a str> {
+        match self.peek() {
+            Some(TokenKind::Identifier(name)) => {
+                self.position += 1;
+                Ok(name)
+            }
+            _ => self.expected("identifier"),
+        }
+    }
+
+    fn string(&mut self) -> ParseResult<&'a str> {
+        match self.peek() {
+            Some(TokenKind::String(string)) => {
+                self.position += 1;
+                Ok(string)
+            }
+            _ => self.expected("string literal"),
+        }
+    }
+
+    fn unsigned(&mut self) -> ParseResult<usize> {
+        let column = self.column();
+        match self.peek() {
+            Some(TokenKind::Number(number)) => {
+                self.position += 1;
+                parse_integer(number)
+                    .and_then(|value| usize::try_from(value).ok())
+                    .ok_or((column, format!("invalid number '{}'", number)))
+            }
+            _ => self.expected("number"),
+        }
+    }
+
+    fn register(&mut self) -> ParseResult<(usize, crate::instruction::RegisterType)> {
+        let column = self.column();
+        let name = self.identifier()?;
+        let index = name.strip_prefix('r')
+            .and_then(|index| index.parse::<usize>().ok())
+            .ok_or((column, format!("expected register, found '{}'", name)))?;
+        match self.next() {
+            Some(TokenKind::Colon) => {}
+            _ => return Err((column, format!("register r{} is missing a type, for example r{}:u64", index, index))),
+        }
+        let column = self.column();
+        let type_name = self.identifier()?;
+        let register_type = parse_register_type(type_name)
+            .ok_or((column, format!("unknown register type '{}'", type_name)))?;
+        Ok((index, register_type))
+    }
+
+    fn target(&mut self) -> ParseResult<Target> {
+        let (index, register_type) = self.register()?;
+        Ok(Target(index, register_type))
+    }
+
+    fn source(&mut self) -> ParseResult<Source> {
+        match self.peek() {
+            Some(TokenKind::Identifier(name)) if is_float_bits(name) => Ok(Source::Immediate(self.immediate()?)),
+            Some(TokenKind::Identifier(_)) => {
+                let (index, register_type) = self.register()?;
+                Ok(Source::Register(index, register_type))
+            }
+            Some(TokenKind::Number(_)) | Some(TokenKind::Minus) => Ok(Source::Immediate(self.immediate()?)),
+            _ => self.expected("register or immediate"),
+        }
+    }
+
+    fn immediate(&mut self) -> ParseResult<Immediate> {
+        let negative = if let Some(TokenKind::Minus) = self.peek() {
+            self.position += 1;
+            true
+        } else {
+            false
+        };
+        if !negative {
+            if let Some(TokenKind::Identifier(name)) = self.peek() {
+                if is_float_bits(name) {
+                    return self.float_bits();
+                }
+            }
+        }
+        let column = self.column();
+        match self.next() {
+            Some(TokenKind::Number(number)) => parse_immediate(number, negative).map_err(|message| (column, message)),
+            _ => {
+                self.position -= 1;
+                self.expected("number")
+            }
+        }
+    }
+
+    /// Parses `f32bits:0x...` or `f64bits:0x...`.
+    fn float_bits(&mut self) -> ParseResult<Immediate> {
+        let kind = self.identifier()?;
+        match self.next() {
+            Some(TokenKind::Colon) => {}
+            _ => {
+                self.position -= 1;
+                return self.expected("':'");
+            }
+        }
+        let column = self.column();
+        let bits = match self.next() {
+            Some(TokenKind::Number(number)) => number,
+            _ => {
+                self.position -= 1;
+                return self.expected("number");
+            }
+        };
+        let invalid = || (column, format!("invalid {} literal '{}'", kind, bits));
+        let value = parse_integer(bits).ok_or_else(invalid)?;
+        let immediate = if kind == "f32bits" {
+            Immediate::F32(f32::from_bits(u32::try_from(value).map_err(|_| invalid())?))
+        } else {
+            Immediate::F64(f64::from_bits(u64::try_from(value).map_err(|_| invalid())?))
+        };
+        Ok(immediate)
+    }
+
+    fn condition(&mut self) -> ParseResult<Condition> {
+        let column = self.column();
+        let name = self.identifier()?;
+        parse_condition(name).ok_or((column, format!("unknown condition '{}'", name)))
+    }
+
+    /// Parses `, condition` if present, defaulting to `always`.
+    fn optional_condition(&mut self) -> ParseResult<Condition> {
+        if self.at_end() {
+            return Ok(Condition::Always);
+        }
+        self.expect_comma()?;
+        self.condition()
+    }
+
+    fn path(&mut self) -> ParseResult<Vec<Box<str>>> {
+        let mut path = vec![self.identifier()?.into()];
+        while let Some(TokenKind::PathSeparator) = self.peek() {
+            self.position += 1;
+            path.push(self.identifier()?.into());
+        }
+        Ok(path)
+    }
+}
+
+fn describe(token: &TokenKind) -> String {
+    match token {
+        TokenKind::Identifier(name) => format!("'{}'", name),
+        TokenKind::Number(number) => format!("'{}'", number),
+        TokenKind::String(string) => format!("{:?}", string),
+        TokenKind::Comma => "','".to_string(),
+        TokenKind::Colon => "':'".to_string(),
+        TokenKind::PathSeparator => "'::'".to_string(),
+        TokenKind::Plus => "'+'".to_string(),
+        TokenKind::Minus => "'-'".to_string(),
+    }
+}
+
+fn parse_integer(text: &str) -> Option<i128> {
+    let text = text.replace('_', "");
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text.as_str()),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i128::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse::<i128>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn is_float_bits(name: &str) -> bool {
+    name == "f32bits" || name == "f64bits"
+}
+
+const IMMEDIATE_SUFFIXES: [&str; 10] = ["u8", "u16", "u32", "u64", "i8", "i16", "i32", "i64", "f32", "f64"];
+
+fn parse_immediate(number: &str, negative: bool) -> Result<Immediate, String> {
+    let suffix = IMMEDIATE_SUFFIXES.iter()
+        .find(|suffix| number.ends_with(*suffix))
+        .ok_or(format!("immediate '{}' is missing a type suffix, for example {}u64", number, number))?;
+    let digits = format!("{}{}", if negative { "-" } else { "" }, &number[..number.len() - suffix.len()]);
+    let invalid = || format!("invalid {} literal '{}'", suffix, digits);
+
+    fn integer<T: TryFrom<i128>>(digits: &str) -> Option<T> {
+        parse_integer(digits).and_then(|value| T::try_from(value).ok())
+    }
+
+    let immediate = match *suffix {
+        "u8" => Immediate::U8(integer(&digits).ok_or_else(invalid)?),
+        "u16" => Immediate::U16(integer(&digits).ok_or_else(invalid)?),
+        "u32" => Immediate::U32(integer(&digits).ok_or_else(invalid)?),
+        "u64" => Immediate::U64(integer(&digits).ok_or_else(invalid)?),
+        "i8" => Immediate::I8(integer(&digits).ok_or_else(invalid)?),
+        "i16" => Immediate::I16(integer(&digits).ok_or_else(invalid)?),
+        "i32" => Immediate::I32(integer(&digits).ok_or_else(invalid)?),
+        "i64" => Immediate::I64(integer(&digits).ok_or_else(invalid)?),
+        "f32" => Immediate::F32(digits.replace('_', "").parse().map_err(|_| invalid())?),
+        "f64" => Immediate::F64(digits.replace('_', "").parse().map_err(|_| invalid())?),
+        _ => unreachable!("Unknown immediate suffix"),
+    };
+    Ok(immediate)
+}
+
+
+/// The operand of `getstr`.
+enum StringOperand<'a> {
+    /// A string literal, interned into the current module's string table.
+    Literal(&'a str),
+    /// A string declared with `.string name "text"` in the current module.
+    Named(&'a str),
+    /// An explicit string table path from the root module followed by an index.
+    Explicit(Vec<Box<str>>, u64),
+}
+
+struct ModuleState {
+    module: Module,
+    path: Vec<Box<str>>,
+    strings: Vec<Box<str>>,
+    string_names: HashMap<Box<str>, u64>,
+    line: usize,
+    column: usize,
+}
+
+impl ModuleState {
+    fn new(module: Module, path: Vec<Box<str>>, line: usize, column: usize) -> Self {
+        ModuleState {
+            module,
+            path,
+            strings: Vec::new(),
+            string_names: HashMap::new(),
+            line,
+            column,
+        }
+    }
+
+    fn add_string(&mut self, string: &str) -> u64 {
+        self.strings.push(string.into());
+        self.module.add_string(&StringTablePath { path: Box::new([]) }, string)
+    }
+}
+
+struct FunctionState {
+    name: Box<str>,
+    line: usize,
+    column: usize,
+    instructions: Vec<Instruction>,
+    labels: LabelTable,
+    /// Labels used by jumps, checked once the whole function has been read.
+    /// Holds the label and the line and column of the reference.
+    label_references: Vec<(Box<str>, usize, usize)>,
+}
+
+struct ClassState {
+    class: Class,
+    line: usize,
+    column: usize,
+}
+
+struct Assembler<'a> {
+    file: &'a str,
+    natives: &'a NativeRegistry,
+    line: usize,
+    modules: Vec<ModuleState>,
+    function: Option<FunctionState>,
+    class: Option<ClassState>,
+}
+
+impl<'a> Assembler<'a> {
+    fn error(&self, (column, message): (usize, String)) -> AssemblyError {
+        self.error_at(self.line, column, message)
+    }
+
+    fn error_at(&self, line: usize, column: usize, message: String) -> AssemblyError {
+        AssemblyError {
+            file: self.file.into(),
+            line,
+            column,
+            message,
+        }
+    }
+
+    fn current_module(&mut self) -> &mut ModuleState {
+        self.modules.last_mut().expect("root module")
+    }
+
+    /// Assembles one line and returns the function closed by it, if any.
+    fn assemble_line(&mut self, tokens: &[Token], end_column: usize) -> ParseResult<Option<FunctionState>> {
+        let mut cursor = Cursor { tokens, position: 0, end_column };
+
+        while let (Some(TokenKind::Identifier(name)), Some(TokenKind::Colon)) = (cursor.peek(), cursor.peek_nth(1)) {
+            if name.starts_with('.') {
+                break;
+            }
+            let column = cursor.column();
+            let function = self.function.as_mut().ok_or((column, format!("label {} is outside of a function", name)))?;
+            if function.labels.insert(name.clone(), function.instructions.len()).is_some() {
+                return Err((column, format!("label {} is defined more than once", name)));
+            }
+            cursor.position += 2;
+        }
+
+        if cursor.at_end() {
+            return Ok(None);
+        }
+
+        let column = cursor.column();
+        let name = cursor.identifier()?;
+        if name.starts_with('.') {
+            self.assemble_directive(name, column, &mut cursor)
+        } else {
+            let instruction = self.assemble_instruction(name, column, &mut cursor)?;
+            cursor.expect_end()?;
+            let line = self.line;
+            let function = self.function.as_mut().expect("checked by assemble_instruction");
+            function.instructions.push(Instruction::new(instruction, line, column));
+            Ok(None)
+        }
+    }
+
+    fn assemble_directive(&mut self, directive: &str, column: usize, cursor: &mut Cursor) -> ParseResult<Option<FunctionState>> {
+        if self.function.is_some() && directive != ".end" {
+            return Err((column, format!("{} is not allowed inside a function", directive)));
+        }
+        match (&self.class, directive) {
+            (Some(_), ".field" | ".method" | ".end") | (None, _) => {}
+            (Some(_), _) => return Err((column, format!("{} is not allowed inside a class", directive))),
+        }
+
+        match directive {
+            ".module" => {
+                let name = cursor.identifier()?;
+                cursor.expect_end()?;
+                let mut path = self.current_module().path.clone();
+                path.push(name.into());
+                let module = Module::new(name, HashMap::new(), Vec::new(), HashMap::new());
+                self.modules.push(ModuleState::new(module, path, self.line, column));
+            }
+            ".function" => {
+                let name_column = cursor.column();
+                let name = cursor.identifier()?;
+                cursor.expect_end()?;
+                if self.current_module().module.get_functions().contains_key(name) {
+                    return Err((name_column, format!("function {} is defined more than once", name)));
+                }
+                self.function = Some(FunctionState {
+                    name: name.into(),
+                    line: self.line,
+                    column,
+                    instructions: Vec::new(),
+                    labels: LabelTable::new(),
+                    label_references: Vec::new(),
+                });
+            }
+            ".native" => {
+                let name_column = cursor.column();
+                let name = cursor.identifier()?;
+                cursor.expect_end()?;
+                let module = self.current_module();
+                let mut path = module.path.clone();
+                path.push(name.into());
+                let path = FunctionPath { path: path.into_boxed_slice() };
+                let native = self.natives.get(&path).ok_or((name_column, format!("unknown native function {}", path)))?;
+                let module = self.current_module();
+                if module.module.get_functions().contains_key(name) {
+                    return Err((name_column, format!("function {} is defined more than once", name)));
+                }
+                module.module.add_shared_native_function(name, native);
+            }
+            ".string" => {
+                let name = match cursor.peek() {
+                    Some(TokenKind::Identifier(_)) => Some((cursor.column(), cursor.identifier()?)),
+                    _ => None,
+                };
+                let string = cursor.string()?;
+                cursor.expect_end()?;
+                let module = self.current_module();
+                let index = module.add_string(string);
+                if let Some((name_column, name)) = name {
+                    if module.string_names.insert(name.into(), index).is_some() {
+                        return Err((name_column, format!("string {} is defined more than once", name)));
+                    }
+                }
+            }
+            ".class" => {
+                let name_column = cursor.column();
+                let name = cursor.identifier()?;
+                let super_class = if cursor.at_end() {
+                    None
+                } else {
+                    cursor.expect_comma()?;
+                    Some(FunctionPath { path: cursor.path()?.into_boxed_slice() })
+                };
+                cursor.expect_end()?;
+                if self.current_module().module.get_classes().contains_key(name) {
+                    return Err((name_column, format!("class {} is defined more than once", name)));
+                }
+                self.class = Some(ClassState {
+                    class: Class::new(name, super_class),
+                    line: self.line,
+                    column,
+                });
+            }
+            ".field" => {
+                let class = self.class.as_mut().ok_or((column, ".field is only allowed inside a class".to_string()))?;
+                let name_column = cursor.column();
+                let name = cursor.identifier()?;
+                cursor.expect_comma()?;
+                let type_column = cursor.column();
+                let type_name = cursor.identifier()?;
+                let field_type = parse_register_type(type_name)
+                    .ok_or((type_column, format!("unknown field type '{}'", type_name)))?;
+                cursor.expect_end()?;
+                if class.class.get_fields().iter().any(|(field, _)| field.as_ref() == name) {
+                    return Err((name_column, format!("field {} is defined more than once", name)));
+                }
+                class.class.add_field(name, field_type);
+            }
+            ".method" => {
+                let class = self.class.as_mut().ok_or((column, ".method is only allowed inside a class".to_string()))?;
+                let name_column = cursor.column();
+                let name = cursor.identifier()?;
+                cursor.expect_comma()?;
+                let function = FunctionPath { path: cursor.path()?.into_boxed_slice() };
+                cursor.expect_end()?;
+                if class.class.get_methods().iter().any(|(method, _)| method.as_ref() == name) {
+                    return Err((name_column, format!("method {} is defined more than once", name)));
+                }
+                class.class.add_method(name, function);
+            }
+            ".end" => {
+                cursor.expect_end()?;
+                if let Some(function) = self.function.take() {
+                    return Ok(Some(function));
+                } else if let Some(class) = self.class.take() {
+                    self.current_module().module.add_class(class.class);
+                } else if self.modules.len() > 1 {
+                    let module = self.modules.pop().expect("checked length");
+                    let parent = self.current_module();
+                    if parent.module.get_sub_modules().contains_key(module.module.get_module_name()) {
+                        return Err((module.column, format!("module {} is defined more than once", module.module.get_module_name())));
+                    }
+                    parent.module.add_sub_module(module.module);
+                } else {
+                    return Err((column, ".end without a matching .module, .function or .class".to_string()));
+                }
+            }
+            _ => return Err((column, format!("unknown directive {}", directive))),
+        }
+        Ok(None)
+    }
+
+    fn finish_function(&mut self, function: FunctionState) -> Result<(), AssemblyError> {
+        for (label, line, column) in function.label_references.iter() {
+            if !function.labels.contains_key(label) {
+                return Err(self.error_at(*line, *column, format!("unknown label {}", label)));
+            }
+        }
+        let bytecode = Function::ByteCode(Arc::from(function.instructions), Arc::new(function.labels));
+        self.current_module().module.add_function(&function.name, bytecode)
+            .map_err(|fault| self.error_at(function.line, function.column, format!("{:?}", fault)))
+    }
+
+    fn jump_target(&mut self, cursor: &mut Cursor) -> ParseResult<JumpTarget> {
+        let column = cursor.column();
+        match cursor.peek() {
+            Some(TokenKind::Plus) | Some(TokenKind::Minus) => {
+                let negative = cursor.next() == Some(&TokenKind::Minus);
+                let offset = cursor.unsigned()? as isize;
+                Ok(JumpTarget::Relative(if negative { -offset } else { offset }))
+            }
+            Some(TokenKind::Number(_)) => Ok(JumpTarget::Absolute(cursor.unsigned()?)),
+            Some(TokenKind::Identifier(label)) => {
+                cursor.position += 1;
+                let line = self.line;
+                let function = self.function.as_mut().expect("checked by assemble_instruction");
+                function.label_references.push((label.clone(), line, column));
+                Ok(JumpTarget::Label(label.clone()))
+            }
+            _ => cursor.expected("jump target"),
+        }
+    }
+
+    fn call_target(&mut self, cursor: &mut Cursor) -> ParseResult<CallTarget> {
+        let special = match (cursor.peek(), cursor.peek_nth(1)) {
+            (Some(TokenKind::Identifier(name)), Some(next)) if *next != TokenKind::Comma && *next != TokenKind::PathSeparator => Some(name.as_ref()),
+            _ => None,
+        };
+        match special {
+            Some("vtable") => {
+                cursor.position += 1;
+                let object = cursor.source()?;
+                cursor.expect_comma()?;
+                Ok(CallTarget::Vtable(object, cursor.source()?))
+            }
+            Some("cont") => {
+                cursor.position += 1;
+                Ok(CallTarget::Continuation(cursor.source()?))
+            }
+            Some("closure") => {
+                cursor.position += 1;
+                Ok(CallTarget::Closure(cursor.source()?))
+            }
+            _ => Ok(CallTarget::Label(FunctionPath { path: cursor.path()?.into_boxed_slice() })),
+        }
+    }
+
+    fn string_operand<'t>(&self, cursor: &mut Cursor<'t>) -> ParseResult<StringOperand<'t>> {
+        match (cursor.peek(), cursor.peek_nth(1)) {
+            (Some(TokenKind::String(_)), _) => Ok(StringOperand::Literal(cursor.string()?)),
+            (Some(TokenKind::Number(_)), _) => Ok(StringOperand::Explicit(Vec::new(), cursor.unsigned()? as u64)),
+            (Some(TokenKind::Identifier(_)), Some(TokenKind::PathSeparator)) => {
+                let mut path = Vec::new();
+                loop {
+                    path.push(cursor.identifier()?.into());
+                    match cursor.next() {
+                        Some(TokenKind::PathSeparator) => {}
+                        _ => {
+                            cursor.position -= 1;
+                            return cursor.expected("'::'");
+                        }
+                    }
+                    if let Some(TokenKind::Number(_)) = cursor.peek() {
+                        return Ok(StringOperand::Explicit(path, cursor.unsigned()? as u64));
+                    }
+                }
+            }
+            (Some(TokenKind::Identifier(_)), _) => Ok(StringOperand::Named(cursor.identifier()?)),
+            _ => cursor.expected("string"),
+        }
+    }
+
+    fn resolve_string(&mut self, operand: StringOperand, column: usize) -> ParseResult<(StringTablePath, u64)> {
+        let module = self.current_module();
+        match operand {
+            StringOperand::Literal(string) => {
+                let index = match module.strings.iter().position(|existing| existing.as_ref() == string) {
+                    Some(index) => index as u64,
+                    None => module.add_string(string),
+                };
+                Ok((StringTablePath { path: module.path.clone().into_boxed_slice() }, index))
+            }
+            StringOperand::Named(name) => {
+                let index = *module.string_names.get(name).ok_or((column, format!("unknown string {}", name)))?;
+                Ok((StringTablePath { path: module.path.clone().into_boxed_slice() }, index))
+            }
+            StringOperand::Explicit(path, index) => Ok((StringTablePath { path: path.into_boxed_slice() }, index)),
+        }
+    }
+
+    fn assemble_instruction(&mut self, mnemonic: &str, column: usize, cursor: &mut Cursor) -> ParseResult<RealInstruction> {
+        if self.function.is_none() {
+            return Err((column, format!("instruction {} is outside of a function", mnemonic)));
+        }
+
+        let mut parts = mnemonic.split('.');
+        let base = parts.next().unwrap_or_default();
+        let mut can_wrap = false;
+        let mut use_carry = false;
+        let mut one_shot = false;
+        for modifier in parts {
+            match (base, modifier) {
+                ("add" | "sub" | "mul" | "div" | "mod", "wrap") => can_wrap = true,
+                ("add" | "sub", "carry") => use_carry = true,
+                ("mkcont" | "handle", "once") => one_shot = true,
+                _ => return Err((column, format!("unknown modifier .{} for {}", modifier, base))),
+            }
+        }
+
+        use RealInstruction::*;
+        let instruction = match base {
+            "halt" => Halt,
+            "nop" => NoOp,
+            "load" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                Load(target, cursor.source()?)
+            }
+            "store" => {
+                let reference = cursor.source()?;
+                cursor.expect_comma()?;
+                let offset = cursor.source()?;
+                cursor.expect_comma()?;
+                Store(reference, offset, cursor.source()?)
+            }
+            "stackderef" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let stack_level = cursor.source()?;
+                cursor.expect_comma()?;
+                StackDeref(target, stack_level, cursor.source()?)
+            }
+            "stackstore" => {
+                let stack_level = cursor.source()?;
+                cursor.expect_comma()?;
+                let offset = cursor.source()?;
+                cursor.expect_comma()?;
+                StackStore(stack_level, offset, cursor.source()?)
+            }
+            "add" | "sub" | "mul" | "div" | "mod" | "and" | "or" | "xor" | "shl" | "shr" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let source = cursor.source()?;
+                match base {
+                    "add" => Add(target, source, can_wrap, use_carry),
+                    "sub" => Sub(target, source, can_wrap, use_carry),
+                    "mul" => Mul(target, source, can_wrap),
+                    "div" => Div(target, source, can_wrap),
+                    "mod" => Mod(target, source, can_wrap),
+                    "and" => And(target, source),
+                    "or" => Or(target, source),
+                    "xor" => Xor(target, source),
+                    "shl" => ShiftLeft(target, source),
+                    _ => ShiftRight(target, source),
+                }
+            }
+            "not" => Not(cursor.target()?),
+            "goto" => {
+                let jump_target = self.jump_target(cursor)?;
+                Goto(jump_target, cursor.optional_condition()?)
+            }
+            "cmp" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let source = cursor.source()?;
+                cursor.expect_comma()?;
+                let comparison_column = cursor.column();
+                let name = cursor.identifier()?;
+                let comparison_type = parse_comparison_type(name)
+                    .ok_or((comparison_column, format!("unknown comparison '{}'", name)))?;
+                Compare(target, source, comparison_type)
+            }
+            "push" => Push(cursor.source()?),
+            "pop" => Pop(cursor.target()?),
+            "call" => {
+                let call_target = self.call_target(cursor)?;
+                Call(call_target, cursor.optional_condition()?)
+            }
+            "ret" => {
+                if cursor.at_end() {
+                    Return(Condition::Always)
+                } else {
+                    Return(cursor.condition()?)
+                }
+            }
+            "mkcont" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                CreateContinuation(target, cursor.path()?.join("::").into(), one_shot)
+            }
+            "newobj" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                CreateObject(target, FunctionPath { path: cursor.path()?.into_boxed_slice() })
+            }
+            "accessobj" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let object = cursor.source()?;
+                cursor.expect_comma()?;
+                AccessObject(target, object, cursor.source()?)
+            }
+            "storeobj" => {
+                let object = cursor.source()?;
+                cursor.expect_comma()?;
+                let field = cursor.source()?;
+                cursor.expect_comma()?;
+                StoreObject(object, field, cursor.source()?)
+            }
+            "newlist" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                CreateList(target, cursor.source()?)
+            }
+            "listlen" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                ListLength(target, cursor.source()?)
+            }
+            "listget" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let list = cursor.source()?;
+                cursor.expect_comma()?;
+                ListAccess(target, list, cursor.source()?)
+            }
+            "listset" => {
+                let list = cursor.source()?;
+                cursor.expect_comma()?;
+                let index = cursor.source()?;
+                cursor.expect_comma()?;
+                ListStore(list, index, cursor.source()?)
+            }
+            "getstr" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let string_column = cursor.column();
+                let operand = self.string_operand(cursor)?;
+                let (path, index) = self.resolve_string(operand, string_column)?;
+                GetStringRef(target, path, index)
+            }
+            "handle" => {
+                let effect = cursor.path()?.join("::");
+                cursor.expect_comma()?;
+                let jump_target = self.jump_target(cursor)?;
+                cursor.expect_comma()?;
+                InstallHandler(effect.into(), jump_target, cursor.target()?, one_shot)
+            }
+            "unhandle" => RemoveHandler(cursor.path()?.join("::").into()),
+            "perform" => Perform(cursor.path()?.join("::").into()),
+            "pushprompt" => PushPrompt(cursor.path()?.join("::").into()),
+            "popprompt" => PopPrompt(cursor.path()?.join("::").into()),
+            "mkclosure" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let function = FunctionPath { path: cursor.path()?.into_boxed_slice() };
+                let mut captures = Vec::new();
+                while !cursor.at_end() {
+                    cursor.expect_comma()?;
+                    captures.push(cursor.source()?);
+                }
+                CreateClosure(target, function, captures)
+            }
+            "getcap" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                LoadCapture(target, cursor.source()?)
+            }
+            "cas" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let reference = cursor.source()?;
+                cursor.expect_comma()?;
+                let index = cursor.source()?;
+                cursor.expect_comma()?;
+                let expected = cursor.source()?;
+                cursor.expect_comma()?;
+                AtomicCompareExchange(target, reference, index, expected, cursor.source()?)
+            }
+            "fetchadd" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let reference = cursor.source()?;
+                cursor.expect_comma()?;
+                let index = cursor.source()?;
+                cursor.expect_comma()?;
+                AtomicFetchAdd(target, reference, index, cursor.source()?)
+            }
+            "xchg" => {
+                let target = cursor.target()?;
+                cursor.expect_comma()?;
+                let reference = cursor.source()?;
+                cursor.expect_comma()?;
+                let index = cursor.source()?;
+                cursor.expect_comma()?;
+                AtomicExchange(target, reference, index, cursor.source()?)
+            }
+            _ => return Err((column, format!("unknown instruction {}", mnemonic))),
+        };
+        Ok(instruction)
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use crate::assembly::{comparison_type_name, condition_name, register_type_name};
+use crate::instruction::{CallTarget, Condition, Immediate, Instruction, JumpTarget, RealInstruction, Source, Target};
+use crate::program::function::{Function, LabelTable};
+use crate::program::{Module, StringTablePath};
+
+
+#[derive(Debug, Clone, Default)]
+pub struct DisassemblyOptions {
+    /// Appends the line and column of each instruction as a comment.
+    pub source_positions: bool,
+}
+
+/// Prints a module and its sub modules as assembly text that `assemble` accepts.
+/// Jump targets inside a function get synthesized labels.
+/// String references into the module's own string table are printed as string literals.
+pub fn disassemble(module: &Module, options: &DisassemblyOptions) -> String {
+    let mut disassembler = Disassembler {
+        options,
+        output: String::new(),
+    };
+    disassembler.module(module, &mut Vec::new(), 0);
+    disassembler.output
+}
+
+/// Prints a single instruction without any function context.
+/// Jumps are printed as raw offsets and string references as explicit paths.
+pub fn disassemble_instruction(instruction: &RealInstruction) -> String {
+    InstructionContext { program_counter: 0, labels: &HashMap::new(), module: None, path: &[] }
+        .instruction(instruction)
+}
+
+
+struct Disassembler<'a> {
+    options: &'a DisassemblyOptions,
+    output: String,
+}
+
+impl<'a> Disassembler<'a> {
+    fn line(&mut self, depth: usize, text: &str) {
+        for _ in 0..depth {
+            self.output.push_str("    ");
+        }
+        self.output.push_str(text);
+        self.output.push('\n');
+    }
+
+    fn module(&mut self, module: &Module, path: &mut Vec<Box<str>>, depth: usize) {
+        for string in module.get_string_table().iter() {
+            self.line(depth, &format!(".string {:?}", string));
+        }
+
+        let mut functions = module.get_functions().iter().collect::<Vec<_>>();
+        functions.sort_by_key(|(name, _)| *name);
+        for (name, function) in functions.iter() {
+            if let Function::Native(_) = function {
+                self.line(depth, &format!(".native {}", name));
+            }
+        }
+
+        for (name, function) in functions.iter() {
+            if let Function::ByteCode(instructions, labels) = function {
+                self.output.push('\n');
+                self.line(depth, &format!(".function {}", name));
+                self.function(instructions, labels, module, path, depth);
+                self.line(depth, ".end");
+            }
+        }
+
+        let mut classes = module.get_classes().values().collect::<Vec<_>>();
+        classes.sort_by_key(|class| class.get_name());
+        for class in classes {
+            self.output.push('\n');
+            match class.get_super_class() {
+                Some(super_class) => self.line(depth, &format!(".class {}, {}", class.get_name(), super_class)),
+                None => self.line(depth, &format!(".class {}", class.get_name())),
+            }
+            for (name, field_type) in class.get_fields() {
+                self.line(depth + 1, &format!(".field {}, {}", name, register_type_name(*field_type)));
+            }
+            for (name, function) in class.get_methods() {
+                self.line(depth + 1, &format!(".method {}, {}", name, function));
+            }
+            self.line(depth, ".end");
+        }
+
+        let mut sub_modules = module.get_sub_modules().iter().collect::<Vec<_>>();
+        sub_modules.sort_by_key(|(name, _)| *name);
+        for (name, sub_module) in sub_modules {
+            self.output.push('\n');
+            self.line(depth, &format!(".module {}", name));
+            path.push(name.clone());
+            self.module(sub_module, path, depth + 1);
+            path.pop();
+            self.line(depth, ".end");
+        }
+    }
+
+    fn function(&mut self, instructions: &[Instruction], label_table: &LabelTable, module: &Module, path: &[Box<str>], depth: usize) {
+        // Every label of the function is printed, jumps use the first one in name order.
+        let mut defined = BTreeMap::<usize, Vec<String>>::new();
+        let mut sorted_labels = label_table.iter().collect::<Vec<_>>();
+        sorted_labels.sort_by_key(|(label, _)| *label);
+        for (label, address) in sorted_labels {
+            defined.entry(*address).or_default().push(label.to_string());
+        }
+
+        let mut targets = BTreeSet::new();
+        for (program_counter, instruction) in instructions.iter().enumerate() {
+            if let Some(jump_target) = instruction.get_jump_target() {
+                if let Some(address) = jump_address(jump_target, program_counter, instructions.len()) {
+                    targets.insert(address);
+                }
+            }
+        }
+        let mut next_label = 0;
+        for address in targets {
+            if defined.contains_key(&address) {
+                continue;
+            }
+            let mut label = format!("L{}", next_label);
+            while label_table.contains_key(label.as_str()) {
+                next_label += 1;
+                label = format!("L{}", next_label);
+            }
+            next_label += 1;
+            defined.insert(address, vec![label]);
+        }
+        let labels = defined.iter()
+            .map(|(address, names)| (*address, names[0].clone()))
+            .collect::<HashMap<usize, String>>();
+
+        for (program_counter, instruction) in instructions.iter().enumerate() {
+            for label in defined.get(&program_counter).into_iter().flatten() {
+                self.line(depth, &format!("{}:", label));
+            }
+            let context = InstructionContext { program_counter, labels: &labels, module: Some(module), path };
+            let mut text = context.instruction(&instruction.instruction);
+            if self.options.source_positions {
+                text = format!("{:<40} ; {}:{}", text, instruction.line, instruction.column);
+            }
+            self.line(depth + 1, &text);
+        }
+        for label in defined.range(instructions.len()..).flat_map(|(_, names)| names) {
+            self.line(depth, &format!("{}:", label));
+        }
+    }
+}
+
+/// The absolute address a jump lands on, if it is inside the function or just past its end.
+fn jump_address(jump_target: &JumpTarget, program_counter: usize, length: usize) -> Option<usize> {
+    let address = match jump_target {
+        JumpTarget::Relative(offset) => program_counter.checked_add_signed(*offset)?,
+        JumpTarget::Absolute(address) => *address,
+        JumpTarget::Label(_) => return None,
+    };
+    if address <= length {
+        Some(address)
+    } else {
+        None
+    }
+}
+
+
+struct InstructionContext<'a> {
+    program_counter: usize,
+    labels: &'a HashMap<usize, String>,
+    module: Option<&'a Module>,
+    path: &'a [Box<str>],
+}
+
+impl<'a> InstructionContext<'a> {
+    fn target(&self, target: &Target) -> String {
+        format!("r{}:{}", target.0, register_type_name(target.1))
+    }
+
+    fn source(&self, source: &Source) -> String {
+        match source {
+            Source::Register(index, register_type) => format!("r{}:{}", index, register_type_name(*register_type)),
+            Source::Immediate(immediate) => self.immediate(immediate),
+        }
+    }
+
+    fn immediate(&self, immediate: &Immediate) -> String {
+        match immediate {
+            Immediate::U8(value) => format!("{}u8", value),
+            Immediate::U16(value) => format!("{}u16", value),
+            Immediate::U32(value) => format!("{}u32", value),
+            Immediate::U64(value) => format!("{}u64", value),
+            Immediate::I8(value) => format!("{}i8", value),
+            Immediate::I16(value) => format!("{}i16", value),
+            Immediate::I32(value) => format!("{}i32", value),
+            Immediate::I64(value) => format!("{}i64", value),
+            Immediate::F32(value) if !value.is_finite() => format!("f32bits:{:#010x}", value.to_bits()),
+            Immediate::F64(value) if !value.is_finite() => format!("f64bits:{:#018x}", value.to_bits()),
+            Immediate::F32(value) => format!("{}f32", value),
+            Immediate::F64(value) => format!("{}f64", value),
+        }
+    }
+
+    fn jump_target(&self, jump_target: &JumpTarget) -> String {
+        let label = jump_address(jump_target, self.program_counter, usize::MAX)
+            .and_then(|address| self.labels.get(&address));
+        if let Some(label) = label {
+            return label.clone();
+        }
+        match jump_target {
+            JumpTarget::Relative(offset) if *offset < 0 => format!("{}", offset),
+            JumpTarget::Relative(offset) => format!("+{}", offset),
+            JumpTarget::Absolute(address) => format!("{}", address),
+            JumpTarget::Label(label) => label.to_string(),
+        }
+    }
+
+    fn with_condition(&self, text: String, condition: &Condition) -> String {
+        match condition {
+            Condition::Always => text,
+            condition => format!("{}, {}", text, condition_name(condition)),
+        }
+    }
+
+    fn call_target(&self, call_target: &CallTarget) -> String {
+        match call_target {
+            CallTarget::Label(path) => path.to_string(),
+            CallTarget::Vtable(object, index) => format!("vtable {}, {}", self.source(object), self.source(index)),
+            CallTarget::Continuation(continuation) => format!("cont {}", self.source(continuation)),
+            CallTarget::Closure(closure) => format!("closure {}", self.source(closure)),
+        }
+    }
+
+    fn string(&self, path: &StringTablePath, index: u64) -> String {
+        if let Some(module) = self.module {
+            let string_table = module.get_string_table();
+            let first_match = string_table.get(index as usize)
+                .and_then(|string| string_table.iter().position(|existing| existing == string));
+            if path.path.as_ref() == self.path && first_match == Some(index as usize) {
+                return format!("{:?}", string_table[index as usize]);
+            }
+        }
+        let mut text = String::new();
+        for part in path.path.iter() {
+            let _ = write!(text, "{}::", part);
+        }
+        let _ = write!(text, "{}", index);
+        text
+    }
+
+    fn binary(&self, mnemonic: &str, target: &Target, source: &Source) -> String {
+        format!("{} {}, {}", mnemonic, self.target(target), self.source(source))
+    }
+
+    fn instruction(&self, instruction: &RealInstruction) -> String {
+        let modifiers = |can_wrap: bool, use_carry: bool| {
+            let mut modifiers = String::new();
+            if can_wrap {
+                modifiers.push_str(".wrap");
+            }
+            if use_carry {
+                modifiers.push_str(".carry");
+            }
+            modifiers
+        };
+
+        let once = |one_shot: bool| if one_shot { ".once" } else { "" };
+
+        use RealInstruction::*;
+        match instruction {
+            Halt => "halt".to_string(),
+            NoOp => "nop".to_string(),
+            Load(target, source) => self.binary("load", target, source),
+            Store(reference, offset, value) => format!("store {}, {}, {}", self.source(reference), self.source(offset), self.source(value)),
+            StackDeref(target, stack_level, offset) => format!("stackderef {}, {}, {}", self.target(target), self.source(stack_level), self.source(offset)),
+            StackStore(stack_level, offset, value) => format!("stackstore {}, {}, {}", self.source(stack_level), self.source(offset), self.source(value)),
+            Add(target, source, can_wrap, use_carry) => self.binary(&format!("add{}", modifiers(*can_wrap, *use_carry)), target, source),
+            Sub(target, source, can_wrap, use_carry) => self.binary(&format!("sub{}", modifiers(*can_wrap, *use_carry)), target, source),
+            Mul(target, source, can_wrap) => self.binary(&format!("mul{}", modifiers(*can_wrap, false)), target, source),
+            Div(target, source, can_wrap) => self.binary(&format!("div{}", modifiers(*can_wrap, false)), target, source),
+            Mod(target, source, can_wrap) => self.binary(&format!("mod{}", modifiers(*can_wrap, false)), target, source),
+            And(target, source) => self.binary("and", target, source),
+            Or(target, source) => self.binary("or", target, source),
+            Xor(target, source) => self.binary("xor", target, source),
+            Not(target) => format!("not {}", self.target(target)),
+            ShiftLeft(target, source) => self.binary("shl", target, source),
+            ShiftRight(target, source) => self.binary("shr", target, source),
+            Goto(jump_target, condition) => self.with_condition(format!("goto {}", self.jump_target(jump_target)), condition),
+            Compare(target, source, comparison_type) => format!("cmp {}, {}, {}", self.target(target), self.source(source), comparison_type_name(comparison_type)),
+            Push(source) => format!("push {}", self.source(source)),
+            Pop(target) => format!("pop {}", self.target(target)),
+            Call(call_target, condition) => self.with_condition(format!("call {}", self.call_target(call_target)), condition),
+            Return(Condition::Always) => "ret".to_string(),
+            Return(condition) => format!("ret {}", condition_name(condition)),
+            CreateContinuation(target, tag, one_shot) => format!("mkcont{} {}, {}", once(*one_shot), self.target(target), tag),
+            CreateObject(target, class) => format!("newobj {}, {}", self.target(target), class),
+            AccessObject(target, object, field) => format!("accessobj {}, {}, {}", self.target(target), self.source(object), self.source(field)),
+            StoreObject(object, field, value) => format!("storeobj {}, {}, {}", self.source(object), self.source(field), self.source(value)),
+            CreateList(target, size) => self.binary("newlist", target, size),
+            ListLength(target, list) => self.binary("listlen", target, list),
+            ListAccess(target, list, index) => format!("listget {}, {}, {}", self.target(target), self.source(list), self.source(index)),
+            ListStore(list, index, value) => format!("listset {}, {}, {}", self.source(list), self.source(index), self.source(value)),
+            GetStringRef(target, path, index) => format!("getstr {}, {}", self.target(target), self.string(path, *index)),
+            InstallHandler(effect, jump_target, target, one_shot) => format!("handle{} {}, {}, {}", once(*one_shot), effect, self.jump_target(jump_target), self.target(target)),
+            RemoveHandler(effect) => format!("unhandle {}", effect),
+            Perform(effect) => format!("perform {}", effect),
+            PushPrompt(tag) => format!("pushprompt {}", tag),
+            PopPrompt(tag) => format!("popprompt {}", tag),
+            CreateClosure(target, function, captures) => {
+                let mut text = format!("mkclosure {}, {}", self.target(target), function);
+                for capture in captures.iter() {
+                    write!(text, ", {}", self.source(capture)).expect("writing to a string");
+                }
+                text
+            }
+            LoadCapture(target, index) => self.binary("getcap", target, index),
+            AtomicCompareExchange(target, reference, index, expected, value) => format!("cas {}, {}, {}, {}, {}", self.target(target), self.source(reference), self.source(index), self.source(expected), self.source(value)),
+            AtomicFetchAdd(target, reference, index, value) => format!("fetchadd {}, {}, {}, {}", self.target(target), self.source(reference), self.source(index), self.source(value)),
+            AtomicExchange(target, reference, index, value) => format!("xchg {}, {}, {}, {}", self.target(target), self.source(reference), self.source(index), self.source(value)),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use std::path::Path;
+use crate::assembly::assembler::assemble;
+use crate::instruction::{ComparisonType, Condition, RegisterType};
+use crate::program::bytecode::{load_module, MAGIC};
+use crate::program::function::NativeRegistry;
+use crate::program::Module;
+
+pub mod assembler;
+pub mod disassembler;
+
+
+pub fn register_type_name(register_type: RegisterType) -> &'static str {
+    match register_type {
+        RegisterType::U8 => "u8",
+        RegisterType::U16 => "u16",
+        RegisterType::U32 => "u32",
+        RegisterType::U64 => "u64",
+        RegisterType::I8 => "i8",
+        RegisterType::I16 => "i16",
+        RegisterType::I32 => "i32",
+        RegisterType::I64 => "i64",
+        RegisterType::F32 => "f32",
+        RegisterType::F64 => "f64",
+        RegisterType::Reference => "ref",
+    }
+}
+
+pub fn parse_register_type(name: &str) -> Option<RegisterType> {
+    match name {
+        "u8" => Some(RegisterType::U8),
+        "u16" => Some(RegisterType::U16),
+        "u32" => Some(RegisterType::U32),
+        "u64" => Some(RegisterType::U64),
+        "i8" => Some(RegisterType::I8),
+        "i16" => Some(RegisterType::I16),
+        "i32" => Some(RegisterType::I32),
+        "i64" => Some(RegisterType::I64),
+        "f32" => Some(RegisterType::F32),
+        "f64" => Some(RegisterType::F64),
+        "ref" => Some(RegisterType::Reference),
+        _ => None,
+    }
+}
+
+pub fn condition_name(condition: &Condition) -> &'static str {
+    match condition {
+        Condition::Always => "always",
+        Condition::Equal => "eq",
+        Condition::NotEqual => "ne",
+        Condition::GreaterThan => "gt",
+        Condition::GreaterThanOrEqual => "ge",
+        Condition::LessThan => "lt",
+        Condition::LessThanOrEqual => "le",
+        Condition::Zero => "zero",
+        Condition::NotZero => "nonzero",
+        Condition::Carry => "carry",
+        Condition::NotCarry => "nocarry",
+        Condition::Negative => "neg",
+        Condition::NotNegative => "nonneg",
+        Condition::InContinuation => "cont",
+        Condition::NotInContinuation => "nocont",
+    }
+}
+
+pub fn parse_condition(name: &str) -> Option<Condition> {
+    match name {
+        "always" => Some(Condition::Always),
+        "eq" => Some(Condition::Equal),
+        "ne" => Some(Condition::NotEqual),
+        "gt" => Some(Condition::GreaterThan),
+        "ge" => Some(Condition::GreaterThanOrEqual),
+        "lt" => Some(Condition::LessThan),
+        "le" => Some(Condition::LessThanOrEqual),
+        "zero" => Some(Condition::Zero),
+        "nonzero" => Some(Condition::NotZero),
+        "carry" => Some(Condition::Carry),
+        "nocarry" => Some(Condition::NotCarry),
+        "neg" => Some(Condition::Negative),
+        "nonneg" => Some(Condition::NotNegative),
+        "cont" => Some(Condition::InContinuation),
+        "nocont" => Some(Condition::NotInContinuation),
+        _ => None,
+    }
+}
+
+pub fn comparison_type_name(comparison_type: &ComparisonType) -> &'static str {
+    match comparison_type {
+        ComparisonType::Equal => "eq",
+        ComparisonType::NotEqual => "ne",
+        ComparisonType::GreaterThan => "gt",
+        ComparisonType::GreaterThanOrEqual => "ge",
+        ComparisonType::LessThan => "lt",
+        ComparisonType::LessThanOrEqual => "le",
+    }
+}
+
+pub fn parse_comparison_type(name: &str) -> Option<ComparisonType> {
+    match name {
+        "eq" => Some(ComparisonType::Equal),
+        "ne" => Some(ComparisonType::NotEqual),
+        "gt" => Some(ComparisonType::GreaterThan),
+        "ge" => Some(ComparisonType::GreaterThanOrEqual),
+        "lt" => Some(ComparisonType::LessThan),
+        "le" => Some(ComparisonType::LessThanOrEqual),
+        _ => None,
+    }
+}
+
+/// Loads a module from bytecode, or assembles it if the file doesn't start like bytecode.
+pub fn load_file(file: &Path, natives: &NativeRegistry) -> Result<Module, String> {
+    let bytes = std::fs::read(file).map_err(|error| format!("{}: {}", file.display(), error))?;
+    if bytes.starts_with(&MAGIC) {
+        return load_module(&bytes, natives).map_err(|error| format!("{}: {}", file.display(), error));
+    }
+    let source = String::from_utf8(bytes).map_err(|_| format!("{}: neither bytecode nor assembly", file.display()))?;
+    assemble(&source, &file.display().to_string(), natives).map_err(|error| error.to_string())
+}
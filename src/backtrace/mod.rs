@@ -33,6 +33,12 @@ impl BacktraceInfo {
         self.backtrace.pop()
     }
 
+    /// Drops the entries above `len`, including ones left for unwinding.
+    pub fn truncate(&mut self, len: usize) {
+        self.backtrace.truncate(len);
+        self.unwind_levels = 0;
+    }
+
     pub fn get(&self, index: usize) -> Option<&BacktraceEntry> {
         self.backtrace.get(index)
     }
@@ -41,6 +47,10 @@ impl BacktraceInfo {
         self.backtrace.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.backtrace.is_empty()
+    }
+
     pub fn set_row_column(&mut self, row: usize, column: usize) {
         if let Some(entry) = self.backtrace.last_mut() {
             entry.line = Some(row);
@@ -49,6 +59,12 @@ impl BacktraceInfo {
     }
 }
 
+impl Default for BacktraceInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Display for BacktraceInfo {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         for entry in self.backtrace.iter() {
@@ -0,0 +1,455 @@
+//! The C interface of the VM, declared in `include/crayfish.h`, which `cargo build --features header` regenerates from this file.
+//!
+//! Functions that can fail return `CRAYFISH_OK` or `CRAYFISH_ERROR`, after an error
+//! `crayfish_vm_get_error` describes it and `crayfish_vm_get_backtrace` says where it happened.
+//! Strings passed in are UTF-8 and NUL terminated unless they come with a length.
+
+use std::ffi::{c_char, c_void, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::Arc;
+use crate::machine::native::{FromValue, IntoValue, NativeContext};
+use crate::machine::{Fault, InstructionResult};
+use crate::memory::Memory;
+use crate::native_lib::get_std_module;
+use crate::program::bytecode::load_module;
+use crate::program::function::{NativeFunction, NativeRegistry};
+use crate::program::Module;
+use crate::value::Value;
+use crate::vm::{Vm, VmError};
+
+
+pub const CRAYFISH_OK: i32 = 0;
+pub const CRAYFISH_ERROR: i32 = -1;
+
+/// The type of a `CrayfishValue`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CrayfishKind {
+    I64,
+    U64,
+    F64,
+    String,
+}
+
+/// An argument or result, only the field for its kind is used.
+#[repr(C)]
+pub struct CrayfishValue {
+    pub kind: CrayfishKind,
+    pub i64: i64,
+    pub u64: u64,
+    pub f64: f64,
+    /// UTF-8 that isn't NUL terminated, `length` bytes long.
+    pub string: *const c_char,
+    pub length: usize,
+}
+
+/// A native function written in C.
+/// It returns `CRAYFISH_OK`, or something else to fault with the message given to `crayfish_context_set_error`.
+/// It may be called from any thread the program spawns.
+pub type CrayfishNative = extern "C" fn(context: *mut CrayfishContext, user_data: *mut c_void) -> i32;
+
+/// The arguments and results of a call to a `CrayfishNative`, only valid during the call.
+pub struct CrayfishContext {
+    context: *mut NativeContext<'static>,
+    error: Option<String>,
+}
+
+/// A VM, set up by registering natives and loading a module before the first call.
+pub struct CrayfishVm {
+    natives: NativeRegistry,
+    program: Module,
+    vm: Option<Vm>,
+    error: Option<CString>,
+    backtrace: Option<CString>,
+    /// Where a string result lives until the next call.
+    result: Option<CString>,
+}
+
+/// A C callback and the pointer it is called with.
+struct Callback {
+    function: CrayfishNative,
+    user_data: *mut c_void,
+}
+
+// The host promises its callbacks can be called from any thread.
+unsafe impl Send for Callback {}
+unsafe impl Sync for Callback {}
+
+impl Callback {
+    fn call(&self, context: &mut NativeContext) -> Result<InstructionResult, Fault> {
+        let mut context = CrayfishContext {
+            context: (context as *mut NativeContext).cast(),
+            error: None,
+        };
+        let status = (self.function)(&mut context, self.user_data);
+        if status != CRAYFISH_OK {
+            let message = context.error.unwrap_or_else(|| format!("native function failed with {}", status));
+            return Err(Fault::InvalidOperation(message));
+        }
+        Ok(InstructionResult::Continue)
+    }
+}
+
+/// An argument passed in from C.
+enum Argument {
+    Number(Value),
+    String(String),
+}
+
+impl IntoValue for Argument {
+    fn into_value(self, memory: &mut Memory) -> Result<Value, Fault> {
+        match self {
+            Argument::Number(value) => Ok(value),
+            Argument::String(string) => string.into_value(memory),
+        }
+    }
+}
+
+impl CrayfishVm {
+    fn set_error(&mut self, message: String, backtrace: Option<String>) {
+        self.error = Some(to_c_string(message));
+        self.backtrace = backtrace.map(to_c_string);
+    }
+
+    fn check_not_started(&mut self) -> Result<(), String> {
+        match self.vm {
+            Some(_) => Err("the VM has already started".to_string()),
+            None => Ok(()),
+        }
+    }
+
+    fn get_vm(&mut self) -> Result<&mut Vm, VmError> {
+        if self.vm.is_none() {
+            let program = std::mem::take(&mut self.program);
+            self.vm = Some(Vm::builder(program).build()?);
+        }
+        Ok(self.vm.as_mut().expect("the VM was just built"))
+    }
+
+    fn call(&mut self, path: &str, arguments: Vec<Argument>, kind: CrayfishKind) -> Result<CrayfishValue, VmError> {
+        let vm = self.get_vm()?;
+        let value = vm.call::<Value>(path, arguments)?;
+        let (memory, module) = (vm.get_memory(), vm.get_module());
+        let mut result = CrayfishValue { kind, i64: 0, u64: 0, f64: 0.0, string: std::ptr::null(), length: 0 };
+        match kind {
+            CrayfishKind::I64 => result.i64 = i64::from_value(value, memory, module)?,
+            CrayfishKind::U64 => result.u64 = u64::from_value(value, memory, module)?,
+            CrayfishKind::F64 => result.f64 = f64::from_value(value, memory, module)?,
+            CrayfishKind::String => {
+                let string = String::from_value(value, memory, module)?;
+                // The C string stops at the first NUL, so the length is taken from it.
+                let string = self.result.insert(to_c_string(string));
+                result.length = string.as_bytes().len();
+                result.string = string.as_ptr();
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// A C string that stops at the first NUL instead of failing on it.
+fn to_c_string(string: String) -> CString {
+    let mut bytes = string.into_bytes();
+    if let Some(end) = bytes.iter().position(|byte| *byte == 0) {
+        bytes.truncate(end);
+    }
+    CString::new(bytes).expect("NUL bytes were removed")
+}
+
+unsafe fn read_str<'a>(string: *const c_char) -> Result<&'a str, String> {
+    if string.is_null() {
+        return Err("string is null".to_string());
+    }
+    CStr::from_ptr(string).to_str().map_err(|error| error.to_string())
+}
+
+unsafe fn read_slice<'a>(string: *const c_char, length: usize) -> Result<&'a str, String> {
+    if string.is_null() {
+        return Err("string is null".to_string());
+    }
+    std::str::from_utf8(std::slice::from_raw_parts(string.cast(), length)).map_err(|error| error.to_string())
+}
+
+unsafe fn read_argument(value: &CrayfishValue) -> Result<Argument, String> {
+    Ok(match value.kind {
+        CrayfishKind::I64 => Argument::Number(Value::I64(value.i64)),
+        CrayfishKind::U64 => Argument::Number(Value::U64(value.u64)),
+        CrayfishKind::F64 => Argument::Number(Value::F64(value.f64)),
+        CrayfishKind::String => Argument::String(read_slice(value.string, value.length)?.to_string()),
+    })
+}
+
+/// Stores the argument `read` returns in `value`, or keeps the fault as the message the native faults with.
+unsafe fn read_number<T>(context: *mut CrayfishContext, value: *mut T, read: impl FnOnce(&NativeContext) -> Result<T, Fault>) -> i32 {
+    let context = &mut *context;
+    match read(&*context.context) {
+        Ok(number) => {
+            *value = number;
+            CRAYFISH_OK
+        }
+        Err(Fault::InvalidOperation(message)) => {
+            context.error = Some(message);
+            CRAYFISH_ERROR
+        }
+        Err(fault) => {
+            context.error = Some(format!("{:?}", fault));
+            CRAYFISH_ERROR
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => format!("the VM panicked: {}", message),
+        None => match panic.downcast_ref::<String>() {
+            Some(message) => format!("the VM panicked: {}", message),
+            None => "the VM panicked".to_string(),
+        },
+    }
+}
+
+
+/// Creates a VM with the standard library, free it with `crayfish_vm_free`.
+#[no_mangle]
+pub extern "C" fn crayfish_vm_new() -> *mut CrayfishVm {
+    let mut natives = NativeRegistry::new();
+    natives.register_module(&get_std_module());
+    Box::into_raw(Box::new(CrayfishVm {
+        natives,
+        program: Module::default(),
+        vm: None,
+        error: None,
+        backtrace: None,
+        result: None,
+    }))
+}
+
+/// # Safety
+/// `vm` must come from `crayfish_vm_new` and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_vm_free(vm: *mut CrayfishVm) {
+    if !vm.is_null() {
+        drop(Box::from_raw(vm));
+    }
+}
+
+/// Registers `callback` as the native function at `path`, like `host::log`, for modules loaded afterwards to import.
+/// Natives can only be registered before the first call.
+///
+/// # Safety
+/// `vm` must be a live VM and `path` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_vm_register_native(vm: *mut CrayfishVm, path: *const c_char, callback: CrayfishNative, user_data: *mut c_void) -> i32 {
+    let vm = &mut *vm;
+    let result = vm.check_not_started().and_then(|_| read_str(path));
+    match result {
+        Ok(path) => {
+            let callback = Callback { function: callback, user_data };
+            let native: NativeFunction = Arc::new(move |context: &mut NativeContext| callback.call(context));
+            vm.natives.register(path, native);
+            CRAYFISH_OK
+        }
+        Err(message) => {
+            vm.set_error(message, None);
+            CRAYFISH_ERROR
+        }
+    }
+}
+
+/// Loads a serialized bytecode module as the program, its native imports are looked up in the standard library
+/// and the registered natives. A module can only be loaded before the first call.
+///
+/// # Safety
+/// `vm` must be a live VM and `bytes` point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_vm_load_module(vm: *mut CrayfishVm, bytes: *const u8, length: usize) -> i32 {
+    let vm = &mut *vm;
+    if let Err(message) = vm.check_not_started() {
+        vm.set_error(message, None);
+        return CRAYFISH_ERROR;
+    }
+    if bytes.is_null() {
+        vm.set_error("bytes are null".to_string(), None);
+        return CRAYFISH_ERROR;
+    }
+    let bytes = std::slice::from_raw_parts(bytes, length);
+    match load_module(bytes, &vm.natives) {
+        Ok(module) => {
+            vm.program = module;
+            CRAYFISH_OK
+        }
+        Err(error) => {
+            vm.set_error(error.to_string(), None);
+            CRAYFISH_ERROR
+        }
+    }
+}
+
+/// Calls the function at `path` with `count` arguments and stores what it returns in `result`
+/// as the kind `result` has when it is passed in. A string result stays valid until the next call.
+/// The first call verifies the program.
+///
+/// # Safety
+/// `vm` must be a live VM, `path` a NUL terminated string, `arguments` point to `count` values
+/// and `result` to a value.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_vm_call(vm: *mut CrayfishVm, path: *const c_char, arguments: *const CrayfishValue, count: usize, result: *mut CrayfishValue) -> i32 {
+    let vm = &mut *vm;
+    vm.error = None;
+    vm.backtrace = None;
+    vm.result = None;
+
+    let input = read_str(path).and_then(|path| {
+        let arguments = match count {
+            0 => &[],
+            _ => std::slice::from_raw_parts(arguments, count),
+        };
+        let arguments = arguments.iter().map(|argument| read_argument(argument)).collect::<Result<Vec<_>, _>>()?;
+        Ok((path, arguments))
+    });
+    let (path, arguments) = match input {
+        Ok(input) => input,
+        Err(message) => {
+            vm.set_error(message, None);
+            return CRAYFISH_ERROR;
+        }
+    };
+
+    let kind = (*result).kind;
+    match catch_unwind(AssertUnwindSafe(|| vm.call(path, arguments, kind))) {
+        Ok(Ok(value)) => {
+            *result = value;
+            CRAYFISH_OK
+        }
+        Ok(Err(error)) => {
+            let backtrace = error.get_backtrace().to_string();
+            vm.set_error(format!("{:?}", error.get_fault()), Some(backtrace));
+            CRAYFISH_ERROR
+        }
+        Err(panic) => {
+            vm.set_error(panic_message(panic), None);
+            CRAYFISH_ERROR
+        }
+    }
+}
+
+/// Describes the last error, or returns null if the last call succeeded.
+/// The string stays valid until the next call on `vm`.
+///
+/// # Safety
+/// `vm` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_vm_get_error(vm: *const CrayfishVm) -> *const c_char {
+    (*vm).error.as_ref().map_or(std::ptr::null(), |error| error.as_ptr())
+}
+
+/// The backtrace of the last fault, one function per line with the innermost last,
+/// or null if the last error wasn't a fault in running code.
+///
+/// # Safety
+/// `vm` must be a live VM.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_vm_get_backtrace(vm: *const CrayfishVm) -> *const c_char {
+    (*vm).backtrace.as_ref().map_or(std::ptr::null(), |backtrace| backtrace.as_ptr())
+}
+
+/// Stores argument `index` in `value`, or returns `CRAYFISH_ERROR` if it isn't a number.
+/// The native can return that status to fault with the reason.
+///
+/// # Safety
+/// `context` must be the context a native was called with and `value` point to an `int64_t`.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_get_i64(context: *mut CrayfishContext, index: usize, value: *mut i64) -> i32 {
+    read_number(context, value, |native| native.get_i64(index))
+}
+
+/// Stores argument `index` in `value` like `crayfish_context_get_i64`, references are read as their handle.
+///
+/// # Safety
+/// `context` must be the context a native was called with and `value` point to a `uint64_t`.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_get_u64(context: *mut CrayfishContext, index: usize, value: *mut u64) -> i32 {
+    read_number(context, value, |native| native.get_u64(index))
+}
+
+/// Stores argument `index` in `value` like `crayfish_context_get_i64`.
+///
+/// # Safety
+/// `context` must be the context a native was called with and `value` point to a `double`.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_get_f64(context: *mut CrayfishContext, index: usize, value: *mut f64) -> i32 {
+    read_number(context, value, |native| native.get_f64(index))
+}
+
+/// The string argument `index` refers to, its length is stored in `length`.
+/// The string isn't NUL terminated and is only valid until the native returns.
+/// Returns null if the argument isn't a string.
+///
+/// # Safety
+/// `context` must be the context a native was called with and `length` point to a `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_get_string(context: *const CrayfishContext, index: usize, length: *mut usize) -> *const c_char {
+    match (*(*context).context).get_string(index) {
+        Ok(string) => {
+            *length = string.len();
+            string.as_ptr().cast()
+        }
+        Err(_) => std::ptr::null(),
+    }
+}
+
+/// # Safety
+/// `context` must be the context a native was called with.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_set_i64(context: *mut CrayfishContext, value: i64) {
+    (*(*context).context).set_return(0, value);
+}
+
+/// # Safety
+/// `context` must be the context a native was called with.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_set_u64(context: *mut CrayfishContext, value: u64) {
+    (*(*context).context).set_return(0, value);
+}
+
+/// # Safety
+/// `context` must be the context a native was called with.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_set_f64(context: *mut CrayfishContext, value: f64) {
+    (*(*context).context).set_return(0, value);
+}
+
+/// Returns a copy of the `length` bytes of UTF-8 at `string` as a string on the heap.
+///
+/// # Safety
+/// `context` must be the context a native was called with and `string` point to `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_set_string(context: *mut CrayfishContext, string: *const c_char, length: usize) -> i32 {
+    let context = &mut *context;
+    let result = read_slice(string, length).and_then(|string| {
+        let native = &mut *context.context;
+        let value = native.allocate_string(string).map_err(|fault| format!("{:?}", fault))?;
+        native.set_return(0, value);
+        Ok(())
+    });
+    match result {
+        Ok(()) => CRAYFISH_OK,
+        Err(message) => {
+            context.error = Some(message);
+            CRAYFISH_ERROR
+        }
+    }
+}
+
+/// Sets the message of the fault the native faults with when it returns something other than `CRAYFISH_OK`.
+///
+/// # Safety
+/// `context` must be the context a native was called with and `message` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn crayfish_context_set_error(context: *mut CrayfishContext, message: *const c_char) {
+    let context = &mut *context;
+    context.error = Some(match read_str(message) {
+        Ok(message) => message.to_string(),
+        Err(error) => error,
+    });
+}
@@ -1,4 +1,5 @@
 use std::fmt::{Debug, Display};
+use crate::assembly::disassembler::disassemble_instruction;
 use crate::program::function::FunctionPath;
 use crate::program::StringTablePath;
 use crate::value::{Value, ValueType};
@@ -127,7 +128,7 @@ pub enum JumpTarget {
     /// A label jump target.
     /// The String represents the name of the label.
     /// This is the name of the label, not the address of the memory.
-    /// The address of the label is resolved at runtime.
+    /// The label is resolved to an absolute jump when its function is added to a module.
     Label(Box<str>),
 }
 
@@ -184,8 +185,12 @@ pub enum ComparisonType {
 #[derive(Debug, Clone)]
 pub enum CallTarget {
     Label(FunctionPath),
+    /// Calls the method in a slot of the class of an object.
+    /// The first source is the object, which is passed to the method in `RECEIVER_REGISTER`,
+    /// and the second source is the method slot.
     Vtable(Source, Source),
     Continuation(Source),
+    /// Calls a closure, the callee reads the values it captured with `LoadCapture`.
     Closure(Source),
 }
 
@@ -220,11 +225,35 @@ impl Instruction {
     pub fn get_column(&self) -> usize {
         self.column
     }
+
+    pub fn get_jump_target(&self) -> Option<&JumpTarget> {
+        match &self.instruction {
+            RealInstruction::Goto(jump_target, _) => Some(jump_target),
+            RealInstruction::InstallHandler(_, jump_target, _, _) => Some(jump_target),
+            _ => None,
+        }
+    }
+
+    pub fn set_jump_target(&mut self, new_target: JumpTarget) {
+        match &mut self.instruction {
+            RealInstruction::Goto(jump_target, _) => *jump_target = new_target,
+            RealInstruction::InstallHandler(_, jump_target, _, _) => *jump_target = new_target,
+            _ => {},
+        }
+    }
+
+    /// The label this instruction jumps to, if its jump target has not been resolved yet.
+    pub fn get_label(&self) -> Option<&str> {
+        match self.get_jump_target() {
+            Some(JumpTarget::Label(label)) => Some(label),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?} {}:{}", self.instruction, self.line, self.column)
+        write!(f, "{} {}:{}", disassemble_instruction(&self.instruction), self.line, self.column)
     }
 }
 
@@ -337,16 +366,70 @@ pub enum RealInstruction {
     /// The return address is popped from the stack.
     Return(Condition),
     /// Create Delimited Continuation instruction.
-    /// This instruction creates a delimited continuation from the current stack frame.
+    /// This instruction captures a copy of every stack frame from the current one up to,
+    /// but not including, the nearest calling frame with a prompt of the given tag.
     /// The index/reference of the continuation is stored in the target.
     /// The target can be a register or a memory address.
-    CreateContinuation(Target),
-    CreateObject(Target),
+    /// Resuming the continuation continues after this instruction with the `InContinuation` condition set.
+    /// The bool is if the continuation is one-shot, resuming it a second time is an error.
+    CreateContinuation(Target, Box<str>, bool),
+    /// Create object instruction.
+    /// This instruction creates an instance of the class at the path and stores a reference to it in the target.
+    /// The fields of the instance start with the default value of their type.
+    CreateObject(Target, FunctionPath),
+    /// Access object instruction.
+    /// This instruction reads the field at the index in the second source of the object in the first source.
+    /// The value is stored in the target.
     AccessObject(Target, Source, Source),
+    /// Store object instruction.
+    /// This instruction stores the third source in the field at the index in the second source of the object in the first source.
+    /// The value must have the type the field was declared with.
+    StoreObject(Source, Source, Source),
     CreateList(Target, Source),
     ListLength(Target, Source),
     ListAccess(Target, Source, Source),
     ListStore(Source, Source, Source),
     GetStringRef(Target, StringTablePath, u64),
-
+    /// Install effect handler instruction.
+    /// This instruction installs a handler for the named effect on the current stack frame.
+    /// When the effect is performed here or in a callee, execution continues at the jump target.
+    /// The index of the continuation of the performing code is stored in the target.
+    /// The bool is if the continuation is one-shot, resuming it a second time is an error.
+    InstallHandler(Box<str>, JumpTarget, Target, bool),
+    /// Remove effect handler instruction.
+    /// This instruction removes the most recently installed handler for the named effect from the current stack frame.
+    RemoveHandler(Box<str>),
+    /// Perform effect instruction.
+    /// This instruction unwinds to the nearest handler for the named effect.
+    /// Registers 0 to 7 are left as they are, so they can carry the effect's arguments.
+    /// When the continuation is resumed, register 0 holds the value it was resumed with.
+    Perform(Box<str>),
+    /// Push prompt instruction.
+    /// This instruction marks the current stack frame as a prompt with the given tag,
+    /// delimiting the continuations captured by callees.
+    PushPrompt(Box<str>),
+    /// Pop prompt instruction.
+    /// This instruction removes the most recently pushed prompt with the given tag from the current stack frame.
+    PopPrompt(Box<str>),
+    /// Create closure instruction.
+    /// This instruction creates a closure of the function at the path that captures the values of the sources.
+    /// A reference to the closure is stored in the target.
+    CreateClosure(Target, FunctionPath, Vec<Source>),
+    /// Load capture instruction.
+    /// This instruction loads the captured value at the index in the source into the target.
+    /// Only frames called through a closure have captured values.
+    LoadCapture(Target, Source),
+    /// Atomic compare and swap instruction.
+    /// This instruction compares the element at the index in the second source of the list or pointer in the first source
+    /// with the third source, and replaces it with the fourth source if they are equal.
+    /// The old element is stored in the target and the comparison flag is set to equal if the element was replaced.
+    AtomicCompareExchange(Target, Source, Source, Source, Source),
+    /// Atomic fetch and add instruction.
+    /// This instruction adds the third source to the element at the index in the second source of the list or pointer in the first source.
+    /// The addition wraps on overflow and the old element is stored in the target.
+    AtomicFetchAdd(Target, Source, Source, Source),
+    /// Atomic exchange instruction.
+    /// This instruction replaces the element at the index in the second source of the list or pointer in the first source with the third source.
+    /// The old element is stored in the target.
+    AtomicExchange(Target, Source, Source, Source),
 }
\ No newline at end of file
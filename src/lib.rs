@@ -0,0 +1,18 @@
+// Lets the code `#[native]` generates name this crate the same way inside and outside of it.
+extern crate self as crayfish_vm2;
+
+pub mod instruction;
+pub mod stack_frame;
+pub mod value;
+pub mod machine;
+pub mod program;
+pub mod memory;
+pub mod assembly;
+pub mod backtrace;
+pub mod native_lib;
+pub mod vm;
+pub mod capi;
+pub mod repl;
+
+pub use crayfish::native;
+pub use vm::{IntoArguments, Vm, VmBuilder, VmError};
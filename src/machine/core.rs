@@ -1,14 +1,22 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::sync::Arc;
+use crate::assembly::disassembler::disassemble_instruction;
 use crate::backtrace::BacktraceInfo;
-use crate::instruction::{CallTarget, ComparisonType, Condition, RealInstruction, JumpTarget, RegisterType, Source, Target};
+use crate::instruction::{CallTarget, ComparisonType, Condition, Instruction, RealInstruction, JumpTarget, RegisterType, Source, Target};
 use crate::machine::{Fault, InstructionResult, Register};
+use crate::machine::fiber::FiberScheduler;
+use crate::machine::statistics::{Counter, Statistics};
+use crate::machine::stdio::Stdio;
 use crate::memory::Memory;
 use crate::program::{Module, StringTablePath};
-use crate::stack_frame::{REGISTER_COUNT, StackFrame};
+use crate::program::function::FunctionPath;
+use crate::stack_frame::{EffectHandler, RECEIVER_REGISTER, REGISTER_COUNT, StackFrame};
 use crate::stack_frame::delimited_continuation::ContinuationStore;
 use crate::stack_frame::frame::Frame;
 use crate::value::{Value, ValueType};
+use crate::value::closure::Closure;
+use crate::value::object::{ClassId, Method};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Comparison {
@@ -40,6 +48,12 @@ impl Default for CoreFlags {
     }
 }
 
+impl Display for CoreFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "comparison: {:?}, carry: {}, zero: {}, negative: {}", self.comparison, self.carry, self.zero, self.negative)
+    }
+}
+
 impl Display for Core {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Flags:")?;
@@ -60,6 +74,13 @@ impl Default for Core {
         Core {
             flags: CoreFlags::default(),
             registers: std::array::from_fn(|_| Register::default()),
+            inline_caches: HashMap::new(),
+            scheduler: FiberScheduler::default(),
+            native_roots: Vec::new(),
+            limits: Limits::default(),
+            stdio: Stdio::default(),
+            trace: false,
+            statistics: None,
         }
     }
 }
@@ -115,14 +136,79 @@ impl CoreUtils<&Target> for Core {
     }
 }
 
-unsafe impl Send for Core {}
+/// The method a vtable call site resolved to last, valid while the receiver has the same class.
 #[derive(Clone)]
+struct InlineCache {
+    class_id: ClassId,
+    slot: u64,
+    method: Method,
+}
+
+/// How many calls deep bytecode can go unless changed with `Limits`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+/// The stack size of the threads running bytecode unless changed with `Limits`.
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Limits on the code a core runs, threads it spawns get the same ones.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// A call that would go deeper faults with `StackOverflow`.
+    pub max_call_depth: usize,
+    /// The stack size of spawned threads, every call takes up stack so it has to fit `max_call_depth` calls.
+    pub stack_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            stack_size: DEFAULT_STACK_SIZE,
+        }
+    }
+}
+
+unsafe impl Send for Core {}
 pub struct Core {
     flags: CoreFlags,
     pub registers: [Register; REGISTER_COUNT],
+    /// Keyed on the address of the call site's function and its program counter.
+    inline_caches: HashMap<(usize, usize), InlineCache>,
+    /// The fibers running on this core.
+    pub scheduler: FiberScheduler,
+    /// Heap values the running natives keep alive while they call back into bytecode.
+    pub native_roots: Vec<Value>,
+    pub limits: Limits,
+    pub stdio: Stdio,
+    /// Prints every instruction to stderr before it is executed.
+    pub trace: bool,
+    pub statistics: Option<Arc<Statistics>>,
 }
 
 impl Core {
+    /// A core for a thread this one spawns, with the same limits, fiber budget, stdio, tracing and statistics.
+    pub fn new_thread_core(&self) -> Core {
+        let mut core = Core {
+            limits: self.limits,
+            stdio: self.stdio.clone(),
+            trace: self.trace,
+            statistics: self.statistics.clone(),
+            ..Core::default()
+        };
+        core.scheduler.set_budget(self.scheduler.get_budget());
+        core
+    }
+
+    pub fn get_flags(&self) -> CoreFlags {
+        self.flags
+    }
+
+    /// Adds to a counter of the statistics if they are being kept.
+    pub fn count(&self, counter: Counter, amount: u64) {
+        if let Some(statistics) = &self.statistics {
+            statistics.add(counter, amount);
+        }
+    }
 
     pub fn set_value(&mut self, target: &Target, value: Value) {
         match target {
@@ -146,6 +232,11 @@ impl Core {
 
 
         backtrace.set_row_column(instruction.line, instruction.column);
+        if self.trace {
+            let program_counter = stack_frame.get_program_counter();
+            self.stdio.eprint(format_args!("{}[{}] {}\n", stack_frame.get_function_name(), program_counter, disassemble_instruction(&instruction.instruction)))?;
+        }
+        self.count(Counter::Instructions, 1);
 
         use RealInstruction::*;
         match instruction.instruction {
@@ -175,6 +266,20 @@ impl Core {
             ListAccess(ref target, ref source, ref index) => self.list_access_instruction(target, source, index, &memory)?,
             ListStore(ref source, ref index, ref value) => self.list_store_instruction(source, index, value, &mut memory)?,
             CreateList(ref target, ref size) => self.create_list_instruction(target, size, &mut memory)?,
+            CreateClosure(ref target, ref function, ref captures) => self.create_closure_instruction(target, function, captures, module, &mut memory)?,
+            LoadCapture(ref target, ref index) => self.load_capture_instruction(stack_frame, target, index)?,
+            AtomicCompareExchange(ref target, ref reference, ref index, ref expected, ref value) => self.atomic_compare_exchange_instruction(target, reference, index, expected, value, &memory)?,
+            AtomicFetchAdd(ref target, ref reference, ref index, ref value) => self.atomic_fetch_add_instruction(target, reference, index, value, &memory)?,
+            AtomicExchange(ref target, ref reference, ref index, ref value) => self.atomic_exchange_instruction(target, reference, index, value, &memory)?,
+            CreateObject(ref target, ref class) => self.create_object_instruction(target, class, &mut memory)?,
+            AccessObject(ref target, ref object, ref field) => self.access_object_instruction(target, object, field, &memory)?,
+            StoreObject(ref object, ref field, ref value) => self.store_object_instruction(object, field, value, &mut memory)?,
+            InstallHandler(ref effect, ref jump_target, ref target, one_shot) => self.install_handler_instruction(stack_frame, effect, jump_target, target, one_shot)?,
+            RemoveHandler(ref effect) => stack_frame.remove_handler(effect),
+            Perform(effect) => return Ok(self.perform_instruction(stack_frame, effect)),
+            PushPrompt(tag) => stack_frame.push_prompt(tag),
+            PopPrompt(ref tag) => stack_frame.pop_prompt(tag),
+            CreateContinuation(ref target, ref tag, one_shot) => self.create_continuation_instruction(stack_frame, frames, continuation_store, target, tag, one_shot)?,
 
             x => unreachable!("Unimplemented instruction: {:?}", x),
         }
@@ -522,8 +627,9 @@ impl Core {
         if self.can_jump(condition, stack_frame) {
             match jump_target {
                 JumpTarget::Label(label) => {
-                    todo!("Label Goto");
-                    return Ok(InstructionResult::Continue);
+                    // Labels are resolved when a function is added to a module,
+                    // so only hand built frames can still contain one.
+                    return Err(Fault::LabelNotFound(label.clone()));
                 },
                 JumpTarget::Relative(offset) => {
                     stack_frame.set_program_counter((stack_frame.get_program_counter() as isize + *offset) as usize);
@@ -539,6 +645,64 @@ impl Core {
         Ok(InstructionResult::Continue)
     }
 
+    fn install_handler_instruction(&mut self, stack_frame: &mut dyn StackFrame, effect: &str, jump_target: &JumpTarget, target: &Target, one_shot: bool) -> Result<(), Fault> {
+        let address = match jump_target {
+            JumpTarget::Label(label) => return Err(Fault::LabelNotFound(label.clone())),
+            JumpTarget::Relative(offset) => (stack_frame.get_program_counter() as isize + *offset) as usize,
+            JumpTarget::Absolute(address) => *address,
+        };
+        stack_frame.install_handler(EffectHandler {
+            effect: effect.into(),
+            address,
+            target: target.clone(),
+            one_shot,
+        });
+        Ok(())
+    }
+
+    fn perform_instruction(&mut self, stack_frame: &mut dyn StackFrame, effect: Box<str>) -> InstructionResult {
+        stack_frame.increment_program_counter();
+        let mut continuation = stack_frame.make_continuation();
+        continuation.capture_registers(&self.registers);
+        InstructionResult::Unwind(effect, Some(continuation))
+    }
+
+    fn create_continuation_instruction(&mut self,
+                                       stack_frame: &mut dyn StackFrame,
+                                       frames: &mut Vec<*mut dyn StackFrame>,
+                                       continuation_store: &mut ContinuationStore,
+                                       target: &Target,
+                                       tag: &str,
+                                       one_shot: bool) -> Result<(), Fault> {
+        // The captured frame resumes after this instruction.
+        let program_counter = stack_frame.get_program_counter();
+        stack_frame.set_program_counter(program_counter + 1);
+        let mut continuation = stack_frame.make_continuation();
+        stack_frame.set_program_counter(program_counter);
+        continuation.capture_registers(&self.registers);
+
+        let mut found_prompt = false;
+        for frame in frames.iter().rev() {
+            let frame = unsafe {frame.as_ref()}.expect("Frame is null");
+            if frame.has_prompt(tag) {
+                found_prompt = true;
+                break;
+            }
+            continuation = continuation.enclose(frame.make_continuation());
+        }
+        if !found_prompt {
+            return Err(Fault::PromptNotFound(tag.into()));
+        }
+
+        if one_shot {
+            continuation_store.store_one_shot(continuation);
+        } else {
+            continuation_store.store(continuation);
+        }
+        self.set_value(target, Value::U64(continuation_store.get_last_index()));
+        Ok(())
+    }
+
     fn compare_instruction(&mut self, target: &Target, source: &Source, comparison_type: &ComparisonType) {
         let rhs = self.get_value(source);
         let lhs = self.get_value(target);
@@ -616,16 +780,57 @@ impl Core {
 
                     return Ok(InstructionResult::Call(function, new_stack_frame));
                 }
-                CallTarget::Vtable(object_reference_source, index_source) => {
-                    todo!("Lookup object reference and create the stack frame from the vtable")
+                CallTarget::Vtable(object, slot) => {
+                    let call_site = (Arc::as_ptr(&stack_frame.get_instructions()) as *const Instruction as usize, stack_frame.get_program_counter() - 1);
+                    let (path, function) = self.vtable_lookup(call_site, &object, &slot, &memory)?;
+                    let new_stack_frame = Frame::new(path, function.get_instructions());
+
+                    return Ok(InstructionResult::Call(function, new_stack_frame));
+                }
+                CallTarget::Continuation(continuation_index) => {
+                    let index = self.get_value(&continuation_index).to_usize();
+                    let continuation = continuation_store.resume(index)?;
+
+                    return Ok(InstructionResult::CallContinuation(continuation));
+                }
+                CallTarget::Closure(closure) => {
+                    let reference = match self.get_value(&closure) {
+                        Value::MemoryRef(reference) | Value::U64(reference) => reference,
+                        _ => return Err(Fault::InvalidReference),
+                    };
+                    let closure = memory.get_closure(reference)?;
+                    let function = closure.get_function().clone();
+                    let new_stack_frame = Frame::new_closure(closure.get_path().clone(), function.get_instructions(), closure.get_captures().into());
+
+                    return Ok(InstructionResult::Call(function, new_stack_frame));
                 }
-                CallTarget::Continuation(continuation_index) => {}
-                CallTarget::Closure(_) => {}
             }
         }
         Ok(InstructionResult::Continue)
     }
 
+    /// Resolves the method of a vtable call and moves the receiver into `RECEIVER_REGISTER`.
+    fn vtable_lookup(&mut self, call_site: (usize, usize), object: &Source, slot: &Source, memory: &Memory) -> Result<Method, Fault> {
+        let reference = match self.get_value(object) {
+            Value::MemoryRef(reference) | Value::U64(reference) => reference,
+            _ => return Err(Fault::InvalidReference),
+        };
+        let slot = self.get_value(slot).to_usize() as u64;
+        let class_id = memory.get_object_class(reference)?;
+
+        let method = match self.inline_caches.get(&call_site) {
+            Some(cache) if cache.class_id == class_id && cache.slot == slot => cache.method.clone(),
+            _ => {
+                let method = memory.lookup_method(class_id, slot)?;
+                self.inline_caches.insert(call_site, InlineCache { class_id, slot, method: method.clone() });
+                method
+            }
+        };
+
+        self.registers[RECEIVER_REGISTER].set_value(Value::MemoryRef(reference));
+        Ok(method)
+    }
+
     fn stack_dereference_instruction(&mut self,
                                      target: &Target,
                                      stack_level: &Source,
@@ -739,6 +944,110 @@ impl Core {
 
         Ok(())
     }
+
+    fn create_closure_instruction(&mut self, target: &Target, function: &FunctionPath, captures: &[Source], module: &Module, memory: &mut Memory) -> Result<(),Fault>{
+        let implementation = module.get_function(function).ok_or(Fault::FunctionNotFound(function.clone()))?;
+        let captures = captures.iter().map(|capture| self.get_value(capture)).collect();
+
+        let closure = memory.allocate_closure(Closure::new(function.clone(), implementation, captures))?;
+        self.set_value(target, closure);
+        Ok(())
+    }
+
+    fn load_capture_instruction(&mut self, stack_frame: &dyn StackFrame, target: &Target, index: &Source) -> Result<(),Fault>{
+        let index = self.get_value(index).to_usize();
+        let value = stack_frame.get_capture(index).ok_or(Fault::InvalidCaptureIndex(index as u64))?;
+        self.set_value(target, value);
+        Ok(())
+    }
+
+    fn create_object_instruction(&mut self, target: &Target, class: &FunctionPath, memory: &mut Memory) -> Result<(),Fault>{
+        let object = memory.allocate_object(class)?;
+        self.set_value(target, object);
+        Ok(())
+    }
+
+    /// Field indices can be of any integer type, like capture indices.
+    fn get_field_index(&self, field: &Source) -> Result<u64, Fault> {
+        match self.get_value(field) {
+            field @ (Value::U8(_) | Value::I8(_) | Value::U16(_) | Value::I16(_)
+                | Value::U32(_) | Value::I32(_) | Value::U64(_) | Value::I64(_)) => Ok(field.to_usize() as u64),
+            Value::F32(field) => Err(Fault::InvalidFieldIndex(field as u64)),
+            Value::F64(field) => Err(Fault::InvalidFieldIndex(field as u64)),
+            field => Err(Fault::InvalidFieldIndex(field.get_reference().unwrap_or_default())),
+        }
+    }
+
+    fn access_object_instruction(&mut self, target: &Target, object: &Source, field: &Source, memory: &Memory) -> Result<(),Fault>{
+        let object = self.get_value(object);
+        let field = self.get_field_index(field)?;
+
+        let value = match object {
+            Value::MemoryRef(object) | Value::U64(object) => memory.access_object(object, field)?,
+            _ => return Err(Fault::InvalidReference),
+        };
+
+        self.set_value(target, value);
+        Ok(())
+    }
+
+    fn store_object_instruction(&mut self, object: &Source, field: &Source, value: &Source, memory: &mut Memory) -> Result<(),Fault>{
+        let object = self.get_value(object);
+        let field = self.get_field_index(field)?;
+        let value = self.get_value(value);
+
+        match object {
+            Value::MemoryRef(object) | Value::U64(object) => memory.store_object(object, field, value)?,
+            _ => return Err(Fault::InvalidReference),
+        };
+
+        Ok(())
+    }
+
+    fn atomic_cell(&self, reference: &Source, index: &Source) -> Result<(u64, u64), Fault> {
+        match (self.get_value(reference), self.get_value(index)) {
+            (Value::MemoryRef(reference) | Value::U64(reference), Value::U64(index)) => Ok((reference, index)),
+            _ => Err(Fault::InvalidReference),
+        }
+    }
+
+    fn atomic_compare_exchange_instruction(&mut self, target: &Target, reference: &Source, index: &Source, expected: &Source, value: &Source, memory: &Memory) -> Result<(),Fault>{
+        let (reference, index) = self.atomic_cell(reference, index)?;
+        let expected = self.get_value(expected);
+        let value = self.get_value(value);
+
+        let old = memory.atomic_update(reference, index, |old| Ok(if old == expected { value } else { old }))?;
+        self.flags.comparison = if old == expected {
+            Comparison::Equal
+        } else {
+            Comparison::NotEqual
+        };
+        self.set_value(target, old);
+        Ok(())
+    }
+
+    fn atomic_fetch_add_instruction(&mut self, target: &Target, reference: &Source, index: &Source, value: &Source, memory: &Memory) -> Result<(),Fault>{
+        let (reference, index) = self.atomic_cell(reference, index)?;
+        let value = self.get_value(value);
+
+        let old = memory.atomic_update(reference, index, |old| {
+            if old.get_type() != value.get_type() {
+                return Err(Fault::InvalidOperation(format!("cannot add {:?} to an element of type {:?}", value.get_type(), old.get_type())));
+            }
+            Ok(old.overflowing_add(value).0)
+        })?;
+        self.set_value(target, old);
+        Ok(())
+    }
+
+    fn atomic_exchange_instruction(&mut self, target: &Target, reference: &Source, index: &Source, value: &Source, memory: &Memory) -> Result<(),Fault>{
+        let (reference, index) = self.atomic_cell(reference, index)?;
+        let value = self.get_value(value);
+
+        let old = memory.atomic_update(reference, index, |_| Ok(value))?;
+        self.set_value(target, old);
+        Ok(())
+    }
 }
 
 
@@ -0,0 +1,412 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::os::fd::RawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::backtrace::{BacktraceEntry, BacktraceInfo};
+use crate::instruction::{RegisterType, Target};
+use crate::machine::core::Core;
+use crate::machine::{call_bytecode_function, call_native_function, Fault, InstructionResult};
+use crate::memory::Memory;
+use crate::machine::reactor::{Interest, Reactor};
+use crate::memory::slab::{Handle, Slab};
+use crate::program::function::Function;
+use crate::program::Module;
+use crate::stack_frame::delimited_continuation::{ContinuationStore, DelimitedContinuation};
+use crate::stack_frame::frame::Frame;
+use crate::stack_frame::StackFrame;
+use crate::value::Value;
+
+
+/// The effect a fiber unwinds to the scheduler with when it stops running before it has finished.
+pub const SUSPEND_EFFECT: &str = "std::fiber::suspend";
+
+/// How many instructions a fiber runs before it is preempted, unless changed with `set_budget`.
+pub const DEFAULT_BUDGET: u64 = 10_000;
+
+/// How often blocked fibers are tried again while nothing else is ready and another thread could wake them.
+const BLOCKED_RETRY: Duration = Duration::from_millis(1);
+
+pub type FiberId = Handle;
+
+#[derive(Debug, Clone)]
+enum FiberState {
+    New(Function, Box<Frame>, Value),
+    /// The continuation resumes with the value in register 0.
+    Suspended(DelimitedContinuation, Value),
+    Running,
+    /// What the fiber left in register 0, or the fault it stopped with.
+    Finished(Result<Value, Fault>),
+}
+
+/// Why the running fiber is suspending itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Wait {
+    Yield,
+    Join(FiberId),
+    /// Until the scheduler clock reaches the time in milliseconds.
+    Sleep(u64),
+    /// Until the file descriptor is ready, then the native that suspended is called again.
+    Io(RawFd, Interest),
+    /// Until a synchronization object might be available, then the native that suspended is called again.
+    Blocked,
+}
+
+#[derive(Debug, Clone)]
+struct Fiber {
+    state: FiberState,
+    /// Fibers waiting for this one to finish.
+    joiners: Vec<FiberId>,
+}
+
+/// Where the scheduler gets its time from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Clock {
+    /// Time only passes when every fiber is asleep and then jumps to the next wake up,
+    /// so runs are reproducible.
+    Virtual,
+    /// Time is wall clock time and the thread sleeps when every fiber is asleep.
+    Real,
+}
+
+/// A cooperative scheduler for the fibers of one core.
+/// Fibers run in the order they became ready, and one that runs for the whole budget is suspended
+/// if another fiber is ready, so with the virtual clock every run of a program without I/O is the same.
+#[derive(Debug)]
+pub struct FiberScheduler {
+    fibers: Slab<Fiber>,
+    ready: VecDeque<FiberId>,
+    /// Ordered by wake up time and then by the order the fibers went to sleep.
+    sleeping: BinaryHeap<Reverse<(u64, u64, FiberId)>>,
+    sleep_count: u64,
+    /// Fibers parked on a synchronization object, made ready again by `wake_blocked`.
+    blocked: Vec<FiberId>,
+    current: Option<FiberId>,
+    /// Set by the natives that suspend the current fiber.
+    wait: Option<Wait>,
+    budget: u64,
+    executed: u64,
+    /// How many natives are calling back into bytecode, the running fiber isn't preempted while any are.
+    pinned: u32,
+    clock: Clock,
+    virtual_time: u64,
+    start: Instant,
+    /// Created by the first fiber that waits on a file descriptor.
+    reactor: Option<Reactor>,
+}
+
+impl Default for FiberScheduler {
+    fn default() -> Self {
+        FiberScheduler {
+            fibers: Slab::new(),
+            ready: VecDeque::new(),
+            sleeping: BinaryHeap::new(),
+            sleep_count: 0,
+            blocked: Vec::new(),
+            current: None,
+            wait: None,
+            budget: DEFAULT_BUDGET,
+            executed: 0,
+            pinned: 0,
+            clock: Clock::Virtual,
+            virtual_time: 0,
+            start: Instant::now(),
+            reactor: None,
+        }
+    }
+}
+
+impl FiberScheduler {
+    /// Adds a fiber that calls `function` with `argument` in register 8 and makes it ready.
+    pub fn spawn(&mut self, function: Function, frame: Frame, argument: Value) -> FiberId {
+        let fiber = self.fibers.insert(Fiber {
+            state: FiberState::New(function, Box::new(frame), argument),
+            joiners: Vec::new(),
+        });
+        self.ready.push_back(fiber);
+        fiber
+    }
+
+    pub fn get_current(&self) -> Option<FiberId> {
+        self.current
+    }
+
+    pub fn get_budget(&self) -> u64 {
+        self.budget
+    }
+
+    /// The number of instructions a fiber runs before it is preempted, 0 turns preemption off.
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = budget;
+    }
+
+    pub fn set_clock(&mut self, clock: Clock) {
+        if clock == Clock::Virtual && self.clock == Clock::Real {
+            self.virtual_time = self.get_time();
+        }
+        self.clock = clock;
+    }
+
+    /// The time in milliseconds.
+    pub fn get_time(&self) -> u64 {
+        match self.clock {
+            Clock::Virtual => self.virtual_time,
+            Clock::Real => self.start.elapsed().as_millis() as u64,
+        }
+    }
+
+    /// Whether a fiber other than the running one could still run.
+    pub fn has_other_fibers(&self) -> bool {
+        self.fibers.iter().any(|fiber| !matches!(fiber.state, FiberState::Running | FiberState::Finished(_)))
+    }
+
+    /// Makes the fibers parked on synchronization objects ready, so they try again.
+    /// Called when this core released something they could be waiting on.
+    pub fn wake_blocked(&mut self) {
+        self.ready.extend(self.blocked.drain(..));
+    }
+
+    /// Called by a native before it returns `SUSPEND_EFFECT` to say when the fiber should run again.
+    pub fn set_wait(&mut self, wait: Wait) {
+        self.wait = Some(wait);
+    }
+
+    /// Forgets the wait of a suspend that never reached the scheduler.
+    pub fn cancel_wait(&mut self) {
+        self.wait = None;
+    }
+
+    /// Takes the result of a finished fiber, `None` if it hasn't finished.
+    /// The fiber is removed, so its id can't be joined again.
+    pub fn take_result(&mut self, fiber: FiberId) -> Result<Option<Result<Value, Fault>>, Fault> {
+        if !matches!(self.fibers.get(fiber)?.state, FiberState::Finished(_)) {
+            return Ok(None);
+        }
+        match self.fibers.remove(fiber)?.state {
+            FiberState::Finished(result) => Ok(Some(result)),
+            _ => unreachable!("checked the fiber has finished"),
+        }
+    }
+
+    /// Keeps the running fiber from being preempted until `unpin`,
+    /// for natives calling back into bytecode since their Rust frames can't be suspended.
+    pub fn pin(&mut self) {
+        self.pinned += 1;
+    }
+
+    pub fn unpin(&mut self) {
+        self.pinned -= 1;
+    }
+
+    /// Counts an executed instruction and returns if the running fiber should be preempted.
+    pub fn should_preempt(&mut self) -> bool {
+        if self.budget == 0 || self.current.is_none() || self.pinned > 0 {
+            return false;
+        }
+        self.executed += 1;
+        if self.executed < self.budget {
+            return false;
+        }
+        self.executed = 0;
+        !self.ready.is_empty() || self.has_woken_sleeper() || self.is_waiting_on_io()
+    }
+
+    fn has_woken_sleeper(&self) -> bool {
+        self.clock == Clock::Real && self.sleeping.peek().is_some_and(|Reverse((wake, _, _))| *wake <= self.get_time())
+    }
+
+    fn is_waiting_on_io(&self) -> bool {
+        self.reactor.as_ref().is_some_and(Reactor::is_waiting)
+    }
+
+    /// Wakes the fibers waiting on a file descriptor that is being closed.
+    pub fn close_fd(&mut self, fd: RawFd) {
+        if let Some(reactor) = &mut self.reactor {
+            reactor.remove(fd, &mut self.ready);
+        }
+    }
+
+    fn wake_sleepers(&mut self) {
+        let now = self.get_time();
+        while let Some(Reverse((wake, _, fiber))) = self.sleeping.peek().copied() {
+            if wake > now {
+                break;
+            }
+            self.sleeping.pop();
+            self.ready.push_back(fiber);
+        }
+    }
+
+    /// Picks the next fiber to run, waiting for I/O or a sleeping fiber if none is ready.
+    /// Parked descriptors are polled on every switch so fibers waiting on them aren't starved.
+    /// While `other_threads` run, blocked fibers are tried again every `BLOCKED_RETRY`.
+    /// `None` means every fiber left is waiting on another.
+    fn next(&mut self, other_threads: bool) -> Result<Option<FiberId>, Fault> {
+        loop {
+            self.wake_sleepers();
+            let next_wake = self.sleeping.peek().map(|Reverse((wake, _, _))| *wake);
+            let retry_blocked = other_threads && !self.blocked.is_empty();
+            if let Some(reactor) = self.reactor.as_mut().filter(|reactor| reactor.is_waiting()) {
+                // The virtual clock jumps straight to the next timer, so it never waits for I/O while one is pending.
+                let timeout = match (self.ready.is_empty(), next_wake, self.clock) {
+                    (false, _, _) | (true, Some(_), Clock::Virtual) => Some(Duration::ZERO),
+                    (true, Some(wake), Clock::Real) => Some(Duration::from_millis(wake.saturating_sub(self.start.elapsed().as_millis() as u64))),
+                    (true, None, _) => None,
+                };
+                let timeout = match timeout {
+                    Some(timeout) if retry_blocked => Some(timeout.min(BLOCKED_RETRY)),
+                    None if retry_blocked => Some(BLOCKED_RETRY),
+                    timeout => timeout,
+                };
+                reactor.poll(timeout, &mut self.ready)?;
+            }
+            if let Some(fiber) = self.ready.pop_front() {
+                return Ok(Some(fiber));
+            }
+            match (next_wake, self.clock) {
+                (Some(wake), Clock::Virtual) => self.virtual_time = wake,
+                (Some(wake), Clock::Real) if !self.is_waiting_on_io() => {
+                    let pause = Duration::from_millis(wake.saturating_sub(self.get_time()));
+                    std::thread::sleep(if retry_blocked { pause.min(BLOCKED_RETRY) } else { pause });
+                }
+                (Some(_), Clock::Real) => {}
+                (None, _) if self.is_waiting_on_io() => {}
+                (None, _) if retry_blocked => std::thread::sleep(BLOCKED_RETRY),
+                (None, _) => return Ok(None),
+            }
+            if retry_blocked {
+                self.wake_blocked();
+            }
+        }
+    }
+
+    fn suspend(&mut self, fiber: FiberId, mut continuation: DelimitedContinuation) -> Result<(), Fault> {
+        let wait = self.wait.take().unwrap_or(Wait::Yield);
+        if let Wait::Io(..) | Wait::Blocked = wait {
+            continuation.rewind();
+        }
+        self.fibers.get_mut(fiber)?.state = FiberState::Suspended(continuation, Value::U64(0));
+        match wait {
+            Wait::Yield => self.ready.push_back(fiber),
+            Wait::Join(target) => self.fibers.get_mut(target)?.joiners.push(fiber),
+            Wait::Sleep(wake) => {
+                self.sleeping.push(Reverse((wake, self.sleep_count, fiber)));
+                self.sleep_count += 1;
+            }
+            Wait::Io(fd, interest) => {
+                let reactor = match &mut self.reactor {
+                    Some(reactor) => reactor,
+                    None => self.reactor.insert(Reactor::new()?),
+                };
+                if !reactor.register(fd, interest, fiber)? {
+                    self.ready.push_back(fiber);
+                }
+            }
+            Wait::Blocked => self.blocked.push(fiber),
+        }
+        Ok(())
+    }
+
+    /// Marks a fiber as finished and hands its result to the fibers joining it.
+    fn finish(&mut self, fiber: FiberId, result: Result<Value, Fault>, memory: &mut Memory) -> Result<(), Fault> {
+        let joiners = std::mem::take(&mut self.fibers.get_mut(fiber)?.joiners);
+        if joiners.is_empty() {
+            self.fibers.get_mut(fiber)?.state = FiberState::Finished(result);
+            return Ok(());
+        }
+        self.fibers.remove(fiber)?;
+        let (value, status) = join_result(result, memory)?;
+        for joiner in joiners {
+            if let FiberState::Suspended(continuation, resume_value) = &mut self.fibers.get_mut(joiner)?.state {
+                *resume_value = value.clone();
+                continuation.set_register(1, Value::U64(status));
+            }
+            self.ready.push_back(joiner);
+        }
+        Ok(())
+    }
+
+    pub fn trace_roots(&self, roots: &mut Vec<u64>) {
+        for fiber in self.fibers.iter() {
+            match &fiber.state {
+                FiberState::New(_, frame, argument) => {
+                    frame.trace_roots(roots);
+                    roots.extend(argument.get_reference());
+                }
+                FiberState::Suspended(continuation, resume_value) => {
+                    continuation.trace_roots(roots);
+                    roots.extend(resume_value.get_reference());
+                }
+                FiberState::Finished(Ok(value)) => roots.extend(value.get_reference()),
+                FiberState::Running | FiberState::Finished(Err(_)) => {}
+            }
+        }
+    }
+}
+
+/// The values a join returns in registers 0 and 1.
+/// A fault becomes a reference to a string describing it and a status of 1.
+pub fn join_result(result: Result<Value, Fault>, memory: &mut Memory) -> Result<(Value, u64), Fault> {
+    match result {
+        Ok(value) => Ok((value, 0)),
+        Err(fault) => Ok((memory.allocate_string(&format!("{:?}", fault))?, 1)),
+    }
+}
+
+/// Runs fibers until `root` finishes and returns what it left in r0.
+/// A fault in the root fiber is returned, the faults of the others are handed to whoever joins them.
+pub fn run_fibers(core: &mut Core,
+                  root: FiberId,
+                  module: Arc<Module>,
+                  mut memory: Memory,
+                  frames: &mut Vec<*mut dyn StackFrame>,
+                  continuation_store: &mut ContinuationStore,
+                  backtrace: &mut BacktraceInfo) -> Result<Value, Fault> {
+    let depth = backtrace.len();
+    loop {
+        let fiber = core.scheduler.next(memory.get_thread_count() > 1)?.ok_or(Fault::Deadlock)?;
+        let state = std::mem::replace(&mut core.scheduler.fibers.get_mut(fiber)?.state, FiberState::Running);
+        core.scheduler.current = Some(fiber);
+        core.scheduler.executed = 0;
+
+        let result = match state {
+            FiberState::New(function, frame, argument) => {
+                backtrace.push(BacktraceEntry::new(frame.get_function_name(), None, None));
+                core.set_value(&Target(8, RegisterType::U64), argument);
+                match function {
+                    Function::ByteCode(..) => call_bytecode_function(core, *frame, module.clone(), frames, memory.clone(), continuation_store, backtrace),
+                    Function::Native(native) => call_native_function(native, core, *frame, module.clone(), frames, memory.clone(), continuation_store, backtrace),
+                }
+            }
+            FiberState::Suspended(continuation, resume_value) => {
+                backtrace.push(BacktraceEntry::new(continuation.get_function_name(), None, None));
+                core.set_value(&Target(0, RegisterType::U64), resume_value);
+                call_bytecode_function(core, continuation, module.clone(), frames, memory.clone(), continuation_store, backtrace)
+            }
+            FiberState::Running | FiberState::Finished(_) => unreachable!("only new and suspended fibers are ready"),
+        };
+        core.scheduler.current = None;
+
+        let result = match result {
+            Ok(InstructionResult::Stop) => return Ok(core.registers[0].value.clone()),
+            Ok(InstructionResult::Continue | InstructionResult::Return) => {
+                backtrace.pop();
+                Ok(core.registers[0].value.clone())
+            }
+            Ok(InstructionResult::Unwind(effect, Some(continuation))) if effect.as_ref() == SUSPEND_EFFECT => {
+                backtrace.truncate(depth);
+                core.scheduler.suspend(fiber, continuation)?;
+                continue;
+            }
+            Ok(InstructionResult::Unwind(effect, _)) => Err(Fault::UnhandledEffect(effect)),
+            Ok(result) => panic!("Invalid instruction result: {:?}", result),
+            Err(fault) => Err(fault),
+        };
+
+        if fiber == root {
+            return result;
+        }
+        backtrace.truncate(depth);
+        core.scheduler.finish(fiber, result, &mut memory)?;
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fmt::Display;
 use std::sync::Arc;
 use crate::backtrace::{BacktraceEntry, BacktraceInfo};
@@ -5,12 +6,21 @@ use crate::machine::core::Core;
 use crate::memory::Memory;
 use crate::program::function::{Function, FunctionPath, NativeFunction};
 use crate::program::Module;
+use crate::program::verifier::{Diagnostic, verify_module};
 use crate::stack_frame::frame::Frame;
 use crate::value::{Value, ValueType};
 use crate::stack_frame::delimited_continuation::{ContinuationStore, DelimitedContinuation};
 use crate::stack_frame::StackFrame;
+use crate::machine::fiber::{run_fibers, Wait, SUSPEND_EFFECT};
+use crate::machine::native::NativeContext;
+use crate::machine::statistics::Counter;
 
 pub mod core;
+pub mod fiber;
+pub mod reactor;
+pub mod native;
+pub mod stdio;
+pub mod statistics;
 
 
 /// The result of executing an instruction.
@@ -19,13 +29,15 @@ pub enum InstructionResult {
     Stop,
     Continue,
     Return,
-    Unwind(Box<str>),
+    /// Unwinds to the nearest handler for the named effect.
+    /// The continuation resumes the performing code, natives may leave it out.
+    Unwind(Box<str>, Option<DelimitedContinuation>),
     Call(Function, Frame),
     CallContinuation(DelimitedContinuation),
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Fault {
     DivisionByZero,
     StackOverflow,
@@ -35,10 +47,13 @@ pub enum Fault {
     InvalidRegister,
     InvalidJump,
     FunctionNotFound(FunctionPath),
+    LabelNotFound(Box<str>),
     ContinuationNotFound(u64),
+    ContinuationAlreadyResumed(u64),
     InvalidString,
     InvalidOperation(String),
     MemoryError(String),
+    IoError(String),
     InvalidStackLevel,
     InvalidStackOffset,
     StackFrameOutOfBounds,
@@ -46,7 +61,24 @@ pub enum Fault {
     StackOutOfBounds,
     NullPointerReference,
     InvalidReference,
+    UseAfterFree(u64),
+    /// The thread with this handle was joined before.
+    ThreadAlreadyJoined(u64),
+    MutexNotOwned,
+    MutexAlreadyOwned,
+    ChannelClosed,
+    /// Blocking would never return because no other thread could wake this one up.
+    Deadlock,
     IndexOutOfBounds,
+    InvalidFieldIndex(u64),
+    InvalidMethodIndex(u64),
+    InvalidCaptureIndex(u64),
+    ClassNotFound(FunctionPath),
+    VerificationFailed(Vec<Diagnostic>),
+    UnhandledEffect(Box<str>),
+    PromptNotFound(Box<str>),
+    /// An effect performed by code a native called wasn't handled before it reached the native.
+    EffectThroughNative(Box<str>),
 }
 
 #[derive(Debug, Clone)]
@@ -191,33 +223,26 @@ impl Default for Register {
 
 
 pub fn call_main(core: &mut Core, module: Arc<Module>, memory: Memory, backtrace: &mut BacktraceInfo) -> Result<(), Fault> {
+    let diagnostics = verify_module(&module);
+    if !diagnostics.is_empty() {
+        return Err(Fault::VerificationFailed(diagnostics));
+    }
+
     let main = module.get_function(&"main".into()).ok_or(Fault::FunctionNotFound("main".into()))?;
     let main_frame = Frame::new("main".into(), main.get_instructions());
+    run_function(core, main, main_frame, module, memory, backtrace).map(|_| ())
+}
+
+/// Runs a function as the root fiber of a thread, with a continuation store of its own.
+/// The function gets the argument registers of `core` as its arguments, and it returns what it left in r0
+/// once the root fiber finishes even if other fibers haven't.
+pub fn run_function(core: &mut Core, function: Function, frame: Frame, module: Arc<Module>, memory: Memory, backtrace: &mut BacktraceInfo) -> Result<Value, Fault> {
     let mut frames = Vec::new();
     let mut continuation_store = ContinuationStore::new();
 
-    backtrace.push(BacktraceEntry::new("main".into(), None, None));
-
-    let result = match main {
-        Function::ByteCode(_) => call_bytecode_function(core, main_frame, module, &mut frames, memory, &mut continuation_store, backtrace)?,
-        Function::Native(native) => call_native_function(native, core, main_frame, module, &mut frames, memory, &mut continuation_store, backtrace)?,
-    };
-
-    match result {
-        InstructionResult::Stop => {
-            return Ok(())
-        }
-        InstructionResult::Continue => {
-            return Ok(())
-        }
-        InstructionResult::Return => {
-            return Ok(())
-        }
-        InstructionResult::Unwind(effect) => {
-            todo!("Add code for error message for letting an effect escape beyond the main function")
-        }
-        _ => panic!("Invalid instruction result"),
-    }
+    let argument = core.registers[8].value.clone();
+    let root = core.scheduler.spawn(function, frame, argument);
+    run_fibers(core, root, module, memory, &mut frames, &mut continuation_store, backtrace)
 }
 
 
@@ -229,93 +254,186 @@ pub fn call_bytecode_function(core: &mut Core,
                               continuation_store: &mut ContinuationStore,
                               backtrace: &mut BacktraceInfo) -> Result<InstructionResult,Fault> {
     stack_frame.backup_registers(&core.registers);
+    stack_frame.resume(&mut core.registers);
+    run_frame(core, &mut stack_frame, module, frames, memory, continuation_store, backtrace)
+}
+
+/// Executes the instructions of a frame until it returns, stops or an effect unwinds out of it,
+/// which restores the registers the frame backed up.
+/// A frame that didn't back them up leaves the registers as its instructions left them.
+pub fn run_frame(core: &mut Core,
+                 stack_frame: &mut (impl StackFrame + 'static),
+                 module: Arc<Module>,
+                 frames: &mut Vec<*mut dyn StackFrame>,
+                 mut memory: Memory,
+                 continuation_store: &mut ContinuationStore,
+                 backtrace: &mut BacktraceInfo) -> Result<InstructionResult,Fault> {
+    let mut inner_continuation = stack_frame.take_inner_continuation();
 
     loop {
-        let result = core.execute_instruction(&mut stack_frame, &module, frames, memory.clone(), continuation_store, backtrace)?;
-        match result {
-            InstructionResult::Continue => {},
+        let result = match inner_continuation.take() {
+            Some(continuation) => InstructionResult::CallContinuation(continuation),
+            None => {
+                if memory.should_collect() {
+                    collect_garbage(core, stack_frame, frames, &mut memory, continuation_store)?;
+                }
+                if core.scheduler.should_preempt() {
+                    core.scheduler.set_wait(Wait::Yield);
+                    if let Some(result) = handle_effect(core, stack_frame, continuation_store, backtrace, SUSPEND_EFFECT.into(), None, false) {
+                        return Ok(result);
+                    }
+                    continue;
+                }
+                core.execute_instruction(stack_frame, &module, frames, memory.clone(), continuation_store, backtrace)?
+            },
+        };
+        let callee_result = match result {
+            InstructionResult::Continue => continue,
             InstructionResult::Stop => {
                 stack_frame.restore_registers(&mut core.registers);
-                frames.pop();
                 return Ok(InstructionResult::Stop);
             },
             InstructionResult::Return => {
                 stack_frame.restore_registers(&mut core.registers);
-                frames.pop();
-                backtrace.pop();
                 return Ok(InstructionResult::Continue);
             },
-            InstructionResult::Unwind(effect_name) => {
-                backtrace.increment_unwind_levels();
-                todo!("Add code for checking effect to handle before continue unwinding")
+            InstructionResult::Unwind(effect_name, continuation) => {
+                if let Some(result) = handle_effect(core, stack_frame, continuation_store, backtrace, effect_name, continuation, false) {
+                    return Ok(result);
+                }
+                continue;
             },
-
             InstructionResult::Call(function, new_stack_frame) => {
+                check_call_depth(core, frames.len())?;
                 backtrace.push(BacktraceEntry::new(new_stack_frame.get_function_name(), None, None));
-                frames.push(&mut stack_frame as *mut dyn StackFrame);
-                match function {
-                    Function::ByteCode(_) => {
-                        let result = call_bytecode_function(core, new_stack_frame, module.clone(), frames, memory.clone(),continuation_store, backtrace)?;
-                        match result {
-                            InstructionResult::Continue | InstructionResult::Return => {},
-                            InstructionResult::Stop => {
-                                stack_frame.restore_registers(&mut core.registers);
-                                frames.pop();
-                                return Ok(InstructionResult::Stop);
-                            },
-                            InstructionResult::Unwind(effect_name) => {
-                                backtrace.increment_unwind_levels();
-                                todo!("Add code for checking effect to handle before continue unwinding");
-                                continue;
-                            },
-                            x => panic!("Invalid instruction result: {:?}", x),
-                        }
+                frames.push(stack_frame as *mut dyn StackFrame);
+                let result = match function {
+                    Function::ByteCode(..) => {
+                        core.count(Counter::Calls, 1);
+                        call_bytecode_function(core, new_stack_frame, module.clone(), frames, memory.clone(), continuation_store, backtrace)
                     },
                     Function::Native(native) => {
-                        let result = call_native_function(native, core, new_stack_frame, module.clone(), frames, memory.clone(),continuation_store, backtrace)?;
-                        match result {
-                            InstructionResult::Continue | InstructionResult::Return => {}
-                            InstructionResult::Stop => {
-                                stack_frame.restore_registers(&mut core.registers);
-                                frames.pop();
-                                return Ok(InstructionResult::Stop);
-                            },
-                            InstructionResult::Unwind(effect_name) => {
-                                backtrace.increment_unwind_levels();
-                                todo!("Add code for checking effect to handle before continue unwinding");
-                                continue;
-                            },
-                            x => panic!("Invalid instruction result: {:?}", x)
-                        }
-                    }
-                }
+                        core.count(Counter::NativeCalls, 1);
+                        call_native_function(native, core, new_stack_frame, module.clone(), frames, memory.clone(), continuation_store, backtrace)
+                    },
+                };
                 frames.pop();
-                backtrace.pop();
+                result?
             },
             InstructionResult::CallContinuation(continuation) => {
-                frames.push(&mut stack_frame as *mut dyn StackFrame);
+                check_call_depth(core, frames.len())?;
                 backtrace.push(BacktraceEntry::new(continuation.get_function_name(), None, None));
-                let result = call_bytecode_function(core, continuation, module.clone(), frames, memory.clone(), continuation_store, backtrace)?;
+                frames.push(stack_frame as *mut dyn StackFrame);
+                let result = call_bytecode_function(core, continuation, module.clone(), frames, memory.clone(), continuation_store, backtrace);
                 frames.pop();
-                match result {
-                    InstructionResult::Continue => {
-                        backtrace.pop();
-                    },
-                    InstructionResult::Stop => {
-                        stack_frame.restore_registers(&mut core.registers);
-                        return Ok(InstructionResult::Stop);
-                    },
-                    InstructionResult::Unwind(effect_name) => {
-                        backtrace.increment_unwind_levels();
-                        todo!("Add code for checking effect to handle before continue unwinding");
-                        continue;
-                    },
-                    x => panic!("Invalid instruction result: {:?}", x)
+                result?
+            },
+        };
+
+        match callee_result {
+            InstructionResult::Continue | InstructionResult::Return => {
+                backtrace.pop();
+            },
+            InstructionResult::Stop => {
+                stack_frame.restore_registers(&mut core.registers);
+                return Ok(InstructionResult::Stop);
+            },
+            InstructionResult::Unwind(effect_name, continuation) => {
+                // The callee's backtrace entry stays until a handler is found so an unhandled effect shows where it came from.
+                backtrace.increment_unwind_levels();
+                if let Some(result) = handle_effect(core, stack_frame, continuation_store, backtrace, effect_name, continuation, true) {
+                    return Ok(result);
                 }
-            }
+            },
+            x => panic!("Invalid instruction result: {:?}", x),
         }
     }
-    Ok(InstructionResult::Continue)
+}
+
+/// Faults with `StackOverflow` if a call on top of `depth` frames would go deeper than the core's limit.
+pub fn check_call_depth(core: &Core, depth: usize) -> Result<(), Fault> {
+    if depth >= core.limits.max_call_depth {
+        return Err(Fault::StackOverflow);
+    }
+    Ok(())
+}
+
+/// Frees every heap object the running code can no longer reach.
+/// The roots are the registers, which are saved into `stack_frame` for the collection, the frames in `frames`,
+/// the stored continuations, the other fibers and the values natives keep alive.
+/// Returns the number of objects freed.
+/// Only the roots of the calling thread are known, so while other threads are running nothing is collected
+/// and the collection is deferred until the calling thread is the only one left.
+pub fn collect_garbage(core: &mut Core,
+                       stack_frame: &mut dyn StackFrame,
+                       frames: &[*mut (dyn StackFrame + 'static)],
+                       memory: &mut Memory,
+                       continuation_store: &mut ContinuationStore) -> Result<usize, Fault> {
+    if memory.get_thread_count() > 1 {
+        memory.defer_collection();
+        return Ok(0);
+    }
+    stack_frame.backup_registers_for_gc(&mut core.registers);
+
+    let mut roots = Vec::new();
+    stack_frame.trace_roots(&mut roots);
+    for frame in frames.iter() {
+        unsafe { frame.as_ref() }.expect("Frame is null").trace_roots(&mut roots);
+    }
+    core.scheduler.trace_roots(&mut roots);
+    roots.extend(core.native_roots.iter().filter_map(Value::get_reference));
+    // Stored continuations are only kept while something still holds their index.
+    let mut reached = HashSet::new();
+    let result = memory.collect_with(roots, |value, roots| continuation_store.trace(value, &mut reached, roots));
+    if let Ok(freed) = result {
+        continuation_store.retain_reached(&reached);
+        core.count(Counter::Collections, 1);
+        core.count(Counter::Freed, freed as u64);
+    }
+
+    stack_frame.restore_registers_for_gc(&mut core.registers);
+    result
+}
+
+/// Runs the frame's handler for an effect, or returns the result to unwind further with.
+/// A frame that an effect unwinds through becomes the outermost frame of the effect's continuation.
+/// Effects raised by natives carry no continuation, so resuming them continues the calling frame after the call.
+fn handle_effect(core: &mut Core,
+                 stack_frame: &mut impl StackFrame,
+                 continuation_store: &mut ContinuationStore,
+                 backtrace: &mut BacktraceInfo,
+                 effect_name: Box<str>,
+                 continuation: Option<DelimitedContinuation>,
+                 from_callee: bool) -> Option<InstructionResult> {
+    let capture_frame = |core: &Core, stack_frame: &mut dyn StackFrame| {
+        let mut continuation = stack_frame.make_continuation();
+        continuation.capture_registers(&core.registers);
+        continuation
+    };
+
+    match stack_frame.get_handler(&effect_name) {
+        Some(handler) => {
+            let continuation = continuation.unwrap_or_else(|| capture_frame(core, stack_frame));
+            backtrace.remove_unwind_levels();
+            if handler.one_shot {
+                continuation_store.store_one_shot(continuation);
+            } else {
+                continuation_store.store(continuation);
+            }
+            core.set_value(&handler.target, Value::U64(continuation_store.get_last_index()));
+            stack_frame.set_program_counter(handler.address);
+            None
+        },
+        None => {
+            let continuation = match continuation {
+                Some(continuation) if from_callee => continuation.enclose(stack_frame.make_continuation()),
+                Some(continuation) => continuation,
+                None => capture_frame(core, stack_frame),
+            };
+            stack_frame.restore_registers(&mut core.registers);
+            Some(InstructionResult::Unwind(effect_name, Some(continuation)))
+        },
+    }
 }
 
 pub fn call_native_function(native_function: NativeFunction,
@@ -331,24 +449,29 @@ pub fn call_native_function(native_function: NativeFunction,
     frames.push(&mut stack_frame as *mut dyn StackFrame);
 
 
-    let result = native_function(core, module, frames, memory.clone(), continuation_store)?;
+    let native_roots = core.native_roots.len();
+    let mut context = NativeContext::new(core, module, frames, memory, continuation_store, backtrace);
+    let result = native_function(&mut context);
+    let stopped = context.is_stopped();
     frames.pop();
-    match result {
-        InstructionResult::Continue => {
+    core.native_roots.truncate(native_roots);
+    match result? {
+        InstructionResult::Continue | InstructionResult::Return if stopped => {
+            stack_frame.restore_registers(&mut core.registers);
+            Ok(InstructionResult::Stop)
+        },
+        InstructionResult::Continue | InstructionResult::Return => {
             stack_frame.restore_registers(&mut core.registers);
-            backtrace.pop();
-            return Ok(InstructionResult::Continue);
+            Ok(InstructionResult::Continue)
         },
         InstructionResult::Stop => {
             stack_frame.restore_registers(&mut core.registers);
-            return Ok(InstructionResult::Stop);
+            Ok(InstructionResult::Stop)
         },
-        InstructionResult::Unwind(effect_name) => {
-            backtrace.increment_unwind_levels();
-            todo!("Add code for checking effect to handle before continue unwinding");
+        InstructionResult::Unwind(effect_name, continuation) => {
+            stack_frame.restore_registers(&mut core.registers);
+            Ok(InstructionResult::Unwind(effect_name, continuation))
         },
         _ => panic!("Invalid instruction result"),
     }
-
 }
-
@@ -0,0 +1,406 @@
+use std::sync::Arc;
+use crate::backtrace::{BacktraceEntry, BacktraceInfo};
+use crate::instruction::{RegisterType, Target};
+use crate::machine::core::Core;
+use crate::machine::fiber::SUSPEND_EFFECT;
+use crate::machine::statistics::Counter;
+use crate::machine::stdio::Stdio;
+use crate::machine::{call_bytecode_function, call_native_function, check_call_depth, collect_garbage, Fault, InstructionResult};
+use crate::memory::Memory;
+use crate::program::function::{Function, FunctionPath};
+use crate::program::Module;
+use crate::stack_frame::delimited_continuation::ContinuationStore;
+use crate::stack_frame::frame::Frame;
+use crate::stack_frame::{StackFrame, REGISTER_COUNT};
+use crate::value::closure::Closure;
+use crate::value::{Value, ValueType};
+
+
+/// The register the first argument of a call is passed in, the others follow it.
+pub const ARGUMENT_REGISTER: usize = 8;
+
+/// What a native function gets to work with when it is called.
+/// Arguments are numbered from 0, which is the value in r8, and return values are numbered
+/// by the register they are returned in, so the usual result goes in 0 and a status in 1.
+pub struct NativeContext<'a> {
+    core: &'a mut Core,
+    module: Arc<Module>,
+    /// The frames of the calling code, the last one is the native's own frame.
+    frames: &'a mut Vec<*mut dyn StackFrame>,
+    memory: Memory,
+    continuation_store: &'a mut ContinuationStore,
+    backtrace: &'a mut BacktraceInfo,
+    /// Set when code the native called halted, the program stops once the native returns.
+    stopped: bool,
+}
+
+impl<'a> NativeContext<'a> {
+    pub fn new(core: &'a mut Core,
+               module: Arc<Module>,
+               frames: &'a mut Vec<*mut dyn StackFrame>,
+               memory: Memory,
+               continuation_store: &'a mut ContinuationStore,
+               backtrace: &'a mut BacktraceInfo) -> Self {
+        NativeContext { core, module, frames, memory, continuation_store, backtrace, stopped: false }
+    }
+
+    /// An argument as it was stored in its register.
+    pub fn get_argument(&self, index: usize) -> Result<Value, Fault> {
+        self.core.registers.get(ARGUMENT_REGISTER + index)
+            .map(|register| register.value.clone())
+            .ok_or(Fault::InvalidRegister)
+    }
+
+    /// An argument converted to `register_type` like an instruction reading the register would.
+    /// A value that can't be converted, like a reference read as a float, is a fault.
+    pub fn get_typed_argument(&self, index: usize, register_type: RegisterType) -> Result<Value, Fault> {
+        let value = self.get_argument(index)?;
+        let value_type = register_type.into();
+        if !value.can_transmute(value_type) {
+            return Err(Fault::InvalidOperation(format!("argument {} is {:?}, expected {:?}", index, value.get_type(), value_type)));
+        }
+        Ok(value.transmute(value_type))
+    }
+
+    pub fn get_u64(&self, index: usize) -> Result<u64, Fault> {
+        Ok(self.get_typed_argument(index, RegisterType::U64)?.to_usize() as u64)
+    }
+
+    pub fn get_i64(&self, index: usize) -> Result<i64, Fault> {
+        match self.get_argument(index)? {
+            Value::I64(value) => Ok(value),
+            _ => Ok(self.get_typed_argument(index, RegisterType::I64)?.to_usize() as i64),
+        }
+    }
+
+    pub fn get_f64(&self, index: usize) -> Result<f64, Fault> {
+        match self.get_argument(index)? {
+            Value::F64(value) => Ok(value),
+            // A u64 register can hold the bits of a float.
+            Value::U64(bits) => Ok(f64::from_bits(bits)),
+            _ => match self.get_typed_argument(index, RegisterType::F64)? {
+                Value::F64(value) => Ok(value),
+                value => unreachable!("transmuted to F64, got {:?}", value.get_type()),
+            },
+        }
+    }
+
+    /// An argument that must hold a reference, either typed as one or as a u64.
+    pub fn get_reference(&self, index: usize) -> Result<u64, Fault> {
+        self.get_argument(index)?.get_reference().ok_or(Fault::InvalidReference)
+    }
+
+    /// The string an argument refers to, from the heap or a string table.
+    pub fn get_string(&self, index: usize) -> Result<&str, Fault> {
+        let reference = self.get_reference(index)?;
+        self.memory.get_string(reference, &self.module)
+    }
+
+    /// An argument converted to a Rust type.
+    pub fn get<T: FromValue>(&self, index: usize) -> Result<T, Fault> {
+        T::from_value(self.get_argument(index)?, &self.memory, &self.module)
+    }
+
+    pub fn get_closure(&self, index: usize) -> Result<Closure, Fault> {
+        self.memory.get_closure(self.get_reference(index)?)
+    }
+
+    /// Sets the value returned in register `index`.
+    pub fn set_return(&mut self, index: usize, value: impl Into<Value>) {
+        self.core.set_value(&Target(index, RegisterType::U64), value.into());
+    }
+
+    /// Converts a Rust value and returns it in r0.
+    pub fn set_return_value(&mut self, value: impl IntoValue) -> Result<(), Fault> {
+        let value = value.into_value(&mut self.memory)?;
+        self.set_return(0, value);
+        Ok(())
+    }
+
+    /// Allocates a string on the heap and returns a reference to it.
+    pub fn allocate_string(&mut self, string: &str) -> Result<Value, Fault> {
+        self.memory.allocate_string(string)
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn get_memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    pub fn get_core(&mut self) -> &mut Core {
+        self.core
+    }
+
+    /// Where the program's output goes.
+    pub fn get_stdio(&self) -> &Stdio {
+        &self.core.stdio
+    }
+
+    pub fn get_module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    pub fn get_continuation_store(&mut self) -> &mut ContinuationStore {
+        self.continuation_store
+    }
+
+    /// The calls that led to this native, the native itself is the last entry.
+    pub fn get_backtrace(&self) -> &BacktraceInfo {
+        self.backtrace
+    }
+
+    /// Makes the garbage collector treat `value` as reachable until the native returns.
+    pub fn keep_alive(&mut self, value: Value) {
+        self.core.native_roots.push(value);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Calls the function at `path` with `arguments` in r8 onwards and returns what it left in r0.
+    /// The native's own arguments are still there afterwards.
+    ///
+    /// The called code runs on top of the native's frame, so faults and backtraces go through the native.
+    /// Effects can't be handled outside of the native since its Rust frame can't be captured,
+    /// so one that isn't handled by the called code is a fault, and the fiber isn't preempted during the call.
+    /// Heap objects the native only holds in Rust may be collected during the call unless passed to `keep_alive`.
+    pub fn call(&mut self, path: &FunctionPath, arguments: &[Value]) -> Result<Value, Fault> {
+        let function = self.module.get_function(path).ok_or_else(|| Fault::FunctionNotFound(path.clone()))?;
+        let frame = Frame::new(path.clone(), function.get_instructions());
+        self.call_frame(function, frame, arguments)
+    }
+
+    /// Calls a closure like `call` does a function.
+    pub fn call_closure(&mut self, closure: &Closure, arguments: &[Value]) -> Result<Value, Fault> {
+        let function = closure.get_function().clone();
+        let frame = Frame::new_closure(closure.get_path().clone(), function.get_instructions(), closure.get_captures().into());
+        self.call_frame(function, frame, arguments)
+    }
+
+    fn call_frame(&mut self, function: Function, frame: Frame, arguments: &[Value]) -> Result<Value, Fault> {
+        if ARGUMENT_REGISTER + arguments.len() > REGISTER_COUNT {
+            return Err(Fault::InvalidRegister);
+        }
+        check_call_depth(self.core, self.frames.len())?;
+        let saved = self.core.registers.clone();
+        for (index, argument) in arguments.iter().enumerate() {
+            self.core.set_value(&Target(ARGUMENT_REGISTER + index, RegisterType::U64), argument.clone());
+        }
+
+        self.backtrace.push(BacktraceEntry::new(frame.get_function_name(), None, None));
+        self.core.scheduler.pin();
+        let result = match function {
+            Function::ByteCode(..) => {
+                self.core.count(Counter::Calls, 1);
+                call_bytecode_function(self.core, frame, self.module.clone(), self.frames, self.memory.clone(), self.continuation_store, self.backtrace)
+            },
+            Function::Native(native) => {
+                self.core.count(Counter::NativeCalls, 1);
+                call_native_function(native, self.core, frame, self.module.clone(), self.frames, self.memory.clone(), self.continuation_store, self.backtrace)
+            },
+        };
+        self.core.scheduler.unpin();
+
+        let value = self.core.registers[0].value.clone();
+        self.core.registers[ARGUMENT_REGISTER..].clone_from_slice(&saved[ARGUMENT_REGISTER..]);
+        match result? {
+            InstructionResult::Continue | InstructionResult::Return => {
+                self.backtrace.pop();
+                Ok(value)
+            }
+            InstructionResult::Stop => {
+                self.backtrace.pop();
+                self.stopped = true;
+                Ok(value)
+            }
+            InstructionResult::Unwind(effect_name, _) => {
+                // The backtrace keeps the entries of the code that performed the effect.
+                if &*effect_name == SUSPEND_EFFECT {
+                    self.core.scheduler.cancel_wait();
+                }
+                Err(Fault::EffectThroughNative(effect_name))
+            }
+            result => panic!("Invalid instruction result: {:?}", result),
+        }
+    }
+
+    /// Collects garbage with the calling code's frames as roots and returns the number of objects freed.
+    pub fn collect_garbage(&mut self) -> Result<usize, Fault> {
+        let (current, callers) = self.frames.split_last().expect("native functions run in their own frame");
+        let current = unsafe { current.as_mut() }.expect("Frame is null");
+        collect_garbage(self.core, current, callers, &mut self.memory, self.continuation_store)
+    }
+}
+
+
+/// A Rust type that native functions can take as an argument.
+pub trait FromValue: Sized {
+    fn from_value(value: Value, memory: &Memory, module: &Module) -> Result<Self, Fault>;
+}
+
+/// A Rust type that native functions can return.
+pub trait IntoValue {
+    fn into_value(self, memory: &mut Memory) -> Result<Value, Fault>;
+}
+
+/// An error a native function can return, it becomes the fault of the call.
+pub trait IntoFault {
+    fn into_fault(self) -> Fault;
+}
+
+impl IntoFault for Fault {
+    fn into_fault(self) -> Fault {
+        self
+    }
+}
+
+impl IntoFault for String {
+    fn into_fault(self) -> Fault {
+        Fault::InvalidOperation(self)
+    }
+}
+
+impl IntoFault for &str {
+    fn into_fault(self) -> Fault {
+        Fault::InvalidOperation(self.to_string())
+    }
+}
+
+impl IntoFault for std::io::Error {
+    fn into_fault(self) -> Fault {
+        Fault::IoError(self.to_string())
+    }
+}
+
+fn is_number(value: &Value) -> bool {
+    matches!(value, Value::U8(_) | Value::I8(_) | Value::U16(_) | Value::I16(_) | Value::U32(_) | Value::I32(_)
+        | Value::U64(_) | Value::I64(_) | Value::F32(_) | Value::F64(_))
+}
+
+macro_rules! impl_number {
+    ($type:ty, $variant:ident) => {
+        impl FromValue for $type {
+            fn from_value(value: Value, _memory: &Memory, _module: &Module) -> Result<Self, Fault> {
+                if !is_number(&value) {
+                    return Err(Fault::InvalidOperation(format!("expected a number, got {:?}", value.get_type())));
+                }
+                match value.transmute(ValueType::$variant) {
+                    Value::$variant(value) => Ok(value),
+                    _ => unreachable!("transmuted to {}", stringify!($variant)),
+                }
+            }
+        }
+
+        impl IntoValue for $type {
+            fn into_value(self, _memory: &mut Memory) -> Result<Value, Fault> {
+                Ok(Value::$variant(self))
+            }
+        }
+    };
+}
+
+impl_number!(u8, U8);
+impl_number!(i8, I8);
+impl_number!(u16, U16);
+impl_number!(i16, I16);
+impl_number!(u32, U32);
+impl_number!(i32, I32);
+impl_number!(u64, U64);
+impl_number!(i64, I64);
+impl_number!(f32, F32);
+impl_number!(f64, F64);
+
+impl FromValue for bool {
+    fn from_value(value: Value, memory: &Memory, module: &Module) -> Result<Self, Fault> {
+        u8::from_value(value, memory, module).map(|value| value != 0)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _memory: &mut Memory) -> Result<Value, Fault> {
+        Ok(Value::U8(self as u8))
+    }
+}
+
+/// The value as it is, references stay references.
+impl FromValue for Value {
+    fn from_value(value: Value, _memory: &Memory, _module: &Module) -> Result<Self, Fault> {
+        Ok(value)
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _memory: &mut Memory) -> Result<Value, Fault> {
+        Ok(self)
+    }
+}
+
+/// Ignores the value.
+impl FromValue for () {
+    fn from_value(_value: Value, _memory: &Memory, _module: &Module) -> Result<Self, Fault> {
+        Ok(())
+    }
+}
+
+/// Returns 0.
+impl IntoValue for () {
+    fn into_value(self, _memory: &mut Memory) -> Result<Value, Fault> {
+        Ok(Value::U64(0))
+    }
+}
+
+/// Copied out of the heap or a string table.
+impl FromValue for String {
+    fn from_value(value: Value, memory: &Memory, module: &Module) -> Result<Self, Fault> {
+        let reference = value.get_reference().ok_or(Fault::InvalidReference)?;
+        memory.get_string(reference, module).map(str::to_string)
+    }
+}
+
+/// Allocated on the heap.
+impl IntoValue for String {
+    fn into_value(self, memory: &mut Memory) -> Result<Value, Fault> {
+        memory.allocate_string(&self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, memory: &mut Memory) -> Result<Value, Fault> {
+        memory.allocate_string(self)
+    }
+}
+
+/// Copied out of a list on the heap.
+impl<T: FromValue> FromValue for Vec<T> {
+    fn from_value(value: Value, memory: &Memory, module: &Module) -> Result<Self, Fault> {
+        let reference = value.get_reference().ok_or(Fault::InvalidReference)?;
+        let length = memory.get_list_length(reference)?.to_usize() as u64;
+        (0..length)
+            .map(|index| T::from_value(memory.access_list(reference, index)?, memory, module))
+            .collect()
+    }
+}
+
+/// Allocated as a list on the heap.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, memory: &mut Memory) -> Result<Value, Fault> {
+        let list = memory.allocate_list(self.len(), ValueType::U64)?;
+        let reference = list.get_reference().ok_or(Fault::InvalidReference)?;
+        for (index, element) in self.into_iter().enumerate() {
+            let element = element.into_value(memory)?;
+            memory.store_list(reference, index as u64, element)?;
+        }
+        Ok(list)
+    }
+}
+
+/// An `Err` becomes the fault of the call.
+impl<T: IntoValue, E: IntoFault> IntoValue for Result<T, E> {
+    fn into_value(self, memory: &mut Memory) -> Result<Value, Fault> {
+        self.map_err(IntoFault::into_fault)?.into_value(memory)
+    }
+}
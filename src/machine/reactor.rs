@@ -0,0 +1,159 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::Duration;
+use crate::machine::Fault;
+use crate::machine::fiber::FiberId;
+
+
+/// How many events a single poll takes from the kernel.
+const EVENT_CAPACITY: usize = 64;
+
+/// What a fiber is waiting for a file descriptor to become.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interest {
+    Readable,
+    Writable,
+}
+
+#[derive(Debug, Default)]
+struct Waiters {
+    readers: Vec<FiberId>,
+    writers: Vec<FiberId>,
+    /// If the descriptor is in the epoll set.
+    registered: bool,
+}
+
+impl Waiters {
+    fn get_events(&self) -> u32 {
+        let mut events = 0;
+        if !self.readers.is_empty() {
+            events |= libc::EPOLLIN as u32;
+        }
+        if !self.writers.is_empty() {
+            events |= libc::EPOLLOUT as u32;
+        }
+        events
+    }
+}
+
+pub fn io_error(error: io::Error) -> Fault {
+    Fault::IoError(error.to_string())
+}
+
+/// Parks fibers on file descriptors until epoll reports them ready.
+/// A descriptor is only in the epoll set while some fiber waits on it.
+#[derive(Debug)]
+pub struct Reactor {
+    epoll: OwnedFd,
+    waiters: HashMap<RawFd, Waiters>,
+}
+
+impl Reactor {
+    pub fn new() -> Result<Self, Fault> {
+        let epoll = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll < 0 {
+            return Err(io_error(io::Error::last_os_error()));
+        }
+        Ok(Reactor {
+            epoll: unsafe { OwnedFd::from_raw_fd(epoll) },
+            waiters: HashMap::new(),
+        })
+    }
+
+    /// Returns whether any fiber is parked.
+    pub fn is_waiting(&self) -> bool {
+        !self.waiters.is_empty()
+    }
+
+    /// Parks `fiber` until `fd` is ready for `interest`.
+    /// Returns false if the descriptor can't be polled, regular files for example are always ready.
+    pub fn register(&mut self, fd: RawFd, interest: Interest, fiber: FiberId) -> Result<bool, Fault> {
+        let waiters = self.waiters.entry(fd).or_default();
+        match interest {
+            Interest::Readable => waiters.readers.push(fiber),
+            Interest::Writable => waiters.writers.push(fiber),
+        }
+        match self.update(fd) {
+            Err(error) if error.raw_os_error() == Some(libc::EPERM) => {
+                self.waiters.remove(&fd);
+                Ok(false)
+            }
+            result => result.map(|_| true).map_err(io_error),
+        }
+    }
+
+    /// Makes every fiber parked on `fd` ready, for when it is closed.
+    pub fn remove(&mut self, fd: RawFd, ready: &mut VecDeque<FiberId>) {
+        if let Some(waiters) = self.waiters.remove(&fd) {
+            if waiters.registered {
+                unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) };
+            }
+            ready.extend(waiters.readers);
+            ready.extend(waiters.writers);
+        }
+    }
+
+    /// Brings the epoll set in line with the fibers waiting on `fd`.
+    fn update(&mut self, fd: RawFd) -> io::Result<()> {
+        let Some(waiters) = self.waiters.get_mut(&fd) else {
+            return Ok(());
+        };
+        let events = waiters.get_events();
+        let operation = match (events, waiters.registered) {
+            (0, false) => {
+                self.waiters.remove(&fd);
+                return Ok(());
+            }
+            (0, true) => libc::EPOLL_CTL_DEL,
+            (_, true) => libc::EPOLL_CTL_MOD,
+            (_, false) => libc::EPOLL_CTL_ADD,
+        };
+        let mut event = libc::epoll_event { events, u64: fd as u64 };
+        if unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), operation, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if events == 0 {
+            self.waiters.remove(&fd);
+        } else {
+            waiters.registered = true;
+        }
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for parked descriptors to become ready and adds their fibers to `ready`.
+    /// `None` waits until one does.
+    pub fn poll(&mut self, timeout: Option<Duration>, ready: &mut VecDeque<FiberId>) -> Result<(), Fault> {
+        let timeout = match timeout {
+            // Round up so a timer isn't woken before its deadline.
+            Some(timeout) => timeout.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32,
+            None => -1,
+        };
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; EVENT_CAPACITY];
+        let count = unsafe { libc::epoll_wait(self.epoll.as_raw_fd(), events.as_mut_ptr(), EVENT_CAPACITY as i32, timeout) };
+        if count < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                return Ok(());
+            }
+            return Err(io_error(error));
+        }
+
+        for event in &events[..count as usize] {
+            let fd = event.u64 as RawFd;
+            let Some(waiters) = self.waiters.get_mut(&fd) else {
+                continue;
+            };
+            // Errors and hang ups wake everyone so they see them when they retry.
+            let failed = event.events & (libc::EPOLLERR | libc::EPOLLHUP) as u32 != 0;
+            if failed || event.events & libc::EPOLLIN as u32 != 0 {
+                ready.extend(waiters.readers.drain(..));
+            }
+            if failed || event.events & libc::EPOLLOUT as u32 != 0 {
+                ready.extend(waiters.writers.drain(..));
+            }
+            self.update(fd).map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
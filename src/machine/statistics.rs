@@ -0,0 +1,58 @@
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// Something `Statistics` counts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Counter {
+    Instructions,
+    /// Calls of bytecode functions, by instructions or natives.
+    Calls,
+    NativeCalls,
+    /// Fibers spawned by the program, not counting the root fiber of each thread.
+    Fibers,
+    Threads,
+    Collections,
+    /// Heap objects freed by garbage collections.
+    Freed,
+}
+
+const COUNTERS: [(Counter, &str); 7] = [
+    (Counter::Instructions, "instructions"),
+    (Counter::Calls, "calls"),
+    (Counter::NativeCalls, "native calls"),
+    (Counter::Fibers, "fibers spawned"),
+    (Counter::Threads, "threads spawned"),
+    (Counter::Collections, "collections"),
+    (Counter::Freed, "objects freed"),
+];
+
+/// Counts what a VM does, shared by the cores of all of its threads.
+#[derive(Debug, Default)]
+pub struct Statistics {
+    counters: [AtomicU64; COUNTERS.len()],
+}
+
+impl Statistics {
+    pub fn new() -> Self {
+        Statistics::default()
+    }
+
+    pub fn add(&self, counter: Counter, amount: u64) {
+        self.counters[counter as usize].fetch_add(amount, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize].load(Ordering::Relaxed)
+    }
+}
+
+/// One counter per line.
+impl Display for Statistics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (counter, name) in COUNTERS {
+            writeln!(f, "{:<16}{}", name, self.get(counter))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+use std::fmt::Arguments;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::machine::Fault;
+
+
+/// A stream output is written to, shared by the threads of a VM.
+pub type Output = Arc<Mutex<dyn Write + Send>>;
+
+/// Where the printing natives write to, the process's stdout and stderr unless the host redirects them.
+#[derive(Clone)]
+pub struct Stdio {
+    stdout: Output,
+    stderr: Output,
+}
+
+impl Stdio {
+    pub fn new(stdout: Output, stderr: Output) -> Self {
+        Stdio { stdout, stderr }
+    }
+
+    pub fn set_stdout(&mut self, stdout: Output) {
+        self.stdout = stdout;
+    }
+
+    pub fn set_stderr(&mut self, stderr: Output) {
+        self.stderr = stderr;
+    }
+
+    pub fn print(&self, arguments: Arguments) -> Result<(), Fault> {
+        write_output(&self.stdout, arguments)
+    }
+
+    pub fn eprint(&self, arguments: Arguments) -> Result<(), Fault> {
+        write_output(&self.stderr, arguments)
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Stdio {
+            stdout: Arc::new(Mutex::new(std::io::stdout())),
+            stderr: Arc::new(Mutex::new(std::io::stderr())),
+        }
+    }
+}
+
+fn write_output(output: &Output, arguments: Arguments) -> Result<(), Fault> {
+    let mut output = output.lock().map_err(|_| Fault::MemoryError("Poisoned".to_string()))?;
+    output.write_fmt(arguments).map_err(|error| Fault::IoError(error.to_string()))
+}
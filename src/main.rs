@@ -1,155 +1,214 @@
-use std::sync::Arc;
-use crate::backtrace::BacktraceInfo;
-use crate::instruction::{CallTarget, ComparisonType, Condition, Immediate, Instruction, JumpTarget, RegisterType, Source, Target};
-use crate::instruction::RealInstruction;
-use crate::machine::{call_main, Fault, InstructionResult};
-use crate::machine::core::{Core, CoreUtils};
-use crate::memory::Memory;
-use crate::program::function::Function;
-use crate::program::Module;
-use crate::stack_frame::delimited_continuation::ContinuationStore;
-use crate::stack_frame::StackFrame;
-use crate::value::Value;
-
-pub mod instruction;
-pub mod stack_frame;
-pub mod value;
-pub mod machine;
-pub mod program;
-pub mod memory;
-mod backtrace;
-mod native_lib;
-
-
-fn dp_fib() -> Arc<[Instruction]> {
-    use RealInstruction::*;
-    Arc::new([
-        Instruction::new_without_metadata(Load(Target(1, RegisterType::U64), Source::Immediate(Immediate::U64(0)))),
-        Instruction::new_without_metadata(Load(Target(2, RegisterType::U64), Source::Immediate(Immediate::U64(1)))),
-        Instruction::new_without_metadata(Load(Target(3, RegisterType::U64), Source::Immediate(Immediate::U64(2)))),
-        Instruction::new_without_metadata(Compare(Target(3, RegisterType::U64), Source::Immediate(Immediate::U64(10)), ComparisonType::Equal)),
-        Instruction::new_without_metadata(Goto(JumpTarget::Relative(8), Condition::Equal)),
-        Instruction::new_without_metadata(Add(Target(4, RegisterType::U64), Source::Register(1, RegisterType::U64), false, false)),
-        Instruction::new_without_metadata(Add(Target(4, RegisterType::U64), Source::Register(2, RegisterType::U64), false, false)),
-        Instruction::new_without_metadata(Load(Target(1, RegisterType::U64), Source::Register(2, RegisterType::U64))),
-        Instruction::new_without_metadata(Load(Target(2, RegisterType::U64), Source::Register(4, RegisterType::U64))),
-        Instruction::new_without_metadata(Sub(Target(4, RegisterType::U64), Source::Register(4, RegisterType::U64), false, false)),
-        Instruction::new_without_metadata(Add(Target(3, RegisterType::U64), Source::Immediate(Immediate::U64(1)), false, false)),
-        Instruction::new_without_metadata(Goto(JumpTarget::Relative(-8), Condition::Always)),
-        Instruction::new_without_metadata(GetStringRef(Target(8, RegisterType::U64), "".into(), 0)),
-        Instruction::new_without_metadata(Call(CallTarget::Label("std::io::println_string".into()), Condition::Always)),
-        Instruction::new_without_metadata(Load(Target(8, RegisterType::U64), Source::Register(2, RegisterType::U64))),
-        Instruction::new_without_metadata(Call(CallTarget::Label("std::io::println_u64".into()), Condition::Always)),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-    ])
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use crayfish_vm2::assembly;
+use crayfish_vm2::assembly::disassembler::{disassemble, DisassemblyOptions};
+use crayfish_vm2::machine::core::DEFAULT_STACK_SIZE;
+use crayfish_vm2::machine::native::FromValue;
+use crayfish_vm2::native_lib::get_std_module;
+use crayfish_vm2::program::bytecode::serialize_module;
+use crayfish_vm2::program::function::NativeRegistry;
+use crayfish_vm2::program::verifier::verify_module;
+use crayfish_vm2::program::Module;
+use crayfish_vm2::repl::Repl;
+use crayfish_vm2::value::Value;
+use crayfish_vm2::Vm;
+
+
+const USAGE: &str = "\
+Usage: crayfish-vm2 <command> [options]
+
+Commands:
+  run [--trace] [--stats] <file> [arguments...]
+      Runs the main function of a module, which gets the arguments as a list of strings in r8.
+      Exits with the status main returns in r0, 255 if it is outside of 0 to 255.
+  asm <file> [-o <output>]
+      Assembles a module into bytecode, written next to it with the extension .cfbc by default.
+  disasm [--positions] <file> [-o <output>]
+      Prints a module as assembly, to stdout by default.
+  verify <file>
+      Checks a module without running it.
+  repl [file]
+      Executes assembly a line at a time, with the module loaded if one is given.
+
+A <file> is either bytecode or assembly text.
+Options:
+  --trace      Prints every instruction to stderr before it is executed.
+  --stats      Prints what the program did to stderr once it finishes.
+  --positions  Adds the line and column of each instruction as a comment.";
+
+/// Exit status for a command line that couldn't be understood.
+const USAGE_ERROR: u8 = 2;
+
+
+/// A command and its parsed options.
+enum Command {
+    Run { file: PathBuf, arguments: Vec<String>, trace: bool, stats: bool },
+    Asm { file: PathBuf, output: Option<PathBuf> },
+    Disasm { file: PathBuf, output: Option<PathBuf>, positions: bool },
+    Verify { file: PathBuf },
+    Repl { file: Option<PathBuf> },
+    Help,
 }
 
-fn hello_world_main() -> Arc<[Instruction]> {
-    use RealInstruction::*;
-    Arc::new([
-        Instruction::new_without_metadata(Call(CallTarget::Label("hello_world".into()), Condition::Always)),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-    ])
-}
-
-fn hello_world(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-    println!("Hello, world!");
-    Ok(InstructionResult::Continue)
-}
-
-fn print_string(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-
-    let reference = core.get_value(&Source::Register(8, RegisterType::Reference));
-    match reference {
-        Value::MemoryRef(refe) => {
-            let string = memory.get_string(refe, &module).unwrap();
-            println!("{}", string);
+fn parse_command(mut arguments: impl Iterator<Item = String>) -> Result<Command, String> {
+    let command = arguments.next().ok_or("missing command")?;
+    let mut file = None;
+    let mut output = None;
+    let mut rest = Vec::new();
+    let mut trace = false;
+    let mut stats = false;
+    let mut positions = false;
+
+    while let Some(argument) = arguments.next() {
+        // Everything after the file of `run` belongs to the program.
+        if command == "run" && file.is_some() {
+            rest.push(argument);
+            continue;
         }
-        _ => {
-            return Err(Fault::InvalidString);
+        match argument.as_str() {
+            "--trace" if command == "run" => trace = true,
+            "--stats" if command == "run" => stats = true,
+            "--positions" if command == "disasm" => positions = true,
+            "-o" if command == "asm" || command == "disasm" => {
+                output = Some(PathBuf::from(arguments.next().ok_or("-o needs a file")?));
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            option if option.starts_with('-') => return Err(format!("unknown option {} for {}", option, command)),
+            _ if file.is_none() => file = Some(PathBuf::from(argument)),
+            _ => return Err(format!("unexpected argument {}", argument)),
         }
     }
-    Ok(InstructionResult::Continue)
+
+    if matches!(command.as_str(), "help" | "-h" | "--help") {
+        return Ok(Command::Help);
+    }
+    if command == "repl" {
+        return Ok(Command::Repl { file });
+    }
+    let file = file.ok_or_else(|| format!("{} needs a file", command))?;
+    match command.as_str() {
+        "run" => Ok(Command::Run { file, arguments: rest, trace, stats }),
+        "asm" => Ok(Command::Asm { file, output }),
+        "disasm" => Ok(Command::Disasm { file, output, positions }),
+        "verify" => Ok(Command::Verify { file }),
+        _ => Err(format!("unknown command {}", command)),
+    }
 }
 
-fn print_string_main() -> Arc<[Instruction]> {
-    use RealInstruction::*;
-    Arc::new([
-        Instruction::new_without_metadata(GetStringRef(Target(8, RegisterType::U64), "".into(), 0)),
-        Instruction::new_without_metadata(Call(CallTarget::Label("print_string".into()), Condition::Always)),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-    ])
+
+/// The natives that loaded modules can import.
+fn get_natives() -> NativeRegistry {
+    let mut natives = NativeRegistry::new();
+    natives.register_module(&get_std_module());
+    natives
 }
 
+/// Loads a module from bytecode or assembly with the natives of `get_natives` to import.
+fn load_file(file: &Path) -> Result<Module, String> {
+    assembly::load_file(file, &get_natives())
+}
 
-fn rec_fib() -> Arc<[Instruction]> {
-    use RealInstruction::*;
-    Arc::new([
-        Instruction::new_without_metadata(Compare(Target(1, RegisterType::U64), Source::Immediate(Immediate::U64(1)), ComparisonType::GreaterThan)),
-        Instruction::new_without_metadata(Goto(JumpTarget::Relative(3), Condition::GreaterThan)),
-        Instruction::new_without_metadata(Load(Target(0, RegisterType::U64), Source::Register(1, RegisterType::U64))),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-        Instruction::new_without_metadata(Sub(Target(1, RegisterType::U64), Source::Immediate(Immediate::U64(1)), false, false)),
-        Instruction::new_without_metadata(Push(Source::Register(1, RegisterType::U64))),
-        Instruction::new_without_metadata(Call(CallTarget::Label("fib".into()), Condition::Always)),
-        Instruction::new_without_metadata(Pop(Target(1, RegisterType::U64))),
-        Instruction::new_without_metadata(Sub(Target(1, RegisterType::U64), Source::Immediate(Immediate::U64(1)), false, false)),
-        Instruction::new_without_metadata(Push(Source::Register(0, RegisterType::U64))),
-        Instruction::new_without_metadata(Call(CallTarget::Label("fib".into()), Condition::Always)),
-        Instruction::new_without_metadata(Pop(Target(2, RegisterType::U64))),
-        Instruction::new_without_metadata(Add(Target(0, RegisterType::U64), Source::Register(2, RegisterType::U64), false, false)),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-    ])
+fn write_file(file: &Path, contents: &[u8]) -> Result<(), String> {
+    std::fs::write(file, contents).map_err(|error| format!("{}: {}", file.display(), error))
 }
 
+/// Runs `main` and returns the status it left in r0, a main that doesn't leave a number there exits with 0
+/// and one outside of 0 to 255 with 255, so a status like 256 doesn't look like success.
+fn run(file: &Path, arguments: Vec<String>, trace: bool, stats: bool) -> Result<ExitCode, String> {
+    let module = load_file(file)?;
+    let mut vm = match Vm::builder(module).set_trace(trace).set_statistics(stats).build() {
+        Ok(vm) => vm,
+        Err(error) => {
+            eprintln!("{}", error);
+            return Ok(ExitCode::FAILURE);
+        }
+    };
+
+    let result = vm.call::<Value>("main", (arguments,));
+    if let Some(statistics) = vm.get_statistics() {
+        eprint!("{}", statistics);
+    }
+    match result {
+        Ok(value) => {
+            let status = i64::from_value(value, vm.get_memory(), vm.get_module()).unwrap_or(0);
+            Ok(ExitCode::from(u8::try_from(status).unwrap_or(u8::MAX)))
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            Ok(ExitCode::FAILURE)
+        }
+    }
+}
 
-fn rec_fib_main() -> Arc<[Instruction]> {
-    use RealInstruction::*;
-    Arc::new([
-        Instruction::new_without_metadata(Load(Target(1, RegisterType::U64), Source::Immediate(Immediate::U64(10)))),
-        Instruction::new_without_metadata(Call(CallTarget::Label("fib".into()), Condition::Always)),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-    ])
+fn verify(file: &Path) -> Result<ExitCode, String> {
+    let mut module = load_file(file)?;
+    module.add_sub_module(get_std_module());
+    let diagnostics = verify_module(&module);
+    for diagnostic in diagnostics.iter() {
+        eprintln!("{}", diagnostic);
+    }
+    match diagnostics.is_empty() {
+        true => Ok(ExitCode::SUCCESS),
+        false => Ok(ExitCode::FAILURE),
+    }
 }
 
-fn print_io_mod() -> Arc<[Instruction]> {
-    use RealInstruction::*;
-    Arc::new([
-        Instruction::new_without_metadata(GetStringRef(Target(8, RegisterType::U64), "".into(), 0)),
-        Instruction::new_without_metadata(Call(CallTarget::Label("std::io::println_string".into()), Condition::Always)),
-        Instruction::new_without_metadata(Return(Condition::Always)),
-    ])
+fn run_repl(file: Option<PathBuf>) -> Result<ExitCode, String> {
+    let mut repl = Repl::new().map_err(|fault| format!("{:?}", fault))?;
+    if let Some(file) = file {
+        repl.load_module(load_file(&file)?).map_err(|fault| format!("{:?}", fault))?;
+    }
+    repl.run(std::io::stdin().lock(), &mut std::io::stdout(), &mut std::io::stderr()).map_err(|error| error.to_string())?;
+    Ok(ExitCode::SUCCESS)
 }
 
-fn main() {
-    let mut module = Module::default();
-    module.add_sub_module(native_lib::get_std_module());
-    module.add_function("main", Function::ByteCode(hello_world_main()));
-    module.add_function("hello_world", Function::Native(hello_world));
-    module.add_function("print_string", Function::Native(print_string));
-    module.add_function("main", Function::ByteCode(print_string_main()));
-    module.add_function("fib", Function::ByteCode(rec_fib()));
-    module.add_function("main", Function::ByteCode(rec_fib_main()));
-    module.add_function("main", Function::ByteCode(print_io_mod()));
-    module.add_function("main", Function::ByteCode(dp_fib()));
-    module.add_string(&"".into(), "Hello, world!");
-
-    let mut core = Core::default();
-    let module = Arc::new(module);
-
-    let mut memory = Memory::new();
-    module.add_strings_to_memory(&mut memory);
-    let mut backtrace = BacktraceInfo::new();
-
-    match call_main(&mut core, module, memory, &mut backtrace) {
-        Ok(_) => {
-            //println!("Program finished successfully");
-        },
-        Err(fault) => {
-            println!("Program faulted: {:?}", fault);
-            println!("{}", backtrace);
+fn run_command(command: Command) -> Result<ExitCode, String> {
+    match command {
+        Command::Run { file, arguments, trace, stats } => run(&file, arguments, trace, stats),
+        Command::Asm { file, output } => {
+            let module = load_file(&file)?;
+            let output = output.unwrap_or_else(|| file.with_extension("cfbc"));
+            write_file(&output, &serialize_module(&module))?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Disasm { file, output, positions } => {
+            let module = load_file(&file)?;
+            let text = disassemble(&module, &DisassemblyOptions { source_positions: positions });
+            match output {
+                Some(output) => write_file(&output, text.as_bytes())?,
+                None => print!("{}", text),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Command::Verify { file } => verify(&file),
+        Command::Repl { file } => {
+            // Calls take a lot of stack in debug builds, so the repl gets as much as the VM's threads.
+            let thread = std::thread::Builder::new()
+                .stack_size(DEFAULT_STACK_SIZE)
+                .spawn(move || run_repl(file))
+                .map_err(|error| error.to_string())?;
+            thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(ExitCode::SUCCESS)
         }
     }
+}
 
-    //println!("{}", core);
+fn main() -> ExitCode {
+    let command = match parse_command(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            return ExitCode::from(USAGE_ERROR);
+        }
+    };
+    match run_command(command) {
+        Ok(status) => status,
+        Err(message) => {
+            eprintln!("error: {}", message);
+            ExitCode::FAILURE
+        }
+    }
 }
@@ -1,63 +1,148 @@
+pub mod slab;
+
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock, TryLockError};
-use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::JoinHandle;
+use crate::memory::slab::{lock, read, write, ShardedSlab, Slab};
 use crate::machine::Fault;
-use crate::program::function::Function;
+use crate::program::function::FunctionPath;
 use crate::program::{Module, StringTablePath};
-use crate::value::object::Object;
+use crate::value::closure::Closure;
+use crate::value::sync::SyncObject;
+use crate::value::object::{ClassId, ClassRef, Method, Object};
 use crate::value::{Value, ValueType};
 
 unsafe impl Send for MemoryObject {}
+unsafe impl Sync for MemoryObject {}
 #[derive(Debug, Clone)]
 pub enum MemoryObject {
     Null,
     StringTableRef(StringTablePath, u64),
     String(*const str),
     Object(*mut Object),
+    Closure(*mut Closure),
+    Sync(*mut SyncObject),
     List(*mut [Value]),
     Pointer(*mut u8, usize, ValueType),
 }
 
 
+/// What a spawned thread finished with, set before the thread stops counting as running.
+pub type ThreadResult = Arc<Mutex<Option<Result<Value, Fault>>>>;
+
+/// A thread spawned over a memory that hasn't been joined yet.
+#[derive(Debug)]
+pub struct SpawnedThread {
+    pub handle: JoinHandle<()>,
+    pub result: ThreadResult,
+}
+
+unsafe impl Send for SpawnedThread {}
+
+
+/// How many bytes can be allocated before the next garbage collection, unless changed with `set_collection_threshold`.
+pub const DEFAULT_COLLECTION_THRESHOLD: usize = 8 * 1024 * 1024;
+
+/// The number of locks the atomic instructions are spread over.
+const ATOMIC_LOCK_COUNT: usize = 64;
+
 unsafe impl Send for Memory {}
 #[derive(Debug, Clone)]
 pub struct Memory {
-    reference_table: Arc<RwLock<HashMap<u64, MemoryObject>>>,
+    /// Sharded so that threads working on different objects don't wait on each other.
+    reference_table: Arc<ShardedSlab<MemoryObject>>,
     string_lookup_table: Arc<RwLock<HashMap<(StringTablePath, u64), u64>>>,
+    /// Classes indexed by their class id.
+    /// Boxed so that class references stay valid when the table grows.
+    #[allow(clippy::vec_box)]
+    class_table: Arc<RwLock<Vec<Box<Object>>>>,
+    class_lookup_table: Arc<RwLock<HashMap<FunctionPath, ClassId>>>,
+    /// An estimate of the bytes allocated since the last garbage collection.
+    allocated_bytes: Arc<AtomicUsize>,
+    collection_threshold: usize,
+    /// Set when a collection was skipped because other threads were running,
+    /// so that it runs once the calling thread is the only one left.
+    collection_deferred: Arc<AtomicBool>,
+    /// The number of threads running bytecode over this memory.
+    thread_count: Arc<AtomicUsize>,
+    /// Spawned threads that haven't been joined yet, thread handles are handles into this slab.
+    /// The results of finished threads are roots until they are joined.
+    threads: Arc<Mutex<Slab<SpawnedThread>>>,
+    /// Locks for the atomic instructions, picked by the address of the cell they work on.
+    atomic_locks: Arc<[Mutex<()>]>,
 }
 
 impl Memory {
     pub fn new() -> Self {
-        Memory { reference_table: Arc::new(Default::default()), string_lookup_table: Arc::new(Default::default())}
+        Memory {
+            reference_table: Arc::new(Default::default()),
+            string_lookup_table: Arc::new(Default::default()),
+            class_table: Arc::new(Default::default()),
+            class_lookup_table: Arc::new(Default::default()),
+            allocated_bytes: Arc::new(AtomicUsize::new(0)),
+            collection_threshold: DEFAULT_COLLECTION_THRESHOLD,
+            collection_deferred: Arc::new(AtomicBool::new(false)),
+            thread_count: Arc::new(AtomicUsize::new(1)),
+            threads: Arc::new(Default::default()),
+            atomic_locks: (0..ATOMIC_LOCK_COUNT).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    pub fn set_collection_threshold(&mut self, collection_threshold: usize) {
+        self.collection_threshold = collection_threshold;
+    }
+
+    /// If enough has been allocated since the last collection that the next safe point should collect,
+    /// or a deferred collection can run now that only one thread is left.
+    pub fn should_collect(&self) -> bool {
+        self.allocated_bytes.load(Ordering::Relaxed) >= self.collection_threshold
+            || (self.collection_deferred.load(Ordering::Relaxed) && self.get_thread_count() == 1)
+    }
+
+    /// Records a collection that couldn't run, to be run by `should_collect` once the other threads have finished.
+    pub fn defer_collection(&self) {
+        self.collection_deferred.store(true, Ordering::Relaxed);
+    }
+
+    pub fn get_thread_count(&self) -> usize {
+        self.thread_count.load(Ordering::Acquire)
+    }
+
+    /// Called before a thread starts running bytecode over this memory.
+    pub fn add_thread(&self) {
+        self.thread_count.fetch_add(1, Ordering::AcqRel);
     }
 
+    /// Called after a thread added with `add_thread` finishes.
+    pub fn remove_thread(&self) {
+        self.thread_count.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Keeps a spawned thread until it is joined and returns its handle.
+    pub fn insert_thread(&self, thread: SpawnedThread) -> Result<u64, Fault> {
+        Ok(lock(&self.threads)?.insert(thread))
+    }
+
+    pub fn remove_thread_handle(&self, handle: u64) -> Result<SpawnedThread, Fault> {
+        lock(&self.threads)?.remove(handle)
+    }
+
+    /// The references of every object on the heap, ordered by their slot.
+    pub fn get_references(&self) -> Result<Vec<u64>, Fault> {
+        let mut references = self.reference_table.get_handles()?;
+        references.sort_by_key(|reference| Slab::<MemoryObject>::get_slot_index(*reference));
+        Ok(references)
+    }
+
+    /// A copy of the object, the objects it points at are not locked once this returns.
     pub fn get(&self, reference: u64) -> Result<MemoryObject, Fault> {
-        loop {
-            match self.reference_table.try_read() {
-                Ok(reference_table) => {
-                    return reference_table.get(&reference).cloned().ok_or(Fault::InvalidReference);
-                }
-                Err(TryLockError::WouldBlock)=> {}
-                Err(TryLockError::Poisoned(_)) => Err(Fault::MemoryError("Poisoned".to_string()))?,
-            }
-        }
+        self.reference_table.with(reference, MemoryObject::clone)
     }
 
     pub fn allocate(&mut self, object: MemoryObject) -> Result<u64, Fault> {
-        loop {
-            match self.reference_table.try_write() {
-                Ok(mut reference_table) => {
-                    let mut index = rand::random();
-                    while reference_table.contains_key(&index) {
-                        index = rand::random();
-                    }
-                    reference_table.insert(index, object);
-                    return Ok(index);
-                }
-                Err(TryLockError::WouldBlock)=> {}
-                Err(TryLockError::Poisoned(_)) => Err(Fault::MemoryError("Poisoned".to_string()))?,
-            }
-        }
+        self.allocated_bytes.fetch_add(Self::get_allocation_size(&object), Ordering::Relaxed);
+        self.reference_table.insert(object)
     }
 
     fn get_layout(size: usize, value_type: ValueType) -> std::alloc::Layout {
@@ -76,63 +161,79 @@ impl Memory {
         }
     }
 
-    fn deallocate_helper(reference_table: &mut HashMap<u64, MemoryObject>, reference: u64) -> Result<(), Fault> {
-        let object = reference_table.remove(&reference);
-        if let Some(object) = object {
-            match object {
-                MemoryObject::Pointer(pointer, size, value_type) => {
-                    let layout = Self::get_layout(size, value_type);
-                    unsafe { std::alloc::dealloc(pointer, layout) };
-                }
-                MemoryObject::List(list) => {
-                    let list = unsafe { Box::from_raw(list) };
-
-                    for value in list.iter() {
-                        match value {
-                            Value::MemoryRef(index) => {
-                                Self::deallocate_helper(reference_table, *index)?;
-                            }
-                            Value::ArrayRef(index) => {
-                                Self::deallocate_helper(reference_table, *index)?;
-                            }
-                            Value::ObjectRef(index) => {
-                                Self::deallocate_helper(reference_table, *index)?;
-                            }
-                            Value::StringRef(index) => {
-                                Self::deallocate_helper(reference_table, *index)?;
-                            }
-                            _ => {}
-                        }
+    fn get_allocation_size(object: &MemoryObject) -> usize {
+        let size = match object {
+            MemoryObject::Null | MemoryObject::StringTableRef(_, _) => 0,
+            MemoryObject::String(string) => unsafe { &**string }.len(),
+            MemoryObject::Object(object) => std::mem::size_of_val(unsafe { &**object }.get_fields()),
+            MemoryObject::Closure(closure) => std::mem::size_of_val(unsafe { &**closure }.get_captures()),
+            MemoryObject::Sync(_) => std::mem::size_of::<SyncObject>(),
+            MemoryObject::List(list) => std::mem::size_of_val(unsafe { &**list }),
+            MemoryObject::Pointer(_, size, value_type) => size * value_type.get_size(),
+        };
+        size + std::mem::size_of::<MemoryObject>()
+    }
+
+    /// Frees the memory of an object without touching the objects it references.
+    fn free(object: MemoryObject) {
+        match object {
+            MemoryObject::Pointer(pointer, size, value_type) => {
+                let layout = Self::get_layout(size, value_type);
+                unsafe { std::alloc::dealloc(pointer, layout) };
+            }
+            MemoryObject::List(list) => {
+                let _ = unsafe { Box::from_raw(list) };
+            }
+            MemoryObject::String(pointer) => {
+                let _ = unsafe { Box::from_raw(pointer as *mut str) };
+            }
+            MemoryObject::Object(object) => {
+                let _ = unsafe { Box::from_raw(object) };
+            }
+            MemoryObject::Closure(closure) => {
+                let _ = unsafe { Box::from_raw(closure) };
+            }
+            MemoryObject::Sync(object) => {
+                let _ = unsafe { Box::from_raw(object) };
+            }
+            MemoryObject::StringTableRef(_, _) | MemoryObject::Null => {}
+        }
+    }
+
+    fn deallocate_helper(reference_table: &ShardedSlab<MemoryObject>, reference: u64) -> Result<(), Fault> {
+        let object = reference_table.remove(reference)?;
+        if let MemoryObject::List(list) = object {
+            let list = unsafe { &*list };
+
+            for value in list.iter() {
+                match value {
+                    Value::MemoryRef(index) => {
+                        Self::deallocate_helper(reference_table, *index)?;
                     }
+                    Value::ArrayRef(index) => {
+                        Self::deallocate_helper(reference_table, *index)?;
+                    }
+                    Value::ObjectRef(index) => {
+                        Self::deallocate_helper(reference_table, *index)?;
+                    }
+                    Value::StringRef(index) => {
+                        Self::deallocate_helper(reference_table, *index)?;
+                    }
+                    _ => {}
                 }
-                MemoryObject::StringTableRef(_, _) => {}
-                MemoryObject::String(pointer) => {
-                    let _ = unsafe { Box::from_raw(pointer as *mut str) };
-                }
-                MemoryObject::Object(object) => {
-                    let _ = unsafe { Box::from_raw(object) };
-                }
-                MemoryObject::Null => {}
             }
         }
+        Self::free(object);
         Ok(())
     }
     pub fn deallocate(&mut self, reference: u64) -> Result<(), Fault> {
-        loop {
-            match self.reference_table.try_write() {
-                Ok(mut reference_table) => {
-                    Self::deallocate_helper(&mut reference_table, reference)?;
-                    return Ok(());
-                }
-                Err(TryLockError::WouldBlock)=> {}
-                Err(TryLockError::Poisoned(_)) => Err(Fault::MemoryError("Poisoned".to_string()))?,
-            }
-        }
+        Self::deallocate_helper(&self.reference_table, reference)
     }
 
+    /// Allocates `size` zeroed elements of `value_type`.
     pub fn allocate_pointer(&mut self, size: usize, value_type: ValueType) -> Result<Value, Fault> {
         let layout = Self::get_layout(size, value_type);
-        let pointer = unsafe { std::alloc::alloc(layout) };
+        let pointer = unsafe { std::alloc::alloc_zeroed(layout) };
         if pointer.is_null() {
             Err(Fault::MemoryError("Failed to allocate memory".to_string()))?;
         }
@@ -155,46 +256,44 @@ impl Memory {
     }
 
     pub fn access_list(&self, reference: u64, index: u64) -> Result<Value, Fault> {
-        let list = self.get(reference)?;
-        match list {
+        self.reference_table.with(reference, |list| match list {
             MemoryObject::List(list) => {
-                let list = unsafe { &mut *list };
+                let list = unsafe { &**list };
                 let value = list.get(index as usize).ok_or(Fault::IndexOutOfBounds)?;
 
                 Ok(value.clone())
             }
             MemoryObject::Null => Err(Fault::NullPointerReference),
             _ => Err(Fault::InvalidReference),
-        }
+        })?
     }
 
     pub fn get_list_length(&self, reference: u64) -> Result<Value, Fault> {
-        let list = self.get(reference)?;
-        match list {
+        self.reference_table.with(reference, |list| match list {
             MemoryObject::List(list) => {
-                let list = unsafe { &mut *list };
+                let list = unsafe { &**list };
                 Ok(Value::U64(list.len() as u64))
             }
             MemoryObject::Null => Err(Fault::NullPointerReference),
             _ => Err(Fault::InvalidReference),
-        }
+        })?
     }
 
     pub fn store_list(&mut self, reference: u64, index: u64, value: Value) -> Result<(), Fault> {
-        let list = self.get(reference)?;
-        match list {
+        self.reference_table.with_mut(reference, |list| match list {
             MemoryObject::List(list) => {
-                let list = unsafe { &mut *list };
-                list[index as usize] = value;
+                let list = unsafe { &mut **list };
+                let cell = list.get_mut(index as usize).ok_or(Fault::IndexOutOfBounds)?;
+                *cell = value;
                 Ok(())
             }
             MemoryObject::Null => Err(Fault::NullPointerReference),
             _ => Err(Fault::InvalidReference),
-        }
+        })?
     }
 
     pub fn allocate_string(&mut self, string: &str) -> Result<Value, Fault> {
-        let string = MemoryObject::String(string);
+        let string = MemoryObject::String(Box::into_raw(Box::<str>::from(string)));
         let index = self.allocate(string)?;
         Ok(Value::MemoryRef(index))
     }
@@ -218,32 +317,15 @@ impl Memory {
     pub fn allocate_string_ref(&mut self, path: &StringTablePath, table_index: u64) -> Result<Value, Fault> {
         let string = MemoryObject::StringTableRef(path.clone(), table_index);
         let index = self.allocate(string)?;
-        loop {
-            match self.string_lookup_table.try_write() {
-                Ok(mut string_lookup_table) => {
-                    string_lookup_table.insert((path.clone(), table_index), index);
-
-                    return Ok(Value::MemoryRef(index));
-                }
-                Err(TryLockError::WouldBlock)=> {}
-                Err(TryLockError::Poisoned(_)) => Err(Fault::MemoryError("Poisoned".to_string()))?,
-            }
-        }
+        write(&self.string_lookup_table)?.insert((path.clone(), table_index), index);
+        Ok(Value::MemoryRef(index))
     }
 
     pub fn get_string_ref_from_path(&self, path: &StringTablePath, table_index: u64) -> Result<Value, Fault> {
-        loop {
-            match self.string_lookup_table.try_read() {
-                Ok(string_lookup_table) => {
-                    return if let Some(index) = string_lookup_table.get(&(path.clone(), table_index)) {
-                        Ok(Value::MemoryRef(*index))
-                    } else {
-                        Err(Fault::InvalidReference)
-                    }
-                }
-                Err(TryLockError::WouldBlock)=> {}
-                Err(TryLockError::Poisoned(_)) => Err(Fault::MemoryError("Poisoned".to_string()))?,
-            }
+        if let Some(index) = read(&self.string_lookup_table)?.get(&(path.clone(), table_index)) {
+            Ok(Value::MemoryRef(*index))
+        } else {
+            Err(Fault::InvalidReference)
         }
     }
 
@@ -253,4 +335,249 @@ impl Memory {
         let string = format!("{}{}", left, right);
         self.allocate_string(&string)
     }
+
+    /// Registers a class under `path`.
+    /// The fields of its instances are the fields of the super class followed by `fields`.
+    pub fn allocate_class(&mut self, path: &FunctionPath, super_class: Option<ClassId>, fields: Vec<Value>, methods: Vec<Option<Method>>) -> Result<ClassId, Fault> {
+        let super_class = match super_class {
+            Some(super_class) => Some(self.get_class(super_class)?),
+            None => None,
+        };
+        let fields = match super_class {
+            Some(super_class) => {
+                let super_class = unsafe { &*super_class };
+                super_class.get_fields().iter().cloned().chain(fields).collect()
+            }
+            None => fields,
+        };
+        let class_name = path.path.last().map(|name| name.as_ref()).unwrap_or("");
+
+        let class_id = {
+            let mut class_table = write(&self.class_table)?;
+            let class_id = class_table.len() as ClassId;
+            let class = Object::new_class(class_id, class_name, super_class, fields, methods);
+            class_table.push(Box::new(class));
+            class_id
+        };
+        write(&self.class_lookup_table)?.insert(path.clone(), class_id);
+        Ok(class_id)
+    }
+
+    pub fn get_class_id(&self, path: &FunctionPath) -> Result<Option<ClassId>, Fault> {
+        Ok(read(&self.class_lookup_table)?.get(path).cloned())
+    }
+
+    pub fn get_class(&self, class_id: ClassId) -> Result<ClassRef, Fault> {
+        let class_table = read(&self.class_table)?;
+        let class = class_table.get(class_id as usize).ok_or(Fault::InvalidReference)?;
+        Ok(class.as_ref() as *const Object as ClassRef)
+    }
+
+    pub fn allocate_object(&mut self, path: &FunctionPath) -> Result<Value, Fault> {
+        let class_id = self.get_class_id(path)?.ok_or(Fault::ClassNotFound(path.clone()))?;
+        let class = unsafe { &*self.get_class(class_id)? };
+        let object = Box::into_raw(Box::new(Object::new_instance(class)));
+        let index = self.allocate(MemoryObject::Object(object))?;
+        Ok(Value::MemoryRef(index))
+    }
+
+    fn get_object(&self, reference: u64) -> Result<*mut Object, Fault> {
+        if reference == 0 {
+            return Err(Fault::NullPointerReference);
+        }
+        match self.get(reference)? {
+            MemoryObject::Object(object) => Ok(object),
+            MemoryObject::Null => Err(Fault::NullPointerReference),
+            _ => Err(Fault::InvalidReference),
+        }
+    }
+
+    pub fn get_object_class(&self, reference: u64) -> Result<ClassId, Fault> {
+        let object = unsafe { &*self.get_object(reference)? };
+        Ok(object.get_class_id())
+    }
+
+    pub fn lookup_method(&self, class_id: ClassId, slot: u64) -> Result<Method, Fault> {
+        let class = unsafe { &*self.get_class(class_id)? };
+        class.lookup_method(slot).cloned()
+    }
+
+    pub fn allocate_closure(&mut self, closure: Closure) -> Result<Value, Fault> {
+        let closure = Box::into_raw(Box::new(closure));
+        let index = self.allocate(MemoryObject::Closure(closure))?;
+        Ok(Value::MemoryRef(index))
+    }
+
+    pub fn get_closure(&self, reference: u64) -> Result<Closure, Fault> {
+        if reference == 0 {
+            return Err(Fault::NullPointerReference);
+        }
+        self.reference_table.with(reference, |closure| match closure {
+            MemoryObject::Closure(closure) => Ok(unsafe { &**closure }.clone()),
+            MemoryObject::Null => Err(Fault::NullPointerReference),
+            _ => Err(Fault::InvalidReference),
+        })?
+    }
+
+    pub fn access_object(&self, reference: u64, field: u64) -> Result<Value, Fault> {
+        if reference == 0 {
+            return Err(Fault::NullPointerReference);
+        }
+        self.reference_table.with(reference, |object| match object {
+            MemoryObject::Object(object) => unsafe { &**object }.get_field(field),
+            MemoryObject::Null => Err(Fault::NullPointerReference),
+            _ => Err(Fault::InvalidReference),
+        })?
+    }
+
+    pub fn store_object(&mut self, reference: u64, field: u64, value: Value) -> Result<(), Fault> {
+        if reference == 0 {
+            return Err(Fault::NullPointerReference);
+        }
+        self.reference_table.with_mut(reference, |object| match object {
+            MemoryObject::Object(object) => unsafe { &mut **object }.set_field(field, value),
+            MemoryObject::Null => Err(Fault::NullPointerReference),
+            _ => Err(Fault::InvalidReference),
+        })?
+    }
+
+    pub fn allocate_sync(&mut self, object: SyncObject) -> Result<Value, Fault> {
+        let object = Box::into_raw(Box::new(object));
+        let index = self.allocate(MemoryObject::Sync(object))?;
+        Ok(Value::MemoryRef(index))
+    }
+
+    /// A clone of the synchronization object, taken while its shard is locked so that it can't be freed meanwhile.
+    pub fn get_sync(&self, reference: u64) -> Result<SyncObject, Fault> {
+        if reference == 0 {
+            return Err(Fault::NullPointerReference);
+        }
+        self.reference_table.with(reference, |object| match object {
+            // SAFETY: the object is only freed after it is removed from the table, which can't happen while the shard is locked.
+            MemoryObject::Sync(object) => Ok(unsafe { &**object }.clone()),
+            MemoryObject::Null => Err(Fault::NullPointerReference),
+            _ => Err(Fault::InvalidReference),
+        })?
+    }
+
+    /// Replaces the element at `index` of a list or pointer with the result of `update` and returns the old value.
+    /// List elements are updated while the list is locked.
+    /// Pointer cells are atomic with respect to other atomic updates of the same cell, but not to plain loads and stores.
+    pub fn atomic_update(&self, reference: u64, index: u64, update: impl FnOnce(Value) -> Result<Value, Fault>) -> Result<Value, Fault> {
+        match self.get(reference)? {
+            MemoryObject::List(_) => self.reference_table.with_mut(reference, |list| match list {
+                MemoryObject::List(list) => {
+                    let list = unsafe { &mut **list };
+                    let cell = list.get_mut(index as usize).ok_or(Fault::IndexOutOfBounds)?;
+                    let old = cell.clone();
+                    *cell = update(old.clone())?;
+                    Ok(old)
+                }
+                _ => Err(Fault::InvalidReference),
+            })?,
+            MemoryObject::Pointer(pointer, size, value_type) => {
+                if index as usize >= size {
+                    return Err(Fault::IndexOutOfBounds);
+                }
+                let cell = unsafe { pointer.add(index as usize * value_type.get_size()) };
+                let _guard = self.lock_atomic(cell as usize)?;
+                let old = unsafe { Self::read_cell(cell, value_type) };
+                let new = update(old.clone())?;
+                let new = match new.get_reference() {
+                    Some(reference) => Value::U64(reference),
+                    None if matches!(new, Value::Object(_) | Value::String(_) | Value::Array(_) | Value::Function(_)) => {
+                        return Err(Fault::InvalidOperation("only numbers and references can be stored in a pointer".to_string()));
+                    }
+                    None => new,
+                };
+                unsafe { Self::write_cell(cell, new.transmute(value_type)) };
+                Ok(old)
+            }
+            MemoryObject::Null => Err(Fault::NullPointerReference),
+            _ => Err(Fault::InvalidReference),
+        }
+    }
+
+    fn lock_atomic(&self, address: usize) -> Result<std::sync::MutexGuard<'_, ()>, Fault> {
+        lock(&self.atomic_locks[(address >> 3) % ATOMIC_LOCK_COUNT])
+    }
+
+    unsafe fn read_cell(cell: *mut u8, value_type: ValueType) -> Value {
+        match value_type {
+            ValueType::U8 => Value::U8(cell.read()),
+            ValueType::I8 => Value::I8((cell as *mut i8).read()),
+            ValueType::U16 => Value::U16((cell as *mut u16).read()),
+            ValueType::I16 => Value::I16((cell as *mut i16).read()),
+            ValueType::U32 => Value::U32((cell as *mut u32).read()),
+            ValueType::I32 => Value::I32((cell as *mut i32).read()),
+            ValueType::U64 => Value::U64((cell as *mut u64).read()),
+            ValueType::I64 => Value::I64((cell as *mut i64).read()),
+            ValueType::F32 => Value::F32((cell as *mut f32).read()),
+            ValueType::F64 => Value::F64((cell as *mut f64).read()),
+            _ => panic!("Invalid value type"),
+        }
+    }
+
+    unsafe fn write_cell(cell: *mut u8, value: Value) {
+        match value {
+            Value::U8(value) => cell.write(value),
+            Value::I8(value) => (cell as *mut i8).write(value),
+            Value::U16(value) => (cell as *mut u16).write(value),
+            Value::I16(value) => (cell as *mut i16).write(value),
+            Value::U32(value) => (cell as *mut u32).write(value),
+            Value::I32(value) => (cell as *mut i32).write(value),
+            Value::U64(value) => (cell as *mut u64).write(value),
+            Value::I64(value) => (cell as *mut i64).write(value),
+            Value::F32(value) => (cell as *mut f32).write(value),
+            Value::F64(value) => (cell as *mut f64).write(value),
+            _ => panic!("Invalid value type"),
+        }
+    }
+
+    /// Frees every object that cannot be reached from `roots` or the interned string table references.
+    /// Roots that are not references to live objects are ignored.
+    /// Returns the number of objects freed.
+    pub fn collect(&mut self, roots: Vec<u64>) -> Result<usize, Fault> {
+        self.collect_with(roots, |_, _| {})
+    }
+
+    /// Collects like `collect`, and calls `visit` with every value that could be a reference,
+    /// so things outside the heap can be kept alive by the values that reach them.
+    pub fn collect_with(&mut self, mut roots: Vec<u64>, visit: impl FnMut(u64, &mut Vec<u64>)) -> Result<usize, Fault> {
+        roots.extend(read(&self.string_lookup_table)?.values());
+        for thread in lock(&self.threads)?.iter() {
+            if let Some(Ok(value)) = &*lock(&thread.result)? {
+                roots.extend(value.get_reference());
+            }
+        }
+
+        let garbage = self.reference_table.retain_reachable(roots, Self::trace_children, visit)?;
+        let freed = garbage.len();
+        for object in garbage {
+            Self::free(object);
+        }
+
+        self.allocated_bytes.store(0, Ordering::Relaxed);
+        self.collection_deferred.store(false, Ordering::Relaxed);
+        Ok(freed)
+    }
+
+    fn trace_children(object: &MemoryObject, references: &mut Vec<u64>) {
+        let values = match object {
+            MemoryObject::List(list) => unsafe { &**list },
+            MemoryObject::Object(object) => unsafe { &**object }.get_fields(),
+            MemoryObject::Closure(closure) => unsafe { &**closure }.get_captures(),
+            MemoryObject::Sync(object) => {
+                unsafe { &**object }.trace_roots(references);
+                return;
+            }
+            MemoryObject::Pointer(pointer, size, ValueType::U64) => {
+                let cells = unsafe { std::slice::from_raw_parts(*pointer as *const u64, *size) };
+                references.extend(cells);
+                return;
+            }
+            _ => return,
+        };
+        references.extend(values.iter().filter_map(Value::get_reference));
+    }
  }
\ No newline at end of file
@@ -0,0 +1,277 @@
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::machine::Fault;
+
+
+/// A reference to a value in a `Slab`.
+/// The low 32 bits are the index of the slot and the high 32 bits are the generation of the slot.
+/// Generations start at 1, so the handle 0 is never valid and is used as the null reference.
+pub type Handle = u64;
+
+#[derive(Debug, Clone)]
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Storage that hands out generational handles.
+/// Freed slots are reused, most recently freed first, and freeing a slot bumps its generation
+/// so handles to the old value are detected instead of aliasing the new one.
+#[derive(Debug, Clone)]
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32>,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Slab {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn make_handle(index: u32, generation: u32) -> Handle {
+        ((generation as u64) << 32) | index as u64
+    }
+
+    /// The index of the slot a handle points at.
+    pub fn get_slot_index(handle: Handle) -> usize {
+        (handle & u32::MAX as u64) as usize
+    }
+
+    fn get_generation(handle: Handle) -> u32 {
+        (handle >> 32) as u32
+    }
+
+    /// The number of slots, used or free.
+    pub fn get_slot_count(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                Self::make_handle(index, slot.generation)
+            }
+            None => {
+                let index = self.slots.len() as u32;
+                self.slots.push(Slot { generation: 1, value: Some(value) });
+                Self::make_handle(index, 1)
+            }
+        }
+    }
+
+    fn check(&self, handle: Handle) -> Result<usize, Fault> {
+        let index = Self::get_slot_index(handle);
+        let generation = Self::get_generation(handle);
+        match self.slots.get(index) {
+            Some(slot) if slot.generation == generation && slot.value.is_some() => Ok(index),
+            Some(slot) if generation != 0 && slot.generation >= generation => Err(Fault::UseAfterFree(handle)),
+            _ => Err(Fault::InvalidReference),
+        }
+    }
+
+    pub fn get(&self, handle: Handle) -> Result<&T, Fault> {
+        let index = self.check(handle)?;
+        Ok(self.slots[index].value.as_ref().expect("checked slot"))
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> Result<&mut T, Fault> {
+        let index = self.check(handle)?;
+        Ok(self.slots[index].value.as_mut().expect("checked slot"))
+    }
+
+    /// The values in slot order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|slot| slot.value.as_ref())
+    }
+
+    /// The handles of the values in slot order.
+    pub fn handles(&self) -> impl Iterator<Item = Handle> + '_ {
+        self.slots.iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.value.is_some())
+            .map(|(index, slot)| Self::make_handle(index as u32, slot.generation))
+    }
+
+    pub fn contains(&self, handle: Handle) -> bool {
+        self.check(handle).is_ok()
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Result<T, Fault> {
+        let index = self.check(handle)?;
+        Ok(self.free_slot(index))
+    }
+
+    fn free_slot(&mut self, index: usize) -> T {
+        let slot = &mut self.slots[index];
+        let value = slot.value.take().expect("freeing a used slot");
+        slot.generation = slot.generation.checked_add(1).unwrap_or(1);
+        self.free.push(index as u32);
+        value
+    }
+
+    /// Removes every value for which `keep` returns false and returns them in slot order.
+    pub fn retain(&mut self, mut keep: impl FnMut(Handle, &T) -> bool) -> Vec<T> {
+        let mut removed = Vec::new();
+        for index in 0..self.slots.len() {
+            let slot = &self.slots[index];
+            if let Some(value) = &slot.value {
+                if !keep(Self::make_handle(index as u32, slot.generation), value) {
+                    removed.push(self.free_slot(index));
+                }
+            }
+        }
+        removed
+    }
+}
+
+
+/// The number of shards in a `ShardedSlab`, must be a power of two.
+pub const SHARD_COUNT: usize = 16;
+
+thread_local! {
+    /// The slab this thread last inserted into, by the address of its shards, and the shard it picked there.
+    /// Threads that allocate keep to their own shard so they don't contend for the same lock.
+    static THREAD_SHARD: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// Blocks until `lock` can be read, a poisoned lock is a `Fault::MemoryError`.
+pub fn read<T>(lock: &RwLock<T>) -> Result<RwLockReadGuard<'_, T>, Fault> {
+    lock.read().map_err(|_| Fault::MemoryError("Poisoned".to_string()))
+}
+
+/// Blocks until `lock` can be written, a poisoned lock is a `Fault::MemoryError`.
+pub fn write<T>(lock: &RwLock<T>) -> Result<RwLockWriteGuard<'_, T>, Fault> {
+    lock.write().map_err(|_| Fault::MemoryError("Poisoned".to_string()))
+}
+
+/// Blocks until `mutex` can be locked, a poisoned mutex is a `Fault::MemoryError`.
+pub fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>, Fault> {
+    mutex.lock().map_err(|_| Fault::MemoryError("Poisoned".to_string()))
+}
+
+/// A slab split into shards that are locked separately.
+/// The shard of a value is stored in the low bits of the slot index of its handle,
+/// so finding it only takes the lock of that shard.
+#[derive(Debug)]
+pub struct ShardedSlab<T> {
+    shards: Box<[RwLock<Slab<T>>]>,
+    /// Hands out shards to the threads that insert, one after the other.
+    next_shard: AtomicUsize,
+}
+
+impl<T> Default for ShardedSlab<T> {
+    fn default() -> Self {
+        ShardedSlab {
+            shards: (0..SHARD_COUNT).map(|_| RwLock::new(Slab::new())).collect(),
+            next_shard: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl<T> ShardedSlab<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get_shard(handle: Handle) -> usize {
+        Slab::<T>::get_slot_index(handle) % SHARD_COUNT
+    }
+
+    fn to_shard_handle(handle: Handle) -> Handle {
+        Slab::<T>::make_handle((Slab::<T>::get_slot_index(handle) / SHARD_COUNT) as u32, Slab::<T>::get_generation(handle))
+    }
+
+    fn from_shard_handle(shard: usize, handle: Handle) -> Handle {
+        let index = Slab::<T>::get_slot_index(handle) * SHARD_COUNT + shard;
+        Slab::<T>::make_handle(index as u32, Slab::<T>::get_generation(handle))
+    }
+
+    fn get_thread_shard(&self) -> usize {
+        let slab = self.shards.as_ptr() as usize;
+        THREAD_SHARD.with(|shard| match shard.get() {
+            Some((last_slab, last_shard)) if last_slab == slab => last_shard,
+            _ => {
+                let next = self.next_shard.fetch_add(1, Ordering::Relaxed) % SHARD_COUNT;
+                shard.set(Some((slab, next)));
+                next
+            }
+        })
+    }
+
+    pub fn insert(&self, value: T) -> Result<Handle, Fault> {
+        let shard = self.get_thread_shard();
+        let handle = write(&self.shards[shard])?.insert(value);
+        Ok(Self::from_shard_handle(shard, handle))
+    }
+
+    /// Calls `function` with the value of `handle` while its shard is read locked.
+    pub fn with<R>(&self, handle: Handle, function: impl FnOnce(&T) -> R) -> Result<R, Fault> {
+        let shard = read(&self.shards[Self::get_shard(handle)])?;
+        shard.get(Self::to_shard_handle(handle)).map(function)
+    }
+
+    /// Calls `function` with the value of `handle` while its shard is write locked.
+    pub fn with_mut<R>(&self, handle: Handle, function: impl FnOnce(&mut T) -> R) -> Result<R, Fault> {
+        let mut shard = write(&self.shards[Self::get_shard(handle)])?;
+        shard.get_mut(Self::to_shard_handle(handle)).map(function)
+    }
+
+    /// The handles of every value, shard by shard.
+    pub fn get_handles(&self) -> Result<Vec<Handle>, Fault> {
+        let mut handles = Vec::new();
+        for (index, shard) in self.shards.iter().enumerate() {
+            handles.extend(read(shard)?.handles().map(|handle| Self::from_shard_handle(index, handle)));
+        }
+        Ok(handles)
+    }
+
+    pub fn remove(&self, handle: Handle) -> Result<T, Fault> {
+        write(&self.shards[Self::get_shard(handle)])?.remove(Self::to_shard_handle(handle))
+    }
+
+    /// Removes every value that cannot be reached from `roots` and returns them.
+    /// `trace` adds the handles a value refers to, handles that don't point at a value are ignored.
+    /// `visit` is called with every handle taken from `roots`, whether it points at a value or not, and may add more.
+    /// Every shard is write locked until the sweep is done.
+    pub fn retain_reachable(&self,
+                            mut roots: Vec<Handle>,
+                            mut trace: impl FnMut(&T, &mut Vec<Handle>),
+                            mut visit: impl FnMut(Handle, &mut Vec<Handle>)) -> Result<Vec<T>, Fault> {
+        let mut shards = self.shards.iter().map(write).collect::<Result<Vec<_>, _>>()?;
+        let mut marked = shards.iter()
+            .map(|shard| vec![false; shard.get_slot_count()])
+            .collect::<Vec<_>>();
+
+        while let Some(handle) = roots.pop() {
+            visit(handle, &mut roots);
+            let shard = Self::get_shard(handle);
+            let shard_handle = Self::to_shard_handle(handle);
+            let Ok(value) = shards[shard].get(shard_handle) else {
+                continue;
+            };
+            let index = Slab::<T>::get_slot_index(shard_handle);
+            if !marked[shard][index] {
+                marked[shard][index] = true;
+                trace(value, &mut roots);
+            }
+        }
+
+        let mut garbage = Vec::new();
+        for (shard, marked) in shards.iter_mut().zip(marked.iter()) {
+            garbage.extend(shard.retain(|handle, _| marked[Slab::<T>::get_slot_index(handle)]));
+        }
+        Ok(garbage)
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use crate::instruction::RegisterType;
+use crate::machine::fiber::{join_result, Clock, Wait, SUSPEND_EFFECT};
+use crate::machine::native::NativeContext;
+use crate::machine::statistics::Counter;
+use crate::machine::{Fault, InstructionResult};
+use crate::program::Module;
+use crate::stack_frame::frame::Frame;
+
+pub fn get_fiber_module() -> Module {
+    let mut module = Module::new("fiber", HashMap::new(), Vec::new(), HashMap::new());
+
+    module.add_native_function("spawn", spawn_fiber);
+    module.add_native_function("yield", yield_fiber);
+    module.add_native_function("join", join_fiber);
+    module.add_native_function("sleep", sleep);
+    module.add_native_function("sleep_until", sleep_until);
+    module.add_native_function("now", now);
+    module.add_native_function("set_budget", set_budget);
+    module.add_native_function("use_real_time", use_real_time);
+
+    module
+}
+
+fn suspend(context: &mut NativeContext, wait: Wait) -> Result<InstructionResult,Fault> {
+    context.get_core().scheduler.set_wait(wait);
+    Ok(InstructionResult::Unwind(SUSPEND_EFFECT.into(), None))
+}
+
+/// Adds a fiber that runs the closure in r8 with r9 passed to it as its first argument.
+/// The fiber runs on this core once the running fibers yield, its handle is returned in r0.
+fn spawn_fiber(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let closure = context.get_closure(0)?;
+    let function = closure.get_function().clone();
+    let frame = Frame::new_closure(closure.get_path().clone(), function.get_instructions(), closure.get_captures().into());
+
+    let argument = context.get_typed_argument(1, RegisterType::U64)?;
+    let fiber = context.get_core().scheduler.spawn(function, frame, argument);
+    context.get_core().count(Counter::Fibers, 1);
+    context.set_return(0, fiber);
+    Ok(InstructionResult::Continue)
+}
+
+/// Lets the other ready fibers run before this one continues.
+fn yield_fiber(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    suspend(context, Wait::Yield)
+}
+
+/// Waits until the fiber with the handle in r8 finishes.
+/// If it returned, r0 is its r0 and r1 is 0.
+/// If it faulted, r0 is a reference to a string describing the fault and r1 is 1.
+fn join_fiber(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let fiber = context.get_u64(0)?;
+    if context.get_core().scheduler.get_current() == Some(fiber) {
+        return Err(Fault::Deadlock);
+    }
+    match context.get_core().scheduler.take_result(fiber)? {
+        Some(result) => {
+            let (value, status) = join_result(result, context.get_memory_mut())?;
+            context.set_return(0, value);
+            context.set_return(1, status);
+            Ok(InstructionResult::Continue)
+        }
+        None => suspend(context, Wait::Join(fiber)),
+    }
+}
+
+/// Suspends this fiber for the number of milliseconds in r8.
+fn sleep(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let duration = context.get_u64(0)?;
+    let wake = context.get_core().scheduler.get_time().saturating_add(duration);
+    suspend(context, Wait::Sleep(wake))
+}
+
+/// Suspends this fiber until the scheduler's time reaches the millisecond in r8.
+fn sleep_until(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let deadline = context.get_u64(0)?;
+    suspend(context, Wait::Sleep(deadline))
+}
+
+/// Returns the scheduler's time in milliseconds in r0.
+fn now(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let time = context.get_core().scheduler.get_time();
+    context.set_return(0, time);
+    Ok(InstructionResult::Continue)
+}
+
+/// Sets how many instructions a fiber runs before it is preempted to r8, 0 only switches fibers when they suspend.
+fn set_budget(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let budget = context.get_u64(0)?;
+    context.get_core().scheduler.set_budget(budget);
+    Ok(InstructionResult::Continue)
+}
+
+/// Sleeping uses wall clock time if r8 is not 0, otherwise the virtual clock that only moves when every fiber sleeps.
+fn use_real_time(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let real = context.get_u64(0)? != 0;
+    context.get_core().scheduler.set_clock(if real { Clock::Real } else { Clock::Virtual });
+    Ok(InstructionResult::Continue)
+}
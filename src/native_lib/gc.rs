@@ -0,0 +1,22 @@
+use std::collections::HashMap;
+use crate::machine::{Fault, InstructionResult};
+use crate::machine::native::NativeContext;
+use crate::program::Module;
+
+pub fn get_gc_module() -> Module {
+    let mut module = Module::new("gc", HashMap::new(), Vec::new(), HashMap::new());
+
+    module.add_native_function("collect", collect);
+
+    module
+}
+
+
+/// Collects garbage now instead of waiting for the allocation threshold.
+/// The number of freed objects is returned in r0.
+/// While other threads are running nothing is freed and 0 is returned, the collection runs once they have finished.
+fn collect(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let freed = context.collect_garbage()?;
+    context.set_return(0, freed as u64);
+    Ok(InstructionResult::Continue)
+}
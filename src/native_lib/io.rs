@@ -1,159 +1,173 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use crate::instruction::{RegisterType, Source};
-use crate::machine::core::{Core, CoreUtils};
+use std::fs::OpenOptions;
+use std::io;
+use std::os::fd::{IntoRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use crate::machine::fiber::{Wait, SUSPEND_EFFECT};
+use crate::machine::native::NativeContext;
+use crate::machine::reactor::{io_error, Interest};
 use crate::machine::{Fault, InstructionResult};
-use crate::memory::Memory;
-use crate::program::function::Function;
 use crate::program::Module;
-use crate::stack_frame::delimited_continuation::ContinuationStore;
-use crate::stack_frame::StackFrame;
-use crate::value::Value;
+
+/// The most bytes `read` reads at once, larger requests read less like a short read.
+const MAX_READ: usize = 64 * 1024;
 
 pub fn get_io_module() -> Module {
     let mut module = Module::new("io", HashMap::new(), Vec::new(), HashMap::new());
 
-    module.add_function("println_string", Function::Native(println_string));
-    module.add_function("print_string", Function::Native(print_string));
-    module.add_function("println_u8", Function::Native(println_u8));
-    module.add_function("println_u16", Function::Native(println_u16));
-    module.add_function("println_u32", Function::Native(println_u32));
-    module.add_function("println_u64", Function::Native(println_u64));
-    module.add_function("println_i8", Function::Native(println_i8));
-    module.add_function("println_i16", Function::Native(println_i16));
-    module.add_function("println_i32", Function::Native(println_i32));
-    module.add_function("println_i64", Function::Native(println_i64));
-    module.add_function("println_f32", Function::Native(println_f32));
-    module.add_function("println_f64", Function::Native(println_f64));
-    module.add_function("eprintln_string", Function::Native(eprintln_string));
-    module.add_function("eprint_string", Function::Native(eprint_string));
-    module.add_function("eprintln_u8", Function::Native(eprintln_u8));
-    module.add_function("eprintln_u16", Function::Native(eprintln_u16));
-    module.add_function("eprintln_u32", Function::Native(eprintln_u32));
-    module.add_function("eprintln_u64", Function::Native(eprintln_u64));
-    module.add_function("eprintln_i8", Function::Native(eprintln_i8));
-    module.add_function("eprintln_i16", Function::Native(eprintln_i16));
-    module.add_function("eprintln_i32", Function::Native(eprintln_i32));
-    module.add_function("eprintln_i64", Function::Native(eprintln_i64));
-    module.add_function("eprintln_f32", Function::Native(eprintln_f32));
-    module.add_function("eprintln_f64", Function::Native(eprintln_f64));
+    println_string::register(&mut module);
+    print_string::register(&mut module);
+    println_u8::register(&mut module);
+    println_u16::register(&mut module);
+    println_u32::register(&mut module);
+    println_u64::register(&mut module);
+    println_i8::register(&mut module);
+    println_i16::register(&mut module);
+    println_i32::register(&mut module);
+    println_i64::register(&mut module);
+    println_f32::register(&mut module);
+    println_f64::register(&mut module);
+    eprintln_string::register(&mut module);
+    eprint_string::register(&mut module);
+    eprintln_u8::register(&mut module);
+    eprintln_u16::register(&mut module);
+    eprintln_u32::register(&mut module);
+    eprintln_u64::register(&mut module);
+    eprintln_i8::register(&mut module);
+    eprintln_i16::register(&mut module);
+    eprintln_i32::register(&mut module);
+    eprintln_i64::register(&mut module);
+    eprintln_f32::register(&mut module);
+    eprintln_f64::register(&mut module);
+    module.add_native_function("open", open);
+    module.add_native_function("read", read);
+    module.add_native_function("write", write);
+    module.add_native_function("close", close);
+    module.add_native_function("pipe", pipe);
 
     module
 }
 
 
 
-fn println_string(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-    let reference = core.get_value(&Source::Register(8, RegisterType::Reference));
-    match reference {
-        Value::MemoryRef(ref_) => {
-            let string = memory.get_string(ref_, &module).unwrap();
-            println!("{}", string);
-        },
-        _ => {}
-    }
-
-    Ok(InstructionResult::Continue)
+/// Generates the natives that print a value of each type, to stdout and to stderr.
+macro_rules! generate_print {
+    ($($println:ident, $eprintln:ident: $type:ty;)*) => {
+        $(
+            #[crayfish::native]
+            fn $println(context: &mut NativeContext, value: $type) -> Result<(), Fault> {
+                context.get_stdio().print(format_args!("{}\n", value))
+            }
+
+            #[crayfish::native]
+            fn $eprintln(context: &mut NativeContext, value: $type) -> Result<(), Fault> {
+                context.get_stdio().eprint(format_args!("{}\n", value))
+            }
+        )*
+    };
 }
 
-fn print_string(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-    let reference = core.get_value(&Source::Register(8, RegisterType::Reference));
-    match reference {
-        Value::MemoryRef(ref_) => {
-            let string = memory.get_string(ref_, &module).unwrap();
-            print!("{}", string);
-        },
-        _ => {}
-    }
-
-    Ok(InstructionResult::Continue)
+generate_print! {
+    println_string, eprintln_string: String;
+    println_u8, eprintln_u8: u8;
+    println_u16, eprintln_u16: u16;
+    println_u32, eprintln_u32: u32;
+    println_u64, eprintln_u64: u64;
+    println_i8, eprintln_i8: i8;
+    println_i16, eprintln_i16: i16;
+    println_i32, eprintln_i32: i32;
+    println_i64, eprintln_i64: i64;
+    println_f32, eprintln_f32: f32;
+    println_f64, eprintln_f64: f64;
 }
 
-macro_rules! generate_println {
-    ($name:ident, $type:ident) => {
-        fn $name(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-            let value = core.get_value(&Source::Register(8, RegisterType::$type));
-            println!("{}", value);
-            Ok(InstructionResult::Continue)
-        }
-    };
+#[crayfish::native]
+fn print_string(context: &mut NativeContext, value: String) -> Result<(), Fault> {
+    context.get_stdio().print(format_args!("{}", value))
 }
 
-macro_rules! generate_print {
-    ($name:ident, $type:ident) => {
-        fn $name(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-            let value = core.get_value(&Source::Register(8, RegisterType::$type));
-            print!("{}", value);
-            Ok(InstructionResult::Continue)
-        }
-    };
+#[crayfish::native]
+fn eprint_string(context: &mut NativeContext, value: String) -> Result<(), Fault> {
+    context.get_stdio().eprint(format_args!("{}", value))
 }
 
-generate_println!(println_u8, U8);
-generate_println!(println_u16, U16);
-generate_println!(println_u32, U32);
-generate_println!(println_u64, U64);
-generate_println!(println_i8, I8);
-generate_println!(println_i16, I16);
-generate_println!(println_i32, I32);
-generate_println!(println_i64, I64);
-generate_println!(println_f32, F32);
-generate_println!(println_f64, F64);
-
-
-fn eprintln_string(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-    let reference = core.get_value(&Source::Register(8, RegisterType::Reference));
-    match reference {
-        Value::MemoryRef(ref_) => {
-            let string = memory.get_string(ref_, &module).unwrap();
-            eprintln!("{}", string);
-        },
-        _ => {}
+/// Turns the result of a system call into an `io::Result`, -1 is an error.
+pub fn check_syscall(result: isize) -> io::Result<usize> {
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
     }
+}
 
-    Ok(InstructionResult::Continue)
+/// Suspends the calling fiber until `fd` is ready for `interest` and then calls the native again.
+pub fn park(context: &mut NativeContext, fd: RawFd, interest: Interest) -> Result<InstructionResult,Fault> {
+    context.get_core().scheduler.set_wait(Wait::Io(fd, interest));
+    Ok(InstructionResult::Unwind(SUSPEND_EFFECT.into(), None))
 }
 
-fn eprint_string(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-    let reference = core.get_value(&Source::Register(8, RegisterType::Reference));
-    match reference {
-        Value::MemoryRef(ref_) => {
-            let string = memory.get_string(ref_, &module).unwrap();
-            eprint!("{}", string);
-        },
-        _ => {}
-    }
+pub fn get_fd(context: &NativeContext, index: usize) -> Result<RawFd, Fault> {
+    Ok(context.get_u64(index)? as RawFd)
+}
 
+/// Opens the file at the path in r8 and returns its file descriptor in r0.
+/// r9 is the mode, 0 reads, 1 creates or truncates the file to write it and 2 appends to it.
+fn open(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let mut options = OpenOptions::new();
+    match context.get_u64(1)? {
+        0 => options.read(true),
+        1 => options.write(true).create(true).truncate(true),
+        2 => options.append(true).create(true),
+        mode => return Err(Fault::InvalidOperation(format!("invalid open mode {}", mode))),
+    };
+    let file = options.custom_flags(libc::O_NONBLOCK).open(context.get_string(0)?).map_err(io_error)?;
+    context.set_return(0, file.into_raw_fd() as u64);
     Ok(InstructionResult::Continue)
 }
 
-macro_rules! generate_eprintln {
-    ($name:ident, $type:ident) => {
-        fn $name(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-            let value = core.get_value(&Source::Register(8, RegisterType::$type));
-            eprintln!("{}", value);
-            Ok(InstructionResult::Continue)
-        }
+/// Reads up to r9 bytes, at most 64 KiB, from the file descriptor in r8, waiting without blocking the core until there are some.
+/// r0 is a string of what was read, with invalid UTF-8 replaced, and r1 is the number of bytes, 0 at the end of the file.
+fn read(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let fd = get_fd(context, 0)?;
+    let mut buffer = vec![0u8; (context.get_u64(1)? as usize).min(MAX_READ)];
+    let count = match check_syscall(unsafe { libc::read(fd, buffer.as_mut_ptr().cast(), buffer.len()) }) {
+        Ok(count) => count,
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => return park(context, fd, Interest::Readable),
+        Err(error) => return Err(io_error(error)),
     };
+    let string = context.allocate_string(&String::from_utf8_lossy(&buffer[..count]))?;
+    context.set_return(0, string);
+    context.set_return(1, count as u64);
+    Ok(InstructionResult::Continue)
 }
 
-macro_rules! generate_eprint {
-    ($name:ident, $type:ident) => {
-        fn $name(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-            let value = core.get_value(&Source::Register(8, RegisterType::$type));
-            eprint!("{}", value);
-            Ok(InstructionResult::Continue)
-        }
+/// Writes the string in r9 to the file descriptor in r8, waiting without blocking the core until it can.
+/// Like the system call it can write only part of the string, r0 is the number of bytes written.
+fn write(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let fd = get_fd(context, 0)?;
+    let string = context.get_string(1)?;
+    let count = match check_syscall(unsafe { libc::write(fd, string.as_ptr().cast(), string.len()) }) {
+        Ok(count) => count,
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => return park(context, fd, Interest::Writable),
+        Err(error) => return Err(io_error(error)),
     };
+    context.set_return(0, count as u64);
+    Ok(InstructionResult::Continue)
+}
+
+/// Closes the file descriptor in r8, fibers waiting on it are woken up and see the error.
+fn close(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let fd = get_fd(context, 0)?;
+    context.get_core().scheduler.close_fd(fd);
+    check_syscall(unsafe { libc::close(fd) } as isize).map_err(io_error)?;
+    Ok(InstructionResult::Continue)
 }
 
-generate_eprintln!(eprintln_u8, U8);
-generate_eprintln!(eprintln_u16, U16);
-generate_eprintln!(eprintln_u32, U32);
-generate_eprintln!(eprintln_u64, U64);
-generate_eprintln!(eprintln_i8, I8);
-generate_eprintln!(eprintln_i16, I16);
-generate_eprintln!(eprintln_i32, I32);
-generate_eprintln!(eprintln_i64, I64);
-generate_eprintln!(eprintln_f32, F32);
-generate_eprintln!(eprintln_f64, F64);
\ No newline at end of file
+/// Creates a pipe, r0 is the file descriptor of its read end and r1 of its write end.
+fn pipe(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let mut fds = [0 as RawFd; 2];
+    check_syscall(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } as isize).map_err(io_error)?;
+    context.set_return(0, fds[0] as u64);
+    context.set_return(1, fds[1] as u64);
+    Ok(InstructionResult::Continue)
+}
@@ -0,0 +1,74 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use crate::machine::{Fault, InstructionResult};
+use crate::machine::native::{FromValue, NativeContext};
+use crate::program::Module;
+use crate::value::{Value, ValueType};
+
+pub fn get_list_module() -> Module {
+    let mut module = Module::new("list", HashMap::new(), Vec::new(), HashMap::new());
+
+    module.add_native_function("map", map);
+    module.add_native_function("sort", sort);
+
+    module
+}
+
+
+/// Calls the closure in r9 with each element of the list in r8 and returns a new list of the results in r0.
+fn map(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let list = context.get_reference(0)?;
+    let closure = context.get_closure(1)?;
+    let length = context.get_memory().get_list_length(list)?.to_usize() as u64;
+
+    let result = context.get_memory_mut().allocate_list(length as usize, ValueType::U64)?;
+    context.keep_alive(result.clone());
+    let result_reference = result.get_reference().ok_or(Fault::InvalidReference)?;
+    for index in 0..length {
+        let element = context.get_memory().access_list(list, index)?;
+        let value = context.call_closure(&closure, &[element])?;
+        context.get_memory_mut().store_list(result_reference, index, value)?;
+    }
+    context.set_return(0, result);
+    Ok(InstructionResult::Continue)
+}
+
+/// Sorts the list in r8 in place with the closure in r9, which is called with two elements
+/// and returns a negative i64 if the first goes before the second, 0 if they are equal and a positive one otherwise.
+/// The sort is stable.
+fn sort(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let list = context.get_reference(0)?;
+    let closure = context.get_closure(1)?;
+    let length = context.get_memory().get_list_length(list)?.to_usize() as u64;
+
+    let elements = (0..length)
+        .map(|index| context.get_memory().access_list(list, index))
+        .collect::<Result<Vec<_>, _>>()?;
+    let sorted = merge_sort(elements, &mut |first, second| {
+        let order = context.call_closure(&closure, &[first.clone(), second.clone()])?;
+        Ok(i64::from_value(order, context.get_memory(), context.get_module())?.cmp(&0))
+    })?;
+    for (index, element) in sorted.into_iter().enumerate() {
+        context.get_memory_mut().store_list(list, index as u64, element)?;
+    }
+    Ok(InstructionResult::Continue)
+}
+
+/// A stable merge sort with a comparison that can fault, which `slice::sort_by` doesn't allow.
+fn merge_sort(mut values: Vec<Value>, compare: &mut impl FnMut(&Value, &Value) -> Result<Ordering, Fault>) -> Result<Vec<Value>, Fault> {
+    if values.len() <= 1 {
+        return Ok(values);
+    }
+    let right = values.split_off(values.len() / 2);
+    let mut left = merge_sort(values, compare)?.into_iter().peekable();
+    let mut right = merge_sort(right, compare)?.into_iter().peekable();
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    while let (Some(first), Some(second)) = (left.peek(), right.peek()) {
+        let next = if compare(second, first)? == Ordering::Less { &mut right } else { &mut left };
+        merged.extend(next.next());
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
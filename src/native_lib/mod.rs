@@ -2,13 +2,24 @@ use std::collections::HashMap;
 use crate::program::Module;
 
 pub mod io;
-mod threading;
+pub mod gc;
+pub mod threading;
+pub mod sync;
+pub mod fiber;
+pub mod net;
+pub mod list;
 
 
 pub fn get_std_module() -> Module {
     let mut module = Module::new("std", HashMap::new(), Vec::new(), HashMap::new());
 
     module.add_sub_module(io::get_io_module());
+    module.add_sub_module(gc::get_gc_module());
+    module.add_sub_module(threading::get_threading_module());
+    module.add_sub_module(sync::get_sync_module());
+    module.add_sub_module(fiber::get_fiber_module());
+    module.add_sub_module(net::get_net_module());
+    module.add_sub_module(list::get_list_module());
 
     module
 }
\ No newline at end of file
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::os::fd::IntoRawFd;
+use crate::machine::native::NativeContext;
+use crate::machine::reactor::{io_error, Interest};
+use crate::machine::{Fault, InstructionResult};
+use crate::native_lib::io::{check_syscall, get_fd, park};
+use crate::program::Module;
+
+pub fn get_net_module() -> Module {
+    let mut module = Module::new("net", HashMap::new(), Vec::new(), HashMap::new());
+
+    module.add_native_function("listen", listen);
+    module.add_native_function("accept", accept);
+    module.add_native_function("connect", connect);
+    module.add_native_function("local_port", local_port);
+
+    module
+}
+
+
+/// The socket address made of the IP address string in r8 and the port in r9.
+fn get_address(context: &NativeContext) -> Result<SocketAddr, Fault> {
+    let ip = context.get_string(0)?;
+    let ip = ip.parse::<IpAddr>().map_err(|_| Fault::InvalidOperation(format!("invalid IP address {}", ip)))?;
+    let port = context.get_u64(1)?;
+    let port = u16::try_from(port).map_err(|_| Fault::InvalidOperation(format!("invalid port {}", port)))?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+fn to_sockaddr(address: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let length = match address {
+        SocketAddr::V4(address) => {
+            let sockaddr = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+            sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+            sockaddr.sin_port = address.port().to_be();
+            sockaddr.sin_addr.s_addr = u32::from(*address.ip()).to_be();
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(address) => {
+            let sockaddr = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>() };
+            sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sockaddr.sin6_port = address.port().to_be();
+            sockaddr.sin6_addr.s6_addr = address.ip().octets();
+            sockaddr.sin6_flowinfo = address.flowinfo();
+            sockaddr.sin6_scope_id = address.scope_id();
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, length as libc::socklen_t)
+}
+
+/// Listens for TCP connections on the IP address in r8 and the port in r9, port 0 picks a free one.
+/// The file descriptor of the listening socket is returned in r0.
+fn listen(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let address = get_address(context)?;
+    let listener = TcpListener::bind(address).map_err(io_error)?;
+    listener.set_nonblocking(true).map_err(io_error)?;
+    context.set_return(0, listener.into_raw_fd() as u64);
+    Ok(InstructionResult::Continue)
+}
+
+/// Waits without blocking the core for a connection on the listening socket in r8.
+/// The file descriptor of the connection is returned in r0.
+fn accept(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let fd = get_fd(context, 0)?;
+    let result = unsafe { libc::accept4(fd, std::ptr::null_mut(), std::ptr::null_mut(), libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC) };
+    let connection = match check_syscall(result as isize) {
+        Ok(connection) => connection,
+        Err(error) if error.kind() == io::ErrorKind::WouldBlock => return park(context, fd, Interest::Readable),
+        Err(error) => return Err(io_error(error)),
+    };
+    context.set_return(0, connection as u64);
+    Ok(InstructionResult::Continue)
+}
+
+/// Starts connecting to the IP address in r8 and the port in r9 and returns the file descriptor of the socket in r0.
+/// The connection is made in the background, reads and writes wait for it and report if it failed.
+fn connect(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let address = get_address(context)?;
+    let family = if address.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+    let socket = unsafe { libc::socket(family, libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    let socket = check_syscall(socket as isize).map_err(io_error)? as i32;
+
+    let (sockaddr, length) = to_sockaddr(&address);
+    let result = unsafe { libc::connect(socket, (&sockaddr as *const libc::sockaddr_storage).cast(), length) };
+    if let Err(error) = check_syscall(result as isize) {
+        if error.raw_os_error() != Some(libc::EINPROGRESS) {
+            unsafe { libc::close(socket) };
+            return Err(io_error(error));
+        }
+    }
+    context.set_return(0, socket as u64);
+    Ok(InstructionResult::Continue)
+}
+
+/// Returns the local port of the socket in r8 in r0.
+fn local_port(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let fd = get_fd(context, 0)?;
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut length = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let result = unsafe { libc::getsockname(fd, (&mut storage as *mut libc::sockaddr_storage).cast(), &mut length) };
+    check_syscall(result as isize).map_err(io_error)?;
+    let port = match storage.ss_family as i32 {
+        libc::AF_INET => unsafe { (*(&storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>()).sin_port },
+        libc::AF_INET6 => unsafe { (*(&storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()).sin6_port },
+        _ => return Err(Fault::InvalidOperation("not an IP socket".to_string())),
+    };
+    context.set_return(0, u16::from_be(port) as u64);
+    Ok(InstructionResult::Continue)
+}
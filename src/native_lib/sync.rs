@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::instruction::RegisterType;
+use crate::machine::fiber::{Wait, SUSPEND_EFFECT};
+use crate::machine::native::NativeContext;
+use crate::machine::{Fault, InstructionResult};
+use crate::program::Module;
+use crate::value::sync::{Channel, Condvar, Mutex, Owner, Received, Semaphore, SyncObject, TryWait};
+use crate::value::ValueType;
+
+pub fn get_sync_module() -> Module {
+    let mut module = Module::new("sync", HashMap::new(), Vec::new(), HashMap::new());
+
+    module.add_native_function("new_mutex", new_mutex);
+    module.add_native_function("lock", lock);
+    module.add_native_function("try_lock", try_lock);
+    module.add_native_function("unlock", unlock);
+    module.add_native_function("new_condvar", new_condvar);
+    module.add_native_function("wait", wait);
+    module.add_native_function("notify_one", notify_one);
+    module.add_native_function("notify_all", notify_all);
+    module.add_native_function("new_semaphore", new_semaphore);
+    module.add_native_function("acquire", acquire);
+    module.add_native_function("release", release);
+    module.add_native_function("new_channel", new_channel);
+    module.add_native_function("send", send);
+    module.add_native_function("receive", receive);
+    module.add_native_function("close", close);
+    module.add_native_function("cells", cells);
+
+    module
+}
+
+
+fn get_sync_object(context: &NativeContext, index: usize) -> Result<SyncObject, Fault> {
+    context.get_memory().get_sync(context.get_reference(index)?)
+}
+
+fn get_mutex(context: &NativeContext, index: usize) -> Result<Arc<Mutex>, Fault> {
+    match get_sync_object(context, index)? {
+        SyncObject::Mutex(mutex) => Ok(mutex),
+        _ => Err(Fault::InvalidReference),
+    }
+}
+
+fn get_condvar(context: &NativeContext, index: usize) -> Result<Arc<Condvar>, Fault> {
+    match get_sync_object(context, index)? {
+        SyncObject::Condvar(condvar) => Ok(condvar),
+        _ => Err(Fault::InvalidReference),
+    }
+}
+
+fn get_semaphore(context: &NativeContext, index: usize) -> Result<Arc<Semaphore>, Fault> {
+    match get_sync_object(context, index)? {
+        SyncObject::Semaphore(semaphore) => Ok(semaphore),
+        _ => Err(Fault::InvalidReference),
+    }
+}
+
+fn get_channel(context: &NativeContext, index: usize) -> Result<Arc<Channel>, Fault> {
+    match get_sync_object(context, index)? {
+        SyncObject::Channel(channel) => Ok(channel),
+        _ => Err(Fault::InvalidReference),
+    }
+}
+
+/// With no other thread running, nothing could wake up a thread that blocks.
+fn is_only_thread(context: &NativeContext) -> bool {
+    context.get_memory().get_thread_count() == 1
+}
+
+/// The running fiber of this thread.
+fn get_owner(context: &mut NativeContext) -> Owner {
+    (std::thread::current().id(), context.get_core().scheduler.get_current())
+}
+
+/// Whether a call that can't go on yet should park the fiber rather than block the thread,
+/// which is the case when other fibers of this core could run in the meantime.
+fn should_park(context: &mut NativeContext) -> bool {
+    context.get_core().scheduler.has_other_fibers()
+}
+
+/// Suspends the calling fiber until something is released and then calls the native again.
+fn park(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    context.get_core().scheduler.set_wait(Wait::Blocked);
+    Ok(InstructionResult::Unwind(SUSPEND_EFFECT.into(), None))
+}
+
+/// Lets the fibers of this core that are parked on a synchronization object try again.
+fn wake_parked(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    context.get_core().scheduler.wake_blocked();
+    Ok(InstructionResult::Continue)
+}
+
+fn return_new(context: &mut NativeContext, object: SyncObject) -> Result<InstructionResult,Fault> {
+    let reference = context.get_memory_mut().allocate_sync(object)?;
+    context.set_return(0, reference);
+    Ok(InstructionResult::Continue)
+}
+
+/// Returns a new unlocked mutex in r0.
+fn new_mutex(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    return_new(context, SyncObject::Mutex(Arc::default()))
+}
+
+/// Waits until the mutex in r8 is unlocked and locks it.
+fn lock(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let owner = get_owner(context);
+    if get_mutex(context, 0)?.try_lock(owner)? {
+        return Ok(InstructionResult::Continue);
+    }
+    if should_park(context) {
+        return park(context);
+    }
+    if is_only_thread(context) {
+        return Err(Fault::Deadlock);
+    }
+    get_mutex(context, 0)?.lock(owner)?;
+    Ok(InstructionResult::Continue)
+}
+
+/// Locks the mutex in r8 if it is unlocked, r0 is 1 if it was locked and 0 if not.
+fn try_lock(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let owner = get_owner(context);
+    let locked = get_mutex(context, 0)?.try_lock(owner)?;
+    context.set_return(0, locked as u64);
+    Ok(InstructionResult::Continue)
+}
+
+/// Unlocks the mutex in r8, which must be locked by this fiber.
+fn unlock(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let owner = get_owner(context);
+    get_mutex(context, 0)?.unlock(owner)?;
+    wake_parked(context)
+}
+
+/// Returns a new condition variable in r0.
+fn new_condvar(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    return_new(context, SyncObject::Condvar(Arc::default()))
+}
+
+/// Unlocks the mutex in r9 and waits on the condition variable in r8, then locks the mutex again.
+fn wait(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let owner = get_owner(context);
+    let mutex = get_mutex(context, 1)?;
+    if should_park(context) {
+        return match get_condvar(context, 0)?.try_wait(&mutex, owner)? {
+            TryWait::Done => Ok(InstructionResult::Continue),
+            TryWait::Waiting => park(context),
+            TryWait::Started => {
+                // The mutex was unlocked, so fibers parked on it can try again.
+                context.get_core().scheduler.wake_blocked();
+                park(context)
+            }
+        };
+    }
+    if is_only_thread(context) {
+        return Err(Fault::Deadlock);
+    }
+    get_condvar(context, 0)?.wait(&mutex, owner)?;
+    Ok(InstructionResult::Continue)
+}
+
+/// Wakes up one fiber waiting on the condition variable in r8.
+fn notify_one(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    get_condvar(context, 0)?.notify_one()?;
+    wake_parked(context)
+}
+
+/// Wakes up every fiber waiting on the condition variable in r8.
+fn notify_all(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    get_condvar(context, 0)?.notify_all()?;
+    wake_parked(context)
+}
+
+/// Returns a new semaphore with the number of permits in r8 in r0.
+fn new_semaphore(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let permits = context.get_u64(0)?;
+    return_new(context, SyncObject::Semaphore(Arc::new(Semaphore::new(permits))))
+}
+
+/// Waits until the semaphore in r8 has a permit and takes it.
+fn acquire(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    if get_semaphore(context, 0)?.try_acquire()? {
+        return Ok(InstructionResult::Continue);
+    }
+    if should_park(context) {
+        return park(context);
+    }
+    if is_only_thread(context) {
+        return Err(Fault::Deadlock);
+    }
+    get_semaphore(context, 0)?.acquire()?;
+    Ok(InstructionResult::Continue)
+}
+
+/// Gives a permit back to the semaphore in r8.
+fn release(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    get_semaphore(context, 0)?.release()?;
+    wake_parked(context)
+}
+
+/// Returns a new channel in r0.
+fn new_channel(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    return_new(context, SyncObject::Channel(Arc::default()))
+}
+
+/// Sends r9 on the channel in r8.
+fn send(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let value = context.get_typed_argument(1, RegisterType::U64)?;
+    get_channel(context, 0)?.send(value)?;
+    wake_parked(context)
+}
+
+/// Waits until there is a value on the channel in r8.
+/// The value is returned in r0 and r1 is 0, or r1 is 1 if the channel was closed and is empty.
+fn receive(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let received = match get_channel(context, 0)?.try_receive()? {
+        Received::Empty if should_park(context) => return park(context),
+        Received::Empty if is_only_thread(context) => return Err(Fault::Deadlock),
+        Received::Empty => get_channel(context, 0)?.receive()?,
+        Received::Value(value) => Some(value),
+        Received::Closed => None,
+    };
+    match received {
+        Some(value) => {
+            context.set_return(0, value);
+            context.set_return(1, 0u64);
+        }
+        None => context.set_return(1, 1u64),
+    }
+    Ok(InstructionResult::Continue)
+}
+
+/// Closes the channel in r8, sending on it afterwards is a fault.
+fn close(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    get_channel(context, 0)?.close()?;
+    wake_parked(context)
+}
+
+/// Returns a pointer to the number of zeroed u64 cells in r8 in r0, for use with the atomic instructions.
+fn cells(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let count = context.get_u64(0)? as usize;
+    if count == 0 {
+        return Err(Fault::InvalidOperation("cannot allocate zero cells".to_string()));
+    }
+    let pointer = context.get_memory_mut().allocate_pointer(count, ValueType::U64)?;
+    context.set_return(0, pointer);
+    Ok(InstructionResult::Continue)
+}
@@ -1,62 +1,104 @@
 use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::Arc;
 use crate::backtrace::BacktraceInfo;
 use crate::machine::core::Core;
-use crate::machine::{call_bytecode_function, call_native_function, Fault, InstructionResult};
-use crate::memory::Memory;
+use crate::machine::native::{NativeContext, ARGUMENT_REGISTER};
+use crate::machine::reactor::io_error;
+use crate::machine::statistics::Counter;
+use crate::machine::{run_function, Fault, InstructionResult};
+use crate::memory::{Memory, SpawnedThread, ThreadResult};
+use crate::memory::slab::lock;
 use crate::program::function::Function;
 use crate::program::Module;
-use crate::stack_frame::delimited_continuation::ContinuationStore;
-use crate::stack_frame::StackFrame;
+use crate::stack_frame::frame::Frame;
 
 pub fn get_threading_module() -> Module {
     let mut module = Module::new("thread", HashMap::new(), Vec::new(), HashMap::new());
 
+    module.add_native_function("spawn", spawn_thread);
+    module.add_native_function("join", join_thread);
 
     module
 }
 
+/// What a new thread runs, the core it runs it on and where it leaves its result.
+struct ThreadStart {
+    core: Core,
+    function: Function,
+    frame: Frame,
+    module: Arc<Module>,
+    memory: Memory,
+    result: ThreadResult,
+}
 
-
-
-fn spawn_thread(core: &mut Core, module: Arc<Module>, stack_frames: &mut Vec<*mut dyn StackFrame>, memory: Memory, continuation_store: &mut ContinuationStore) -> Result<InstructionResult,Fault> {
-
-
-    let new_core = core.clone();
-
-    let thread = std::thread::spawn(move || {
-        let mut core = new_core;
-        //let mut stack_frames = Vec::new();
-        let mut continuation_store = ContinuationStore::new();
-        let mut memory = memory.clone();
-        let mut backtrace = BacktraceInfo::new();
-
-        /*let function = memory.get_function()
-
-        let result = match function {
-            Function::ByteCode(_) => call_bytecode_function(core, frame, module, &mut stack_frames, memory, &mut continuation_store, &mut backtrace)?,
-            Function::Native(native) => call_native_function(native, core, frame, module, &mut stack_frames, memory, &mut continuation_store, &mut backtrace)?,
-        };
-
-        match result {
-            InstructionResult::Stop => {
-                return Ok(())
-            }
-            InstructionResult::Continue => {
-                return Ok(())
-            }
-            InstructionResult::Return => {
-                return Ok(())
-            }
-            InstructionResult::Unwind(effect) => {
-                todo!("Add code for error message for letting an effect escape beyond the main function")
-            }
-            _ => panic!("Invalid instruction result"),
-        }*/
-
-
-    });
+unsafe impl Send for ThreadStart {}
+
+/// Starts the closure in r8 on a new thread, with r9 passed to it as its first argument.
+/// The thread gets its own core and continuations but shares memory with the spawning thread.
+/// The handle of the thread is returned in r0.
+fn spawn_thread(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let closure = context.get_closure(0)?;
+    let function = closure.get_function().clone();
+    let frame = Frame::new_closure(closure.get_path().clone(), function.get_instructions(), closure.get_captures().into());
+
+    let mut new_core = context.get_core().new_thread_core();
+    new_core.registers[ARGUMENT_REGISTER] = context.get_core().registers[ARGUMENT_REGISTER + 1].clone();
+
+    let memory = context.get_memory().clone();
+    let result = ThreadResult::default();
+    memory.add_thread();
+    let start = ThreadStart { core: new_core, function, frame, module: context.get_module().clone(), memory: memory.clone(), result: result.clone() };
+    let handle = std::thread::Builder::new()
+        .stack_size(context.get_core().limits.stack_size)
+        .spawn(move || run_thread(start))
+        .map_err(|error| {
+            memory.remove_thread();
+            io_error(error)
+        })?;
+
+    context.get_core().count(Counter::Threads, 1);
+    let handle = memory.insert_thread(SpawnedThread { handle, result })?;
+    context.set_return(0, handle);
     Ok(InstructionResult::Continue)
+}
 
+/// A panic is turned into a fault, so the thread always stops counting as running.
+fn run_thread(start: ThreadStart) {
+    let ThreadStart { mut core, function, frame, module, memory, result } = start;
+    let mut backtrace = BacktraceInfo::new();
+    let outcome = catch_unwind(AssertUnwindSafe(|| run_function(&mut core, function, frame, module, memory.clone(), &mut backtrace)))
+        .unwrap_or_else(|_| Err(Fault::InvalidOperation("thread panicked".to_string())));
+    if let Ok(mut result) = result.lock() {
+        *result = Some(outcome);
+    }
+    memory.remove_thread();
 }
 
+/// Blocks until the thread with the handle in r8 finishes.
+/// If it returned, r0 is its r0 and r1 is 0.
+/// If it faulted, r0 is a reference to a string describing the fault and r1 is 1.
+/// Joining a thread a second time faults with `ThreadAlreadyJoined`.
+fn join_thread(context: &mut NativeContext) -> Result<InstructionResult,Fault> {
+    let handle = context.get_u64(0)?;
+    let thread = context.get_memory().remove_thread_handle(handle).map_err(|fault| match fault {
+        Fault::UseAfterFree(_) => Fault::ThreadAlreadyJoined(handle),
+        fault => fault,
+    })?;
+    let _ = thread.handle.join();
+
+    let result = lock(&thread.result)?.take()
+        .unwrap_or_else(|| Err(Fault::InvalidOperation("thread panicked".to_string())));
+    match result {
+        Ok(value) => {
+            context.set_return(0, value);
+            context.set_return(1, 0u64);
+        }
+        Err(fault) => {
+            let message = context.allocate_string(&format!("{:?}", fault))?;
+            context.set_return(0, message);
+            context.set_return(1, 1u64);
+        }
+    }
+    Ok(InstructionResult::Continue)
+}
@@ -0,0 +1,968 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use crate::instruction::{CallTarget, ComparisonType, Condition, Immediate, Instruction, JumpTarget, RealInstruction, RegisterType, Source, Target};
+use crate::machine::Fault;
+use crate::program::class::Class;
+use crate::program::function::{Function, FunctionPath, LabelTable, NativeRegistry};
+use crate::program::{Module, StringTablePath};
+
+/// The first four bytes of every bytecode file.
+pub const MAGIC: [u8; 4] = *b"CRFY";
+/// The version of the bytecode format produced by `serialize_module`.
+/// Files with a different version are rejected by `load_module`.
+pub const FORMAT_VERSION: u16 = 7;
+
+/// How deeply sub modules may be nested before `load_module` gives up.
+pub const MAX_MODULE_DEPTH: usize = 64;
+
+const FUNCTION_BYTECODE: u8 = 0;
+const FUNCTION_NATIVE: u8 = 1;
+
+mod opcode {
+    pub const HALT: u8 = 0;
+    pub const NO_OP: u8 = 1;
+    pub const LOAD: u8 = 2;
+    pub const STORE: u8 = 3;
+    pub const STACK_DEREF: u8 = 4;
+    pub const STACK_STORE: u8 = 5;
+    pub const ADD: u8 = 6;
+    pub const SUB: u8 = 7;
+    pub const MUL: u8 = 8;
+    pub const DIV: u8 = 9;
+    pub const MOD: u8 = 10;
+    pub const AND: u8 = 11;
+    pub const OR: u8 = 12;
+    pub const XOR: u8 = 13;
+    pub const NOT: u8 = 14;
+    pub const SHIFT_LEFT: u8 = 15;
+    pub const SHIFT_RIGHT: u8 = 16;
+    pub const GOTO: u8 = 17;
+    pub const COMPARE: u8 = 18;
+    pub const PUSH: u8 = 19;
+    pub const POP: u8 = 20;
+    pub const CALL: u8 = 21;
+    pub const RETURN: u8 = 22;
+    pub const CREATE_CONTINUATION: u8 = 23;
+    pub const CREATE_OBJECT: u8 = 24;
+    pub const ACCESS_OBJECT: u8 = 25;
+    pub const CREATE_LIST: u8 = 26;
+    pub const LIST_LENGTH: u8 = 27;
+    pub const LIST_ACCESS: u8 = 28;
+    pub const LIST_STORE: u8 = 29;
+    pub const GET_STRING_REF: u8 = 30;
+    pub const INSTALL_HANDLER: u8 = 31;
+    pub const REMOVE_HANDLER: u8 = 32;
+    pub const PERFORM: u8 = 33;
+    pub const PUSH_PROMPT: u8 = 34;
+    pub const POP_PROMPT: u8 = 35;
+    pub const STORE_OBJECT: u8 = 36;
+    pub const CREATE_CLOSURE: u8 = 37;
+    pub const LOAD_CAPTURE: u8 = 38;
+    pub const ATOMIC_COMPARE_EXCHANGE: u8 = 39;
+    pub const ATOMIC_FETCH_ADD: u8 = 40;
+    pub const ATOMIC_EXCHANGE: u8 = 41;
+}
+
+/// An error produced while loading a bytecode file.
+/// Offsets are byte offsets into the input buffer.
+#[derive(Debug)]
+pub enum LoadError {
+    UnexpectedEnd(usize),
+    BadMagic,
+    UnsupportedVersion(u16),
+    InvalidTag { what: &'static str, tag: u8, offset: usize },
+    InvalidUtf8(usize),
+    UnresolvedImport(FunctionPath),
+    UnknownLabel { function: Box<str>, label: Box<str> },
+    LabelOutOfBounds { function: Box<str>, label: Box<str>, address: usize },
+    InvalidFunction { function: Box<str>, fault: Fault },
+    NestingTooDeep(usize),
+    TrailingBytes(usize),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::UnexpectedEnd(offset) => write!(f, "unexpected end of input at byte {}", offset),
+            LoadError::BadMagic => write!(f, "not a bytecode file"),
+            LoadError::UnsupportedVersion(version) => write!(f, "unsupported bytecode version {} (expected {})", version, FORMAT_VERSION),
+            LoadError::InvalidTag { what, tag, offset } => write!(f, "invalid {} tag {} at byte {}", what, tag, offset),
+            LoadError::InvalidUtf8(offset) => write!(f, "invalid utf-8 string at byte {}", offset),
+            LoadError::UnresolvedImport(path) => write!(f, "unresolved native import {}", path),
+            LoadError::UnknownLabel { function, label } => write!(f, "unknown label {} in function {}", label, function),
+            LoadError::LabelOutOfBounds { function, label, address } => write!(f, "label {} in function {} points past the end at {}", label, function, address),
+            LoadError::InvalidFunction { function, fault } => write!(f, "invalid function {}: {:?}", function, fault),
+            LoadError::NestingTooDeep(offset) => write!(f, "modules nested deeper than {} at byte {}", MAX_MODULE_DEPTH, offset),
+            LoadError::TrailingBytes(offset) => write!(f, "trailing bytes after module at byte {}", offset),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+/// Encodes a module and all of its sub modules into the binary bytecode format.
+/// Native functions are written as imports named by their path from `module`.
+pub fn serialize_module(module: &Module) -> Vec<u8> {
+    let mut writer = ByteWriter { bytes: Vec::new() };
+    writer.bytes.extend_from_slice(&MAGIC);
+    writer.write_u16(FORMAT_VERSION);
+
+    let mut path = Vec::new();
+    if !module.module_name.is_empty() {
+        path.push(module.module_name.clone());
+    }
+    writer.write_module(module, &mut path);
+    writer.bytes
+}
+
+/// Rebuilds a module from the binary bytecode format.
+/// Native imports are looked up in `natives`.
+pub fn load_module(bytes: &[u8], natives: &NativeRegistry) -> Result<Module, LoadError> {
+    let mut reader = ByteReader { bytes, offset: 0 };
+    if reader.read_bytes(MAGIC.len()).map_err(|_| LoadError::BadMagic)? != MAGIC {
+        return Err(LoadError::BadMagic);
+    }
+    let version = reader.read_u16()?;
+    if version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let module = reader.read_module(natives, 0)?;
+    if reader.offset != bytes.len() {
+        return Err(LoadError::TrailingBytes(reader.offset));
+    }
+    Ok(module)
+}
+
+struct ByteWriter {
+    bytes: Vec<u8>,
+}
+
+impl ByteWriter {
+    fn write_u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn write_u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    fn write_str(&mut self, value: &str) {
+        self.write_u32(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn write_path(&mut self, path: &[Box<str>]) {
+        self.write_u32(path.len() as u32);
+        for part in path.iter() {
+            self.write_str(part);
+        }
+    }
+
+    fn write_module(&mut self, module: &Module, path: &mut Vec<Box<str>>) {
+        self.write_str(&module.module_name);
+
+        self.write_u32(module.string_table.len() as u32);
+        for string in module.string_table.iter() {
+            self.write_str(string);
+        }
+
+        let mut functions = module.functions.iter().collect::<Vec<_>>();
+        functions.sort_by_key(|(name, _)| *name);
+        self.write_u32(functions.len() as u32);
+        for (name, function) in functions {
+            self.write_str(name);
+            match function {
+                Function::ByteCode(instructions, labels) => {
+                    self.write_u8(FUNCTION_BYTECODE);
+                    self.write_u32(instructions.len() as u32);
+                    for instruction in instructions.iter() {
+                        self.write_instruction(instruction);
+                    }
+
+                    let mut labels = labels.iter().collect::<Vec<_>>();
+                    labels.sort_by_key(|(label, _)| *label);
+                    self.write_u32(labels.len() as u32);
+                    for (label, address) in labels {
+                        self.write_str(label);
+                        self.write_u64(*address as u64);
+                    }
+                }
+                Function::Native(_) => {
+                    self.write_u8(FUNCTION_NATIVE);
+                    path.push(name.clone());
+                    self.write_path(path);
+                    path.pop();
+                }
+            }
+        }
+
+        let mut classes = module.classes.values().collect::<Vec<_>>();
+        classes.sort_by_key(|class| class.get_name());
+        self.write_u32(classes.len() as u32);
+        for class in classes {
+            self.write_str(class.get_name());
+            match class.get_super_class() {
+                Some(super_class) => {
+                    self.write_bool(true);
+                    self.write_path(&super_class.path);
+                }
+                None => self.write_bool(false),
+            }
+            self.write_u32(class.get_fields().len() as u32);
+            for (name, field_type) in class.get_fields() {
+                self.write_str(name);
+                self.write_register_type(*field_type);
+            }
+            self.write_u32(class.get_methods().len() as u32);
+            for (name, function) in class.get_methods() {
+                self.write_str(name);
+                self.write_path(&function.path);
+            }
+        }
+
+        let mut sub_modules = module.sub_modules.iter().collect::<Vec<_>>();
+        sub_modules.sort_by_key(|(name, _)| *name);
+        self.write_u32(sub_modules.len() as u32);
+        for (name, sub_module) in sub_modules {
+            path.push(name.clone());
+            self.write_module(sub_module, path);
+            path.pop();
+        }
+    }
+
+    fn write_register_type(&mut self, register_type: RegisterType) {
+        let tag = match register_type {
+            RegisterType::U8 => 0,
+            RegisterType::U16 => 1,
+            RegisterType::U32 => 2,
+            RegisterType::U64 => 3,
+            RegisterType::I8 => 4,
+            RegisterType::I16 => 5,
+            RegisterType::I32 => 6,
+            RegisterType::I64 => 7,
+            RegisterType::F32 => 8,
+            RegisterType::F64 => 9,
+            RegisterType::Reference => 10,
+        };
+        self.write_u8(tag);
+    }
+
+    fn write_target(&mut self, target: &Target) {
+        self.write_u32(target.0 as u32);
+        self.write_register_type(target.1);
+    }
+
+    fn write_source(&mut self, source: &Source) {
+        match source {
+            Source::Register(index, register_type) => {
+                self.write_u8(0);
+                self.write_u32(*index as u32);
+                self.write_register_type(*register_type);
+            }
+            Source::Immediate(immediate) => {
+                self.write_u8(1);
+                self.write_immediate(immediate);
+            }
+        }
+    }
+
+    fn write_immediate(&mut self, immediate: &Immediate) {
+        match immediate {
+            Immediate::U8(value) => {
+                self.write_u8(0);
+                self.write_u8(*value);
+            }
+            Immediate::U16(value) => {
+                self.write_u8(1);
+                self.write_u16(*value);
+            }
+            Immediate::U32(value) => {
+                self.write_u8(2);
+                self.write_u32(*value);
+            }
+            Immediate::U64(value) => {
+                self.write_u8(3);
+                self.write_u64(*value);
+            }
+            Immediate::I8(value) => {
+                self.write_u8(4);
+                self.write_u8(*value as u8);
+            }
+            Immediate::I16(value) => {
+                self.write_u8(5);
+                self.write_u16(*value as u16);
+            }
+            Immediate::I32(value) => {
+                self.write_u8(6);
+                self.write_u32(*value as u32);
+            }
+            Immediate::I64(value) => {
+                self.write_u8(7);
+                self.write_u64(*value as u64);
+            }
+            Immediate::F32(value) => {
+                self.write_u8(8);
+                self.write_u32(value.to_bits());
+            }
+            Immediate::F64(value) => {
+                self.write_u8(9);
+                self.write_u64(value.to_bits());
+            }
+        }
+    }
+
+    fn write_jump_target(&mut self, jump_target: &JumpTarget) {
+        match jump_target {
+            JumpTarget::Relative(offset) => {
+                self.write_u8(0);
+                self.write_u64(*offset as i64 as u64);
+            }
+            JumpTarget::Absolute(address) => {
+                self.write_u8(1);
+                self.write_u64(*address as u64);
+            }
+            JumpTarget::Label(label) => {
+                self.write_u8(2);
+                self.write_str(label);
+            }
+        }
+    }
+
+    fn write_condition(&mut self, condition: &Condition) {
+        let tag = match condition {
+            Condition::Always => 0,
+            Condition::Equal => 1,
+            Condition::NotEqual => 2,
+            Condition::GreaterThan => 3,
+            Condition::GreaterThanOrEqual => 4,
+            Condition::LessThan => 5,
+            Condition::LessThanOrEqual => 6,
+            Condition::Zero => 7,
+            Condition::NotZero => 8,
+            Condition::Carry => 9,
+            Condition::NotCarry => 10,
+            Condition::Negative => 11,
+            Condition::NotNegative => 12,
+            Condition::InContinuation => 13,
+            Condition::NotInContinuation => 14,
+        };
+        self.write_u8(tag);
+    }
+
+    fn write_comparison_type(&mut self, comparison_type: &ComparisonType) {
+        let tag = match comparison_type {
+            ComparisonType::Equal => 0,
+            ComparisonType::NotEqual => 1,
+            ComparisonType::GreaterThan => 2,
+            ComparisonType::GreaterThanOrEqual => 3,
+            ComparisonType::LessThan => 4,
+            ComparisonType::LessThanOrEqual => 5,
+        };
+        self.write_u8(tag);
+    }
+
+    fn write_call_target(&mut self, call_target: &CallTarget) {
+        match call_target {
+            CallTarget::Label(path) => {
+                self.write_u8(0);
+                self.write_path(&path.path);
+            }
+            CallTarget::Vtable(object, index) => {
+                self.write_u8(1);
+                self.write_source(object);
+                self.write_source(index);
+            }
+            CallTarget::Continuation(continuation) => {
+                self.write_u8(2);
+                self.write_source(continuation);
+            }
+            CallTarget::Closure(closure) => {
+                self.write_u8(3);
+                self.write_source(closure);
+            }
+        }
+    }
+
+    fn write_instruction(&mut self, instruction: &Instruction) {
+        self.write_u64(instruction.line as u64);
+        self.write_u64(instruction.column as u64);
+
+        use RealInstruction::*;
+        match &instruction.instruction {
+            Halt => self.write_u8(opcode::HALT),
+            NoOp => self.write_u8(opcode::NO_OP),
+            Load(target, source) => {
+                self.write_u8(opcode::LOAD);
+                self.write_target(target);
+                self.write_source(source);
+            }
+            Store(reference, offset, value) => {
+                self.write_u8(opcode::STORE);
+                self.write_source(reference);
+                self.write_source(offset);
+                self.write_source(value);
+            }
+            StackDeref(target, stack_level, offset) => {
+                self.write_u8(opcode::STACK_DEREF);
+                self.write_target(target);
+                self.write_source(stack_level);
+                self.write_source(offset);
+            }
+            StackStore(stack_level, offset, value) => {
+                self.write_u8(opcode::STACK_STORE);
+                self.write_source(stack_level);
+                self.write_source(offset);
+                self.write_source(value);
+            }
+            Add(target, source, can_wrap, use_carry) => {
+                self.write_u8(opcode::ADD);
+                self.write_target(target);
+                self.write_source(source);
+                self.write_bool(*can_wrap);
+                self.write_bool(*use_carry);
+            }
+            Sub(target, source, can_wrap, use_carry) => {
+                self.write_u8(opcode::SUB);
+                self.write_target(target);
+                self.write_source(source);
+                self.write_bool(*can_wrap);
+                self.write_bool(*use_carry);
+            }
+            Mul(target, source, can_wrap) => {
+                self.write_u8(opcode::MUL);
+                self.write_target(target);
+                self.write_source(source);
+                self.write_bool(*can_wrap);
+            }
+            Div(target, source, can_wrap) => {
+                self.write_u8(opcode::DIV);
+                self.write_target(target);
+                self.write_source(source);
+                self.write_bool(*can_wrap);
+            }
+            Mod(target, source, can_wrap) => {
+                self.write_u8(opcode::MOD);
+                self.write_target(target);
+                self.write_source(source);
+                self.write_bool(*can_wrap);
+            }
+            And(target, source) => {
+                self.write_u8(opcode::AND);
+                self.write_target(target);
+                self.write_source(source);
+            }
+            Or(target, source) => {
+                self.write_u8(opcode::OR);
+                self.write_target(target);
+                self.write_source(source);
+            }
+            Xor(target, source) => {
+                self.write_u8(opcode::XOR);
+                self.write_target(target);
+                self.write_source(source);
+            }
+            Not(target) => {
+                self.write_u8(opcode::NOT);
+                self.write_target(target);
+            }
+            ShiftLeft(target, source) => {
+                self.write_u8(opcode::SHIFT_LEFT);
+                self.write_target(target);
+                self.write_source(source);
+            }
+            ShiftRight(target, source) => {
+                self.write_u8(opcode::SHIFT_RIGHT);
+                self.write_target(target);
+                self.write_source(source);
+            }
+            Goto(jump_target, condition) => {
+                self.write_u8(opcode::GOTO);
+                self.write_jump_target(jump_target);
+                self.write_condition(condition);
+            }
+            Compare(target, source, comparison_type) => {
+                self.write_u8(opcode::COMPARE);
+                self.write_target(target);
+                self.write_source(source);
+                self.write_comparison_type(comparison_type);
+            }
+            Push(source) => {
+                self.write_u8(opcode::PUSH);
+                self.write_source(source);
+            }
+            Pop(target) => {
+                self.write_u8(opcode::POP);
+                self.write_target(target);
+            }
+            Call(call_target, condition) => {
+                self.write_u8(opcode::CALL);
+                self.write_call_target(call_target);
+                self.write_condition(condition);
+            }
+            Return(condition) => {
+                self.write_u8(opcode::RETURN);
+                self.write_condition(condition);
+            }
+            CreateContinuation(target, tag, one_shot) => {
+                self.write_u8(opcode::CREATE_CONTINUATION);
+                self.write_target(target);
+                self.write_str(tag);
+                self.write_bool(*one_shot);
+            }
+            CreateObject(target, class) => {
+                self.write_u8(opcode::CREATE_OBJECT);
+                self.write_target(target);
+                self.write_path(&class.path);
+            }
+            AccessObject(target, object, field) => {
+                self.write_u8(opcode::ACCESS_OBJECT);
+                self.write_target(target);
+                self.write_source(object);
+                self.write_source(field);
+            }
+            StoreObject(object, field, value) => {
+                self.write_u8(opcode::STORE_OBJECT);
+                self.write_source(object);
+                self.write_source(field);
+                self.write_source(value);
+            }
+            CreateList(target, size) => {
+                self.write_u8(opcode::CREATE_LIST);
+                self.write_target(target);
+                self.write_source(size);
+            }
+            ListLength(target, list) => {
+                self.write_u8(opcode::LIST_LENGTH);
+                self.write_target(target);
+                self.write_source(list);
+            }
+            ListAccess(target, list, index) => {
+                self.write_u8(opcode::LIST_ACCESS);
+                self.write_target(target);
+                self.write_source(list);
+                self.write_source(index);
+            }
+            ListStore(list, index, value) => {
+                self.write_u8(opcode::LIST_STORE);
+                self.write_source(list);
+                self.write_source(index);
+                self.write_source(value);
+            }
+            GetStringRef(target, path, index) => {
+                self.write_u8(opcode::GET_STRING_REF);
+                self.write_target(target);
+                self.write_path(&path.path);
+                self.write_u64(*index);
+            }
+            InstallHandler(effect, jump_target, target, one_shot) => {
+                self.write_u8(opcode::INSTALL_HANDLER);
+                self.write_str(effect);
+                self.write_jump_target(jump_target);
+                self.write_target(target);
+                self.write_bool(*one_shot);
+            }
+            RemoveHandler(effect) => {
+                self.write_u8(opcode::REMOVE_HANDLER);
+                self.write_str(effect);
+            }
+            Perform(effect) => {
+                self.write_u8(opcode::PERFORM);
+                self.write_str(effect);
+            }
+            PushPrompt(tag) => {
+                self.write_u8(opcode::PUSH_PROMPT);
+                self.write_str(tag);
+            }
+            PopPrompt(tag) => {
+                self.write_u8(opcode::POP_PROMPT);
+                self.write_str(tag);
+            }
+            CreateClosure(target, function, captures) => {
+                self.write_u8(opcode::CREATE_CLOSURE);
+                self.write_target(target);
+                self.write_path(&function.path);
+                self.write_u32(captures.len() as u32);
+                for capture in captures.iter() {
+                    self.write_source(capture);
+                }
+            }
+            LoadCapture(target, index) => {
+                self.write_u8(opcode::LOAD_CAPTURE);
+                self.write_target(target);
+                self.write_source(index);
+            }
+            AtomicCompareExchange(target, reference, index, expected, value) => {
+                self.write_u8(opcode::ATOMIC_COMPARE_EXCHANGE);
+                self.write_target(target);
+                self.write_source(reference);
+                self.write_source(index);
+                self.write_source(expected);
+                self.write_source(value);
+            }
+            AtomicFetchAdd(target, reference, index, value) => {
+                self.write_u8(opcode::ATOMIC_FETCH_ADD);
+                self.write_target(target);
+                self.write_source(reference);
+                self.write_source(index);
+                self.write_source(value);
+            }
+            AtomicExchange(target, reference, index, value) => {
+                self.write_u8(opcode::ATOMIC_EXCHANGE);
+                self.write_target(target);
+                self.write_source(reference);
+                self.write_source(index);
+                self.write_source(value);
+            }
+        }
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], LoadError> {
+        let end = self.offset.checked_add(length).ok_or(LoadError::UnexpectedEnd(self.offset))?;
+        let bytes = self.bytes.get(self.offset..end).ok_or(LoadError::UnexpectedEnd(self.offset))?;
+        self.offset = end;
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.read_bytes(N)?);
+        Ok(array)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, LoadError> {
+        Ok(u16::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, LoadError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_bool(&mut self) -> Result<bool, LoadError> {
+        let offset = self.offset;
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            tag => Err(LoadError::InvalidTag { what: "bool", tag, offset }),
+        }
+    }
+
+    fn read_str(&mut self) -> Result<Box<str>, LoadError> {
+        let length = self.read_u32()? as usize;
+        let offset = self.offset;
+        let bytes = self.read_bytes(length)?;
+        std::str::from_utf8(bytes)
+            .map(|string| string.into())
+            .map_err(|_| LoadError::InvalidUtf8(offset))
+    }
+
+    fn read_path(&mut self) -> Result<Box<[Box<str>]>, LoadError> {
+        let length = self.read_u32()? as usize;
+        let mut path = Vec::new();
+        for _ in 0..length {
+            path.push(self.read_str()?);
+        }
+        Ok(path.into_boxed_slice())
+    }
+
+    fn read_module(&mut self, natives: &NativeRegistry, depth: usize) -> Result<Module, LoadError> {
+        if depth > MAX_MODULE_DEPTH {
+            return Err(LoadError::NestingTooDeep(self.offset));
+        }
+        let module_name = self.read_str()?;
+
+        let string_count = self.read_u32()?;
+        let mut string_table = Vec::new();
+        for _ in 0..string_count {
+            string_table.push(self.read_str()?);
+        }
+
+        let mut module = Module::new(&module_name, HashMap::new(), string_table, HashMap::new());
+
+        let function_count = self.read_u32()?;
+        for _ in 0..function_count {
+            let name = self.read_str()?;
+            let offset = self.offset;
+            let function = match self.read_u8()? {
+                FUNCTION_BYTECODE => {
+                    let instruction_count = self.read_u32()?;
+                    let mut instructions = Vec::new();
+                    for _ in 0..instruction_count {
+                        instructions.push(self.read_instruction()?);
+                    }
+
+                    // A label may sit right after the last instruction, like a trailing label in assembly.
+                    let label_count = self.read_u32()?;
+                    let mut labels = LabelTable::new();
+                    for _ in 0..label_count {
+                        let label = self.read_str()?;
+                        let address = self.read_u64()? as usize;
+                        if address > instructions.len() {
+                            return Err(LoadError::LabelOutOfBounds { function: name, label, address });
+                        }
+                        labels.insert(label, address);
+                    }
+                    Function::ByteCode(Arc::from(instructions), Arc::new(labels))
+                }
+                FUNCTION_NATIVE => {
+                    let path = FunctionPath { path: self.read_path()? };
+                    let native = natives.get(&path).ok_or(LoadError::UnresolvedImport(path))?;
+                    Function::Native(native)
+                }
+                tag => return Err(LoadError::InvalidTag { what: "function", tag, offset }),
+            };
+            module.add_function(&name, function).map_err(|fault| match fault {
+                Fault::LabelNotFound(label) => LoadError::UnknownLabel { function: name.clone(), label },
+                fault => LoadError::InvalidFunction { function: name.clone(), fault },
+            })?;
+        }
+
+        let class_count = self.read_u32()?;
+        for _ in 0..class_count {
+            let name = self.read_str()?;
+            let super_class = if self.read_bool()? {
+                Some(FunctionPath { path: self.read_path()? })
+            } else {
+                None
+            };
+            let mut class = Class::new(&name, super_class);
+            let field_count = self.read_u32()?;
+            for _ in 0..field_count {
+                let field = self.read_str()?;
+                class.add_field(&field, self.read_register_type()?);
+            }
+            let method_count = self.read_u32()?;
+            for _ in 0..method_count {
+                let method = self.read_str()?;
+                class.add_method(&method, FunctionPath { path: self.read_path()? });
+            }
+            module.add_class(class);
+        }
+
+        let sub_module_count = self.read_u32()?;
+        for _ in 0..sub_module_count {
+            let sub_module = self.read_module(natives, depth + 1)?;
+            module.add_sub_module(sub_module);
+        }
+        Ok(module)
+    }
+
+    fn read_register_type(&mut self) -> Result<RegisterType, LoadError> {
+        let offset = self.offset;
+        let register_type = match self.read_u8()? {
+            0 => RegisterType::U8,
+            1 => RegisterType::U16,
+            2 => RegisterType::U32,
+            3 => RegisterType::U64,
+            4 => RegisterType::I8,
+            5 => RegisterType::I16,
+            6 => RegisterType::I32,
+            7 => RegisterType::I64,
+            8 => RegisterType::F32,
+            9 => RegisterType::F64,
+            10 => RegisterType::Reference,
+            tag => return Err(LoadError::InvalidTag { what: "register type", tag, offset }),
+        };
+        Ok(register_type)
+    }
+
+    fn read_target(&mut self) -> Result<Target, LoadError> {
+        let index = self.read_u32()? as usize;
+        Ok(Target(index, self.read_register_type()?))
+    }
+
+    fn read_source(&mut self) -> Result<Source, LoadError> {
+        let offset = self.offset;
+        match self.read_u8()? {
+            0 => {
+                let index = self.read_u32()? as usize;
+                Ok(Source::Register(index, self.read_register_type()?))
+            }
+            1 => Ok(Source::Immediate(self.read_immediate()?)),
+            tag => Err(LoadError::InvalidTag { what: "source", tag, offset }),
+        }
+    }
+
+    fn read_immediate(&mut self) -> Result<Immediate, LoadError> {
+        let offset = self.offset;
+        let immediate = match self.read_u8()? {
+            0 => Immediate::U8(self.read_u8()?),
+            1 => Immediate::U16(self.read_u16()?),
+            2 => Immediate::U32(self.read_u32()?),
+            3 => Immediate::U64(self.read_u64()?),
+            4 => Immediate::I8(self.read_u8()? as i8),
+            5 => Immediate::I16(self.read_u16()? as i16),
+            6 => Immediate::I32(self.read_u32()? as i32),
+            7 => Immediate::I64(self.read_u64()? as i64),
+            8 => Immediate::F32(f32::from_bits(self.read_u32()?)),
+            9 => Immediate::F64(f64::from_bits(self.read_u64()?)),
+            tag => return Err(LoadError::InvalidTag { what: "immediate", tag, offset }),
+        };
+        Ok(immediate)
+    }
+
+    fn read_jump_target(&mut self) -> Result<JumpTarget, LoadError> {
+        let offset = self.offset;
+        match self.read_u8()? {
+            0 => Ok(JumpTarget::Relative(self.read_u64()? as i64 as isize)),
+            1 => Ok(JumpTarget::Absolute(self.read_u64()? as usize)),
+            2 => Ok(JumpTarget::Label(self.read_str()?)),
+            tag => Err(LoadError::InvalidTag { what: "jump target", tag, offset }),
+        }
+    }
+
+    fn read_condition(&mut self) -> Result<Condition, LoadError> {
+        let offset = self.offset;
+        let condition = match self.read_u8()? {
+            0 => Condition::Always,
+            1 => Condition::Equal,
+            2 => Condition::NotEqual,
+            3 => Condition::GreaterThan,
+            4 => Condition::GreaterThanOrEqual,
+            5 => Condition::LessThan,
+            6 => Condition::LessThanOrEqual,
+            7 => Condition::Zero,
+            8 => Condition::NotZero,
+            9 => Condition::Carry,
+            10 => Condition::NotCarry,
+            11 => Condition::Negative,
+            12 => Condition::NotNegative,
+            13 => Condition::InContinuation,
+            14 => Condition::NotInContinuation,
+            tag => return Err(LoadError::InvalidTag { what: "condition", tag, offset }),
+        };
+        Ok(condition)
+    }
+
+    fn read_comparison_type(&mut self) -> Result<ComparisonType, LoadError> {
+        let offset = self.offset;
+        let comparison_type = match self.read_u8()? {
+            0 => ComparisonType::Equal,
+            1 => ComparisonType::NotEqual,
+            2 => ComparisonType::GreaterThan,
+            3 => ComparisonType::GreaterThanOrEqual,
+            4 => ComparisonType::LessThan,
+            5 => ComparisonType::LessThanOrEqual,
+            tag => return Err(LoadError::InvalidTag { what: "comparison type", tag, offset }),
+        };
+        Ok(comparison_type)
+    }
+
+    fn read_call_target(&mut self) -> Result<CallTarget, LoadError> {
+        let offset = self.offset;
+        match self.read_u8()? {
+            0 => Ok(CallTarget::Label(FunctionPath { path: self.read_path()? })),
+            1 => {
+                let object = self.read_source()?;
+                Ok(CallTarget::Vtable(object, self.read_source()?))
+            }
+            2 => Ok(CallTarget::Continuation(self.read_source()?)),
+            3 => Ok(CallTarget::Closure(self.read_source()?)),
+            tag => Err(LoadError::InvalidTag { what: "call target", tag, offset }),
+        }
+    }
+
+    fn read_instruction(&mut self) -> Result<Instruction, LoadError> {
+        let line = self.read_u64()? as usize;
+        let column = self.read_u64()? as usize;
+
+        let offset = self.offset;
+        use RealInstruction::*;
+        let instruction = match self.read_u8()? {
+            opcode::HALT => Halt,
+            opcode::NO_OP => NoOp,
+            opcode::LOAD => Load(self.read_target()?, self.read_source()?),
+            opcode::STORE => Store(self.read_source()?, self.read_source()?, self.read_source()?),
+            opcode::STACK_DEREF => StackDeref(self.read_target()?, self.read_source()?, self.read_source()?),
+            opcode::STACK_STORE => StackStore(self.read_source()?, self.read_source()?, self.read_source()?),
+            opcode::ADD => Add(self.read_target()?, self.read_source()?, self.read_bool()?, self.read_bool()?),
+            opcode::SUB => Sub(self.read_target()?, self.read_source()?, self.read_bool()?, self.read_bool()?),
+            opcode::MUL => Mul(self.read_target()?, self.read_source()?, self.read_bool()?),
+            opcode::DIV => Div(self.read_target()?, self.read_source()?, self.read_bool()?),
+            opcode::MOD => Mod(self.read_target()?, self.read_source()?, self.read_bool()?),
+            opcode::AND => And(self.read_target()?, self.read_source()?),
+            opcode::OR => Or(self.read_target()?, self.read_source()?),
+            opcode::XOR => Xor(self.read_target()?, self.read_source()?),
+            opcode::NOT => Not(self.read_target()?),
+            opcode::SHIFT_LEFT => ShiftLeft(self.read_target()?, self.read_source()?),
+            opcode::SHIFT_RIGHT => ShiftRight(self.read_target()?, self.read_source()?),
+            opcode::GOTO => Goto(self.read_jump_target()?, self.read_condition()?),
+            opcode::COMPARE => Compare(self.read_target()?, self.read_source()?, self.read_comparison_type()?),
+            opcode::PUSH => Push(self.read_source()?),
+            opcode::POP => Pop(self.read_target()?),
+            opcode::CALL => Call(self.read_call_target()?, self.read_condition()?),
+            opcode::RETURN => Return(self.read_condition()?),
+            opcode::CREATE_CONTINUATION => {
+                let target = self.read_target()?;
+                let tag = self.read_str()?;
+                CreateContinuation(target, tag, self.read_bool()?)
+            }
+            opcode::CREATE_OBJECT => CreateObject(self.read_target()?, FunctionPath { path: self.read_path()? }),
+            opcode::ACCESS_OBJECT => AccessObject(self.read_target()?, self.read_source()?, self.read_source()?),
+            opcode::STORE_OBJECT => StoreObject(self.read_source()?, self.read_source()?, self.read_source()?),
+            opcode::CREATE_LIST => CreateList(self.read_target()?, self.read_source()?),
+            opcode::LIST_LENGTH => ListLength(self.read_target()?, self.read_source()?),
+            opcode::LIST_ACCESS => ListAccess(self.read_target()?, self.read_source()?, self.read_source()?),
+            opcode::LIST_STORE => ListStore(self.read_source()?, self.read_source()?, self.read_source()?),
+            opcode::GET_STRING_REF => {
+                let target = self.read_target()?;
+                let path = StringTablePath { path: self.read_path()? };
+                GetStringRef(target, path, self.read_u64()?)
+            }
+            opcode::INSTALL_HANDLER => {
+                let effect = self.read_str()?;
+                let jump_target = self.read_jump_target()?;
+                let target = self.read_target()?;
+                InstallHandler(effect, jump_target, target, self.read_bool()?)
+            }
+            opcode::REMOVE_HANDLER => RemoveHandler(self.read_str()?),
+            opcode::PERFORM => Perform(self.read_str()?),
+            opcode::PUSH_PROMPT => PushPrompt(self.read_str()?),
+            opcode::POP_PROMPT => PopPrompt(self.read_str()?),
+            opcode::CREATE_CLOSURE => {
+                let target = self.read_target()?;
+                let function = FunctionPath { path: self.read_path()? };
+                let capture_count = self.read_u32()?;
+                let mut captures = Vec::new();
+                for _ in 0..capture_count {
+                    captures.push(self.read_source()?);
+                }
+                CreateClosure(target, function, captures)
+            }
+            opcode::LOAD_CAPTURE => LoadCapture(self.read_target()?, self.read_source()?),
+            opcode::ATOMIC_COMPARE_EXCHANGE => AtomicCompareExchange(self.read_target()?, self.read_source()?, self.read_source()?, self.read_source()?, self.read_source()?),
+            opcode::ATOMIC_FETCH_ADD => AtomicFetchAdd(self.read_target()?, self.read_source()?, self.read_source()?, self.read_source()?),
+            opcode::ATOMIC_EXCHANGE => AtomicExchange(self.read_target()?, self.read_source()?, self.read_source()?, self.read_source()?),
+            tag => return Err(LoadError::InvalidTag { what: "opcode", tag, offset }),
+        };
+        Ok(Instruction::new(instruction, line, column))
+    }
+}
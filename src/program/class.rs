@@ -0,0 +1,51 @@
+use crate::instruction::RegisterType;
+use crate::program::function::FunctionPath;
+
+
+/// A class defined in a module.
+/// Fields are addressed by index and the fields of the super class come first.
+/// Methods are addressed by slot, the slots of the super class come first and
+/// a method with the name of an inherited one overrides it instead of taking a new slot.
+#[derive(Debug, Clone)]
+pub struct Class {
+    name: Box<str>,
+    super_class: Option<FunctionPath>,
+    fields: Vec<(Box<str>, RegisterType)>,
+    /// Method names and the functions that implement them.
+    methods: Vec<(Box<str>, FunctionPath)>,
+}
+
+impl Class {
+    pub fn new(name: &str, super_class: Option<FunctionPath>) -> Self {
+        Class {
+            name: name.into(),
+            super_class,
+            fields: Vec::new(),
+            methods: Vec::new(),
+        }
+    }
+
+    pub fn add_field(&mut self, name: &str, field_type: RegisterType) {
+        self.fields.push((name.into(), field_type));
+    }
+
+    pub fn add_method(&mut self, name: &str, function: FunctionPath) {
+        self.methods.push((name.into(), function));
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_super_class(&self) -> Option<&FunctionPath> {
+        self.super_class.as_ref()
+    }
+
+    pub fn get_fields(&self) -> &[(Box<str>, RegisterType)] {
+        &self.fields
+    }
+
+    pub fn get_methods(&self) -> &[(Box<str>, FunctionPath)] {
+        &self.methods
+    }
+}
@@ -1,19 +1,20 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Display};
 use std::sync::Arc;
-use crate::instruction::Instruction;
-use crate::machine::core::Core;
+use crate::instruction::{Instruction, JumpTarget};
 use crate::machine::{Fault, InstructionResult};
-use crate::memory::Memory;
+use crate::machine::native::NativeContext;
 use crate::program::Module;
-use crate::stack_frame::delimited_continuation::ContinuationStore;
 use crate::stack_frame::frame::Frame;
-use crate::stack_frame::StackFrame;
 
 
-pub type NativeFunction = fn(&mut Core, Arc<Module>, &mut Vec<*mut dyn StackFrame>, Memory, &mut ContinuationStore) -> Result<InstructionResult,Fault>;
+/// Maps label names to the index of the instruction they mark.
+pub type LabelTable = HashMap<Box<str>, usize>;
 
-#[derive(Clone)]
+/// A function implemented by the host, it can be a closure that captures host state.
+pub type NativeFunction = Arc<dyn Fn(&mut NativeContext) -> Result<InstructionResult,Fault> + Send + Sync>;
+
+#[derive(Clone, Eq, Hash, PartialEq)]
 pub struct FunctionPath {
     pub(crate) path: Box<[Box<str>]>
 }
@@ -48,14 +49,15 @@ impl Into<FunctionPath> for &str {
 
 #[derive(Clone)]
 pub enum Function {
-    ByteCode(Arc<[Instruction]>),
+    /// A bytecode function and its label table.
+    ByteCode(Arc<[Instruction]>, Arc<LabelTable>),
     Native(NativeFunction),
 }
 
 impl Display for Function {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Function::ByteCode(_) => write!(f, "ByteCode Function"),
+            Function::ByteCode(..) => write!(f, "ByteCode Function"),
             Function::Native(_) => write!(f, "Native Function"),
         }
     }
@@ -70,10 +72,85 @@ impl Debug for Function {
 impl Function {
     pub fn get_instructions(&self) -> Arc<[Instruction]> {
         match self {
-            Function::ByteCode(instructions) => instructions.clone(),
+            Function::ByteCode(instructions, _) => instructions.clone(),
             Function::Native(_) => Arc::new([]),
         }
     }
+
+    /// Replaces every jump to a label with an absolute jump to the instruction the label marks.
+    /// Fails if a jump refers to a label that is not in the label table.
+    pub fn resolve_labels(self) -> Result<Function, Fault> {
+        let (instructions, labels) = match self {
+            Function::ByteCode(instructions, labels) => (instructions, labels),
+            Function::Native(_) => return Ok(self),
+        };
+
+        let has_labels = instructions.iter().any(|instruction| instruction.get_label().is_some());
+        if !has_labels {
+            return Ok(Function::ByteCode(instructions, labels));
+        }
+
+        let mut resolved = Vec::with_capacity(instructions.len());
+        for instruction in instructions.iter() {
+            let mut instruction = instruction.clone();
+            if let Some(label) = instruction.get_label() {
+                let address = *labels.get(label).ok_or_else(|| Fault::LabelNotFound(label.into()))?;
+                instruction.set_jump_target(JumpTarget::Absolute(address));
+            }
+            resolved.push(instruction);
+        }
+        Ok(Function::ByteCode(Arc::from(resolved), labels))
+    }
 }
 
 
+
+
+/// Native functions that can be linked into a module by path.
+/// Used to resolve the native imports of loaded bytecode.
+#[derive(Default)]
+pub struct NativeRegistry {
+    natives: HashMap<Box<str>, NativeFunction>,
+}
+
+impl NativeRegistry {
+    pub fn new() -> Self {
+        NativeRegistry {
+            natives: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, path: &str, function: NativeFunction) {
+        self.natives.insert(path.into(), function);
+    }
+
+    /// Registers every native function in `module` and its sub modules.
+    /// Paths start with the name of `module` unless it is the unnamed root module.
+    pub fn register_module(&mut self, module: &Module) {
+        let mut path = Vec::new();
+        if !module.get_module_name().is_empty() {
+            path.push(module.get_module_name().into());
+        }
+        self.register_module_helper(module, &mut path);
+    }
+
+    fn register_module_helper(&mut self, module: &Module, path: &mut Vec<Box<str>>) {
+        for (name, function) in module.get_functions().iter() {
+            if let Function::Native(native) = function {
+                path.push(name.clone());
+                self.register(&path.join("::"), native.clone());
+                path.pop();
+            }
+        }
+
+        for (name, sub_module) in module.get_sub_modules().iter() {
+            path.push(name.clone());
+            self.register_module_helper(sub_module, path);
+            path.pop();
+        }
+    }
+
+    pub fn get(&self, path: &FunctionPath) -> Option<NativeFunction> {
+        self.natives.get(path.to_string().as_str()).cloned()
+    }
+}
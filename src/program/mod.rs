@@ -2,9 +2,18 @@ use std::collections::HashMap;
 use std::fmt::{Debug, Display, format};
 use std::hash::Hash;
 use crate::memory::Memory;
-use crate::program::function::{Function, FunctionPath};
+use std::sync::Arc;
+use crate::machine::{Fault, InstructionResult};
+use crate::machine::native::NativeContext;
+use crate::program::class::Class;
+use crate::program::function::{Function, FunctionPath, NativeFunction};
+use crate::value::object::{ClassId, Method};
+use crate::value::Value;
 
 pub mod function;
+pub mod class;
+pub mod bytecode;
+pub mod verifier;
 
 
 #[derive(Clone,Eq, Hash, PartialEq)]
@@ -67,15 +76,22 @@ pub struct Module {
     functions: HashMap<Box<str>, Function>,
     string_table: Vec<Box<str>>,
     sub_modules: HashMap<Box<str>, Module>,
+    classes: HashMap<Box<str>, Class>,
 }
 
 impl Module {
+    /// The labels of `functions` are resolved like `add_function` does.
+    /// A function that jumps to a missing label is kept as it is, for the verifier to report.
     pub fn new(module_name: &str, functions: HashMap<Box<str>, Function>, string_table: Vec<Box<str>>, sub_modules: HashMap<Box<str>, Module>) -> Self {
+        let functions = functions.into_iter()
+            .map(|(name, function)| (name, function.clone().resolve_labels().unwrap_or(function)))
+            .collect();
         Module {
             module_name: module_name.to_string().into(),
             functions,
             string_table,
             sub_modules,
+            classes: HashMap::new(),
         }
     }
 
@@ -91,8 +107,20 @@ impl Module {
         }
     }
 
-    pub fn add_function(&mut self, path: &str, function: Function) {
+    /// Adds a function to this module, resolving the labels of bytecode functions.
+    pub fn add_function(&mut self, path: &str, function: Function) -> Result<(), Fault> {
+        let function = function.resolve_labels()?;
         self.functions.insert(path.to_string().into(), function);
+        Ok(())
+    }
+
+    pub fn add_native_function(&mut self, path: &str, function: impl Fn(&mut NativeContext) -> Result<InstructionResult, Fault> + Send + Sync + 'static) {
+        self.add_shared_native_function(path, Arc::new(function));
+    }
+
+    /// Adds a native function that is already shared, such as one from a `NativeRegistry`.
+    pub fn add_shared_native_function(&mut self, path: &str, function: NativeFunction) {
+        self.functions.insert(path.to_string().into(), Function::Native(function));
     }
 
     pub fn get_string(&self, path: &StringTablePath, index: u64) -> Option<&str> {
@@ -106,7 +134,7 @@ impl Module {
     pub fn add_string(&mut self, path: &StringTablePath, string: &str) -> u64 {
         let mut module = self;
         for part in path.path.iter().take(path.path.len().saturating_sub(1)) {
-            module = module.sub_modules.entry(part.clone()).or_insert_with(|| Module::default());
+            module = module.sub_modules.entry(part.clone()).or_default();
         }
         let index = module.string_table.len() as u64;
         module.string_table.push(string.to_string().into_boxed_str());
@@ -138,9 +166,122 @@ impl Module {
         }
     }
 
+    pub fn get_module_name(&self) -> &str {
+        &self.module_name
+    }
+
+    pub fn get_functions(&self) -> &HashMap<Box<str>, Function> {
+        &self.functions
+    }
+
+    pub fn get_string_table(&self) -> &[Box<str>] {
+        &self.string_table
+    }
+
+    pub fn get_sub_modules(&self) -> &HashMap<Box<str>, Module> {
+        &self.sub_modules
+    }
+
     pub fn add_sub_module(&mut self, module: Module) {
         self.sub_modules.insert(module.module_name.clone(), module);
     }
+
+    pub fn add_class(&mut self, class: Class) {
+        self.classes.insert(class.get_name().into(), class);
+    }
+
+    pub fn get_class(&self, path: &FunctionPath) -> Option<&Class> {
+        let (name, modules) = path.path.split_last()?;
+        let mut module = self;
+        for part in modules.iter() {
+            module = module.sub_modules.get(part)?;
+        }
+        module.classes.get(name)
+    }
+
+    pub fn get_classes(&self) -> &HashMap<Box<str>, Class> {
+        &self.classes
+    }
+
+    /// Registers every class of this module and its sub modules with the memory.
+    /// Super classes and methods are resolved from this module, so it should be the root module.
+    pub fn add_classes_to_memory(&self, memory: &mut Memory) -> Result<(), Fault> {
+        let mut paths = Vec::new();
+        self.get_class_paths(&mut Vec::new(), &mut paths);
+        for path in paths.iter() {
+            self.add_class_to_memory(path, memory, &mut Vec::new())?;
+        }
+        Ok(())
+    }
+
+    fn get_class_paths(&self, path: &mut Vec<Box<str>>, paths: &mut Vec<FunctionPath>) {
+        for name in self.classes.keys() {
+            path.push(name.clone());
+            paths.push(FunctionPath { path: path.clone().into_boxed_slice() });
+            path.pop();
+        }
+        for (name, module) in self.sub_modules.iter() {
+            path.push(name.clone());
+            module.get_class_paths(path, paths);
+            path.pop();
+        }
+    }
+
+    fn add_class_to_memory(&self, path: &FunctionPath, memory: &mut Memory, subclasses: &mut Vec<FunctionPath>) -> Result<ClassId, Fault> {
+        if let Some(class_id) = memory.get_class_id(path)? {
+            return Ok(class_id);
+        }
+        if subclasses.contains(path) {
+            return Err(Fault::InvalidOperation(format!("class {} inherits from itself", path)));
+        }
+        let class = self.get_class(path).ok_or(Fault::ClassNotFound(path.clone()))?;
+
+        let super_class = match class.get_super_class() {
+            Some(super_class) => {
+                subclasses.push(path.clone());
+                let super_class = self.add_class_to_memory(super_class, memory, subclasses)?;
+                subclasses.pop();
+                Some(super_class)
+            }
+            None => None,
+        };
+        let fields = class.get_fields().iter()
+            .map(|(_, field_type)| Value::new((*field_type).into()))
+            .collect::<Vec<_>>();
+        let mut slots = match class.get_super_class() {
+            Some(super_class) => self.get_method_slots(super_class)?,
+            None => Vec::new(),
+        };
+        let mut methods: Vec<Option<Method>> = vec![None; slots.len()];
+        for (name, function) in class.get_methods() {
+            let implementation = self.get_function(function).ok_or(Fault::FunctionNotFound(function.clone()))?;
+            let method = Some((function.clone(), implementation));
+            match slots.iter().position(|slot| slot == name) {
+                Some(slot) => methods[slot] = method,
+                None => {
+                    slots.push(name.clone());
+                    methods.push(method);
+                }
+            }
+        }
+
+        memory.allocate_class(path, super_class, fields, methods)
+    }
+
+    /// The method names of a class in slot order.
+    fn get_method_slots(&self, path: &FunctionPath) -> Result<Vec<Box<str>>, Fault> {
+        let class = self.get_class(path).ok_or(Fault::ClassNotFound(path.clone()))?;
+        let mut slots = match class.get_super_class() {
+            Some(super_class) => self.get_method_slots(super_class)?,
+            None => Vec::new(),
+        };
+        for (name, _) in class.get_methods() {
+            if !slots.contains(name) {
+                slots.push(name.clone());
+            }
+        }
+        Ok(slots)
+    }
 }
 
 impl Default for Module {
@@ -150,6 +291,7 @@ impl Default for Module {
             functions: HashMap::new(),
             string_table: Vec::new(),
             sub_modules: HashMap::new(),
+            classes: HashMap::new(),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,231 @@
+use std::fmt::Display;
+use crate::instruction::{CallTarget, Condition, Instruction, JumpTarget, RealInstruction, Source};
+use crate::program::function::{Function, FunctionPath};
+use crate::program::{Module, StringTablePath};
+use crate::stack_frame::REGISTER_COUNT;
+
+
+/// A problem found in a bytecode function before it is run.
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub function: FunctionPath,
+    /// The index of the offending instruction.
+    pub index: usize,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{}] at {}:{}: {}", self.function, self.index, self.line, self.column, self.message)
+    }
+}
+
+/// Checks every bytecode function in a module and its sub modules.
+/// Call targets and string references are resolved from `module`, so it should be the root module.
+pub fn verify_module(module: &Module) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    verify_module_helper(module, module, &mut Vec::new(), &mut diagnostics);
+    diagnostics
+}
+
+fn verify_module_helper(root: &Module, module: &Module, path: &mut Vec<Box<str>>, diagnostics: &mut Vec<Diagnostic>) {
+    let mut functions = module.get_functions().iter().collect::<Vec<_>>();
+    functions.sort_by_key(|(name, _)| *name);
+    for (name, function) in functions {
+        if let Function::ByteCode(instructions, _) = function {
+            path.push(name.clone());
+            let function_path = FunctionPath { path: path.clone().into_boxed_slice() };
+            path.pop();
+            diagnostics.extend(verify_function(root, &function_path, instructions));
+        }
+    }
+
+    let mut sub_modules = module.get_sub_modules().iter().collect::<Vec<_>>();
+    sub_modules.sort_by_key(|(name, _)| *name);
+    for (name, sub_module) in sub_modules {
+        path.push(name.clone());
+        verify_module_helper(root, sub_module, path, diagnostics);
+        path.pop();
+    }
+}
+
+/// Checks a single bytecode function against the root module it will run in.
+pub fn verify_function(root: &Module, function: &FunctionPath, instructions: &[Instruction]) -> Vec<Diagnostic> {
+    let mut verifier = Verifier {
+        root,
+        function,
+        instructions,
+        diagnostics: Vec::new(),
+    };
+    verifier.verify();
+    verifier.diagnostics
+}
+
+
+struct Verifier<'a> {
+    root: &'a Module,
+    function: &'a FunctionPath,
+    instructions: &'a [Instruction],
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Verifier<'a> {
+    fn report(&mut self, index: usize, message: String) {
+        let (line, column) = match self.instructions.get(index) {
+            Some(instruction) => (instruction.get_line(), instruction.get_column()),
+            None => (0, 0),
+        };
+        self.diagnostics.push(Diagnostic {
+            function: self.function.clone(),
+            index,
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn verify(&mut self) {
+        if self.instructions.is_empty() {
+            self.report(0, "function has no instructions".to_string());
+            return;
+        }
+
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            self.verify_registers(index, &instruction.instruction);
+            if let Some(jump_target) = instruction.get_jump_target() {
+                self.verify_jump_target(index, jump_target);
+            }
+            self.verify_references(index, &instruction.instruction);
+        }
+        self.verify_control_flow();
+    }
+
+    fn verify_registers(&mut self, index: usize, instruction: &RealInstruction) {
+        for register in registers(instruction) {
+            if register >= REGISTER_COUNT {
+                self.report(index, format!("register r{} does not exist, there are {} registers", register, REGISTER_COUNT));
+            }
+        }
+    }
+
+    fn verify_jump_target(&mut self, index: usize, jump_target: &JumpTarget) {
+        if let JumpTarget::Label(label) = jump_target {
+            self.report(index, format!("unresolved label {}", label));
+        } else if self.jump_address(index, jump_target).is_none() {
+            self.report(index, format!("jump target {:?} is outside of the function", jump_target));
+        }
+    }
+
+    fn verify_references(&mut self, index: usize, instruction: &RealInstruction) {
+        match instruction {
+            RealInstruction::Call(CallTarget::Label(path), _)
+                if path.path.is_empty() || self.root.get_function(path).is_none() => {
+                self.report(index, format!("call target {} does not exist", path));
+            },
+            RealInstruction::CreateClosure(_, function, _)
+                if function.path.is_empty() || self.root.get_function(function).is_none() => {
+                self.report(index, format!("closure function {} does not exist", function));
+            },
+            RealInstruction::CreateObject(_, class) if self.root.get_class(class).is_none() => {
+                self.report(index, format!("class {} does not exist", class));
+            },
+            RealInstruction::GetStringRef(_, path, string_index)
+                if self.root.get_string(path, *string_index).is_none() => {
+                self.report(index, format!("string {} does not exist", string_path(path, *string_index)));
+            },
+            _ => {},
+        }
+    }
+
+    /// The instruction a jump lands on, if it is inside the function.
+    fn jump_address(&self, index: usize, jump_target: &JumpTarget) -> Option<usize> {
+        let address = match jump_target {
+            JumpTarget::Relative(offset) => index.checked_add_signed(*offset)?,
+            JumpTarget::Absolute(address) => *address,
+            JumpTarget::Label(_) => return None,
+        };
+        if address < self.instructions.len() {
+            Some(address)
+        } else {
+            None
+        }
+    }
+
+    /// Walks every reachable instruction and reports those that let execution run past the end of the function.
+    fn verify_control_flow(&mut self) {
+        let mut visited = vec![false; self.instructions.len()];
+        let mut pending = vec![0];
+        let mut falls_off = Vec::new();
+
+        while let Some(index) = pending.pop() {
+            if visited[index] {
+                continue;
+            }
+            visited[index] = true;
+
+            let (jump, falls_through) = match &self.instructions[index].instruction {
+                RealInstruction::Halt | RealInstruction::Return(Condition::Always) => (None, false),
+                RealInstruction::Goto(jump_target, Condition::Always) => (Some(jump_target), false),
+                RealInstruction::Goto(jump_target, _) => (Some(jump_target), true),
+                RealInstruction::InstallHandler(_, jump_target, _, _) => (Some(jump_target), true),
+                _ => (None, true),
+            };
+
+            if let Some(address) = jump.and_then(|jump_target| self.jump_address(index, jump_target)) {
+                pending.push(address);
+            }
+            if falls_through {
+                if index + 1 < self.instructions.len() {
+                    pending.push(index + 1);
+                } else {
+                    falls_off.push(index);
+                }
+            }
+        }
+
+        for index in falls_off {
+            self.report(index, "execution can run past the end of the function without a return or halt".to_string());
+        }
+    }
+}
+
+fn string_path(path: &StringTablePath, index: u64) -> String {
+    format!("{}{}", path, index)
+}
+
+/// Every register index an instruction reads or writes.
+fn registers(instruction: &RealInstruction) -> Vec<usize> {
+    let mut registers = Vec::new();
+    use RealInstruction::*;
+    match instruction {
+        Load(t, _) | Add(t, _, _, _) | Sub(t, _, _, _) | Mul(t, _, _) | Div(t, _, _) | Mod(t, _, _)
+        | And(t, _) | Or(t, _) | Xor(t, _) | Not(t) | ShiftLeft(t, _) | ShiftRight(t, _)
+        | Compare(t, _, _) | Pop(t) | CreateContinuation(t, _, _) | CreateObject(t, _) | AccessObject(t, _, _)
+        | CreateList(t, _) | ListLength(t, _) | ListAccess(t, _, _) | StackDeref(t, _, _)
+        | GetStringRef(t, _, _) | InstallHandler(_, _, t, _) | CreateClosure(t, _, _) | LoadCapture(t, _)
+        | AtomicCompareExchange(t, _, _, _, _) | AtomicFetchAdd(t, _, _, _) | AtomicExchange(t, _, _, _) => registers.push(t.0),
+        _ => {},
+    }
+
+    let sources: Vec<&Source> = match instruction {
+        Load(_, s) | Add(_, s, _, _) | Sub(_, s, _, _) | Mul(_, s, _) | Div(_, s, _) | Mod(_, s, _)
+        | And(_, s) | Or(_, s) | Xor(_, s) | ShiftLeft(_, s) | ShiftRight(_, s) | Compare(_, s, _)
+        | Push(s) | CreateList(_, s) | ListLength(_, s) | LoadCapture(_, s) => vec![s],
+        CreateClosure(_, _, captures) => captures.iter().collect(),
+        AccessObject(_, a, b) | ListAccess(_, a, b) | StackDeref(_, a, b) => vec![a, b],
+        Store(a, b, c) | StackStore(a, b, c) | ListStore(a, b, c) | StoreObject(a, b, c)
+        | AtomicFetchAdd(_, a, b, c) | AtomicExchange(_, a, b, c) => vec![a, b, c],
+        AtomicCompareExchange(_, a, b, c, d) => vec![a, b, c, d],
+        Call(CallTarget::Vtable(a, b), _) => vec![a, b],
+        Call(CallTarget::Continuation(s), _) | Call(CallTarget::Closure(s), _) => vec![s],
+        _ => Vec::new(),
+    };
+    for source in sources {
+        if let Source::Register(index, _) = source {
+            registers.push(*index);
+        }
+    }
+    registers
+}
@@ -0,0 +1,389 @@
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::Arc;
+use crate::assembly::assembler::{assemble, AssemblyError};
+use crate::assembly::load_file;
+use crate::backtrace::{BacktraceEntry, BacktraceInfo};
+use crate::instruction::{Instruction, RealInstruction, RegisterType, Target};
+use crate::machine::core::Core;
+use crate::machine::native::ARGUMENT_REGISTER;
+use crate::machine::{run_frame, run_function, Fault, InstructionResult};
+use crate::memory::{Memory, MemoryObject};
+use crate::native_lib::get_std_module;
+use crate::program::function::{FunctionPath, NativeRegistry};
+use crate::program::verifier::verify_function;
+use crate::program::{Module, StringTablePath};
+use crate::stack_frame::delimited_continuation::ContinuationStore;
+use crate::stack_frame::frame::Frame;
+use crate::stack_frame::REGISTER_COUNT;
+use crate::value::sync::SyncObject;
+use crate::value::Value;
+use crate::vm::VmError;
+
+
+/// The name of the scratch frame.
+/// Assembly can't name it, so it doesn't clash with the loaded program.
+const REPL_NAME: &str = "<repl>";
+
+/// What `:help` prints.
+pub const HELP: &str = "\
+Each line is an instruction that is executed right away, registers and the frame's stack carry over.
+The registers it changed and the flags are printed after it.
+
+  :block              Starts a block of instructions that is executed once it is closed with :end.
+  :regs               Prints every register and the flags.
+  :heap               Prints every object on the heap.
+  :load <file>        Loads a module, functions and classes are looked up in it, and resets.
+  :call <path> [arguments...]
+                      Calls a function with arguments like 5u64, -1i32, 1.5f64 or \"some text\" in r8 onwards.
+  :reset              Starts over with empty registers, stack and heap.
+  :help               Prints this.
+  :quit               Leaves, as does the end of input.";
+
+/// Why a block couldn't be run.
+#[derive(Debug)]
+pub enum ReplError {
+    Assembly(AssemblyError),
+    Fault(VmError),
+}
+
+impl Display for ReplError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplError::Assembly(error) => write!(f, "{}", error),
+            ReplError::Fault(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl std::error::Error for ReplError {}
+
+impl From<Fault> for ReplError {
+    fn from(fault: Fault) -> Self {
+        ReplError::Fault(fault.into())
+    }
+}
+
+
+/// A live core, memory and scratch frame that assembly is executed on a block at a time.
+/// Registers, the frame's stack and the heap carry over from one block to the next.
+pub struct Repl {
+    program: Arc<Module>,
+    core: Core,
+    memory: Memory,
+    frame: Frame,
+    continuation_store: ContinuationStore,
+}
+
+impl Repl {
+    /// A session with only the standard library loaded.
+    pub fn new() -> Result<Self, Fault> {
+        let mut program = Module::default();
+        program.add_sub_module(get_std_module());
+        let mut repl = Repl {
+            program: Arc::new(program),
+            core: Core::default(),
+            memory: Memory::new(),
+            frame: Frame::new(REPL_NAME.into(), Arc::new([])),
+            continuation_store: ContinuationStore::new(),
+        };
+        repl.reset()?;
+        Ok(repl)
+    }
+
+    pub fn get_core(&self) -> &Core {
+        &self.core
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn get_module(&self) -> &Arc<Module> {
+        &self.program
+    }
+
+    /// Starts over with a new core, memory and scratch frame, the program stays loaded.
+    pub fn reset(&mut self) -> Result<(), Fault> {
+        let mut memory = Memory::new();
+        self.program.add_strings_to_memory(&mut memory);
+        self.program.add_classes_to_memory(&mut memory)?;
+        self.memory = memory;
+        self.core = Core::default();
+        self.frame = Frame::new(REPL_NAME.into(), Arc::new([]));
+        self.continuation_store = ContinuationStore::new();
+        Ok(())
+    }
+
+    /// Makes `module` the program that blocks can call into, with `std` added, and resets.
+    pub fn load_module(&mut self, mut module: Module) -> Result<(), Fault> {
+        module.add_sub_module(get_std_module());
+        self.program = Arc::new(module);
+        self.reset()
+    }
+
+    /// Assembles the instructions in `source` and executes them in the scratch frame
+    /// until they run past the end, return or halt.
+    /// Returns the registers whose value changed.
+    pub fn execute(&mut self, source: &str) -> Result<Vec<usize>, ReplError> {
+        let instructions = self.assemble_block(source)?;
+        let before = self.core.registers.iter().map(|register| format!("{:?}", register.value)).collect::<Vec<_>>();
+
+        self.frame.set_instructions(instructions);
+        let mut frames = Vec::new();
+        let mut backtrace = BacktraceInfo::new();
+        backtrace.push(BacktraceEntry::new(REPL_NAME.into(), None, None));
+        let result = run_frame(&mut self.core, &mut self.frame, self.program.clone(), &mut frames, self.memory.clone(), &mut self.continuation_store, &mut backtrace);
+        match result {
+            Ok(InstructionResult::Unwind(effect_name, _)) => {
+                return Err(ReplError::Fault(VmError::new(Fault::UnhandledEffect(effect_name), backtrace)));
+            }
+            Ok(_) => {}
+            Err(fault) => return Err(ReplError::Fault(VmError::new(fault, backtrace))),
+        }
+
+        let changed = (0..REGISTER_COUNT)
+            .filter(|index| format!("{:?}", self.core.registers[*index].value) != before[*index])
+            .collect();
+        Ok(changed)
+    }
+
+    /// Calls the function at `path` with `arguments` in r8 onwards as the root fiber of the core,
+    /// so fibers it spawns run too, and returns what it left in r0.
+    pub fn call(&mut self, path: &str, arguments: Vec<Value>) -> Result<Value, VmError> {
+        let path: FunctionPath = path.into();
+        let function = self.program.get_function(&path).ok_or_else(|| Fault::FunctionNotFound(path.clone()))?;
+        if ARGUMENT_REGISTER + arguments.len() > REGISTER_COUNT {
+            return Err(Fault::InvalidRegister.into());
+        }
+        for (index, argument) in arguments.into_iter().enumerate() {
+            self.core.set_value(&Target(ARGUMENT_REGISTER + index, RegisterType::U64), argument);
+        }
+
+        let frame = Frame::new(path, function.get_instructions());
+        let mut backtrace = BacktraceInfo::new();
+        run_function(&mut self.core, function, frame, self.program.clone(), self.memory.clone(), &mut backtrace)
+            .map_err(|fault| VmError::new(fault, backtrace))
+    }
+
+    /// Reads lines from `input` until it ends or `:quit`, executing them as instructions or meta commands
+    /// and writing prompts and what they print to `output` and errors to `errors`.
+    pub fn run(&mut self, input: impl BufRead, output: &mut impl Write, errors: &mut impl Write) -> std::io::Result<()> {
+        let mut block: Option<String> = None;
+        let mut lines = input.lines();
+        loop {
+            write!(output, "{}", if block.is_some() { "| " } else { "> " })?;
+            output.flush()?;
+            let Some(line) = lines.next() else {
+                writeln!(output)?;
+                return Ok(());
+            };
+            let line = line?;
+
+            let mut printed = String::new();
+            let result = match (&mut block, line.trim()) {
+                (Some(source), ":end") => {
+                    let source = std::mem::take(source);
+                    block = None;
+                    self.run_block(&source, &mut printed)
+                }
+                (Some(source), _) => {
+                    source.push_str(&line);
+                    source.push('\n');
+                    Ok(())
+                }
+                (None, "") => Ok(()),
+                (None, ":block") => {
+                    block = Some(String::new());
+                    Ok(())
+                }
+                (None, line) if line.starts_with(':') => match self.run_meta_command(line, &mut printed) {
+                    Ok(true) => Ok(()),
+                    Ok(false) => return Ok(()),
+                    Err(message) => Err(message),
+                },
+                (None, _) => self.run_block(&line, &mut printed),
+            };
+            write!(output, "{}", printed)?;
+            if let Err(message) = result {
+                writeln!(errors, "{}", message)?;
+            }
+        }
+    }
+
+    /// Runs a meta command like `:regs` and appends what it prints to `output`.
+    /// Returns false once the repl should stop.
+    pub fn run_meta_command(&mut self, line: &str, output: &mut String) -> Result<bool, String> {
+        let (command, rest) = line.trim().split_once(char::is_whitespace).unwrap_or((line.trim(), ""));
+        match command {
+            ":help" => output.push_str(&format!("{}\n", HELP)),
+            ":quit" => return Ok(false),
+            ":regs" => {
+                for (index, register) in self.core.registers.iter().enumerate() {
+                    output.push_str(&format!("r{} = {:?}\n", index, register.value));
+                }
+                output.push_str(&format!("flags: {}\n", self.core.get_flags()));
+            }
+            ":heap" => output.push_str(&self.dump_heap().map_err(|fault| format!("{:?}", fault))?),
+            ":load" => {
+                let file = rest.trim();
+                if file.is_empty() {
+                    return Err(":load needs a file".to_string());
+                }
+                let mut natives = NativeRegistry::new();
+                natives.register_module(&get_std_module());
+                let module = load_file(Path::new(file), &natives)?;
+                self.load_module(module).map_err(|fault| format!("{:?}", fault))?;
+            }
+            ":call" => {
+                let mut words = split_words(rest)?.into_iter();
+                let path = words.next().ok_or(":call needs a function")?;
+                let arguments = words.map(|word| self.parse_argument(&word)).collect::<Result<Vec<_>, _>>()?;
+                let value = self.call(&path, arguments).map_err(|error| error.to_string())?;
+                output.push_str(&format!("r0 = {:?}\n", value));
+            }
+            ":reset" => self.reset().map_err(|fault| format!("{:?}", fault))?,
+            command => return Err(format!("unknown command {}, :help lists them", command)),
+        }
+        Ok(true)
+    }
+
+    /// Executes a block and appends the registers it changed and the flags to `output`.
+    fn run_block(&mut self, source: &str, output: &mut String) -> Result<(), String> {
+        let changed = self.execute(source).map_err(|error| error.to_string())?;
+        for index in changed {
+            output.push_str(&format!("r{} = {:?}\n", index, self.core.registers[index].value));
+        }
+        output.push_str(&format!("flags: {}\n", self.core.get_flags()));
+        Ok(())
+    }
+
+    /// Parses an argument of `:call`, a number with the type as a suffix or a string in double quotes.
+    fn parse_argument(&mut self, text: &str) -> Result<Value, String> {
+        if let Some(string) = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')) {
+            return self.memory.allocate_string(string).map_err(|fault| format!("{:?}", fault));
+        }
+        let invalid = || format!("invalid argument {}, expected something like 5u64, 1.5f64 or \"text\"", text);
+        let split = text.rfind(['u', 'i', 'f']).ok_or_else(invalid)?;
+        let (number, suffix) = text.split_at(split);
+        let value = match suffix {
+            "u8" => number.parse().map(Value::U8).ok(),
+            "u16" => number.parse().map(Value::U16).ok(),
+            "u32" => number.parse().map(Value::U32).ok(),
+            "u64" => number.parse().map(Value::U64).ok(),
+            "i8" => number.parse().map(Value::I8).ok(),
+            "i16" => number.parse().map(Value::I16).ok(),
+            "i32" => number.parse().map(Value::I32).ok(),
+            "i64" => number.parse().map(Value::I64).ok(),
+            "f32" => number.parse().map(Value::F32).ok(),
+            "f64" => number.parse().map(Value::F64).ok(),
+            _ => None,
+        };
+        value.ok_or_else(invalid)
+    }
+
+    /// Describes every object on the heap, one per line.
+    pub fn dump_heap(&self) -> Result<String, Fault> {
+        let mut output = String::new();
+        for reference in self.memory.get_references()? {
+            let description = describe_object(&self.memory, &self.program, reference)?;
+            output.push_str(&format!("{}: {}\n", reference, description));
+        }
+        Ok(output)
+    }
+
+    /// Wraps `source` in a function and assembles and verifies it against the program.
+    /// Line numbers are counted from the first line of `source`.
+    fn assemble_block(&mut self, source: &str) -> Result<Arc<[Instruction]>, ReplError> {
+        let wrapped = format!(".function block\n{}\n        ret\n.end\n", source);
+        let assembled = assemble(&wrapped, REPL_NAME, &NativeRegistry::new()).map_err(|mut error| {
+            error.line = error.line.saturating_sub(1);
+            ReplError::Assembly(error)
+        })?;
+        let function = assembled.get_function(&"block".into()).expect("the block was assembled");
+
+        // String literals are appended to the string table of the program, so they stay around for later blocks.
+        let program = Arc::get_mut(&mut self.program)
+            .ok_or_else(|| Fault::InvalidOperation("threads started by an earlier block are still running".to_string()))?;
+        let string_path = StringTablePath { path: Box::new([]) };
+        let mut instructions = Vec::new();
+        for mut instruction in function.get_instructions().iter().cloned() {
+            if let RealInstruction::GetStringRef(target, path, index) = instruction.instruction {
+                let string = assembled.get_string(&path, index).ok_or(Fault::InvalidReference)?;
+                let index = program.add_string(&string_path, string);
+                self.memory.allocate_string_ref(&string_path, index)?;
+                instruction.instruction = RealInstruction::GetStringRef(target, string_path.clone(), index);
+            }
+            instruction.line -= 1;
+            instructions.push(instruction);
+        }
+
+        let diagnostics = verify_function(&self.program, &REPL_NAME.into(), &instructions);
+        if !diagnostics.is_empty() {
+            return Err(Fault::VerificationFailed(diagnostics).into());
+        }
+        Ok(instructions.into())
+    }
+}
+
+/// Splits `text` at whitespace, except inside double quotes, where `\"` is a quote and `\\` a backslash.
+/// Quoted words keep their quotes so they can be told apart from numbers.
+fn split_words(text: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|char| char.is_whitespace()).is_some() {}
+        let Some(first) = chars.next() else {
+            return Ok(words);
+        };
+        let mut word = String::from(first);
+        if first == '"' {
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next_if(|char| matches!(char, '"' | '\\')) {
+                        Some(escaped) => word.push(escaped),
+                        None => word.push('\\'),
+                    },
+                    Some(char) => word.push(char),
+                    None => return Err(format!("unterminated string {}", word)),
+                }
+            }
+            word.push('"');
+            if chars.peek().is_some_and(|char| !char.is_whitespace()) {
+                return Err(format!("expected a space after the string {}", word));
+            }
+        } else {
+            while let Some(char) = chars.next_if(|char| !char.is_whitespace()) {
+                word.push(char);
+            }
+        }
+        words.push(word);
+    }
+}
+
+/// What an object is and what it holds, in one line.
+fn describe_object(memory: &Memory, module: &Module, reference: u64) -> Result<String, Fault> {
+    let description = match memory.get(reference)? {
+        MemoryObject::Null => "null".to_string(),
+        MemoryObject::StringTableRef(..) | MemoryObject::String(..) => format!("string {:?}", memory.get_string(reference, module)?),
+        MemoryObject::Object(object) => {
+            let object = unsafe { &*object };
+            format!("object {} {:?}", object.get_class_name(), object.get_fields())
+        }
+        MemoryObject::Closure(closure) => {
+            let closure = unsafe { &*closure };
+            format!("closure {} {:?}", closure.get_path(), closure.get_captures())
+        }
+        MemoryObject::Sync(sync) => match unsafe { &*sync } {
+            SyncObject::Mutex(_) => "mutex".to_string(),
+            SyncObject::Condvar(_) => "condvar".to_string(),
+            SyncObject::Semaphore(_) => "semaphore".to_string(),
+            SyncObject::Channel(_) => "channel".to_string(),
+        },
+        MemoryObject::List(list) => format!("list {:?}", unsafe { &*list }),
+        MemoryObject::Pointer(_, size, value_type) => format!("pointer to {} x {:?}", size, value_type),
+    };
+    Ok(description)
+}
@@ -1,59 +1,156 @@
-use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
-use std::rc::Rc;
 use std::sync::Arc;
 use crate::instruction::Instruction;
 use crate::machine::{Fault, Register};
 use crate::program::function::FunctionPath;
-use crate::stack_frame::{REGISTER_COUNT, ReturnAddress, StackFrame};
+use crate::stack_frame::{EffectHandler, REGISTER_COUNT, StackFrame};
+use crate::stack_frame::frame::Frame;
 use crate::value::{Value, ValueType};
 
 
 
+enum StoredContinuation {
+    MultiShot(DelimitedContinuation),
+    /// Removed by the first resume.
+    OneShot(DelimitedContinuation),
+}
+
+/// The continuations captured by handlers and `mkcont`, by the index handed to the program.
+/// Entries that nothing refers to anymore are dropped by garbage collections.
 pub struct ContinuationStore {
-    continuations: Vec<DelimitedContinuation>,
+    continuations: HashMap<u64, StoredContinuation>,
+    next_index: u64,
 }
 
 impl ContinuationStore {
     pub fn new() -> Self {
         ContinuationStore {
-            continuations: Vec::new()
+            continuations: HashMap::new(),
+            next_index: 0,
         }
     }
 
     pub fn store(&mut self, continuation: DelimitedContinuation) {
-        self.continuations.push(continuation);
+        self.insert(StoredContinuation::MultiShot(continuation));
+    }
+
+    pub fn store_one_shot(&mut self, continuation: DelimitedContinuation) {
+        self.insert(StoredContinuation::OneShot(continuation));
+    }
+
+    fn insert(&mut self, continuation: StoredContinuation) {
+        self.continuations.insert(self.next_index, continuation);
+        self.next_index += 1;
     }
 
-    pub fn get(&mut self, index: usize) -> Option<DelimitedContinuation> {
-        self.continuations.get(index).map(|c| c.clone())
+    /// Gets a continuation to resume.
+    /// Multi-shot continuations are copied so every resume starts from the captured state,
+    /// one-shot continuations are moved out and fault when resumed again.
+    /// A handed out index that is gone belonged to a one-shot continuation,
+    /// collected ones can't be resumed as nothing refers to them.
+    pub fn resume(&mut self, index: usize) -> Result<DelimitedContinuation, Fault> {
+        let key = index as u64;
+        match self.continuations.remove(&key) {
+            Some(StoredContinuation::MultiShot(continuation)) => {
+                let copy = continuation.clone();
+                self.continuations.insert(key, StoredContinuation::MultiShot(continuation));
+                Ok(copy)
+            }
+            Some(StoredContinuation::OneShot(continuation)) => Ok(continuation),
+            None if key < self.next_index => Err(Fault::ContinuationAlreadyResumed(key)),
+            None => Err(Fault::ContinuationNotFound(key)),
+        }
     }
 
     pub fn get_last_index(&mut self) -> u64 {
-        self.continuations.len() as u64 - 1
+        self.next_index - 1
+    }
+
+    /// Called by the garbage collector for every value that could be a reference.
+    /// When `value` is the index of a continuation that wasn't reached yet,
+    /// adds it to `reached` and the values the continuation holds to `roots`.
+    pub fn trace(&self, value: u64, reached: &mut HashSet<u64>, roots: &mut Vec<u64>) {
+        if let Some(continuation) = self.continuations.get(&value) {
+            if reached.insert(value) {
+                match continuation {
+                    StoredContinuation::MultiShot(continuation) | StoredContinuation::OneShot(continuation) => continuation.trace_roots(roots),
+                }
+            }
+        }
+    }
+
+    /// Drops the continuations a collection didn't reach.
+    pub fn retain_reached(&mut self, reached: &HashSet<u64>) {
+        self.continuations.retain(|index, _| reached.contains(index));
     }
 
 }
 
 
+/// A copy of a captured stack frame together with the captured frames it was calling.
+/// The outermost frame of a continuation is the one a resume returns from.
+/// Cloning copies every frame, so clones can be resumed independently.
 #[derive(Clone)]
 pub struct DelimitedContinuation {
-    stack_frame: Rc<RefCell<dyn StackFrame>>,
+    stack_frame: Box<Frame>,
     start_program_counter: usize,
+    /// The registers at the point the continuation was captured, restored when it is resumed.
+    /// Only set on the innermost frame.
+    registers: Option<Box<[Register; REGISTER_COUNT]>>,
+    inner: Option<Box<DelimitedContinuation>>,
+    outermost: bool,
 }
 
 
 impl DelimitedContinuation {
-    pub fn new(stack_frame: Rc<RefCell<dyn StackFrame>>, start_program_counter: usize) -> Self {
-        let stack_frame = stack_frame.clone();
+    pub fn new(stack_frame: Frame, start_program_counter: usize) -> Self {
         DelimitedContinuation {
-            stack_frame,
+            stack_frame: Box::new(stack_frame),
             start_program_counter,
+            registers: None,
+            inner: None,
+            outermost: true,
         }
     }
 
-    pub fn get_stack_frame(&self) -> Rc<RefCell<dyn StackFrame>> {
-        self.stack_frame.clone()
+    /// Makes `outer` the frame that this continuation returns to.
+    pub fn enclose(mut self, mut outer: DelimitedContinuation) -> DelimitedContinuation {
+        self.outermost = false;
+        outer.registers = None;
+        outer.inner = Some(Box::new(self));
+        outer.outermost = true;
+        outer
+    }
+
+    pub fn capture_registers(&mut self, registers: &[Register; REGISTER_COUNT]) {
+        self.registers = Some(Box::new(registers.clone()));
+    }
+
+    /// Sets a register the continuation restores when it is resumed.
+    pub fn set_register(&mut self, index: usize, value: Value) {
+        match &mut self.inner {
+            Some(inner) => inner.set_register(index, value),
+            None => if let Some(registers) = &mut self.registers {
+                registers[index].value = value;
+            },
+        }
+    }
+
+    /// Moves the innermost frame back to the instruction before the one it resumes at,
+    /// so a native that suspended its caller is called again on resume.
+    pub fn rewind(&mut self) {
+        match &mut self.inner {
+            Some(inner) => inner.rewind(),
+            None => {
+                self.start_program_counter -= 1;
+                self.stack_frame.set_program_counter(self.start_program_counter);
+            }
+        }
+    }
+
+    pub fn get_stack_frame(&self) -> &Frame {
+        &self.stack_frame
     }
 
     pub fn get_start_program_counter(&self) -> usize {
@@ -73,76 +170,128 @@ impl Debug for DelimitedContinuation {
 
 impl StackFrame for DelimitedContinuation {
     fn push(&mut self, value: Value) {
-        self.stack_frame.borrow_mut().push(value)
+        self.stack_frame.push(value)
     }
 
     fn pop(&mut self, size: ValueType) -> Value {
-        let mut stack_frame = self.stack_frame.borrow_mut();
-        stack_frame.pop(size)
+        self.stack_frame.pop(size)
     }
 
     fn get_value(&self, offset: Value, size: ValueType) -> Value {
-        self.stack_frame.borrow().get_value(offset, size)
+        self.stack_frame.get_value(offset, size)
     }
 
     fn set_value(&mut self, offset: Value, value: Value) -> Result<(), Fault> {
-        self.stack_frame.borrow_mut().set_value(offset, value)
+        self.stack_frame.set_value(offset, value)
     }
 
+    /// Only the outermost frame returns to the resumer,
+    /// the others keep the registers of the frame that called them when they were captured.
     fn backup_registers(&mut self, registers: &[Register; REGISTER_COUNT]) {
-        self.stack_frame.borrow_mut().backup_registers(registers)
+        if self.outermost {
+            self.stack_frame.backup_registers(registers)
+        }
     }
 
     fn restore_registers(&mut self, registers: &mut [Register; REGISTER_COUNT]) {
-        self.stack_frame.borrow_mut().restore_registers(registers)
+        self.stack_frame.restore_registers(registers)
     }
 
     fn backup_registers_for_gc(&mut self, registers: &mut [Register; REGISTER_COUNT]) {
-        self.stack_frame.borrow_mut().backup_registers_for_gc(registers)
+        self.stack_frame.backup_registers_for_gc(registers)
     }
 
     fn restore_registers_for_gc(&mut self, registers: &mut [Register; REGISTER_COUNT]) {
-        self.stack_frame.borrow_mut().restore_registers_for_gc(registers)
+        self.stack_frame.restore_registers_for_gc(registers)
     }
 
 
     fn get_function_name(&self) -> FunctionPath {
-        self.stack_frame.borrow().get_function_name()
+        self.stack_frame.get_function_name()
     }
 
     fn set_function_name(&mut self, name: &str) {
-        self.stack_frame.borrow_mut().set_function_name(name)
+        self.stack_frame.set_function_name(name)
     }
 
     fn get_instruction(&self) -> Instruction {
-        self.stack_frame.borrow().get_instruction()
+        self.stack_frame.get_instruction()
     }
 
     fn get_instructions(&self) -> Arc<[Instruction]> {
-        self.stack_frame.borrow().get_instructions()
+        self.stack_frame.get_instructions()
     }
 
     fn increment_program_counter(&mut self) {
-        self.stack_frame.borrow_mut().increment_program_counter()
+        self.stack_frame.increment_program_counter()
     }
 
     fn reset_program_counter(&mut self) {
-        self.stack_frame.borrow_mut().set_program_counter(self.start_program_counter)
+        self.stack_frame.set_program_counter(self.start_program_counter)
     }
 
     fn get_program_counter(&self) -> usize {
-        self.stack_frame.borrow().get_program_counter()
+        self.stack_frame.get_program_counter()
     }
 
     fn set_program_counter(&mut self, program_counter: usize) {
-        self.stack_frame.borrow_mut().set_program_counter(program_counter)
+        self.stack_frame.set_program_counter(program_counter)
     }
 
     fn make_continuation(&self) -> DelimitedContinuation {
-        (*self).clone()
+        self.stack_frame.make_continuation()
     }
 
     fn is_continuation(&self) -> bool {
         true
     }
+
+    fn install_handler(&mut self, handler: EffectHandler) {
+        self.stack_frame.install_handler(handler)
+    }
+
+    fn remove_handler(&mut self, effect: &str) {
+        self.stack_frame.remove_handler(effect)
+    }
+
+    fn get_handler(&self, effect: &str) -> Option<EffectHandler> {
+        self.stack_frame.get_handler(effect)
+    }
+
+    /// Restores every captured register except register 0, which holds the value the continuation is resumed with.
+    fn resume(&mut self, registers: &mut [Register; REGISTER_COUNT]) {
+        if let Some(captured) = &self.registers {
+            registers[1..].clone_from_slice(&captured[1..]);
+        }
+    }
+
+    fn take_inner_continuation(&mut self) -> Option<DelimitedContinuation> {
+        self.inner.take().map(|inner| *inner)
+    }
+
+    fn push_prompt(&mut self, tag: Box<str>) {
+        self.stack_frame.push_prompt(tag)
+    }
+
+    fn pop_prompt(&mut self, tag: &str) {
+        self.stack_frame.pop_prompt(tag)
+    }
+
+    fn has_prompt(&self, tag: &str) -> bool {
+        self.stack_frame.has_prompt(tag)
+    }
+
+    fn get_capture(&self, index: usize) -> Option<Value> {
+        self.stack_frame.get_capture(index)
+    }
+
+    fn trace_roots(&self, roots: &mut Vec<u64>) {
+        self.stack_frame.trace_roots(roots);
+        if let Some(registers) = &self.registers {
+            roots.extend(registers.iter().filter_map(|register| register.value.get_reference()));
+        }
+        if let Some(inner) = &self.inner {
+            inner.trace_roots(roots);
+        }
+    }
 }
\ No newline at end of file
@@ -1,11 +1,9 @@
-use std::cell::RefCell;
-use std::rc::Rc;
 use std::sync::Arc;
 use smallvec::SmallVec;
 use crate::instruction::Instruction;
 use crate::machine::{Fault, Register};
 use crate::program::function::FunctionPath;
-use crate::stack_frame::{REGISTER_COUNT, ReturnAddress, StackFrame};
+use crate::stack_frame::{EffectHandler, REGISTER_COUNT, StackFrame};
 use crate::stack_frame::delimited_continuation::DelimitedContinuation;
 use crate::value::{Value, ValueType};
 
@@ -25,6 +23,10 @@ pub struct Frame {
     stack_pointer: usize,
     call_backup: Option<[Register; REGISTER_COUNT]>,
     gc_backup: Option<[Register; REGISTER_COUNT]>,
+    handlers: Vec<EffectHandler>,
+    prompts: Vec<Box<str>>,
+    /// The values captured by the closure this frame was called through.
+    captures: Box<[Value]>,
 }
 
 impl Frame {
@@ -39,8 +41,25 @@ impl Frame {
             stack_pointer: 0,
             call_backup: None,
             gc_backup: None,
+            handlers: Vec::new(),
+            prompts: Vec::new(),
+            captures: Box::new([]),
         }
     }
+
+    pub fn new_closure(function_name: FunctionPath, instructions: Arc<[Instruction]>, captures: Box<[Value]>) -> Self {
+        let mut frame = Frame::new(function_name, instructions);
+        frame.captures = captures;
+        frame
+    }
+
+    /// Runs `instructions` in this frame from the start, keeping its stack and captures.
+    /// Effect handlers are removed since their addresses point into the old instructions.
+    pub fn set_instructions(&mut self, instructions: Arc<[Instruction]>) {
+        self.frame_info.instructions = instructions;
+        self.frame_info.program_counter = 0;
+        self.handlers.clear();
+    }
 }
 
 
@@ -264,8 +283,9 @@ impl StackFrame for Frame {
 
     }
 
+    /// Also forgets the backup so it does not keep values alive in later collections.
     fn restore_registers_for_gc(&mut self, registers: &mut [Register; REGISTER_COUNT]) {
-        if let Some(gc_backup) = &self.gc_backup {
+        if let Some(gc_backup) = self.gc_backup.take() {
             for i in 8..REGISTER_COUNT {
                 registers[i] = gc_backup[i].clone();
             }
@@ -306,6 +326,47 @@ impl StackFrame for Frame {
     }
 
     fn make_continuation(&self) -> DelimitedContinuation {
-        DelimitedContinuation::new(Rc::new(RefCell::new((*self).clone())), self.frame_info.program_counter)
+        DelimitedContinuation::new(self.clone(), self.frame_info.program_counter)
+    }
+
+    fn install_handler(&mut self, handler: EffectHandler) {
+        self.handlers.push(handler);
+    }
+
+    fn remove_handler(&mut self, effect: &str) {
+        if let Some(index) = self.handlers.iter().rposition(|handler| handler.effect.as_ref() == effect) {
+            self.handlers.remove(index);
+        }
+    }
+
+    fn get_handler(&self, effect: &str) -> Option<EffectHandler> {
+        self.handlers.iter().rev().find(|handler| handler.effect.as_ref() == effect).cloned()
+    }
+
+    fn push_prompt(&mut self, tag: Box<str>) {
+        self.prompts.push(tag);
+    }
+
+    fn pop_prompt(&mut self, tag: &str) {
+        if let Some(index) = self.prompts.iter().rposition(|prompt| prompt.as_ref() == tag) {
+            self.prompts.remove(index);
+        }
+    }
+
+    fn has_prompt(&self, tag: &str) -> bool {
+        self.prompts.iter().any(|prompt| prompt.as_ref() == tag)
+    }
+
+    fn get_capture(&self, index: usize) -> Option<Value> {
+        self.captures.get(index).cloned()
+    }
+
+    fn trace_roots(&self, roots: &mut Vec<u64>) {
+        for registers in [&self.call_backup, &self.gc_backup].into_iter().flatten() {
+            roots.extend(registers.iter().filter_map(|register| register.value.get_reference()));
+        }
+        roots.extend(self.captures.iter().filter_map(Value::get_reference));
+        // The operand stack only holds bytes, so any eight of them could be a reference.
+        roots.extend(self.stack.windows(8).map(|bytes| u64::from_le_bytes(bytes.try_into().expect("window of eight bytes"))));
     }
 }
\ No newline at end of file
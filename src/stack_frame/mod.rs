@@ -2,13 +2,15 @@ pub mod frame;
 pub mod delimited_continuation;
 
 use std::sync::Arc;
-use crate::instruction::Instruction;
+use crate::instruction::{Instruction, Target};
 use crate::machine::{Fault, Register};
 use crate::program::function::FunctionPath;
 use crate::stack_frame::delimited_continuation::DelimitedContinuation;
 use crate::value::{Value, ValueType};
 
 pub const REGISTER_COUNT: usize = 32;
+/// The register a method receives the object it was called on in, its other arguments follow it.
+pub const RECEIVER_REGISTER: usize = 8;
 
 
 
@@ -18,6 +20,18 @@ pub struct ReturnAddress {
     function_name: FunctionPath,
 }
 
+/// A handler installed on a stack frame for a named effect.
+#[derive(Clone, Debug)]
+pub struct EffectHandler {
+    pub effect: Box<str>,
+    /// The instruction the handler starts at.
+    pub address: usize,
+    /// Where the index of the captured continuation is stored.
+    pub target: Target,
+    /// If the captured continuation can only be resumed once.
+    pub one_shot: bool,
+}
+
 
 
 
@@ -62,6 +76,34 @@ pub trait StackFrame {
         false
     }
 
+    fn install_handler(&mut self, handler: EffectHandler);
+
+    fn remove_handler(&mut self, effect: &str);
+
+    /// Gets the most recently installed handler for an effect.
+    fn get_handler(&self, effect: &str) -> Option<EffectHandler>;
+
+    /// Called once the caller's registers have been backed up, before the frame starts executing.
+    fn resume(&mut self, _registers: &mut [Register; REGISTER_COUNT]) {}
+
+    /// Takes the continuation of the frames this frame was calling when it was captured.
+    /// They are resumed before this frame executes any instructions.
+    fn take_inner_continuation(&mut self) -> Option<DelimitedContinuation> {
+        None
+    }
+
+    fn push_prompt(&mut self, tag: Box<str>);
+
+    fn pop_prompt(&mut self, tag: &str);
+
+    fn has_prompt(&self, tag: &str) -> bool;
+
+    /// Gets a value captured by the closure this frame was called through.
+    fn get_capture(&self, index: usize) -> Option<Value>;
+
+    /// Adds every value this frame keeps alive that may be a heap reference.
+    fn trace_roots(&self, roots: &mut Vec<u64>);
+
 
 
 
@@ -0,0 +1,33 @@
+use crate::program::function::{Function, FunctionPath};
+use crate::value::Value;
+
+
+/// A function together with the values it captured when it was created.
+#[derive(Clone)]
+pub struct Closure {
+    path: FunctionPath,
+    function: Function,
+    captures: Box<[Value]>,
+}
+
+impl Closure {
+    pub fn new(path: FunctionPath, function: Function, captures: Vec<Value>) -> Self {
+        Closure {
+            path,
+            function,
+            captures: captures.into_boxed_slice(),
+        }
+    }
+
+    pub fn get_path(&self) -> &FunctionPath {
+        &self.path
+    }
+
+    pub fn get_function(&self) -> &Function {
+        &self.function
+    }
+
+    pub fn get_captures(&self) -> &[Value] {
+        &self.captures
+    }
+}
@@ -4,11 +4,13 @@ use crate::program::function::Function;
 use crate::value::object::Object;
 
 pub mod object;
+pub mod closure;
+pub mod sync;
 
 
 
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ValueType {
     U8,
     I8,
@@ -290,6 +292,32 @@ impl Value {
             _ => panic!("Cannot transmute value of type {:?} to {:?}", self.get_type(), typ),
         }
     }
+
+    /// Whether `transmute` can convert this value to `typ` without panicking.
+    pub fn can_transmute(&self, typ: ValueType) -> bool {
+        let is_number = matches!(self, Value::U8(_) | Value::I8(_) | Value::U16(_) | Value::I16(_) | Value::U32(_)
+            | Value::I32(_) | Value::U64(_) | Value::I64(_) | Value::F32(_) | Value::F64(_));
+        let is_reference = matches!(self, Value::MemoryRef(_) | Value::ObjectRef(_) | Value::StringRef(_) | Value::ArrayRef(_));
+        match typ {
+            ValueType::U8 | ValueType::I8 | ValueType::U16 | ValueType::I16 | ValueType::U32 | ValueType::I32
+            | ValueType::I64 | ValueType::F32 | ValueType::F64 => is_number,
+            ValueType::U64 => is_number || is_reference,
+            ValueType::MemoryRef => is_reference || matches!(self, Value::U64(_)),
+            ValueType::ObjectRef | ValueType::StringRef | ValueType::ArrayRef => matches!(self, Value::U64(_)) || self.get_type() == typ,
+            _ => false,
+        }
+    }
+
+    /// The heap reference this value may hold.
+    /// `U64` values count because references are often moved through `u64` registers.
+    pub fn get_reference(&self) -> Option<u64> {
+        match self {
+            Value::MemoryRef(reference) | Value::ObjectRef(reference) | Value::StringRef(reference)
+            | Value::ArrayRef(reference) | Value::U64(reference) => Some(*reference),
+            _ => None,
+        }
+    }
+
     pub fn get_type(&self) -> ValueType {
         match self {
             Value::U8(_) => ValueType::U8,
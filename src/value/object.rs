@@ -1,4 +1,5 @@
-
+use std::fmt::Debug;
+use crate::machine::Fault;
 use crate::program::function::{Function, FunctionPath};
 use crate::value::Value;
 
@@ -7,13 +8,105 @@ pub type ClassId = u64;
 
 pub type Method = (FunctionPath, Function);
 
+unsafe impl Send for Object {}
+unsafe impl Sync for Object {}
+/// Either a class or an instance of one.
+/// A class holds its super class, its own methods and the initial field values of its instances,
+/// an instance holds its field values and shares the class id and name of its class.
 pub struct Object {
     class_id: ClassId,
     class_name: *const str,
     super_class: Option<ClassRef>,
     fields: *mut [Value],
-    vtable: *mut [Method],
+    /// Indexed by method slot, slots the class inherits without overriding are `None`.
+    vtable: *mut [Option<Method>],
+}
+
+impl Object {
+    pub fn new_class(class_id: ClassId, class_name: &str, super_class: Option<ClassRef>, fields: Vec<Value>, vtable: Vec<Option<Method>>) -> Self {
+        Object {
+            class_id,
+            class_name: Box::into_raw(Box::<str>::from(class_name)),
+            super_class,
+            fields: Box::into_raw(fields.into_boxed_slice()),
+            vtable: Box::into_raw(vtable.into_boxed_slice()),
+        }
+    }
+
+    /// Creates an instance of `class` with the initial field values of the class.
+    pub fn new_instance(class: &Object) -> Self {
+        Object::new_class(class.class_id, class.get_class_name(), None, class.get_fields().to_vec(), Vec::new())
+    }
+
+    pub fn get_class_id(&self) -> ClassId {
+        self.class_id
+    }
+
+    pub fn get_class_name(&self) -> &str {
+        unsafe { &*self.class_name }
+    }
+
+    pub fn get_super_class(&self) -> Option<ClassRef> {
+        self.super_class
+    }
+
+    pub fn get_fields(&self) -> &[Value] {
+        unsafe { &*self.fields }
+    }
+
+    pub fn get_vtable(&self) -> &[Option<Method>] {
+        unsafe { &*self.vtable }
+    }
+
+    /// Finds the method in a slot of this class, walking up the super classes for inherited methods.
+    pub fn lookup_method(&self, slot: u64) -> Result<&Method, Fault> {
+        let mut class = self;
+        loop {
+            if let Some(Some(method)) = class.get_vtable().get(slot as usize) {
+                return Ok(method);
+            }
+            match class.super_class {
+                Some(super_class) => class = unsafe { &*super_class },
+                None => return Err(Fault::InvalidMethodIndex(slot)),
+            }
+        }
+    }
+
+    pub fn get_field(&self, index: u64) -> Result<Value, Fault> {
+        self.get_fields().get(index as usize).cloned().ok_or(Fault::InvalidFieldIndex(index))
+    }
+
+    /// Fields keep the type they were declared with, so the value must have the same type.
+    pub fn set_field(&mut self, index: u64, value: Value) -> Result<(), Fault> {
+        let fields = unsafe { &mut *self.fields };
+        let field = fields.get_mut(index as usize).ok_or(Fault::InvalidFieldIndex(index))?;
+        if field.get_type() != value.get_type() {
+            return Err(Fault::InvalidOperation(format!("field {} of {} is {:?} but was given {:?}", index, self.get_class_name(), field.get_type(), value.get_type())));
+        }
+        *field = value;
+        Ok(())
+    }
+}
+
+impl Debug for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Object")
+            .field("class_id", &self.class_id)
+            .field("class_name", &self.get_class_name())
+            .field("fields", &self.get_fields())
+            .finish()
+    }
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = Box::from_raw(self.class_name as *mut str);
+            let _ = Box::from_raw(self.fields);
+            let _ = Box::from_raw(self.vtable);
+        }
+    }
 }
 
 
-pub type ClassRef = *mut Object;
\ No newline at end of file
+pub type ClassRef = *mut Object;
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar as StdCondvar, Mutex as StdMutex, MutexGuard};
+use std::thread::ThreadId;
+use crate::machine::fiber::FiberId;
+use crate::machine::Fault;
+use crate::value::Value;
+
+
+/// Who holds a mutex or waits on a condition variable, the fiber is `None` for code that doesn't run in one.
+pub type Owner = (ThreadId, Option<FiberId>);
+
+/// The synchronization objects of `std::sync`.
+/// They are shared between threads and only need `&self`.
+/// The blocking methods block the OS thread, the `try_` ones let a fiber park and call them again instead.
+/// A clone shares the object, so a native can keep using it after the heap lock is released even if it is collected.
+#[derive(Debug, Clone)]
+pub enum SyncObject {
+    Mutex(Arc<Mutex>),
+    Condvar(Arc<Condvar>),
+    Semaphore(Arc<Semaphore>),
+    Channel(Arc<Channel>),
+}
+
+unsafe impl Send for SyncObject {}
+unsafe impl Sync for SyncObject {}
+
+fn lock<T>(mutex: &StdMutex<T>) -> Result<MutexGuard<'_, T>, Fault> {
+    mutex.lock().map_err(|_| Fault::MemoryError("Poisoned".to_string()))
+}
+
+impl SyncObject {
+    /// Adds the references held by the object.
+    pub fn trace_roots(&self, roots: &mut Vec<u64>) {
+        if let SyncObject::Channel(channel) = self {
+            if let Ok(state) = channel.state.lock() {
+                roots.extend(state.queue.iter().filter_map(Value::get_reference));
+            }
+        }
+    }
+}
+
+/// A mutex that is owned by the fiber that locked it until that fiber unlocks it.
+#[derive(Debug, Default)]
+pub struct Mutex {
+    owner: StdMutex<Option<Owner>>,
+    unlocked: StdCondvar,
+}
+
+impl Mutex {
+    pub fn lock(&self, current: Owner) -> Result<(), Fault> {
+        let mut owner = lock(&self.owner)?;
+        if *owner == Some(current) {
+            return Err(Fault::MutexAlreadyOwned);
+        }
+        while owner.is_some() {
+            owner = self.unlocked.wait(owner).map_err(|_| Fault::MemoryError("Poisoned".to_string()))?;
+        }
+        *owner = Some(current);
+        Ok(())
+    }
+
+    /// Returns whether the mutex was locked.
+    pub fn try_lock(&self, current: Owner) -> Result<bool, Fault> {
+        let mut owner = lock(&self.owner)?;
+        match *owner {
+            Some(owner) if owner == current => Err(Fault::MutexAlreadyOwned),
+            Some(_) => Ok(false),
+            None => {
+                *owner = Some(current);
+                Ok(true)
+            }
+        }
+    }
+
+    pub fn unlock(&self, current: Owner) -> Result<(), Fault> {
+        let mut owner = lock(&self.owner)?;
+        if *owner != Some(current) {
+            return Err(Fault::MutexNotOwned);
+        }
+        *owner = None;
+        self.unlocked.notify_one();
+        Ok(())
+    }
+}
+
+/// A condition variable, waiting on it releases a `Mutex` until the waiting thread is notified.
+/// Waiters can wake up without being notified, so the condition should be checked again after waiting.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    state: StdMutex<CondvarState>,
+    notified: StdCondvar,
+}
+
+#[derive(Debug, Default)]
+struct CondvarState {
+    /// Bumped by every notification so waiters can tell they were notified.
+    notifications: u64,
+    /// The notification count each parked waiter started waiting at.
+    parked: HashMap<Owner, u64>,
+}
+
+impl Condvar {
+    pub fn wait(&self, mutex: &Mutex, owner: Owner) -> Result<(), Fault> {
+        // Holding the notification count while unlocking means a notification can't be missed.
+        let mut state = lock(&self.state)?;
+        let start = state.notifications;
+        mutex.unlock(owner)?;
+        while state.notifications == start {
+            state = self.notified.wait(state).map_err(|_| Fault::MemoryError("Poisoned".to_string()))?;
+        }
+        drop(state);
+        mutex.lock(owner)
+    }
+
+    /// Waits without blocking, the waiter calls it again until it is done.
+    pub fn try_wait(&self, mutex: &Mutex, owner: Owner) -> Result<TryWait, Fault> {
+        let mut state = lock(&self.state)?;
+        match state.parked.get(&owner).copied() {
+            None => {
+                mutex.unlock(owner)?;
+                let start = state.notifications;
+                state.parked.insert(owner, start);
+                Ok(TryWait::Started)
+            }
+            Some(start) if start == state.notifications => Ok(TryWait::Waiting),
+            Some(_) => {
+                if !mutex.try_lock(owner)? {
+                    return Ok(TryWait::Waiting);
+                }
+                state.parked.remove(&owner);
+                Ok(TryWait::Done)
+            }
+        }
+    }
+
+    pub fn notify_one(&self) -> Result<(), Fault> {
+        lock(&self.state)?.notifications += 1;
+        self.notified.notify_one();
+        Ok(())
+    }
+
+    pub fn notify_all(&self) -> Result<(), Fault> {
+        lock(&self.state)?.notifications += 1;
+        self.notified.notify_all();
+        Ok(())
+    }
+}
+
+/// How far `Condvar::try_wait` got.
+#[derive(Debug, PartialEq)]
+pub enum TryWait {
+    /// The mutex was unlocked and waiting began.
+    Started,
+    /// There was no notification yet, or the mutex is locked by someone else.
+    Waiting,
+    /// There was a notification and the mutex is locked again.
+    Done,
+}
+
+/// A counting semaphore.
+#[derive(Debug, Default)]
+pub struct Semaphore {
+    permits: StdMutex<u64>,
+    released: StdCondvar,
+}
+
+impl Semaphore {
+    pub fn new(permits: u64) -> Self {
+        Semaphore {
+            permits: StdMutex::new(permits),
+            released: StdCondvar::new(),
+        }
+    }
+
+    /// Returns whether a permit was taken.
+    pub fn try_acquire(&self) -> Result<bool, Fault> {
+        let mut permits = lock(&self.permits)?;
+        if *permits == 0 {
+            return Ok(false);
+        }
+        *permits -= 1;
+        Ok(true)
+    }
+
+    pub fn acquire(&self) -> Result<(), Fault> {
+        let mut permits = lock(&self.permits)?;
+        while *permits == 0 {
+            permits = self.released.wait(permits).map_err(|_| Fault::MemoryError("Poisoned".to_string()))?;
+        }
+        *permits -= 1;
+        Ok(())
+    }
+
+    pub fn release(&self) -> Result<(), Fault> {
+        let mut permits = lock(&self.permits)?;
+        *permits = permits.checked_add(1).ok_or(Fault::Overflow)?;
+        self.released.notify_one();
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+struct ChannelState {
+    queue: VecDeque<Value>,
+    closed: bool,
+}
+
+/// What `Channel::try_receive` got.
+#[derive(Debug)]
+pub enum Received {
+    Value(Value),
+    /// The channel was closed and is empty.
+    Closed,
+    Empty,
+}
+
+/// An unbounded channel of values that any thread can send to and receive from.
+#[derive(Debug, Default)]
+pub struct Channel {
+    state: StdMutex<ChannelState>,
+    sent: StdCondvar,
+}
+
+impl Channel {
+    pub fn send(&self, value: Value) -> Result<(), Fault> {
+        let mut state = lock(&self.state)?;
+        if state.closed {
+            return Err(Fault::ChannelClosed);
+        }
+        state.queue.push_back(value);
+        self.sent.notify_one();
+        Ok(())
+    }
+
+    /// Receives without blocking.
+    pub fn try_receive(&self) -> Result<Received, Fault> {
+        let mut state = lock(&self.state)?;
+        match state.queue.pop_front() {
+            Some(value) => Ok(Received::Value(value)),
+            None if state.closed => Ok(Received::Closed),
+            None => Ok(Received::Empty),
+        }
+    }
+
+    /// Blocks until there is a value, `None` means the channel was closed and is empty.
+    pub fn receive(&self) -> Result<Option<Value>, Fault> {
+        let mut state = lock(&self.state)?;
+        loop {
+            if let Some(value) = state.queue.pop_front() {
+                return Ok(Some(value));
+            }
+            if state.closed {
+                return Ok(None);
+            }
+            state = self.sent.wait(state).map_err(|_| Fault::MemoryError("Poisoned".to_string()))?;
+        }
+    }
+
+    /// Values that were already sent can still be received.
+    pub fn close(&self) -> Result<(), Fault> {
+        lock(&self.state)?.closed = true;
+        self.sent.notify_all();
+        Ok(())
+    }
+}
@@ -0,0 +1,321 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crate::backtrace::BacktraceInfo;
+use crate::instruction::{RegisterType, Target};
+use crate::machine::core::{Core, Limits};
+use crate::machine::fiber::DEFAULT_BUDGET;
+use crate::machine::native::{FromValue, IntoValue, NativeContext, ARGUMENT_REGISTER};
+use crate::machine::statistics::Statistics;
+use crate::machine::stdio::Stdio;
+use crate::machine::{run_function, Fault, InstructionResult};
+use crate::memory::{Memory, DEFAULT_COLLECTION_THRESHOLD};
+use crate::native_lib::get_std_module;
+use crate::program::function::{Function, FunctionPath};
+use crate::program::verifier::verify_module;
+use crate::program::Module;
+use crate::stack_frame::frame::Frame;
+use crate::stack_frame::REGISTER_COUNT;
+use crate::value::Value;
+
+
+/// A fault together with the backtrace of where it happened.
+pub struct VmError {
+    fault: Fault,
+    backtrace: BacktraceInfo,
+}
+
+impl VmError {
+    pub fn new(fault: Fault, backtrace: BacktraceInfo) -> Self {
+        VmError { fault, backtrace }
+    }
+
+    pub fn get_fault(&self) -> &Fault {
+        &self.fault
+    }
+
+    pub fn get_backtrace(&self) -> &BacktraceInfo {
+        &self.backtrace
+    }
+}
+
+/// A fault that didn't happen in running code, so it has no backtrace.
+impl From<Fault> for VmError {
+    fn from(fault: Fault) -> Self {
+        VmError::new(fault, BacktraceInfo::new())
+    }
+}
+
+impl Display for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Program faulted: {:?}", self.fault)?;
+        write!(f, "{}", self.backtrace)
+    }
+}
+
+impl Debug for VmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for VmError {}
+
+
+/// The arguments a function is called with from Rust, a tuple of values that are put in r8 onwards.
+pub trait IntoArguments {
+    fn into_arguments(self, memory: &mut Memory) -> Result<Vec<Value>, Fault>;
+}
+
+impl IntoArguments for () {
+    fn into_arguments(self, _memory: &mut Memory) -> Result<Vec<Value>, Fault> {
+        Ok(Vec::new())
+    }
+}
+
+/// Arguments whose number is only known at runtime.
+impl<T: IntoValue> IntoArguments for Vec<T> {
+    fn into_arguments(self, memory: &mut Memory) -> Result<Vec<Value>, Fault> {
+        self.into_iter().map(|argument| argument.into_value(memory)).collect()
+    }
+}
+
+macro_rules! impl_arguments {
+    ($($argument:ident),*) => {
+        impl<$($argument: IntoValue),*> IntoArguments for ($($argument,)*) {
+            #[allow(non_snake_case)]
+            fn into_arguments(self, memory: &mut Memory) -> Result<Vec<Value>, Fault> {
+                let ($($argument,)*) = self;
+                Ok(vec![$($argument.into_value(memory)?),*])
+            }
+        }
+    };
+}
+
+impl_arguments!(A);
+impl_arguments!(A, B);
+impl_arguments!(A, B, C);
+impl_arguments!(A, B, C, D);
+impl_arguments!(A, B, C, D, E);
+impl_arguments!(A, B, C, D, E, F);
+impl_arguments!(A, B, C, D, E, F, G);
+impl_arguments!(A, B, C, D, E, F, G, H);
+
+
+/// Sets up a `Vm`: the program and the modules and natives it can call, the limits it runs under and where its output goes.
+pub struct VmBuilder {
+    program: Module,
+    with_std: bool,
+    limits: Limits,
+    budget: u64,
+    collection_threshold: usize,
+    stdio: Stdio,
+    trace: bool,
+    statistics: bool,
+}
+
+impl VmBuilder {
+    /// Starts from `program` as the root module, `std` is added to it unless `without_std` is called.
+    pub fn new(program: Module) -> Self {
+        VmBuilder {
+            program,
+            with_std: true,
+            limits: Limits::default(),
+            budget: DEFAULT_BUDGET,
+            collection_threshold: DEFAULT_COLLECTION_THRESHOLD,
+            stdio: Stdio::default(),
+            trace: false,
+            statistics: false,
+        }
+    }
+
+    /// Adds a module under the root module.
+    pub fn add_module(mut self, module: Module) -> Self {
+        self.program.add_sub_module(module);
+        self
+    }
+
+    /// Adds a native function to the root module.
+    pub fn add_native_function(mut self, name: &str, function: impl Fn(&mut NativeContext) -> Result<InstructionResult, Fault> + Send + Sync + 'static) -> Self {
+        self.program.add_native_function(name, function);
+        self
+    }
+
+    /// Leaves out the standard library, so the program can only call the natives added to it.
+    pub fn without_std(mut self) -> Self {
+        self.with_std = false;
+        self
+    }
+
+    pub fn set_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Calls deeper than `max_call_depth` fault with `StackOverflow`.
+    pub fn set_max_call_depth(mut self, max_call_depth: usize) -> Self {
+        self.limits.max_call_depth = max_call_depth;
+        self
+    }
+
+    /// The stack size of the threads bytecode runs on.
+    pub fn set_stack_size(mut self, stack_size: usize) -> Self {
+        self.limits.stack_size = stack_size;
+        self
+    }
+
+    /// How many instructions a fiber runs before it is preempted, 0 turns preemption off.
+    pub fn set_budget(mut self, budget: u64) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    /// How many bytes can be allocated between garbage collections.
+    pub fn set_collection_threshold(mut self, collection_threshold: usize) -> Self {
+        self.collection_threshold = collection_threshold;
+        self
+    }
+
+    /// Sends what the program prints to `stdout` instead of the process's stdout.
+    pub fn set_stdout(mut self, stdout: impl Write + Send + 'static) -> Self {
+        self.stdio.set_stdout(Arc::new(Mutex::new(stdout)));
+        self
+    }
+
+    /// Sends what the program prints to stderr to `stderr` instead of the process's stderr.
+    pub fn set_stderr(mut self, stderr: impl Write + Send + 'static) -> Self {
+        self.stdio.set_stderr(Arc::new(Mutex::new(stderr)));
+        self
+    }
+
+    /// Prints every instruction the program executes to its stderr.
+    pub fn set_trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Counts the instructions, calls and collections of the program, read them with `Vm::get_statistics`.
+    pub fn set_statistics(mut self, statistics: bool) -> Self {
+        self.statistics = statistics;
+        self
+    }
+
+    /// Verifies the program and loads its strings and classes.
+    pub fn build(mut self) -> Result<Vm, VmError> {
+        if self.with_std {
+            self.program.add_sub_module(get_std_module());
+        }
+        let diagnostics = verify_module(&self.program);
+        if !diagnostics.is_empty() {
+            return Err(Fault::VerificationFailed(diagnostics).into());
+        }
+
+        let mut memory = Memory::new();
+        memory.set_collection_threshold(self.collection_threshold);
+        self.program.add_strings_to_memory(&mut memory);
+        self.program.add_classes_to_memory(&mut memory)?;
+
+        Ok(Vm {
+            module: Arc::new(self.program),
+            memory,
+            limits: self.limits,
+            budget: self.budget,
+            stdio: self.stdio,
+            trace: self.trace,
+            statistics: self.statistics.then(|| Arc::new(Statistics::new())),
+        })
+    }
+}
+
+
+/// What a call runs and the core it runs it on.
+struct CallStart {
+    core: Core,
+    function: Function,
+    frame: Frame,
+    module: Arc<Module>,
+    memory: Memory,
+}
+
+unsafe impl Send for CallStart {}
+
+/// What a call left in r0, or the fault it stopped with.
+struct CallResult(Result<Value, VmError>);
+
+unsafe impl Send for CallResult {}
+
+fn run_call(start: CallStart) -> CallResult {
+    let CallStart { mut core, function, frame, module, memory } = start;
+    let mut backtrace = BacktraceInfo::new();
+    let result = run_function(&mut core, function, frame, module, memory, &mut backtrace);
+    CallResult(result.map_err(|fault| VmError::new(fault, backtrace)))
+}
+
+
+/// A program loaded into memory, ready for its functions to be called from Rust.
+/// Every call runs on a thread of its own with the fibers it spawns, the heap lives as long as the `Vm`.
+pub struct Vm {
+    module: Arc<Module>,
+    memory: Memory,
+    limits: Limits,
+    budget: u64,
+    stdio: Stdio,
+    trace: bool,
+    statistics: Option<Arc<Statistics>>,
+}
+
+impl Vm {
+    pub fn builder(program: Module) -> VmBuilder {
+        VmBuilder::new(program)
+    }
+
+    pub fn get_module(&self) -> &Arc<Module> {
+        &self.module
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// What the calls so far have done, if the builder turned statistics on.
+    pub fn get_statistics(&self) -> Option<&Statistics> {
+        self.statistics.as_deref()
+    }
+
+    /// Runs the program's `main` function.
+    pub fn run_main(&mut self) -> Result<(), VmError> {
+        self.call("main", ())
+    }
+
+    /// Calls the function at `path` with `arguments` and converts what it returns in r0.
+    /// A fault comes with the backtrace of where it happened.
+    pub fn call<R: FromValue>(&mut self, path: &str, arguments: impl IntoArguments) -> Result<R, VmError> {
+        let path: FunctionPath = path.into();
+        let function = self.module.get_function(&path).ok_or_else(|| Fault::FunctionNotFound(path.clone()))?;
+        let frame = Frame::new(path, function.get_instructions());
+
+        let arguments = arguments.into_arguments(&mut self.memory)?;
+        if ARGUMENT_REGISTER + arguments.len() > REGISTER_COUNT {
+            return Err(Fault::InvalidRegister.into());
+        }
+        let mut core = Core::default();
+        core.limits = self.limits;
+        core.stdio = self.stdio.clone();
+        core.trace = self.trace;
+        core.statistics = self.statistics.clone();
+        core.scheduler.set_budget(self.budget);
+        for (index, argument) in arguments.into_iter().enumerate() {
+            core.set_value(&Target(ARGUMENT_REGISTER + index, RegisterType::U64), argument);
+        }
+
+        let start = CallStart { core, function, frame, module: self.module.clone(), memory: self.memory.clone() };
+        let value = std::thread::scope(|scope| {
+            let thread = std::thread::Builder::new()
+                .stack_size(self.limits.stack_size)
+                .spawn_scoped(scope, move || run_call(start))
+                .map_err(|error| Fault::IoError(error.to_string()))?;
+            thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)).0
+        })?;
+        Ok(R::from_value(value, &self.memory, &self.module)?)
+    }
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::assembly::disassembler::{disassemble, DisassemblyOptions};
+use crayfish_vm2::Vm;
+use common::{get_natives, PROGRAM};
+
+
+#[test]
+fn disassembly_reassembles_to_the_same_module() {
+    let options = DisassemblyOptions { source_positions: false };
+    let module = assemble(PROGRAM, "program.cfasm", &get_natives()).expect("the program assembles");
+    let text = disassemble(&module, &options);
+    let reassembled = assemble(&text, "disassembled.cfasm", &get_natives())
+        .unwrap_or_else(|error| panic!("the disassembly doesn't assemble: {}\n{}", error, text));
+    assert_eq!(disassemble(&reassembled, &options), text);
+
+    let mut vm = Vm::builder(reassembled).build().expect("the module verifies");
+    assert_eq!(vm.call::<u64>("main", ()).expect("main runs"), 97);
+}
+
+#[test]
+fn non_finite_floats_survive_the_round_trip() {
+    let source = "\
+.function main
+        load r0:f64, f64bits:0x7ff8000000000001
+        load r1:f32, f32bits:0xff800000
+        ret
+.end
+";
+    let options = DisassemblyOptions { source_positions: false };
+    let module = assemble(source, "floats.cfasm", &get_natives()).expect("the floats assemble");
+    let text = disassemble(&module, &options);
+    assert!(text.contains("f64bits:0x7ff8000000000001"), "{}", text);
+    assert!(text.contains("f32bits:0xff800000"), "{}", text);
+    let reassembled = assemble(&text, "disassembled.cfasm", &get_natives()).expect("the disassembly assembles");
+    assert_eq!(disassemble(&reassembled, &options), text);
+}
+
+#[test]
+fn errors_point_at_the_offending_token() {
+    let source = "\
+.function main
+        load r0:u64, 1u64
+        frobnicate r0:u64
+        ret
+.end
+";
+    let Err(error) = assemble(source, "bad.cfasm", &get_natives()) else {
+        panic!("frobnicate isn't an instruction");
+    };
+    assert_eq!((error.line, error.column), (3, 9), "{}", error);
+    assert!(error.message.contains("frobnicate"), "{}", error);
+}
+
+#[test]
+fn unknown_labels_and_natives_are_errors() {
+    let label = assemble(".function main\n        goto nowhere\n.end\n", "label.cfasm", &get_natives());
+    assert!(label.is_err());
+    let Err(native) = assemble(".function main\n        call host::missing\n        ret\n.end\n.module host\n.native missing\n.end\n", "native.cfasm", &get_natives()) else {
+        panic!("host::missing isn't registered");
+    };
+    assert!(native.message.contains("host::missing"), "{}", native);
+}
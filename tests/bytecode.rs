@@ -0,0 +1,52 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::program::bytecode::{load_module, serialize_module, LoadError, MAGIC};
+use crayfish_vm2::Vm;
+use common::{get_natives, PROGRAM};
+
+
+fn serialize_program() -> Vec<u8> {
+    let module = assemble(PROGRAM, "program.cfasm", &get_natives()).expect("the program assembles");
+    serialize_module(&module)
+}
+
+#[test]
+fn loaded_module_serializes_to_the_same_bytes() {
+    let bytes = serialize_program();
+    let module = load_module(&bytes, &get_natives()).expect("the module loads");
+    assert_eq!(serialize_module(&module), bytes);
+}
+
+#[test]
+fn loaded_module_runs() {
+    let module = load_module(&serialize_program(), &get_natives()).expect("the module loads");
+    let mut vm = Vm::builder(module).build().expect("the module verifies");
+    assert_eq!(vm.call::<u64>("main", ()).expect("main runs"), 97);
+}
+
+#[test]
+fn bad_magic_is_rejected() {
+    let mut bytes = serialize_program();
+    bytes[0] ^= 0xff;
+    assert!(matches!(load_module(&bytes, &get_natives()), Err(LoadError::BadMagic)));
+    assert!(matches!(load_module(b"", &get_natives()), Err(LoadError::BadMagic)));
+}
+
+#[test]
+fn truncated_input_is_rejected() {
+    let bytes = serialize_program();
+    for length in MAGIC.len()..bytes.len() {
+        match load_module(&bytes[..length], &get_natives()) {
+            Err(LoadError::UnexpectedEnd(_)) => {}
+            result => panic!("loading {} of {} bytes gave {:?}", length, bytes.len(), result.map(|_| ())),
+        }
+    }
+}
+
+#[test]
+fn trailing_bytes_are_rejected() {
+    let mut bytes = serialize_program();
+    bytes.push(0);
+    assert!(matches!(load_module(&bytes, &get_natives()), Err(LoadError::TrailingBytes(_))));
+}
@@ -0,0 +1,185 @@
+mod common;
+
+use std::ffi::{c_void, CStr, CString};
+use std::sync::Arc;
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::capi::*;
+use crayfish_vm2::machine::InstructionResult;
+use crayfish_vm2::program::bytecode::serialize_module;
+use common::get_natives;
+
+
+const SOURCE: &str = "
+.function add
+        add r8:i64, r9:i64
+        load r0:i64, r8:i64
+        ret
+.end
+
+.function hello
+        call host::greet
+        ret
+.end
+
+.function twice
+        call host::twice
+        ret
+.end
+
+.function twice_reference
+        getstr r1:u64, \"not a number\"
+        load r8:ref, r1:ref
+        call host::twice
+        ret
+.end
+
+.function broken
+        call inner
+        ret
+.end
+
+.function inner
+        call host::fail
+        ret
+.end
+
+.module host
+.native greet
+.native twice
+.native fail
+.end
+";
+
+extern "C" fn greet(context: *mut CrayfishContext, user_data: *mut c_void) -> i32 {
+    unsafe {
+        let mut length = 0;
+        let name = crayfish_context_get_string(context, 0, &mut length);
+        if name.is_null() {
+            crayfish_context_set_error(context, c"expected a string".as_ptr());
+            return CRAYFISH_ERROR;
+        }
+        let name = std::str::from_utf8(std::slice::from_raw_parts(name.cast(), length)).unwrap();
+        let greeting = format!("{}, {}!", CStr::from_ptr(user_data.cast()).to_str().unwrap(), name);
+        crayfish_context_set_string(context, greeting.as_ptr().cast(), greeting.len())
+    }
+}
+
+/// Doubles its argument, after reading it with every getter so that each one's status is checked.
+extern "C" fn twice(context: *mut CrayfishContext, _user_data: *mut c_void) -> i32 {
+    unsafe {
+        let (mut signed, mut unsigned, mut float) = (0i64, 0u64, 0f64);
+        for status in [
+            crayfish_context_get_i64(context, 0, &mut signed),
+            crayfish_context_get_u64(context, 0, &mut unsigned),
+            crayfish_context_get_f64(context, 0, &mut float),
+        ] {
+            if status != CRAYFISH_OK {
+                return status;
+            }
+        }
+        crayfish_context_set_i64(context, signed * 2);
+        CRAYFISH_OK
+    }
+}
+
+extern "C" fn fail(context: *mut CrayfishContext, _user_data: *mut c_void) -> i32 {
+    unsafe { crayfish_context_set_error(context, c"host said no".as_ptr()) };
+    1
+}
+
+/// A VM with the host natives registered and `SOURCE` loaded.
+fn new_vm(user_data: &CStr) -> *mut CrayfishVm {
+    let mut natives = get_natives();
+    for name in ["greet", "twice", "fail"] {
+        natives.register(&format!("host::{}", name), Arc::new(|_| Ok(InstructionResult::Continue)));
+    }
+    let bytes = serialize_module(&assemble(SOURCE, "test.cfasm", &natives).expect("the source assembles"));
+
+    let vm = crayfish_vm_new();
+    unsafe {
+        assert_eq!(crayfish_vm_register_native(vm, c"host::greet".as_ptr(), greet, user_data.as_ptr() as *mut c_void), CRAYFISH_OK);
+        assert_eq!(crayfish_vm_register_native(vm, c"host::twice".as_ptr(), twice, std::ptr::null_mut()), CRAYFISH_OK);
+        assert_eq!(crayfish_vm_register_native(vm, c"host::fail".as_ptr(), fail, std::ptr::null_mut()), CRAYFISH_OK);
+        assert_eq!(crayfish_vm_load_module(vm, bytes.as_ptr(), bytes.len()), CRAYFISH_OK);
+    }
+    vm
+}
+
+fn number(kind: CrayfishKind, i64: i64) -> CrayfishValue {
+    CrayfishValue { kind, i64, u64: 0, f64: 0.0, string: std::ptr::null(), length: 0 }
+}
+
+fn string(string: &str) -> CrayfishValue {
+    CrayfishValue { kind: CrayfishKind::String, i64: 0, u64: 0, f64: 0.0, string: string.as_ptr().cast(), length: string.len() }
+}
+
+unsafe fn call(vm: *mut CrayfishVm, path: &str, arguments: &[CrayfishValue], result: &mut CrayfishValue) -> i32 {
+    let path = CString::new(path).unwrap();
+    crayfish_vm_call(vm, path.as_ptr(), arguments.as_ptr(), arguments.len(), result)
+}
+
+unsafe fn get_error(vm: *mut CrayfishVm) -> String {
+    CStr::from_ptr(crayfish_vm_get_error(vm)).to_string_lossy().into_owned()
+}
+
+#[test]
+fn numbers_and_strings_go_in_and_out() {
+    let vm = new_vm(c"Hello");
+    unsafe {
+        let mut result = number(CrayfishKind::I64, 0);
+        assert_eq!(call(vm, "add", &[number(CrayfishKind::I64, 40), number(CrayfishKind::I64, 2)], &mut result), CRAYFISH_OK);
+        assert_eq!(result.i64, 42);
+
+        let mut result = string("");
+        assert_eq!(call(vm, "hello", &[string("crab")], &mut result), CRAYFISH_OK);
+        let greeting = std::slice::from_raw_parts(result.string.cast::<u8>(), result.length);
+        assert_eq!(greeting, b"Hello, crab!");
+        crayfish_vm_free(vm);
+    }
+}
+
+#[test]
+fn getters_return_ok_for_numbers_and_error_for_references() {
+    let vm = new_vm(c"Hello");
+    unsafe {
+        let mut result = number(CrayfishKind::I64, 0);
+        assert_eq!(call(vm, "twice", &[number(CrayfishKind::I64, 21)], &mut result), CRAYFISH_OK);
+        assert_eq!(result.i64, 42);
+
+        // The native returns the status of the failed getter, which faults with the getter's reason.
+        assert_eq!(call(vm, "twice_reference", &[], &mut result), CRAYFISH_ERROR);
+        let error = get_error(vm);
+        assert!(error.contains("argument 0 is"), "{}", error);
+        crayfish_vm_free(vm);
+    }
+}
+
+#[test]
+fn native_error_comes_with_a_backtrace() {
+    let vm = new_vm(c"Hello");
+    unsafe {
+        let mut result = number(CrayfishKind::I64, 0);
+        assert_eq!(call(vm, "broken", &[], &mut result), CRAYFISH_ERROR);
+        assert!(get_error(vm).contains("host said no"), "{}", get_error(vm));
+        let backtrace = CStr::from_ptr(crayfish_vm_get_backtrace(vm)).to_string_lossy();
+        assert!(backtrace.contains("broken") && backtrace.contains("inner"), "{}", backtrace);
+
+        // A successful call clears the error.
+        assert_eq!(call(vm, "add", &[number(CrayfishKind::I64, 1), number(CrayfishKind::I64, 2)], &mut result), CRAYFISH_OK);
+        assert!(crayfish_vm_get_error(vm).is_null());
+        crayfish_vm_free(vm);
+    }
+}
+
+#[test]
+fn setup_after_the_first_call_fails() {
+    let vm = new_vm(c"Hello");
+    unsafe {
+        let mut result = number(CrayfishKind::I64, 0);
+        assert_eq!(call(vm, "missing", &[], &mut result), CRAYFISH_ERROR);
+        assert!(get_error(vm).contains("FunctionNotFound"), "{}", get_error(vm));
+        assert_eq!(crayfish_vm_register_native(vm, c"host::late".as_ptr(), fail, std::ptr::null_mut()), CRAYFISH_ERROR);
+        assert!(get_error(vm).contains("already started"), "{}", get_error(vm));
+        crayfish_vm_free(vm);
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+
+/// Writes `source` to a file of its own and returns the exit status of running it with the binary.
+fn run_source(name: &str, source: &str) -> Option<i32> {
+    let file: PathBuf = std::env::temp_dir().join(format!("crayfish-cli-{}-{}.cfasm", std::process::id(), name));
+    std::fs::write(&file, source).expect("the module is written");
+    let output = Command::new(env!("CARGO_BIN_EXE_crayfish-vm2"))
+        .arg("run")
+        .arg(&file)
+        .output()
+        .expect("the binary runs");
+    std::fs::remove_file(&file).expect("the module is removed");
+    output.status.code()
+}
+
+fn run_status(name: &str, status: &str) -> Option<i32> {
+    run_source(name, &format!("
+.function main
+        load r0:i64, {}
+        ret
+.end
+", status))
+}
+
+#[test]
+fn run_exits_with_the_status_main_returns() {
+    assert_eq!(run_status("zero", "0i64"), Some(0));
+    assert_eq!(run_status("three", "3i64"), Some(3));
+    assert_eq!(run_status("largest", "255i64"), Some(255));
+}
+
+#[test]
+fn run_exits_with_255_for_a_status_out_of_range() {
+    assert_eq!(run_status("too_large", "300i64"), Some(255));
+    assert_eq!(run_status("negative", "-1i64"), Some(255));
+}
+
+#[test]
+fn run_exits_with_1_when_main_faults() {
+    let status = run_source("fault", "
+.function main
+        load r1:u64, 0u64
+        accessobj r0:i64, r1:ref, 0u64
+        ret
+.end
+");
+    assert_eq!(status, Some(1));
+}
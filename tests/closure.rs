@@ -0,0 +1,81 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::machine::Fault;
+use crayfish_vm2::value::Value;
+use crayfish_vm2::Vm;
+use common::get_natives;
+
+
+const SOURCE: &str = "
+.function add_captured
+        getcap r0:i64, 0u64
+        add r0:i64, r8:i64
+        getcap r1:i64, 1u64
+        mul r0:i64, r1:i64
+        ret
+.end
+
+; returns a closure over r8 and r9, whose registers are overwritten before it returns
+.function make_adder
+        mkclosure r0:ref, add_captured, r8:i64, r9:i64
+        load r8:i64, 0i64
+        load r9:i64, 0i64
+        ret
+.end
+
+.function apply_twice
+        load r10:ref, r8:ref
+        load r8:i64, 5i64
+        call closure r10:ref
+        load r8:i64, r0:i64
+        call closure r10:ref
+        ret
+.end
+
+.function call_made_adder
+        load r8:i64, 10i64
+        load r9:i64, 2i64
+        call make_adder
+        load r8:ref, r0:ref
+        call apply_twice
+        ret
+.end
+
+.function missing_capture
+        getcap r0:i64, 2u64
+        ret
+.end
+
+.function call_missing_capture
+        mkclosure r1:ref, missing_capture, 1i64
+        call closure r1:ref
+        ret
+.end
+";
+
+fn build() -> Vm {
+    let module = assemble(SOURCE, "test.cfasm", &get_natives()).expect("the source assembles");
+    Vm::builder(module).build().expect("the module verifies")
+}
+
+#[test]
+fn captures_outlive_the_frame_that_made_the_closure() {
+    let mut vm = build();
+    // (5 + 10) * 2 and then (30 + 10) * 2.
+    assert_eq!(vm.call::<i64>("call_made_adder", ()).expect("call_made_adder runs"), 80);
+}
+
+#[test]
+fn closure_made_by_one_call_can_be_called_by_another() {
+    let mut vm = build();
+    let adder: Value = vm.call("make_adder", (1i64, 3i64)).expect("make_adder runs");
+    assert_eq!(vm.call::<i64>("apply_twice", (adder,)).expect("apply_twice runs"), 57);
+}
+
+#[test]
+fn missing_capture_faults() {
+    let mut vm = build();
+    let error = vm.call::<i64>("call_missing_capture", ()).expect_err("there is only one capture");
+    assert!(matches!(error.get_fault(), Fault::InvalidCaptureIndex(2)), "{:?}", error);
+}
@@ -0,0 +1,103 @@
+// Each test binary includes this module and uses only part of it.
+#![allow(dead_code)]
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::native_lib::get_std_module;
+use crayfish_vm2::program::function::NativeRegistry;
+use crayfish_vm2::{Vm, VmBuilder, VmError};
+
+
+/// A module that uses strings, classes, closures, labels, floats and a sub module.
+/// `main` returns 97.
+pub const PROGRAM: &str = r#"
+.string greeting "hello"
+
+.class Point
+    .field x, i64
+    .field y, i64
+.end
+
+.function main
+        newobj r1:ref, Point
+        storeobj r1:ref, 0u64, 3i64
+        storeobj r1:ref, 1u64, 4i64
+        accessobj r2:i64, r1:ref, 0u64
+        accessobj r3:i64, r1:ref, 1u64
+        add r2:i64, r3:i64
+        mkclosure r4:ref, math::scale, 6i64
+        load r8:i64, r2:i64
+        call closure r4:ref
+        load r5:i64, r0:i64
+        load r8:u64, 10u64
+        call math::sum_to
+        add r0:u64, r5:u64
+        getstr r6:u64, greeting
+        getstr r7:u64, "inline \u{1F980} string"
+        load r9:f64, f64bits:0x7ff8000000000000
+        load r10:f32, -1.5f32
+        ret
+.end
+
+.module math
+.function scale
+        getcap r0:i64, 0u64
+        mul r0:i64, r8:i64
+        ret
+.end
+
+.function sum_to
+        load r0:u64, 0u64
+loop:   add r0:u64, r8:u64
+        sub r8:u64, 1u64
+        cmp r8:u64, 0u64, gt
+        goto loop, gt
+        ret
+.end
+.end
+"#;
+
+/// The standard library, which is all `PROGRAM` imports.
+pub fn get_natives() -> NativeRegistry {
+    let mut natives = NativeRegistry::new();
+    natives.register_module(&get_std_module());
+    natives
+}
+
+/// What a program printed, shared with the VM it was given to.
+#[derive(Clone, Default)]
+pub struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    pub fn get_lines(&self) -> Vec<String> {
+        let output = self.0.lock().unwrap();
+        String::from_utf8_lossy(&output).lines().map(str::to_string).collect()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buffer: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A builder for `source` that prints into the returned output.
+pub fn builder(source: &str) -> (VmBuilder, Output) {
+    let module = assemble(source, "test.cfasm", &get_natives()).expect("the source assembles");
+    let output = Output::default();
+    (Vm::builder(module).set_stdout(output.clone()), output)
+}
+
+/// Runs `main` of `source` and returns how it finished and the lines it printed.
+pub fn run_main(source: &str) -> (Result<(), VmError>, Vec<String>) {
+    let (builder, output) = builder(source);
+    let mut vm = builder.build().expect("the module verifies");
+    let result = vm.run_main();
+    (result, output.get_lines())
+}
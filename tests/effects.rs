@@ -0,0 +1,129 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::machine::Fault;
+use crayfish_vm2::Vm;
+use common::get_natives;
+
+
+const SOURCE: &str = "
+.function resume_with_value
+        handle ask, on_ask, r9:u64
+        call asker
+        ret
+on_ask: load r0:u64, 42u64
+        call cont r9:u64
+        ret
+.end
+
+.function asker
+        load r0:u64, 0u64
+        perform ask
+        ret
+.end
+
+.function escape_main
+        call escape
+        ret
+.end
+
+.function escape
+        perform boom
+        ret
+.end
+
+; captures the frames of body and inner up to the prompt p
+.function resume_multi_frame
+        pushprompt p
+        call body
+        load r0:u64, 100u64
+        call cont r1:u64
+        ret
+.end
+
+.function resume_one_shot_twice
+        handle.once ask, on_ask, r9:u64
+        call asker
+        ret
+on_ask: call cont r9:u64
+        call cont r9:u64
+        ret
+.end
+
+; resumes a continuation of two frames twice, with r21 changed in between
+.function resume_multi_shot_twice
+        pushprompt p
+        call body
+        load r15:u64, r1:u64
+        load r0:u64, 100u64
+        call cont r15:u64
+        load r16:u64, r0:u64
+        load r21:u64, 0u64
+        load r0:u64, 200u64
+        call cont r15:u64
+        mul r16:u64, 10000u64
+        add r0:u64, r16:u64
+        ret
+.end
+
+.function body
+        call inner
+        add r0:u64, 1u64
+        ret
+.end
+
+.function inner
+        load r21:u64, 1000u64
+        mkcont r1:u64, p
+        goto resumed, cont
+        load r0:u64, 10u64
+        ret
+resumed:
+        add r0:u64, r21:u64
+        load r21:u64, 0u64
+        ret
+.end
+";
+
+fn build() -> Vm {
+    let module = assemble(SOURCE, "test.cfasm", &get_natives()).expect("the source assembles");
+    Vm::builder(module).build().expect("the module verifies")
+}
+
+#[test]
+fn resumed_handler_delivers_its_value_in_r0() {
+    let mut vm = build();
+    assert_eq!(vm.call::<u64>("resume_with_value", ()).expect("resume_with_value runs"), 42);
+}
+
+#[test]
+fn effect_escaping_main_is_unhandled() {
+    let mut vm = build();
+    let error = vm.call::<()>("escape_main", ()).expect_err("nothing handles boom");
+    assert!(matches!(error.get_fault(), Fault::UnhandledEffect(effect) if &**effect == "boom"), "{:?}", error);
+    let backtrace = error.get_backtrace();
+    let functions: Vec<_> = (0..backtrace.len()).map(|index| backtrace.get(index).unwrap().get_function_name()).collect();
+    assert_eq!(functions, ["escape_main", "escape"]);
+}
+
+#[test]
+fn continuation_resumes_every_frame_up_to_the_prompt() {
+    let mut vm = build();
+    // inner adds the resumed value to r21, body adds 1 when inner returns.
+    assert_eq!(vm.call::<u64>("resume_multi_frame", ()).expect("resume_multi_frame runs"), 1101);
+}
+
+#[test]
+fn one_shot_continuation_resumed_twice_faults() {
+    let mut vm = build();
+    let error = vm.call::<u64>("resume_one_shot_twice", ()).expect_err("the second resume faults");
+    assert!(matches!(error.get_fault(), Fault::ContinuationAlreadyResumed(_)), "{:?}", error);
+}
+
+#[test]
+fn multi_shot_continuation_resumes_from_its_captured_state() {
+    let mut vm = build();
+    // Each resume runs inner and body again from r21 being 1000: 100 + 1000 + 1 and 200 + 1000 + 1.
+    let result = vm.call::<u64>("resume_multi_shot_twice", ()).expect("resume_multi_shot_twice runs");
+    assert_eq!(result, 1101 * 10000 + 1201);
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use std::time::{Duration, Instant};
+use common::{builder, run_main};
+
+
+const INTERLEAVING: &str = "
+.function worker
+        load r10:u64, r8:u64
+        call std::io::println_u64
+        call std::fiber::yield
+        load r8:u64, r10:u64
+        add r8:u64, 1u64
+        call std::io::println_u64
+        call std::fiber::yield
+        load r0:u64, r10:u64
+        add r0:u64, r10:u64
+        ret
+.end
+
+.function main
+        mkclosure r20:ref, worker
+        load r8:ref, r20:ref
+        load r9:u64, 10u64
+        call std::fiber::spawn
+        load r21:u64, r0:u64
+        load r8:ref, r20:ref
+        load r9:u64, 20u64
+        call std::fiber::spawn
+        load r22:u64, r0:u64
+        load r8:u64, r21:u64
+        call std::fiber::join
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        load r8:u64, r22:u64
+        call std::fiber::join
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+";
+
+#[test]
+fn fibers_interleave_the_same_way_every_run() {
+    let (result, first) = run_main(INTERLEAVING);
+    result.expect("main runs");
+    let (result, second) = run_main(INTERLEAVING);
+    result.expect("main runs");
+    assert_eq!(first, ["10", "20", "11", "21", "20", "40"]);
+    assert_eq!(first, second);
+}
+
+#[test]
+fn sleepers_wake_in_order_of_their_deadline() {
+    let (result, output) = run_main("
+.function sleeper
+        load r10:u64, r8:u64
+        call std::fiber::sleep
+        load r8:u64, r10:u64
+        call std::io::println_u64
+        ret
+.end
+
+.function main
+        mkclosure r20:ref, sleeper
+        load r8:ref, r20:ref
+        load r9:u64, 30u64
+        call std::fiber::spawn
+        load r21:u64, r0:u64
+        load r8:ref, r20:ref
+        load r9:u64, 10u64
+        call std::fiber::spawn
+        load r8:ref, r20:ref
+        load r9:u64, 20u64
+        call std::fiber::spawn
+        load r8:u64, r21:u64
+        call std::fiber::join
+        call std::fiber::now
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["10", "20", "30", "30"]);
+}
+
+#[test]
+fn virtual_sleep_returns_immediately() {
+    let start = Instant::now();
+    let (result, output) = run_main("
+.function main
+        load r8:u64, 5000u64
+        call std::fiber::sleep
+        call std::fiber::now
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["5000"]);
+    assert!(start.elapsed() < Duration::from_secs(1), "{:?}", start.elapsed());
+}
+
+#[test]
+fn joining_a_fiber_that_faulted_returns_the_fault_with_status_1() {
+    let (result, output) = run_main("
+.function bad
+        load r1:u64, 0u64
+        accessobj r0:ref, r1:ref, 0u64
+        ret
+.end
+
+.function main
+        mkclosure r20:ref, bad
+        load r8:ref, r20:ref
+        call std::fiber::spawn
+        load r8:u64, r0:u64
+        call std::fiber::join
+        load r21:u64, r1:u64
+        load r8:ref, r0:ref
+        call std::io::println_string
+        load r8:u64, r21:u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["NullPointerReference", "1"]);
+}
+
+/// A fiber counts to 100000 while another one reads the count once.
+const SPINNER: &str = "
+.function spinner
+        getcap r10:u64, 0u64
+        load r11:u64, 0u64
+loop:   fetchadd r1:u64, r10:u64, 0u64, 1u64
+        add r11:u64, 1u64
+        cmp r11:u64, 100000u64, lt
+        goto loop, lt
+        ret
+.end
+
+.function peek
+        getcap r10:u64, 0u64
+        fetchadd r0:u64, r10:u64, 0u64, 0u64
+        ret
+.end
+
+.function main
+        load r8:u64, 1u64
+        call std::sync::cells
+        load r23:u64, r0:u64
+        mkclosure r20:ref, spinner, r23:u64
+        load r8:ref, r20:ref
+        call std::fiber::spawn
+        load r21:u64, r0:u64
+        mkclosure r20:ref, peek, r23:u64
+        load r8:ref, r20:ref
+        call std::fiber::spawn
+        load r8:u64, r0:u64
+        call std::fiber::join
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        load r8:u64, r21:u64
+        call std::fiber::join
+        ret
+.end
+";
+
+fn peek_spinner(budget: u64) -> u64 {
+    let (builder, output) = builder(SPINNER);
+    let mut vm = builder.set_budget(budget).build().expect("the module verifies");
+    vm.run_main().expect("main runs");
+    output.get_lines()[0].parse().expect("peek prints a number")
+}
+
+#[test]
+fn budget_preempts_a_busy_fiber() {
+    let count = peek_spinner(1000);
+    assert!(count > 0 && count < 100000, "{}", count);
+    // Preemption counts instructions, so it happens at the same point every run.
+    assert_eq!(peek_spinner(1000), count);
+    // Without a budget the spinner only stops when it is done.
+    assert_eq!(peek_spinner(0), 100000);
+}
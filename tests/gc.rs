@@ -0,0 +1,125 @@
+mod common;
+
+use crayfish_vm2::machine::statistics::Counter;
+use common::{builder, run_main};
+
+
+#[test]
+fn reachable_lists_survive_and_unreachable_lists_are_freed() {
+    let source = "
+.function read_capture
+        getcap r0:ref, 0u64
+        ret
+.end
+
+.function main
+        ; held by a register
+        newlist r10:ref, 1u64
+        listset r10:u64, 0u64, 1u64
+        ; held by a list that is held by a register
+        newlist r11:ref, 1u64
+        newlist r1:ref, 1u64
+        listset r1:u64, 0u64, 2u64
+        listset r11:u64, 0u64, r1:ref
+        ; held by a closure capture
+        newlist r1:ref, 1u64
+        listset r1:u64, 0u64, 3u64
+        mkclosure r12:ref, read_capture, r1:ref
+        ; held by nothing
+        newlist r1:ref, 1u64
+        newlist r1:ref, 1u64
+        load r1:u64, 0u64
+        call std::gc::collect
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        call std::gc::collect
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        listget r8:u64, r10:u64, 0u64
+        call std::io::println_u64
+        listget r1:ref, r11:u64, 0u64
+        listget r8:u64, r1:u64, 0u64
+        call std::io::println_u64
+        call closure r12:ref
+        listget r8:u64, r0:u64, 0u64
+        call std::io::println_u64
+        ret
+.end
+";
+    let (result, output) = run_main(source);
+    result.expect("main runs");
+    assert_eq!(output, ["2", "0", "1", "2", "3"]);
+}
+
+#[test]
+fn list_held_by_a_stored_continuation_survives() {
+    let source = "
+.function main
+        handle ask, on_ask, r9:u64
+        call asker
+        ret
+on_ask: load r10:u64, 0u64
+        call std::gc::collect
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        call cont r9:u64
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+
+.function asker
+        newlist r10:ref, 1u64
+        listset r10:u64, 0u64, 42u64
+        perform ask
+        listget r0:u64, r10:u64, 0u64
+        ret
+.end
+";
+    let (result, output) = run_main(source);
+    result.expect("main runs");
+    assert_eq!(output, ["0", "42"]);
+}
+
+#[test]
+fn collection_skipped_while_a_thread_runs_is_deferred_until_it_finishes() {
+    let source = "
+.function wait
+        getcap r8:u64, 0u64
+        call std::sync::receive
+        ret
+.end
+
+.function main
+        call std::sync::new_channel
+        load r10:u64, r0:u64
+        mkclosure r11:ref, wait, r10:u64
+        load r8:ref, r11:ref
+        call std::thread::spawn
+        load r12:u64, r0:u64
+        newlist r1:ref, 1u64
+        newlist r1:ref, 1u64
+        load r1:u64, 0u64
+        call std::gc::collect
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        load r8:u64, r10:u64
+        load r9:u64, 1u64
+        call std::sync::send
+        load r8:u64, r12:u64
+        call std::thread::join
+        nop
+        call std::gc::collect
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+";
+    let (builder, output) = builder(source);
+    let mut vm = builder.set_statistics(true).build().expect("the module verifies");
+    vm.run_main().expect("main runs");
+    // The garbage was freed by the deferred collection as soon as the thread was joined.
+    assert_eq!(output.get_lines(), ["0", "0"]);
+    let statistics = vm.get_statistics().expect("statistics are on");
+    assert_eq!(statistics.get(Counter::Collections), 2);
+}
@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crayfish_vm2::instruction::{ComparisonType, Condition, Immediate, Instruction, JumpTarget, RealInstruction, RegisterType, Source, Target};
+use crayfish_vm2::machine::Fault;
+use crayfish_vm2::program::function::{Function, LabelTable};
+use crayfish_vm2::program::Module;
+use crayfish_vm2::Vm;
+
+
+/// Counts r0 up to 3 with a jump back to the label `again`, while the label table only has `label` at `address`.
+fn count_to_three(label: &str, address: usize) -> Function {
+    let r0 = Target(0, RegisterType::U64);
+    let instructions = [
+        RealInstruction::Load(r0.clone(), Source::Immediate(Immediate::U64(0))),
+        RealInstruction::Add(r0.clone(), Source::Immediate(Immediate::U64(1)), false, false),
+        RealInstruction::Compare(r0, Source::Immediate(Immediate::U64(3)), ComparisonType::LessThan),
+        RealInstruction::Goto(JumpTarget::Label("again".into()), Condition::LessThan),
+        RealInstruction::Return(Condition::Always),
+    ];
+    let instructions = instructions.into_iter().map(Instruction::new_without_metadata).collect::<Vec<_>>();
+    let labels = LabelTable::from([(label.into(), address)]);
+    Function::ByteCode(Arc::from(instructions), Arc::new(labels))
+}
+
+fn module_with(function: Function) -> Module {
+    let functions = HashMap::from([("main".into(), function)]);
+    Module::new("test", functions, Vec::new(), HashMap::new())
+}
+
+#[test]
+fn functions_given_to_new_have_their_labels_resolved() {
+    let mut vm = Vm::builder(module_with(count_to_three("again", 1))).build().expect("the module verifies");
+    assert_eq!(vm.call::<u64>("main", ()).expect("main runs"), 3);
+}
+
+#[test]
+fn missing_label_of_a_function_given_to_new_is_reported_by_the_verifier() {
+    let Err(error) = Vm::builder(module_with(count_to_three("elsewhere", 1))).build() else {
+        panic!("the module verifies");
+    };
+    let Fault::VerificationFailed(diagnostics) = error.get_fault() else {
+        panic!("{:?}", error);
+    };
+    assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+    assert!(diagnostics[0].message.contains("unresolved label again"), "{:?}", diagnostics);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use std::collections::HashMap;
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::machine::native::NativeContext;
+use crayfish_vm2::machine::{Fault, InstructionResult};
+use crayfish_vm2::program::Module;
+use crayfish_vm2::{native, Vm};
+use common::get_natives;
+
+
+const SOURCE: &str = "
+.function scale_number
+        load r8:f64, 1.5f64
+        load r9:i64, 4i64
+        call host::scale
+        ret
+.end
+
+.function scale_string
+        getstr r1:u64, \"not a number\"
+        load r8:ref, r1:ref
+        load r9:i64, 4i64
+        call host::scale
+        ret
+.end
+
+.function negate_number
+        load r8:i64, -7i64
+        call host::negate
+        ret
+.end
+
+.function negate_reference
+        getstr r1:u64, \"not a number\"
+        load r8:ref, r1:ref
+        call host::negate
+        ret
+.end
+
+.module host
+.native scale
+.native negate
+.end
+";
+
+#[native]
+fn scale(value: f64, factor: i64) -> f64 {
+    value * factor as f64
+}
+
+fn negate(context: &mut NativeContext) -> Result<InstructionResult, Fault> {
+    let value = context.get_i64(0)?;
+    context.set_return(0, -value);
+    Ok(InstructionResult::Continue)
+}
+
+fn build() -> Vm {
+    let mut host = Module::new("host", HashMap::new(), Vec::new(), HashMap::new());
+    scale::register(&mut host);
+    host.add_native_function("negate", negate);
+    let mut natives = get_natives();
+    natives.register_module(&host);
+    let module = assemble(SOURCE, "native.cfasm", &natives).expect("the source assembles");
+    Vm::builder(module).build().expect("the module verifies")
+}
+
+#[test]
+fn typed_arguments_are_converted() {
+    let mut vm = build();
+    assert_eq!(vm.call::<f64>("scale_number", ()).expect("scale runs"), 6.0);
+    assert_eq!(vm.call::<i64>("negate_number", ()).expect("negate runs"), 7);
+}
+
+#[test]
+fn argument_of_the_wrong_type_is_a_fault() {
+    let mut vm = build();
+    let error = vm.call::<f64>("scale_string", ()).expect_err("a string isn't a number");
+    assert!(matches!(error.get_fault(), Fault::InvalidOperation(_)), "{:?}", error);
+    let error = vm.call::<i64>("negate_reference", ()).expect_err("a reference isn't an i64");
+    assert!(matches!(error.get_fault(), Fault::InvalidOperation(_)), "{:?}", error);
+}
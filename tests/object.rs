@@ -0,0 +1,197 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::machine::Fault;
+use crayfish_vm2::value::Value;
+use crayfish_vm2::Vm;
+use common::get_natives;
+
+
+const SOURCE: &str = "
+.class Point
+    .field x, i64
+    .field y, i64
+.end
+
+.class Point3, Point
+    .field z, i64
+.end
+
+; Point3 has the fields of Point first
+.function inherited_fields
+        newobj r1:ref, Point3
+        storeobj r1:ref, 0u64, 3i64
+        storeobj r1:ref, 1u64, 20i64
+        storeobj r1:ref, 2u64, 100i64
+        accessobj r2:i64, r1:ref, 0u64
+        accessobj r3:i64, r1:ref, 1u64
+        accessobj r0:i64, r1:ref, 2u64
+        add r0:i64, r2:i64
+        add r0:i64, r3:i64
+        ret
+.end
+
+.function signed_field_index
+        newobj r1:ref, Point
+        load r2:i64, 1i64
+        storeobj r1:ref, r2:i64, 5i64
+        load r3:u32, 1u32
+        accessobj r0:i64, r1:ref, r3:u32
+        ret
+.end
+
+.function field_index_out_of_range
+        newobj r1:ref, Point
+        accessobj r0:i64, r1:ref, 2u64
+        ret
+.end
+
+.function float_field_index
+        newobj r1:ref, Point
+        accessobj r0:i64, r1:ref, 1.0f64
+        ret
+.end
+
+.function null_object
+        load r1:u64, 0u64
+        storeobj r1:ref, 0u64, 1i64
+        ret
+.end
+
+.function wrong_value_type
+        newobj r1:ref, Point
+        storeobj r1:ref, 0u64, 1.5f64
+        ret
+.end
+
+.class Shape
+    .field size, i64
+    .method area, shape_area
+    .method describe, shape_describe
+.end
+
+.class Square, Shape
+    .method area, square_area
+.end
+
+.function shape_area
+        load r0:i64, 1i64
+        ret
+.end
+
+.function shape_describe
+        accessobj r0:i64, r8:ref, 0u64
+        add r0:i64, 1000i64
+        ret
+.end
+
+.function square_area
+        accessobj r0:i64, r8:ref, 0u64
+        mul r0:i64, r0:i64
+        ret
+.end
+
+; the area of the object in r8, always called from the same call site
+.function area
+        call vtable r8:ref, 0u64
+        ret
+.end
+
+.function new_square
+        newobj r0:ref, Square
+        storeobj r0:ref, 0u64, r8:i64
+        ret
+.end
+
+.function new_shape
+        newobj r0:ref, Shape
+        storeobj r0:ref, 0u64, r8:i64
+        ret
+.end
+
+.function describe_square
+        call new_square
+        load r8:ref, r0:ref
+        call vtable r8:ref, 1u64
+        ret
+.end
+
+; the areas of a square, a shape, the square and the shape again as the digit pairs of r0
+.function alternating_areas
+        load r8:i64, 7i64
+        call new_square
+        load r10:ref, r0:ref
+        call new_shape
+        load r11:ref, r0:ref
+        load r12:i64, 0i64
+        load r13:u64, 0u64
+loop:   mul r12:i64, 100i64
+        load r8:ref, r10:ref
+        call area
+        add r12:i64, r0:i64
+        mul r12:i64, 100i64
+        load r8:ref, r11:ref
+        call area
+        add r12:i64, r0:i64
+        add r13:u64, 1u64
+        cmp r13:u64, 2u64, lt
+        goto loop, lt
+        load r0:i64, r12:i64
+        ret
+.end
+";
+
+fn build() -> Vm {
+    let module = assemble(SOURCE, "test.cfasm", &get_natives()).expect("the source assembles");
+    Vm::builder(module).build().expect("the module verifies")
+}
+
+fn call_fault(function: &str) -> Fault {
+    build().call::<i64>(function, ()).expect_err("the call faults").get_fault().clone()
+}
+
+#[test]
+fn subclass_fields_follow_the_fields_of_its_super_class() {
+    assert_eq!(build().call::<i64>("inherited_fields", ()).expect("inherited_fields runs"), 123);
+}
+
+#[test]
+fn field_index_can_be_any_integer() {
+    assert_eq!(build().call::<i64>("signed_field_index", ()).expect("signed_field_index runs"), 5);
+}
+
+#[test]
+fn bad_field_index_faults() {
+    let fault = call_fault("field_index_out_of_range");
+    assert!(matches!(fault, Fault::InvalidFieldIndex(2)), "{:?}", fault);
+    let fault = call_fault("float_field_index");
+    assert!(matches!(fault, Fault::InvalidFieldIndex(_)), "{:?}", fault);
+}
+
+#[test]
+fn null_object_faults() {
+    let fault = call_fault("null_object");
+    assert!(matches!(fault, Fault::NullPointerReference), "{:?}", fault);
+}
+
+#[test]
+fn value_of_the_wrong_type_faults() {
+    let fault = call_fault("wrong_value_type");
+    assert!(matches!(fault, Fault::InvalidOperation(_)), "{:?}", fault);
+}
+
+#[test]
+fn subclass_method_overrides_the_super_class_method() {
+    let mut vm = build();
+    let square: Value = vm.call("new_square", (7i64,)).expect("new_square runs");
+    assert_eq!(vm.call::<i64>("area", (square,)).expect("area runs"), 49);
+    // Methods that aren't overridden are inherited.
+    assert_eq!(vm.call::<i64>("describe_square", (7i64,)).expect("describe_square runs"), 1007);
+}
+
+#[test]
+fn call_site_seeing_another_class_calls_its_method() {
+    let mut vm = build();
+    // The call site in area is cached for Square, then has to miss for Shape and again for Square.
+    assert_eq!(vm.call::<i64>("alternating_areas", ()).expect("alternating_areas runs"), 49014901);
+}
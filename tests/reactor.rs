@@ -0,0 +1,101 @@
+mod common;
+
+use common::run_main;
+
+
+/// Reads up to 16 bytes from the file descriptor captured first, printing 1 before it starts.
+const READER: &str = "
+.function reader
+        load r8:u64, 1u64
+        call std::io::println_u64
+        getcap r8:u64, 0u64
+        load r9:u64, 16u64
+        call std::io::read
+        ret
+.end
+";
+
+#[test]
+fn fiber_parked_on_an_empty_pipe_wakes_with_what_is_written() {
+    let source = format!("{}
+.function main
+        call std::io::pipe
+        load r10:u64, r0:u64
+        load r11:u64, r1:u64
+        mkclosure r12:ref, reader, r10:u64
+        load r8:ref, r12:ref
+        call std::fiber::spawn
+        load r13:u64, r0:u64
+        call std::fiber::yield
+        load r8:u64, 2u64
+        call std::io::println_u64
+        getstr r1:u64, \"crab\"
+        load r8:u64, r11:u64
+        load r9:ref, r1:ref
+        call std::io::write
+        load r8:u64, r13:u64
+        call std::fiber::join
+        load r14:u64, r1:u64
+        load r8:ref, r0:ref
+        call std::io::println_string
+        load r8:u64, r14:u64
+        call std::io::println_u64
+        ret
+.end
+", READER);
+    let (result, output) = run_main(&source);
+    result.expect("main runs");
+    // The reader started reading before anything was written, and got all of it.
+    assert_eq!(output, ["1", "2", "crab", "0"]);
+}
+
+#[test]
+fn closing_a_file_descriptor_wakes_the_fibers_parked_on_it() {
+    let source = format!("{}
+.function main
+        call std::io::pipe
+        load r10:u64, r0:u64
+        mkclosure r12:ref, reader, r10:u64
+        load r8:ref, r12:ref
+        call std::fiber::spawn
+        load r13:u64, r0:u64
+        call std::fiber::yield
+        load r8:u64, r10:u64
+        call std::io::close
+        load r8:u64, r13:u64
+        call std::fiber::join
+        load r14:u64, r1:u64
+        load r8:ref, r0:ref
+        call std::io::println_string
+        load r8:u64, r14:u64
+        call std::io::println_u64
+        ret
+.end
+", READER);
+    let (result, output) = run_main(&source);
+    result.expect("main runs");
+    // The reader tries again after the close and faults on the closed file descriptor.
+    assert_eq!(output.len(), 3, "{:?}", output);
+    assert!(output[1].starts_with("IoError"), "{:?}", output);
+    assert_eq!(output[2], "1");
+}
+
+#[test]
+fn read_at_the_end_of_the_file_returns_no_bytes() {
+    let (result, output) = run_main("
+.function main
+        call std::io::pipe
+        load r10:u64, r0:u64
+        load r8:u64, r1:u64
+        call std::io::close
+        load r8:u64, r10:u64
+        load r9:u64, 16u64
+        call std::io::read
+        load r8:u64, r1:u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["0"]);
+}
@@ -0,0 +1,69 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::repl::Repl;
+use common::get_natives;
+
+
+const SOURCE: &str = "
+.function add
+        add r8:i64, r9:i64
+        load r0:i64, r8:i64
+        ret
+.end
+
+.function first
+        load r0:u64, r8:u64
+        ret
+.end
+";
+
+fn new_repl() -> Repl {
+    let mut repl = Repl::new().expect("the repl starts");
+    repl.load_module(assemble(SOURCE, "test.cfasm", &get_natives()).expect("the source assembles")).expect("the module loads");
+    repl
+}
+
+/// Runs `line` as a meta command and returns what it printed.
+fn run(repl: &mut Repl, line: &str) -> Result<String, String> {
+    let mut output = String::new();
+    repl.run_meta_command(line, &mut output)?;
+    Ok(output)
+}
+
+/// The strings on the heap, as `:heap` describes them.
+fn heap_strings(repl: &mut Repl) -> Vec<String> {
+    let heap = run(repl, ":heap").expect(":heap runs");
+    heap.lines().filter_map(|line| line.split_once(": string ").map(|(_, string)| string.to_string())).collect()
+}
+
+#[test]
+fn call_passes_numbers_with_their_type_suffix() {
+    let mut repl = new_repl();
+    assert_eq!(run(&mut repl, ":call add 40i64 2i64").expect(":call runs"), "r0 = I64(42)\n");
+    assert_eq!(run(&mut repl, ":call   add\t-1i64   3i64  ").expect(":call runs"), "r0 = I64(2)\n");
+}
+
+#[test]
+fn call_passes_quoted_strings_with_spaces_as_one_argument() {
+    let mut repl = new_repl();
+    run(&mut repl, ":call first \"hello crab world\" 7u64").expect(":call runs");
+    assert_eq!(heap_strings(&mut repl), ["\"hello crab world\""]);
+
+    let mut repl = new_repl();
+    run(&mut repl, r#":call first "say \"hi\" \\ bye""#).expect(":call runs");
+    assert_eq!(heap_strings(&mut repl), [r#""say \"hi\" \\ bye""#]);
+}
+
+#[test]
+fn call_rejects_malformed_arguments() {
+    let mut repl = new_repl();
+    let error = run(&mut repl, ":call first \"never closed").expect_err("the string is unterminated");
+    assert!(error.contains("unterminated string"), "{}", error);
+    let error = run(&mut repl, ":call first \"text\"more").expect_err("the string runs into a word");
+    assert!(error.contains("expected a space"), "{}", error);
+    let error = run(&mut repl, ":call first 5").expect_err("the number has no suffix");
+    assert!(error.contains("invalid argument 5"), "{}", error);
+    let error = run(&mut repl, ":call").expect_err("there is no function");
+    assert!(error.contains("needs a function"), "{}", error);
+}
@@ -0,0 +1,51 @@
+use crayfish_vm2::machine::Fault;
+use crayfish_vm2::memory::slab::{ShardedSlab, Slab};
+use crayfish_vm2::memory::Memory;
+use crayfish_vm2::program::Module;
+
+
+#[test]
+fn freed_handle_faults_after_its_slot_is_reused() {
+    let mut slab = Slab::new();
+    let old = slab.insert("old");
+    slab.remove(old).expect("the value is there");
+    let new = slab.insert("new");
+
+    assert_eq!(Slab::<&str>::get_slot_index(new), Slab::<&str>::get_slot_index(old));
+    assert_ne!(new, old);
+    assert!(matches!(slab.get(old), Err(Fault::UseAfterFree(handle)) if handle == old));
+    assert!(matches!(slab.remove(old), Err(Fault::UseAfterFree(_))));
+    assert_eq!(slab.get(new).copied().expect("the new value is there"), "new");
+}
+
+#[test]
+fn null_handle_is_never_valid() {
+    let mut slab = Slab::new();
+    slab.insert(1);
+    assert!(matches!(slab.get(0), Err(Fault::InvalidReference)));
+}
+
+#[test]
+fn sharded_handle_faults_after_its_slot_is_reused() {
+    let slab = ShardedSlab::new();
+    let old = slab.insert(1).expect("inserting works");
+    slab.remove(old).expect("the value is there");
+    let new = slab.insert(2).expect("inserting works");
+
+    assert_ne!(new, old);
+    assert!(matches!(slab.with(old, |value| *value), Err(Fault::UseAfterFree(_))));
+    assert_eq!(slab.with(new, |value| *value).expect("the new value is there"), 2);
+}
+
+#[test]
+fn collected_string_faults_after_its_slot_is_reused() {
+    let module = Module::default();
+    let mut memory = Memory::new();
+    let old = memory.allocate_string("old").expect("allocating works").get_reference().expect("a reference");
+    assert_eq!(memory.collect(Vec::new()).expect("collecting works"), 1);
+    let new = memory.allocate_string("new").expect("allocating works").get_reference().expect("a reference");
+
+    assert_ne!(new, old);
+    assert!(matches!(memory.get_string(old, &module), Err(Fault::UseAfterFree(_))));
+    assert_eq!(memory.get_string(new, &module).expect("the new string is there"), "new");
+}
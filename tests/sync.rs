@@ -0,0 +1,268 @@
+mod common;
+
+use crayfish_vm2::machine::Fault;
+use common::run_main;
+
+
+fn run_fault(source: &str) -> Fault {
+    let (result, _) = run_main(source);
+    result.expect_err("main faults").get_fault().clone()
+}
+
+#[test]
+fn unlocking_a_mutex_that_is_not_locked_faults() {
+    let fault = run_fault("
+.function main
+        call std::sync::new_mutex
+        load r8:u64, r0:u64
+        call std::sync::unlock
+        ret
+.end
+");
+    assert!(matches!(fault, Fault::MutexNotOwned), "{:?}", fault);
+}
+
+#[test]
+fn locking_a_mutex_twice_faults() {
+    let fault = run_fault("
+.function main
+        call std::sync::new_mutex
+        load r10:u64, r0:u64
+        load r8:u64, r10:u64
+        call std::sync::lock
+        load r8:u64, r10:u64
+        call std::sync::lock
+        ret
+.end
+");
+    assert!(matches!(fault, Fault::MutexAlreadyOwned), "{:?}", fault);
+}
+
+#[test]
+fn blocking_with_no_other_thread_to_wake_it_faults() {
+    let fault = run_fault("
+.function lock_and_return
+        getcap r8:u64, 0u64
+        call std::sync::lock
+        ret
+.end
+
+.function main
+        call std::sync::new_mutex
+        load r10:u64, r0:u64
+        mkclosure r11:ref, lock_and_return, r10:u64
+        load r8:ref, r11:ref
+        call std::thread::spawn
+        load r8:u64, r0:u64
+        call std::thread::join
+        load r8:u64, r10:u64
+        call std::sync::lock
+        ret
+.end
+");
+    assert!(matches!(fault, Fault::Deadlock), "{:?}", fault);
+    let fault = run_fault("
+.function main
+        load r8:u64, 0u64
+        call std::sync::new_semaphore
+        load r8:u64, r0:u64
+        call std::sync::acquire
+        ret
+.end
+");
+    assert!(matches!(fault, Fault::Deadlock), "{:?}", fault);
+}
+
+#[test]
+fn condvar_wakes_a_waiting_thread() {
+    let (result, output) = run_main("
+; captures: 0 = mutex, 1 = condvar, 2 = list holding the flag
+.function wait_for_flag
+        getcap r10:u64, 0u64
+        getcap r11:u64, 1u64
+        getcap r12:u64, 2u64
+        load r8:u64, r10:u64
+        call std::sync::lock
+check:  listget r0:u64, r12:u64, 0u64
+        cmp r0:u64, 0u64, eq
+        goto done, ne
+        load r8:u64, r11:u64
+        load r9:u64, r10:u64
+        call std::sync::wait
+        goto check
+done:   load r13:u64, r0:u64
+        load r8:u64, r10:u64
+        call std::sync::unlock
+        load r0:u64, r13:u64
+        ret
+.end
+
+.function main
+        call std::sync::new_mutex
+        load r10:u64, r0:u64
+        call std::sync::new_condvar
+        load r11:u64, r0:u64
+        newlist r12:ref, 1u64
+        mkclosure r13:ref, wait_for_flag, r10:u64, r11:u64, r12:u64
+        load r8:ref, r13:ref
+        call std::thread::spawn
+        load r14:u64, r0:u64
+        load r8:u64, r10:u64
+        call std::sync::lock
+        listset r12:u64, 0u64, 7u64
+        load r8:u64, r11:u64
+        call std::sync::notify_one
+        load r8:u64, r10:u64
+        call std::sync::unlock
+        load r8:u64, r14:u64
+        call std::thread::join
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["7"]);
+}
+
+#[test]
+fn semaphore_blocks_until_a_permit_is_released() {
+    let (result, output) = run_main("
+.function release
+        getcap r8:u64, 0u64
+        call std::sync::release
+        load r0:u64, 1u64
+        ret
+.end
+
+.function main
+        load r8:u64, 1u64
+        call std::sync::new_semaphore
+        load r10:u64, r0:u64
+        load r8:u64, r10:u64
+        call std::sync::acquire
+        mkclosure r11:ref, release, r10:u64
+        load r8:ref, r11:ref
+        call std::thread::spawn
+        load r12:u64, r0:u64
+        load r8:u64, r10:u64
+        call std::sync::acquire
+        load r8:u64, r12:u64
+        call std::thread::join
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["1"]);
+}
+
+#[test]
+fn channel_delivers_in_order_until_closed() {
+    let source = "
+.function main
+        call std::sync::new_channel
+        load r10:u64, r0:u64
+        load r8:u64, r10:u64
+        load r9:u64, 1u64
+        call std::sync::send
+        load r8:u64, r10:u64
+        load r9:u64, 2u64
+        call std::sync::send
+        load r8:u64, r10:u64
+        call std::sync::close
+loop:   load r8:u64, r10:u64
+        call std::sync::receive
+        cmp r1:u64, 0u64, eq
+        goto closed, ne
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        goto loop
+closed: load r8:u64, r1:u64
+        call std::io::println_u64
+        load r8:u64, r10:u64
+        load r9:u64, 3u64
+        call std::sync::send
+        ret
+.end
+";
+    let (result, output) = run_main(source);
+    let error = result.expect_err("sending on a closed channel faults");
+    assert!(matches!(error.get_fault(), Fault::ChannelClosed), "{:?}", error);
+    // The two values, then r1 is 1 because the channel is closed and empty.
+    assert_eq!(output, ["1", "2", "1"]);
+}
+
+#[test]
+fn atomic_instructions_update_cells_and_lists() {
+    let (result, output) = run_main("
+.function main
+        load r8:u64, 1u64
+        call std::sync::cells
+        load r10:u64, r0:u64
+        fetchadd r8:u64, r10:u64, 0u64, 5u64
+        call std::io::println_u64
+        xchg r8:u64, r10:u64, 0u64, 9u64
+        call std::io::println_u64
+        cas r8:u64, r10:u64, 0u64, 4u64, 1u64
+        goto failed, ne
+        call std::io::println_u64
+failed: cas r8:u64, r10:u64, 0u64, 9u64, 2u64
+        goto swapped, eq
+        ret
+swapped:
+        call std::io::println_u64
+        newlist r11:ref, 2u64
+        fetchadd r8:u64, r11:u64, 1u64, 3u64
+        fetchadd r8:u64, r11:u64, 1u64, 3u64
+        call std::io::println_u64
+        fetchadd r8:u64, r10:u64, 0u64, 0u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["0", "5", "9", "3", "2"]);
+}
+
+#[test]
+fn fetch_add_from_eight_threads_loses_no_updates() {
+    let (result, output) = run_main("
+.function count
+        getcap r10:u64, 0u64
+        load r11:u64, 0u64
+loop:   fetchadd r1:u64, r10:u64, 0u64, 1u64
+        add r11:u64, 1u64
+        cmp r11:u64, 1000u64, lt
+        goto loop, lt
+        ret
+.end
+
+.function main
+        load r8:u64, 1u64
+        call std::sync::cells
+        load r10:u64, r0:u64
+        mkclosure r11:ref, count, r10:u64
+        newlist r12:ref, 8u64
+        load r13:u64, 0u64
+spawn:  load r8:ref, r11:ref
+        call std::thread::spawn
+        listset r12:u64, r13:u64, r0:u64
+        add r13:u64, 1u64
+        cmp r13:u64, 8u64, lt
+        goto spawn, lt
+        load r13:u64, 0u64
+join:   listget r8:u64, r12:u64, r13:u64
+        call std::thread::join
+        add r13:u64, 1u64
+        cmp r13:u64, 8u64, lt
+        goto join, lt
+        fetchadd r8:u64, r10:u64, 0u64, 0u64
+        call std::io::println_u64
+        ret
+.end
+");
+    result.expect("main runs");
+    assert_eq!(output, ["8000"]);
+}
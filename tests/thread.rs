@@ -0,0 +1,90 @@
+mod common;
+
+use crayfish_vm2::machine::Fault;
+use common::run_main;
+
+
+const FUNCTIONS: &str = "
+.function sum_to
+        load r0:u64, 0u64
+        getcap r1:u64, 0u64
+loop:   add r0:u64, r8:u64
+        sub r8:u64, 1u64
+        cmp r8:u64, 0u64, gt
+        goto loop, gt
+        add r0:u64, r1:u64
+        ret
+.end
+
+.function bad
+        load r1:u64, 0u64
+        accessobj r0:ref, r1:ref, 0u64
+        ret
+.end
+";
+
+#[test]
+fn joined_thread_returns_its_result_with_status_0() {
+    let source = format!("{}
+.function main
+        mkclosure r10:ref, sum_to, 1000u64
+        load r8:ref, r10:ref
+        load r9:u64, 100u64
+        call std::thread::spawn
+        load r8:u64, r0:u64
+        call std::thread::join
+        load r10:u64, r1:u64
+        load r8:u64, r0:u64
+        call std::io::println_u64
+        load r8:u64, r10:u64
+        call std::io::println_u64
+        ret
+.end
+", FUNCTIONS);
+    let (result, output) = run_main(&source);
+    result.expect("main runs");
+    assert_eq!(output, ["6050", "0"]);
+}
+
+#[test]
+fn joined_thread_that_faulted_returns_the_fault_with_status_1() {
+    let source = format!("{}
+.function main
+        mkclosure r10:ref, bad
+        load r8:ref, r10:ref
+        call std::thread::spawn
+        load r8:u64, r0:u64
+        call std::thread::join
+        load r10:u64, r1:u64
+        load r8:ref, r0:ref
+        call std::io::println_string
+        load r8:u64, r10:u64
+        call std::io::println_u64
+        ret
+.end
+", FUNCTIONS);
+    let (result, output) = run_main(&source);
+    result.expect("main runs");
+    assert_eq!(output, ["NullPointerReference", "1"]);
+}
+
+#[test]
+fn joining_a_thread_twice_faults() {
+    let source = format!("{}
+.function main
+        mkclosure r10:ref, sum_to, 0u64
+        load r8:ref, r10:ref
+        load r9:u64, 1u64
+        call std::thread::spawn
+        load r11:u64, r0:u64
+        load r8:u64, r11:u64
+        call std::thread::join
+        load r8:u64, r11:u64
+        call std::thread::join
+        ret
+.end
+", FUNCTIONS);
+    let (result, _) = run_main(&source);
+    let error = result.expect_err("the second join faults");
+    assert!(matches!(error.get_fault(), Fault::ThreadAlreadyJoined(_)), "{:?}", error);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::instruction::{CallTarget, Condition, Immediate, Instruction, JumpTarget, RealInstruction, RegisterType, Source, Target};
+use crayfish_vm2::program::verifier::{verify_function, verify_module};
+use crayfish_vm2::program::Module;
+use crayfish_vm2::stack_frame::REGISTER_COUNT;
+use common::{get_natives, PROGRAM};
+
+
+fn get_program() -> Module {
+    assemble(PROGRAM, "program.cfasm", &get_natives()).expect("the program assembles")
+}
+
+/// Verifies `instructions` followed by a return against the program and returns the messages.
+fn verify(instructions: Vec<RealInstruction>) -> Vec<String> {
+    let mut instructions = instructions.into_iter().map(Instruction::new_without_metadata).collect::<Vec<_>>();
+    instructions.push(Instruction::new_without_metadata(RealInstruction::Return(Condition::Always)));
+    verify_function(&get_program(), &"checked".into(), &instructions)
+        .into_iter()
+        .map(|diagnostic| diagnostic.message)
+        .collect()
+}
+
+fn assert_reports(messages: Vec<String>, expected: &str) {
+    assert_eq!(messages.len(), 1, "{:?}", messages);
+    assert!(messages[0].contains(expected), "{:?} doesn't mention {:?}", messages[0], expected);
+}
+
+#[test]
+fn program_is_valid() {
+    let diagnostics = verify_module(&get_program());
+    assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+}
+
+#[test]
+fn empty_function() {
+    let diagnostics = verify_function(&get_program(), &"checked".into(), &[]);
+    assert_reports(diagnostics.into_iter().map(|diagnostic| diagnostic.message).collect(), "no instructions");
+}
+
+#[test]
+fn missing_register() {
+    let load = RealInstruction::Load(Target(REGISTER_COUNT, RegisterType::U64), Source::Immediate(Immediate::U64(1)));
+    assert_reports(verify(vec![load]), &format!("register r{} does not exist", REGISTER_COUNT));
+}
+
+#[test]
+fn unresolved_label() {
+    let goto = RealInstruction::Goto(JumpTarget::Label("nowhere".into()), Condition::Zero);
+    assert_reports(verify(vec![goto]), "unresolved label nowhere");
+}
+
+#[test]
+fn jump_outside_of_the_function() {
+    let goto = RealInstruction::Goto(JumpTarget::Absolute(10), Condition::Zero);
+    assert_reports(verify(vec![goto]), "outside of the function");
+}
+
+#[test]
+fn missing_call_target() {
+    let call = RealInstruction::Call(CallTarget::Label("math::missing".into()), Condition::Always);
+    assert_reports(verify(vec![call]), "call target math::missing does not exist");
+}
+
+#[test]
+fn missing_closure_function() {
+    let closure = RealInstruction::CreateClosure(Target(1, RegisterType::Reference), "missing".into(), Vec::new());
+    assert_reports(verify(vec![closure]), "closure function missing does not exist");
+}
+
+#[test]
+fn missing_class() {
+    let object = RealInstruction::CreateObject(Target(1, RegisterType::Reference), "Missing".into());
+    assert_reports(verify(vec![object]), "class Missing does not exist");
+}
+
+#[test]
+fn missing_string() {
+    let string = RealInstruction::GetStringRef(Target(1, RegisterType::U64), "math".into(), 5);
+    assert_reports(verify(vec![string]), "string math::5 does not exist");
+}
+
+#[test]
+fn running_past_the_end() {
+    let instructions = [RealInstruction::NoOp, RealInstruction::Return(Condition::Zero)]
+        .into_iter()
+        .map(Instruction::new_without_metadata)
+        .collect::<Vec<_>>();
+    let diagnostics = verify_function(&get_program(), &"checked".into(), &instructions);
+    assert_reports(diagnostics.iter().map(|diagnostic| diagnostic.message.clone()).collect(), "run past the end");
+    assert_eq!(diagnostics[0].index, 1);
+}
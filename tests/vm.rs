@@ -0,0 +1,83 @@
+mod common;
+
+use crayfish_vm2::assembly::assembler::assemble;
+use crayfish_vm2::machine::Fault;
+use crayfish_vm2::Vm;
+use common::{get_natives, PROGRAM};
+
+
+const FUNCTIONS: &str = "
+.function mix
+        load r0:f64, r9:f64
+        mul r0:f64, 2.0f64
+        add r0:f64, 0.5f64
+        ret
+.end
+
+.function identity
+        load r0:ref, r8:ref
+        ret
+.end
+
+.function outer
+        call inner
+        ret
+.end
+
+.function inner
+        div r8:u64, 0u64
+        ret
+.end
+";
+
+fn build(source: &str) -> Vm {
+    let module = assemble(source, "test.cfasm", &get_natives()).expect("the source assembles");
+    Vm::builder(module).build().expect("the module verifies")
+}
+
+#[test]
+fn typed_arguments_and_result() {
+    let mut vm = build(FUNCTIONS);
+    let result: f64 = vm.call("mix", (7i64, 1.25f64)).expect("mix runs");
+    assert_eq!(result, 3.0);
+}
+
+#[test]
+fn strings_and_lists_are_passed_by_reference() {
+    let mut vm = build(FUNCTIONS);
+    let string: String = vm.call("identity", ("crab".to_string(),)).expect("identity runs");
+    assert_eq!(string, "crab");
+    let list: Vec<i32> = vm.call("identity", (vec![1i32, -2, 3],)).expect("identity runs");
+    assert_eq!(list, [1, -2, 3]);
+}
+
+#[test]
+fn program_calls_into_sub_modules() {
+    let mut vm = build(PROGRAM);
+    assert_eq!(vm.call::<u64>("main", ()).expect("main runs"), 97);
+    assert_eq!(vm.call::<u64>("math::sum_to", (4u64,)).expect("sum_to runs"), 10);
+}
+
+#[test]
+fn result_of_the_wrong_type_is_a_fault() {
+    let mut vm = build(FUNCTIONS);
+    let error = vm.call::<String>("identity", (5u64,)).expect_err("5 isn't a string");
+    assert!(matches!(error.get_fault(), Fault::InvalidReference), "{:?}", error);
+    let error = vm.call::<u64>("identity", ("crab".to_string(),)).expect_err("a string isn't a number");
+    assert!(matches!(error.get_fault(), Fault::InvalidOperation(_)), "{:?}", error);
+}
+
+#[test]
+fn missing_function_is_a_fault() {
+    let mut vm = build(FUNCTIONS);
+    let error = vm.call::<()>("missing", ()).expect_err("there is no such function");
+    assert!(matches!(error.get_fault(), Fault::FunctionNotFound(_)), "{:?}", error);
+}
+
+#[test]
+fn fault_comes_with_a_backtrace() {
+    let mut vm = build(FUNCTIONS);
+    let error = vm.call::<()>("outer", (1u64,)).expect_err("dividing by zero faults");
+    let backtrace = error.get_backtrace().to_string();
+    assert!(backtrace.contains("outer") && backtrace.contains("inner"), "{}", backtrace);
+}